@@ -0,0 +1,123 @@
+//! Allocations and time of a many-file run, with and without reusing the
+//! detrend and template buffers across files.
+//!
+//! Each simulated file has three channels, processed as the binary does:
+//! detrended, then its sample entropy computed with r = 0.2 times the
+//! standard deviation, at the default m = 2 and at m = 3. Run with
+//! `cargo bench --bench work_buffer`.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use sample_entropy::stats::{self, TemplateBuffer};
+
+/// Counts every allocation and reallocation made through it.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const NUM_FILES: usize = 300;
+const CHANNEL_LEN: usize = 2000;
+
+/// A pressure-like wave with pseudo-random noise, different for every seed.
+fn channel(seed: usize) -> Vec<f32> {
+    let mut state = (seed as u64)
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    (0..CHANNEL_LEN)
+        .map(|t| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let noise = (state >> 40) as f32 / (1u64 << 24) as f32;
+            90.0 + 20.0 * (t as f32 * 0.13).sin() + 0.01 * t as f32 + 4.0 * noise
+        })
+        .collect()
+}
+
+/// Runs the batch, returning the allocations, bytes allocated and time.
+fn run(files: &[[Vec<f32>; 3]], m: usize, reuse: bool) -> (usize, usize, Duration) {
+    let mut preprocessed: Vec<f32> = Vec::new();
+    let mut templates = TemplateBuffer::default();
+    let (allocations, bytes) = (
+        ALLOCATIONS.load(Ordering::Relaxed),
+        BYTES.load(Ordering::Relaxed),
+    );
+    let start = Instant::now();
+    let mut total = 0.0;
+    for file in files {
+        for data in file {
+            let entropy = if reuse {
+                stats::detrend_into(data, &mut preprocessed);
+                let r = 0.2 * stats::standard_deviation(&preprocessed);
+                stats::sample_entropy_with_buffer(m, r, &preprocessed, &mut templates)
+            } else {
+                let preprocessed = stats::detrend_data(data);
+                let r = 0.2 * stats::standard_deviation(&preprocessed);
+                stats::sample_entropy(m, r, &preprocessed)
+            };
+            total += entropy;
+        }
+    }
+    let elapsed = start.elapsed();
+    std::hint::black_box(total);
+    (
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        BYTES.load(Ordering::Relaxed) - bytes,
+        elapsed,
+    )
+}
+
+fn main() {
+    // `cargo bench` passes --bench. Under `cargo test` only a few files are
+    // run, to check that the bench still works.
+    let num_files = if std::env::args().any(|arg| arg == "--bench") {
+        NUM_FILES
+    } else {
+        3
+    };
+    let files: Vec<[Vec<f32>; 3]> = (0..num_files)
+        .map(|i| [channel(3 * i), channel(3 * i + 1), channel(3 * i + 2)])
+        .collect();
+    // Warms up the caches and the allocator before timing either.
+    run(&files[..num_files.min(10)], 2, false);
+    println!(
+        "{} files of 3 channels of {} samples",
+        num_files, CHANNEL_LEN
+    );
+    for m in [2, 3] {
+        for (label, reuse) in [("fresh buffers", false), ("reused buffers", true)] {
+            let (allocations, bytes, elapsed) = run(&files, m, reuse);
+            println!(
+                "m = {}, {:>14}: {:>8} allocations, {:>6.1} MiB, {:>8.1?}",
+                m,
+                label,
+                allocations,
+                bytes as f64 / (1 << 20) as f64,
+                elapsed
+            );
+        }
+    }
+}
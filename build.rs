@@ -0,0 +1,28 @@
+//! Records the git commit the crate is built from, for `provenance`.
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    // Rebuild when the checked out commit changes, not on every build.
+    println!("cargo:rerun-if-changed=build.rs");
+    if let Ok(head) = std::fs::read_to_string(".git/HEAD") {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            let reference = Path::new(".git").join(reference);
+            if reference.exists() {
+                println!("cargo:rerun-if-changed={}", reference.display());
+            }
+            println!("cargo:rerun-if-changed=.git/packed-refs");
+        }
+    }
+    // Builds from a source archive, or without git installed, have no hash.
+    let output = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output();
+    if let Ok(output) = output {
+        let hash = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if output.status.success() && !hash.is_empty() {
+            println!("cargo:rustc-env=SAMPLE_ENTROPY_GIT_HASH={}", hash);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Measures where `MatchStrategy::Auto` should switch from brute force to
+//! sorting, on this machine.
+//!
+//! Brute force has no setup cost, so it wins on few templates, and sorting
+//! wins on many; where they cross depends on the cpu and its caches. The
+//! crossover is measured once and cached in a file, so that later runs only
+//! read it. The cache records the build that measured it (see
+//! `provenance::provenance`), and a different build measures again.
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::error::SampenError;
+use crate::interrupt::Interrupt;
+use crate::matching::{self, MatchStrategy};
+use crate::provenance::provenance;
+use crate::rng::SplitMix64;
+use crate::stats::Tolerance;
+
+/// The numbers of templates timed, in increasing order.
+const SIZES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+
+/// How long each strategy is run at each size, repeating it as often as
+/// fits, so that timer resolution doesn't decide the result.
+const MIN_TIMING: Duration = Duration::from_millis(2);
+
+/// Measures the number of templates from which sorting beats brute force.
+///
+/// Both strategies count the matches of length-2 templates of uniform noise
+/// with the default tolerance, at each of 16 to 1024 templates. The result
+/// is the smallest of those sizes from which sorting is faster at every
+/// larger size, or 2048 if brute force was faster even at 1024. Timing
+/// varies a little between calls, by about one size either way.
+pub fn calibrate_brute_force_threshold() -> usize {
+    let m = 2;
+    let noise = SplitMix64::new(0).uniform_vec(SIZES[SIZES.len() - 1] + m - 1);
+    let r = Tolerance::default().resolve(&noise);
+    let mut threshold = 2 * SIZES[SIZES.len() - 1];
+    for size in SIZES.iter().rev() {
+        let data = &noise[..size + m - 1];
+        if time_per_count(MatchStrategy::Sorted, data, m, r)
+            < time_per_count(MatchStrategy::BruteForce, data, m, r)
+        {
+            threshold = *size;
+        } else {
+            break;
+        }
+    }
+    threshold
+}
+
+/// The average time one match count of the data took with the strategy.
+fn time_per_count(strategy: MatchStrategy, data: &[f32], m: usize, r: f32) -> Duration {
+    let mut scratch = matching::MatchScratch::default();
+    let interrupt = Interrupt::default();
+    let start = Instant::now();
+    let mut reps: u32 = 0;
+    while reps == 0 || start.elapsed() < MIN_TIMING {
+        let counts = matching::count_match_pairs(strategy, data, m, r, &mut scratch, &interrupt);
+        // Keeps the computation from being optimized away.
+        std::hint::black_box(counts).ok();
+        reps += 1;
+    }
+    start.elapsed() / reps
+}
+
+/// The file the crossover is cached in:
+/// `sample_entropy/brute_force_threshold` under `$XDG_CACHE_HOME`, else
+/// `%LOCALAPPDATA%`, else `~/.cache`, else the temporary directory.
+pub fn default_cache_path() -> PathBuf {
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .or_else(|| std::env::var_os("LOCALAPPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir);
+    cache_dir
+        .join("sample_entropy")
+        .join("brute_force_threshold")
+}
+
+/// Returns the crossover cached at `path`, or measures it with
+/// `calibrate_brute_force_threshold` and caches it there if there is none,
+/// it was measured by another build, or `recalibrate` is set.
+///
+/// The caller decides whether to use it, with
+/// `matching::set_brute_force_threshold`. Fails only if the cache can't be
+/// written.
+///
+/// # Arguments
+/// * `path` - the cache file, e.g. `default_cache_path()`.
+/// * `recalibrate` - whether to measure again even if a crossover is cached.
+///
+pub fn load_or_calibrate(path: &Path, recalibrate: bool) -> Result<usize, SampenError> {
+    if !recalibrate {
+        if let Some(threshold) = read_cache(path) {
+            return Ok(threshold);
+        }
+    }
+    let threshold = calibrate_brute_force_threshold();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, format!("{}\n{}\n", provenance(), threshold))?;
+    Ok(threshold)
+}
+
+/// Reads a crossover cached by this build, or `None` if there isn't one.
+fn read_cache(path: &Path) -> Option<usize> {
+    let cache = std::fs::read_to_string(path).ok()?;
+    let mut lines = cache.lines();
+    if lines.next()? != provenance() {
+        return None;
+    }
+    lines
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+        .filter(|threshold| *threshold > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibration_is_cached() {
+        let threshold = calibrate_brute_force_threshold();
+        assert!(threshold >= SIZES[0] && threshold <= 2 * SIZES[SIZES.len() - 1]);
+        assert!(threshold.is_power_of_two());
+
+        let dir = std::env::temp_dir().join(format!("sampen_calibration_{}", std::process::id()));
+        let path = dir.join("nested").join("threshold");
+        let measured = load_or_calibrate(&path, false).unwrap();
+        assert!(measured.is_power_of_two());
+        // A cached crossover is read back rather than measured again.
+        std::fs::write(&path, format!("{}\n77\n", provenance())).unwrap();
+        let cached = load_or_calibrate(&path, false).unwrap();
+        let recalibrated = load_or_calibrate(&path, true).unwrap();
+        std::fs::write(&path, "sample_entropy 0.0.0\n77\n").unwrap();
+        let stale = load_or_calibrate(&path, false).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(77, cached);
+        assert_ne!(77, recalibrated);
+        assert_ne!(77, stale);
+    }
+}
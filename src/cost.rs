@@ -0,0 +1,146 @@
+//! Estimates of how long a batch of files will take, before computing it.
+//!
+//! Counting the matches of a series of n samples compares up to n²/2 pairs
+//! of templates, so a handful of long recordings can dominate a batch. The
+//! estimate counts those comparisons and converts them to time with a short
+//! calibration run on this machine.
+use std::time::{Duration, Instant};
+
+use crate::matching::MatchStrategy;
+use crate::rng::SplitMix64;
+use crate::stats;
+use crate::vital_file::VitalFile;
+
+/// The length of the noise series timed to calibrate the estimate, long
+/// enough to take a few milliseconds and short enough not to be noticed.
+const CALIBRATION_LEN: usize = 3000;
+
+/// The estimated work of one file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileCost {
+    pub name: String,
+    /// The number of template pairs compared, summed over the channels.
+    pub comparisons: u64,
+    /// The time the comparisons are expected to take on one thread.
+    pub estimated_time: Duration,
+}
+
+/// The estimated work of a batch of files. See `estimate_cost`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostEstimate {
+    /// One entry per file, in the order given.
+    pub per_file: Vec<FileCost>,
+    /// The sum of the files' comparisons.
+    pub total_comparisons: u64,
+    /// The sum of the files' estimated times, on one thread.
+    pub total_time: Duration,
+}
+
+/// The number of pairs of length-m templates in a series of `len` samples,
+/// T(T-1)/2 for its T = len - m + 1 templates.
+pub fn num_comparisons(len: usize, m: usize) -> u64 {
+    let num_templates = (len + 1).saturating_sub(m) as u64;
+    num_templates * num_templates.saturating_sub(1) / 2
+}
+
+/// Estimates the work of computing the sample entropy of every channel of
+/// the files.
+///
+/// The comparisons are those of a brute force search, every pair of
+/// templates; the pruning strategies `MatchStrategy::Auto` picks compare
+/// fewer, so the time is an upper bound, and on smooth signals often a
+/// generous one. It is calibrated by timing a brute force count on a
+/// uniform noise series, so it reflects this machine at the moment of the
+/// call and varies a little between calls. Divide by the number of threads
+/// for a parallel run.
+///
+/// # Arguments
+/// * `files` - the files to be processed.
+/// * `m` - the smaller of the two template sizes.
+///
+pub fn estimate_cost(files: &[VitalFile], m: usize) -> CostEstimate {
+    estimate_cost_of(files, m)
+}
+
+/// Estimates the work of the files like `estimate_cost`, for files not held
+/// in a single slice.
+///
+/// # Arguments
+/// * `files` - the files to be processed.
+/// * `m` - the smaller of the two template sizes.
+///
+pub fn estimate_cost_of<'a>(
+    files: impl IntoIterator<Item = &'a VitalFile>,
+    m: usize,
+) -> CostEstimate {
+    let seconds_per_comparison = calibrate(m);
+    let per_file: Vec<FileCost> = files
+        .into_iter()
+        .map(|file| {
+            let comparisons: u64 = file
+                .channels()
+                .iter()
+                .map(|(_, data)| num_comparisons(data.len(), m))
+                .sum();
+            FileCost {
+                name: file.name.clone(),
+                comparisons,
+                estimated_time: Duration::from_secs_f64(
+                    comparisons as f64 * seconds_per_comparison,
+                ),
+            }
+        })
+        .collect();
+    CostEstimate {
+        total_comparisons: per_file.iter().map(|file| file.comparisons).sum(),
+        total_time: per_file.iter().map(|file| file.estimated_time).sum(),
+        per_file,
+    }
+}
+
+/// Times a brute force match count on uniform noise, returning the seconds
+/// each comparison took.
+fn calibrate(m: usize) -> f64 {
+    let noise = SplitMix64::new(0).uniform_vec(CALIBRATION_LEN);
+    let start = Instant::now();
+    let entropy = stats::sample_entropy_with_strategy(m, 0.05, &noise, MatchStrategy::BruteForce);
+    let elapsed = start.elapsed();
+    // Keeps the computation from being optimized away.
+    std::hint::black_box(entropy);
+    elapsed.as_secs_f64() / num_comparisons(CALIBRATION_LEN, m).max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vital_file::Channel;
+
+    fn file(len: usize) -> VitalFile {
+        let channel = || Channel::new((0..len).map(|x| (x % 7) as f32).collect());
+        VitalFile {
+            name: format!("len_{}", len),
+            sample_rate: None,
+            sbp: channel(),
+            mbp: channel(),
+            dbp: channel(),
+        }
+    }
+
+    #[test]
+    fn test_estimate_is_monotonic_in_length() {
+        let files: Vec<VitalFile> = [10, 100, 1000, 10000].into_iter().map(file).collect();
+        let estimate = estimate_cost(&files, 2);
+        assert_eq!(4, estimate.per_file.len());
+        // Three channels of 9 templates each.
+        assert_eq!(3 * 36, estimate.per_file[0].comparisons);
+        for pair in estimate.per_file.windows(2) {
+            assert!(pair[0].comparisons < pair[1].comparisons);
+            assert!(pair[0].estimated_time <= pair[1].estimated_time);
+        }
+        assert_eq!(
+            estimate.per_file.iter().map(|f| f.comparisons).sum::<u64>(),
+            estimate.total_comparisons
+        );
+        assert_eq!(0, num_comparisons(2, 2));
+    }
+}
@@ -0,0 +1,150 @@
+//! A template-level record of a sample entropy computation, for checking it
+//! against other implementations.
+//!
+//! Two tools that disagree on an entropy usually disagree on something
+//! smaller: which templates exist, whether the last one is counted, or
+//! whether a pair on the edge of `r` matches. `dump_matching` lists every
+//! template with the number of others it matches, so the two can be compared
+//! template by template, e.g. against the `B_i` and `A_i` of Richman and
+//! Moorman's formulation. The dump serializes with serde, to JSON or any
+//! other format.
+use serde::{Deserialize, Serialize};
+
+use crate::stats::{is_finite_template, is_match, MatchCounts};
+
+/// One length-m template of a series and its matches.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TemplateMatches {
+    /// The index in the series of the template's first sample.
+    pub start: usize,
+    /// The template's m samples.
+    pub values: Vec<f32>,
+    /// The number of other length-m templates it matches.
+    pub m_matches: usize,
+    /// The number of other length-(m+1) templates its extension by the next
+    /// sample matches, or None for the last template, which has no
+    /// extension.
+    pub m_plus_1_matches: Option<usize>,
+}
+
+/// Every template `sample_entropy` compares, with the matches each one has.
+///
+/// A match is counted for both templates of a pair, so the per-template
+/// counts add up to twice the pairs; `MatchingDump::counts` halves them back
+/// into the `MatchCounts` of `stats::match_counts`. Templates containing a
+/// NaN or infinite sample are listed but match nothing, as in
+/// `stats::get_matches`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MatchingDump {
+    pub m: usize,
+    pub r: f32,
+    /// The length-m templates in the order of `stats::construct_templates`.
+    pub templates: Vec<TemplateMatches>,
+}
+
+impl MatchingDump {
+    /// The pair counts the dump adds up to, those of `stats::match_counts`.
+    pub fn counts(&self) -> MatchCounts {
+        MatchCounts {
+            m_matches: self.templates.iter().map(|t| t.m_matches).sum::<usize>() / 2,
+            m_plus_1_matches: self
+                .templates
+                .iter()
+                .filter_map(|t| t.m_plus_1_matches)
+                .sum::<usize>()
+                / 2,
+        }
+    }
+}
+
+/// Lists the templates of a series with the matches of each, by brute force.
+///
+/// This compares every pair of templates with the chebyshev distance and
+/// `r` of `stats::sample_entropy`, whatever `MatchStrategy` would prune, so
+/// it takes quadratic time and is meant for the short series a discrepancy
+/// is reproduced on. A series shorter than m has no templates.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes. Must be at least 1.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn dump_matching(m: usize, r: f32, data: &[f32]) -> MatchingDump {
+    let mut templates: Vec<TemplateMatches> = data
+        .windows(m)
+        .enumerate()
+        .map(|(start, values)| TemplateMatches {
+            start,
+            values: values.to_vec(),
+            m_matches: 0,
+            m_plus_1_matches: (start + m < data.len()).then_some(0),
+        })
+        .collect();
+    let extension = |start: usize| data.get(start..start + m + 1);
+    for i in 0..templates.len() {
+        if !is_finite_template(&templates[i].values) {
+            continue;
+        }
+        for j in i + 1..templates.len() {
+            if !is_match(&templates[i].values, &templates[j].values, &r) {
+                continue;
+            }
+            templates[i].m_matches += 1;
+            templates[j].m_matches += 1;
+            if let (Some(a), Some(b)) = (extension(i), extension(j)) {
+                if is_finite_template(a) && is_finite_template(b) && is_match(a, b, &r) {
+                    for ix in [i, j] {
+                        if let Some(count) = &mut templates[ix].m_plus_1_matches {
+                            *count += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    MatchingDump { m, r, templates }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interrupt::Interrupt;
+    use crate::stats::{construct_templates, get_matches, match_counts, ChebyshevThreshold};
+
+    #[test]
+    fn test_dump_adds_up_to_get_matches() {
+        let mut data: Vec<f32> = (0..200).map(|x| ((x * 13) % 17) as f32).collect();
+        data[50] = f32::NAN;
+        let (m, r) = (2, 3.0);
+        let dump = dump_matching(m, r, &data);
+        let values: Vec<Vec<f32>> = dump.templates.iter().map(|t| t.values.clone()).collect();
+        let templates = construct_templates(m, &data);
+        assert_eq!(templates.len(), values.len());
+        for (dumped, built) in values.iter().zip(&templates) {
+            // NaN != NaN, so the templates are compared bit for bit.
+            let bits = |t: &[f32]| t.iter().map(|x| x.to_bits()).collect::<Vec<u32>>();
+            assert_eq!(bits(built), bits(dumped));
+        }
+
+        let predicate = ChebyshevThreshold(r);
+        let b = get_matches(&templates, &predicate, &Interrupt::default()).unwrap();
+        let longer = construct_templates(m + 1, &data);
+        let a = get_matches(&longer, &predicate, &Interrupt::default()).unwrap();
+        assert_eq!(b, dump.counts().m_matches);
+        assert_eq!(a, dump.counts().m_plus_1_matches);
+        assert_eq!(match_counts(m, r, &data), dump.counts());
+        assert_eq!(None, dump.templates.last().unwrap().m_plus_1_matches);
+        assert_eq!(0, dump.templates[49].m_matches);
+
+        assert!(dump_matching(3, r, &data[..2]).templates.is_empty());
+    }
+
+    #[cfg(feature = "ndjson")]
+    #[test]
+    fn test_dump_round_trips_through_json() {
+        let dump = dump_matching(2, 0.5, &[1.0, 2.0, 1.1, 2.1, 1.0]);
+        let json = serde_json::to_string(&dump).unwrap();
+        assert!(json.contains("\"m_plus_1_matches\":null"));
+        assert_eq!(dump, serde_json::from_str::<MatchingDump>(&json).unwrap());
+    }
+}
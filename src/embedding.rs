@@ -0,0 +1,276 @@
+//! Delay embeddings: estimates of the embedding dimension, for choosing the
+//! template size `m`, sample entropy in an embedding with arbitrary lags, and
+//! sample entropy in the joint embedding of several channels.
+use crate::stats::{is_finite_template, is_match, MatchCounts};
+
+/// Computes the fraction of false nearest neighbors at each embedding
+/// dimension from 1 to `max_m`.
+///
+/// From Kennel, M. B., Brown, R. & Abarbanel, H. D. I. (1992) "Determining
+/// embedding dimension for phase-space reconstruction using a geometrical
+/// construction". Each sample `i` is embedded at dimension d as the delay
+/// vector `(x[i], x[i + delay], ..., x[i + (d-1) * delay])` and paired with
+/// its nearest other delay vector `j`, measuring distance as in template
+/// matching (the largest coordinate difference). The neighbors are false if
+/// adding the next coordinate separates them by more than `r_tol` times
+/// their distance, i.e. `|x[i + d * delay] - x[j + d * delay]| / distance >
+/// r_tol`: they were only close because the attractor was folded onto too
+/// few dimensions. Delay vectors containing NaN or infinite values, and
+/// neighbors at distance zero, are left out; a dimension where no neighbors
+/// are left to test is NaN.
+///
+/// Entry `d - 1` of the result is the fraction at dimension d. The curve
+/// falls as the dimension grows; a good `m` is the first dimension where it
+/// drops near zero, beyond which extra coordinates add nothing. Noise keeps
+/// the curve from reaching zero, so on noisy data look for where it levels
+/// off instead. `r_tol` around 10 to 15 is typical.
+///
+/// # Arguments
+/// * `max_m` - the largest dimension considered.
+/// * `delay` - the number of samples between coordinates of a delay vector.
+/// * `r_tol` - how many times their distance the neighbors must separate by.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn false_nearest_neighbors(max_m: usize, delay: usize, r_tol: f32, data: &[f32]) -> Vec<f32> {
+    (1..=max_m)
+        .map(|d| false_neighbor_fraction(d, delay, r_tol, data))
+        .collect()
+}
+
+/// The fraction of false nearest neighbors at dimension `d`. See
+/// `false_nearest_neighbors`.
+fn false_neighbor_fraction(d: usize, delay: usize, r_tol: f32, data: &[f32]) -> f32 {
+    // Vectors must have a sample d * delay after their start, to test the
+    // next coordinate.
+    let starts: Vec<usize> = (0..data.len().saturating_sub(d * delay))
+        .filter(|i| (0..=d).all(|k| data[i + k * delay].is_finite()))
+        .collect();
+    let distance = |i: usize, j: usize| -> f32 {
+        (0..d)
+            .map(|k| (data[i + k * delay] - data[j + k * delay]).abs())
+            .fold(0.0, f32::max)
+    };
+
+    let (mut num_false, mut num_tested): (usize, usize) = (0, 0);
+    for i in starts.iter() {
+        let nearest = starts
+            .iter()
+            .filter(|j| *j != i)
+            .map(|j| (*j, distance(*i, *j)))
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        let Some((j, nearest_distance)) = nearest else {
+            continue;
+        };
+        if nearest_distance == 0.0 {
+            continue;
+        }
+        let separation = (data[i + d * delay] - data[j + d * delay]).abs();
+        num_tested += 1;
+        num_false += usize::from(separation / nearest_distance > r_tol);
+    }
+    num_false as f32 / num_tested as f32
+}
+
+/// Computes sample entropy in a delay-embedded phase space, with a lag per
+/// coordinate.
+///
+/// The template at sample t is `(x[t - delays[0]], ..., x[t - delays[k]])`,
+/// so `delays.len()` is the embedding dimension m and each entry is how far
+/// into the past its coordinate reaches; the m+1 template adds the next
+/// sample `x[t + 1]`. Every t with a full template is used, from the
+/// largest delay onwards. `delays = [0, 1]` (in either order) is the
+/// consecutive embedding of `stats::sample_entropy` with m = 2, and in
+/// general `[0, 1, ..., m - 1]` is m. Spreading the lags out lets templates
+/// span a slow oscillation with few coordinates. Templates containing NaN or
+/// infinite values are left out. All pairs are compared, in O(n^2).
+///
+/// # Arguments
+/// * `delays` - the lag of each coordinate of a template.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn sample_entropy_embedded(delays: &[usize], r: f32, data: &[f32]) -> f32 {
+    let max_delay = delays.iter().copied().max().unwrap_or(0);
+    let mut templates: Vec<(Vec<f32>, Option<f32>)> = Vec::new();
+    for t in max_delay..data.len() {
+        let template: Vec<f32> = delays.iter().map(|delay| data[t - delay]).collect();
+        if is_finite_template(&template) {
+            let next = data.get(t + 1).copied().filter(|x| x.is_finite());
+            templates.push((template, next));
+        }
+    }
+
+    let mut counts = MatchCounts {
+        m_matches: 0,
+        m_plus_1_matches: 0,
+    };
+    for (i, (template, next)) in templates.iter().enumerate() {
+        for (other, other_next) in templates[i + 1..].iter() {
+            if !is_match(template, other, &r) {
+                continue;
+            }
+            counts.m_matches += 1;
+            if let (Some(a), Some(b)) = (next, other_next) {
+                if is_match(&[*a], &[*b], &r) {
+                    counts.m_plus_1_matches += 1;
+                }
+            }
+        }
+    }
+    counts.sample_entropy()
+}
+
+/// Computes the multivariate sample entropy of several channels embedded
+/// jointly.
+///
+/// From Ahmed, M. U. & Mandic, D. P. (2011) "Multivariate multiscale entropy:
+/// a tool for complexity analysis of multichannel data". Rather than a
+/// template per channel, each sample i has one composite delay vector, the
+/// concatenation of `m[k]` consecutive samples of each channel k from i, so
+/// `m` is the embedding dimension of every channel and the vector has their
+/// sum as its dimension. Samples i run from 0 to `N - max(m)`, N being the
+/// length of the shortest channel. The m+1 vectors extend the embedding of
+/// each channel by one sample in turn, giving `channels.len()` of them per
+/// sample, and all of them are compared with each other, whichever channel
+/// they extend. Distances are the largest coordinate difference, as in
+/// `stats::sample_entropy`, and the entropy is `-ln(A / B)` for the
+/// fractions A and B of the pairs of m+1 and m vectors that match, which
+/// unlike counts are comparable despite the different number of vectors.
+/// Vectors containing NaN or infinite values are left out. All pairs are
+/// compared, in `O((channels.len() N)²)`.
+///
+/// `r` applies to every coordinate, so the channels must be on the same
+/// scale: as in the paper, normalize each to unit standard deviation first
+/// (e.g. dividing by `stats::standard_deviation`), and `r` is then a
+/// fraction of it, 0.15 in the paper.
+///
+/// Coupling raises the entropy, as the paper reports for correlated noise.
+/// Two identical channels have the m-vector matches of one channel, since
+/// the copy repeats every coordinate, but a vector extending one copy
+/// rarely matches a vector extending the other, whose coordinates are
+/// shifted against it; independent channels pay for the second channel in
+/// the m-vector matches instead, and their extensions match as often as
+/// either channel's own.
+///
+/// # Arguments
+/// * `m` - the embedding dimension of each channel.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `channels` - the waveform data of each channel.
+///
+pub fn multivariate_sample_entropy(m: &[usize], r: f32, channels: &[Vec<f32>]) -> f32 {
+    assert_eq!(
+        m.len(),
+        channels.len(),
+        "m must have an embedding dimension per channel"
+    );
+    let len = channels.iter().map(Vec::len).min().unwrap_or(0);
+    let max_m = m.iter().copied().max().unwrap_or(0);
+    let num_samples = len.saturating_sub(max_m);
+    let composite = |i: usize, extended: Option<usize>| -> Vec<f32> {
+        channels
+            .iter()
+            .zip(m)
+            .enumerate()
+            .flat_map(|(k, (channel, m_k))| {
+                let end = i + m_k + usize::from(extended == Some(k));
+                channel[i..end].iter().copied()
+            })
+            .collect()
+    };
+    let vectors: Vec<Vec<f32>> = (0..num_samples)
+        .map(|i| composite(i, None))
+        .filter(|vector| is_finite_template(vector))
+        .collect();
+    let extended: Vec<Vec<f32>> = (0..num_samples)
+        .flat_map(|i| (0..channels.len()).map(move |k| (i, k)))
+        .map(|(i, k)| composite(i, Some(k)))
+        .filter(|vector| is_finite_template(vector))
+        .collect();
+
+    let match_fraction = |vectors: &[Vec<f32>]| -> f64 {
+        let mut num_matches: u64 = 0;
+        for (i, vector) in vectors.iter().enumerate() {
+            num_matches += vectors[i + 1..]
+                .iter()
+                .filter(|other| is_match(vector, other, &r))
+                .count() as u64;
+        }
+        let num_pairs = vectors.len() as f64 * vectors.len().saturating_sub(1) as f64 / 2.0;
+        num_matches as f64 / num_pairs
+    };
+    -(match_fraction(&extended) / match_fraction(&vectors)).ln() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::SplitMix64;
+
+    fn henon() -> Vec<f32> {
+        let (mut x, mut y): (f32, f32) = (0.1, 0.1);
+        (0..1200)
+            .map(|_| {
+                (x, y) = (1.0 - 1.4 * x * x + y, 0.3 * x);
+                x
+            })
+            .skip(200)
+            .collect()
+    }
+
+    #[test]
+    fn test_embedded_sample_entropy() {
+        let henon = henon();
+        let r = 0.2 * crate::stats::standard_deviation(&henon);
+        let consecutive = sample_entropy_embedded(&[0, 1], r, &henon);
+        assert_eq!(crate::stats::sample_entropy(2, r, &henon), consecutive);
+        assert_eq!(consecutive, sample_entropy_embedded(&[1, 0], r, &henon));
+        assert_eq!(
+            crate::stats::sample_entropy(3, r, &henon),
+            sample_entropy_embedded(&[0, 1, 2], r, &henon)
+        );
+        // The next value of the map depends on the previous two, so skipping
+        // the one before last loses information and the next value is less
+        // predictable.
+        let gapped = sample_entropy_embedded(&[0, 2], r, &henon);
+        assert!(gapped > consecutive + 0.1, "{} {}", gapped, consecutive);
+    }
+
+    #[test]
+    fn test_multivariate_sample_entropy_of_coupled_channels() {
+        let mut rng = SplitMix64::new(9);
+        let normalized = |data: Vec<f32>| -> Vec<f32> {
+            let std = crate::stats::standard_deviation(&data);
+            data.iter().map(|x| x / std).collect()
+        };
+        let x = normalized(rng.uniform_vec(800));
+        let y = normalized(rng.uniform_vec(800));
+        let identical = multivariate_sample_entropy(&[2, 2], 0.5, &[x.clone(), x.clone()]);
+        let independent = multivariate_sample_entropy(&[2, 2], 0.5, &[x.clone(), y]);
+        assert!(identical.is_finite() && independent.is_finite());
+        // Extending either of the identical channels gives vectors that
+        // rarely match each other.
+        assert!(
+            identical > independent + 0.3,
+            "{} {}",
+            identical,
+            independent
+        );
+
+        // A gap leaves out only the vectors containing it.
+        let mut gappy = x.clone();
+        gappy[400] = f32::NAN;
+        assert!(multivariate_sample_entropy(&[2, 2], 0.5, &[gappy, x]).is_finite());
+    }
+
+    #[test]
+    fn test_henon_map_unfolds_in_two_dimensions() {
+        let henon = henon();
+        let fractions = false_nearest_neighbors(4, 1, 10.0, &henon);
+        assert_eq!(4, fractions.len());
+        assert!(fractions[0] > 0.3, "{:?}", fractions);
+        for pair in fractions.windows(2) {
+            assert!(pair[1] <= pair[0] + 0.01, "{:?}", fractions);
+        }
+        assert!(fractions[1] < 0.05, "{:?}", fractions);
+    }
+}
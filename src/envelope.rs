@@ -0,0 +1,343 @@
+//! Upper and lower envelopes of pulsatile signals.
+//!
+//! The envelope traces the beat-to-beat peaks (or troughs) of a waveform such
+//! as arterial pressure, so its sample entropy reflects the complexity of the
+//! pulse amplitude rather than of the pulse shape. The instantaneous
+//! frequency does the same for the pulse rate.
+
+/// Which signal derived from a wave its entropy is computed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Signal {
+    /// The wave itself.
+    #[default]
+    Wave,
+    /// See `upper_envelope`.
+    UpperEnvelope,
+    /// See `lower_envelope`.
+    LowerEnvelope,
+    /// See `instantaneous_frequency`, in cycles per sample, since the
+    /// sample rate isn't always known: an absolute `r` is in the same units.
+    InstantaneousFrequency,
+}
+
+impl Signal {
+    /// Derives this signal from the wave.
+    pub fn derive(&self, data: &[f32]) -> Vec<f32> {
+        match self {
+            Signal::Wave => data.to_vec(),
+            Signal::UpperEnvelope => upper_envelope(data),
+            Signal::LowerEnvelope => lower_envelope(data),
+            Signal::InstantaneousFrequency => instantaneous_frequency(data, 1.0),
+        }
+    }
+}
+
+/// Parameters deciding which local maxima count as peaks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeakOptions {
+    /// The smallest number of samples between two peaks. Of two peaks closer
+    /// than this, only the higher is kept.
+    pub min_distance: usize,
+    /// The smallest height of a peak above the higher of the lowest points
+    /// separating it from a higher peak on either side (or from the end of
+    /// the series). Smaller peaks are treated as noise on the slope of a
+    /// larger one.
+    pub min_prominence: f32,
+}
+
+impl Default for PeakOptions {
+    /// Every local maximum is a peak.
+    fn default() -> Self {
+        PeakOptions {
+            min_distance: 1,
+            min_prominence: 0.0,
+        }
+    }
+}
+
+/// Returns the indices of the peaks in the data, in increasing order.
+///
+/// A peak is a sample higher than the one before it and at least as high as
+/// the one after it, so a flat-topped peak is found at its first sample.
+/// Peaks are then filtered by `options`.
+///
+/// # Arguments
+/// * `data` - a vector containing the waveform data.
+/// * `options` - the minimum distance and prominence of a peak.
+///
+pub fn find_peaks(data: &[f32], options: &PeakOptions) -> Vec<usize> {
+    let candidates: Vec<usize> = (1..data.len().saturating_sub(1))
+        .filter(|i| data[*i] > data[i - 1] && data[*i] >= data[i + 1])
+        .filter(|i| prominence(data, *i) >= options.min_prominence)
+        .collect();
+    // Keep the highest peaks first, dropping any within min_distance of a
+    // peak already kept.
+    let mut by_height = candidates.clone();
+    by_height.sort_by(|a, b| data[*b].total_cmp(&data[*a]));
+    let mut kept: Vec<usize> = Vec::with_capacity(candidates.len());
+    for peak in by_height {
+        if kept
+            .iter()
+            .all(|other| peak.abs_diff(*other) >= options.min_distance)
+        {
+            kept.push(peak);
+        }
+    }
+    kept.sort_unstable();
+    kept
+}
+
+/// The height of the peak at `peak` above the higher of the lowest points
+/// between it and the nearest higher sample on either side.
+fn prominence(data: &[f32], peak: usize) -> f32 {
+    let height = data[peak];
+    let left_base = data[..peak]
+        .iter()
+        .rev()
+        .take_while(|x| **x <= height)
+        .fold(height, |low, x| low.min(*x));
+    let right_base = data[peak + 1..]
+        .iter()
+        .take_while(|x| **x <= height)
+        .fold(height, |low, x| low.min(*x));
+    height - left_base.max(right_base)
+}
+
+/// Returns the upper envelope of the data with every local maximum as a peak.
+/// See `upper_envelope_with`.
+pub fn upper_envelope(data: &[f32]) -> Vec<f32> {
+    upper_envelope_with(data, &PeakOptions::default())
+}
+
+/// Returns the lower envelope of the data with every local minimum as a
+/// trough. See `lower_envelope_with`.
+pub fn lower_envelope(data: &[f32]) -> Vec<f32> {
+    lower_envelope_with(data, &PeakOptions::default())
+}
+
+/// Returns the upper envelope of the data: the peaks found by `find_peaks`,
+/// linearly interpolated between peaks to the length of the data.
+///
+/// Before the first peak and after the last one, the envelope holds that
+/// peak's value. Without any peaks, the data is returned unchanged.
+///
+/// # Arguments
+/// * `data` - a vector containing the waveform data.
+/// * `options` - the minimum distance and prominence of a peak.
+///
+pub fn upper_envelope_with(data: &[f32], options: &PeakOptions) -> Vec<f32> {
+    let peaks = find_peaks(data, options);
+    let (first, last) = match (peaks.first(), peaks.last()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => return data.to_vec(),
+    };
+    let mut envelope: Vec<f32> = vec![data[first]; first];
+    for pair in peaks.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        let step = (data[end] - data[start]) / (end - start) as f32;
+        envelope.extend((0..end - start).map(|offset| data[start] + step * offset as f32));
+    }
+    envelope.resize(data.len(), data[last]);
+    envelope
+}
+
+/// Returns the lower envelope of the data: the upper envelope of the negated
+/// data, negated back. `options` applies to the troughs.
+///
+/// # Arguments
+/// * `data` - a vector containing the waveform data.
+/// * `options` - the minimum distance and prominence of a trough.
+///
+pub fn lower_envelope_with(data: &[f32], options: &PeakOptions) -> Vec<f32> {
+    let negated: Vec<f32> = data.iter().map(|x| -x).collect();
+    upper_envelope_with(&negated, options)
+        .iter()
+        .map(|x| -x)
+        .collect()
+}
+
+/// Returns the instantaneous frequency of an oscillating wave, in cycles per
+/// unit of time at `sample_rate` samples per unit, from the intervals
+/// between its zero crossings.
+///
+/// The wave is linearly detrended, and a cycle is the interval between two
+/// consecutive upward crossings of zero, each located between its two
+/// samples on the cubic through them and their neighbours, which keeps the
+/// cycles of even a sinusoid sampled at a few points per period the same
+/// length to about 1e-4 of it. A cycle's frequency, the inverse of its
+/// length, is placed at its midpoint and linearly interpolated between
+/// midpoints to the length of the data, held before the first and after the
+/// last, as the envelopes are. Samples that aren't finite don't cross.
+///
+/// This assumes the wave oscillates about its trend once per cycle, as
+/// arterial pressure does about its mean. A wave with several crossings per
+/// cycle, e.g. from noise riding on a slope near its trend, has too high a
+/// frequency: smooth or filter it first. Empty if the wave crosses its trend
+/// upwards fewer than twice.
+///
+/// # Arguments
+/// * `data` - a vector containing the waveform data.
+/// * `sample_rate` - the number of samples per unit of time, 1.0 for a
+///   frequency in cycles per sample.
+///
+pub fn instantaneous_frequency(data: &[f32], sample_rate: f32) -> Vec<f32> {
+    let detrended = crate::stats::detrend_data(data);
+    let crossings: Vec<f64> = detrended
+        .windows(2)
+        .enumerate()
+        .filter(|(_, pair)| pair[0] < 0.0 && pair[1] >= 0.0)
+        .map(|(ix, _)| ix as f64 + crossing_offset(&detrended, ix))
+        .collect();
+    let cycles: Vec<(f64, f64)> = crossings
+        .windows(2)
+        .map(|pair| {
+            let midpoint = (pair[0] + pair[1]) / 2.0;
+            (midpoint, sample_rate as f64 / (pair[1] - pair[0]))
+        })
+        .collect();
+    let (Some(first), Some(last)) = (cycles.first(), cycles.last()) else {
+        return Vec::new();
+    };
+    let mut next = 0;
+    (0..data.len())
+        .map(|t| {
+            let t = t as f64;
+            while next < cycles.len() && cycles[next].0 <= t {
+                next += 1;
+            }
+            let frequency = if next == 0 {
+                first.1
+            } else if next == cycles.len() {
+                last.1
+            } else {
+                let ((t0, f0), (t1, f1)) = (cycles[next - 1], cycles[next]);
+                f0 + (f1 - f0) * (t - t0) / (t1 - t0)
+            };
+            frequency as f32
+        })
+        .collect()
+}
+
+/// The number of Newton steps locating a crossing on its cubic, from the
+/// linear estimate.
+const CROSSING_STEPS: usize = 4;
+
+/// Where between samples `ix` and `ix + 1` the data crosses zero upwards, as
+/// a fraction of the interval: on the cubic through the samples from
+/// `ix - 1` to `ix + 2`, or the line through the two if those neighbours
+/// aren't finite or past the ends.
+fn crossing_offset(data: &[f32], ix: usize) -> f64 {
+    let (before, after) = (data[ix] as f64, data[ix + 1] as f64);
+    let linear = -before / (after - before);
+    let (Some(previous), Some(next)) = (ix.checked_sub(1).map(|i| data[i]), data.get(ix + 2))
+    else {
+        return linear;
+    };
+    if !previous.is_finite() || !next.is_finite() {
+        return linear;
+    }
+    let (previous, next) = (previous as f64, *next as f64);
+    // The cubic through (-1, previous), (0, before), (1, after) and
+    // (2, next), as a + b t + c t² + d t³.
+    let (a, c) = (before, (previous + after) / 2.0 - before);
+    let d = (next - 3.0 * after + 3.0 * before - previous) / 6.0;
+    let b = after - a - c - d;
+    let mut t = linear;
+    for _ in 0..CROSSING_STEPS {
+        let value = a + t * (b + t * (c + t * d));
+        let slope = b + t * (2.0 * c + 3.0 * t * d);
+        if slope <= 0.0 {
+            return linear;
+        }
+        t -= value / slope;
+    }
+    if (0.0..=1.0).contains(&t) {
+        t
+    } else {
+        linear
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 20 sample period carrier whose amplitude is modulated by a slow sine.
+    fn modulated_sine() -> (Vec<f32>, Vec<f32>) {
+        let modulation: Vec<f32> = (0..2000)
+            .map(|t| 1.0 + 0.5 * (std::f32::consts::TAU * t as f32 / 500.0).sin())
+            .collect();
+        let signal: Vec<f32> = modulation
+            .iter()
+            .enumerate()
+            .map(|(t, a)| a * (std::f32::consts::TAU * t as f32 / 20.0).sin())
+            .collect();
+        (signal, modulation)
+    }
+
+    #[test]
+    fn test_envelopes_recover_modulation() {
+        let (signal, modulation) = modulated_sine();
+        let upper = upper_envelope(&signal);
+        let lower = lower_envelope(&signal);
+        assert_eq!(signal.len(), upper.len());
+        assert_eq!(signal.len(), lower.len());
+        for t in 20..1980 {
+            assert!((upper[t] - modulation[t]).abs() < 0.05, "{}", t);
+            assert!((lower[t] + modulation[t]).abs() < 0.05, "{}", t);
+        }
+    }
+
+    #[test]
+    fn test_instantaneous_frequency_of_sinusoids() {
+        for period in [8.3_f32, 12.5, 20.0, 47.3, 100.7] {
+            let sine: Vec<f32> = (0..5000)
+                .map(|t| 90.0 + 20.0 * (std::f32::consts::TAU * t as f32 / period).sin())
+                .collect();
+            let frequency = instantaneous_frequency(&sine, 100.0);
+            assert_eq!(sine.len(), frequency.len());
+            let mean = crate::stats::mean(&frequency);
+            assert!((mean * period / 100.0 - 1.0).abs() < 1e-3, "{}", mean);
+            let spread = crate::stats::standard_deviation(&frequency) / mean;
+            assert!(spread < 2e-4, "{}: {}", period, spread);
+        }
+
+        // The period doubles halfway through.
+        let shift: Vec<f32> = (0..4000)
+            .map(|t| {
+                let phase = if t < 2000 {
+                    t as f32 / 20.0
+                } else {
+                    100.0 + (t - 2000) as f32 / 40.0
+                };
+                (std::f32::consts::TAU * phase).sin()
+            })
+            .collect();
+        let frequency = instantaneous_frequency(&shift, 1.0);
+        assert!((frequency[500] - 0.05).abs() < 1e-4, "{}", frequency[500]);
+        assert!(
+            (frequency[3500] - 0.025).abs() < 1e-4,
+            "{}",
+            frequency[3500]
+        );
+
+        assert!(instantaneous_frequency(&[1.0; 100], 1.0).is_empty());
+    }
+
+    #[test]
+    fn test_find_peaks_options() {
+        let data = [0.0, 5.0, 4.0, 4.5, 0.0, 3.0, 0.0];
+        assert_eq!(vec![1, 3, 5], find_peaks(&data, &PeakOptions::default()));
+        let prominent = PeakOptions {
+            min_prominence: 1.0,
+            ..PeakOptions::default()
+        };
+        assert_eq!(vec![1, 5], find_peaks(&data, &prominent));
+        let distant = PeakOptions {
+            min_distance: 3,
+            ..PeakOptions::default()
+        };
+        assert_eq!(vec![1, 5], find_peaks(&data, &distant));
+    }
+}
@@ -0,0 +1,188 @@
+//! Errors returned by the readers, writers and entropy computations.
+use std::fmt;
+use std::io;
+
+use crate::interrupt::Interrupted;
+
+/// Why a file could not be read or written, or an entropy computed.
+#[derive(Debug)]
+pub enum SampenError {
+    /// Reading or writing a file failed.
+    Io(io::Error),
+    /// A csv could not be read or written, e.g. a row has the wrong number
+    /// of fields or a value is not a number.
+    Csv(csv::Error),
+    /// A glob pattern is malformed.
+    Pattern(glob::PatternError),
+    /// A path matching a glob pattern could not be read.
+    Glob(glob::GlobError),
+    /// The results could not be encoded as MessagePack.
+    #[cfg(feature = "msgpack")]
+    MsgpackEncode(rmp_serde::encode::Error),
+    /// A MessagePack file does not hold results.
+    #[cfg(feature = "msgpack")]
+    MsgpackDecode(rmp_serde::decode::Error),
+    /// A line of an NDJSON file is not a vital file record.
+    #[cfg(feature = "ndjson")]
+    Json(serde_json::Error),
+    /// A results file was written in a layout this version can't read.
+    UnsupportedVersion { found: u32, expected: u32 },
+    /// A csv has no column with this header.
+    MissingColumn(String),
+    /// A csv field that should hold a sample is not a number.
+    InvalidValue { value: String, reason: String },
+    /// A file's channels are empty or have different lengths.
+    InvalidFile { name: String, reason: String },
+    /// A parameter given by the caller is out of range or malformed.
+    InvalidParameter(String),
+    /// The match threshold `r` is zero, negative or NaN, so that no two
+    /// templates could match. This is the `r` as given or, for a relative
+    /// tolerance, as resolved, e.g. from a flat series.
+    InvalidTolerance(f32),
+    /// The series has enough samples for length-m templates but fewer than
+    /// two length-(m+1) templates, so the m+1 match count would be zero by
+    /// construction rather than by the data.
+    InsufficientDataForMPlus1 { m: usize, len: usize },
+    /// A time-based computation was asked of a file whose sample rate is
+    /// not known.
+    MissingSampleRate,
+    /// The computation was abandoned (see `interrupt::Interrupt`).
+    Interrupted,
+    /// The computation was cancelled through the cancel flag of its
+    /// `interrupt::Interrupt`.
+    Cancelled,
+}
+
+impl fmt::Display for SampenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SampenError::Io(error) => write!(f, "{}", error),
+            SampenError::Csv(error) => write!(f, "{}", error),
+            SampenError::Pattern(error) => write!(f, "invalid glob pattern: {}", error),
+            SampenError::Glob(error) => write!(f, "{}", error),
+            #[cfg(feature = "msgpack")]
+            SampenError::MsgpackEncode(error) => write!(f, "{}", error),
+            #[cfg(feature = "msgpack")]
+            SampenError::MsgpackDecode(error) => write!(f, "{}", error),
+            #[cfg(feature = "ndjson")]
+            SampenError::Json(error) => write!(f, "{}", error),
+            SampenError::UnsupportedVersion { found, expected } => write!(
+                f,
+                "results are version {}, but version {} was expected",
+                found, expected
+            ),
+            SampenError::MissingColumn(column) => write!(f, "no column named {}", column),
+            SampenError::InvalidValue { value, reason } => {
+                write!(f, "{:?} is not a number: {}", value, reason)
+            }
+            SampenError::InvalidFile { name, reason } => write!(f, "{}: {}", name, reason),
+            SampenError::InvalidParameter(reason) => write!(f, "{}", reason),
+            SampenError::InvalidTolerance(r) => write!(f, "r must be positive, got {}", r),
+            SampenError::InsufficientDataForMPlus1 { m, len } => write!(
+                f,
+                "a series of {} samples has fewer than two templates of length m+1 = {}",
+                len,
+                m + 1
+            ),
+            SampenError::MissingSampleRate => write!(
+                f,
+                "the file has no sample rate, which this computation requires"
+            ),
+            SampenError::Interrupted => write!(f, "the computation was interrupted"),
+            SampenError::Cancelled => write!(f, "the computation was cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for SampenError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SampenError::Io(error) => Some(error),
+            SampenError::Csv(error) => Some(error),
+            SampenError::Pattern(error) => Some(error),
+            SampenError::Glob(error) => Some(error),
+            #[cfg(feature = "msgpack")]
+            SampenError::MsgpackEncode(error) => Some(error),
+            #[cfg(feature = "msgpack")]
+            SampenError::MsgpackDecode(error) => Some(error),
+            #[cfg(feature = "ndjson")]
+            SampenError::Json(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SampenError {
+    fn from(error: io::Error) -> Self {
+        SampenError::Io(error)
+    }
+}
+
+impl From<csv::Error> for SampenError {
+    fn from(error: csv::Error) -> Self {
+        SampenError::Csv(error)
+    }
+}
+
+impl From<glob::PatternError> for SampenError {
+    fn from(error: glob::PatternError) -> Self {
+        SampenError::Pattern(error)
+    }
+}
+
+impl From<glob::GlobError> for SampenError {
+    fn from(error: glob::GlobError) -> Self {
+        SampenError::Glob(error)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl From<rmp_serde::encode::Error> for SampenError {
+    fn from(error: rmp_serde::encode::Error) -> Self {
+        SampenError::MsgpackEncode(error)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl From<rmp_serde::decode::Error> for SampenError {
+    fn from(error: rmp_serde::decode::Error) -> Self {
+        SampenError::MsgpackDecode(error)
+    }
+}
+
+#[cfg(feature = "ndjson")]
+impl From<serde_json::Error> for SampenError {
+    fn from(error: serde_json::Error) -> Self {
+        SampenError::Json(error)
+    }
+}
+
+impl From<Interrupted> for SampenError {
+    fn from(_: Interrupted) -> Self {
+        SampenError::Interrupted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_wrapped_errors_keep_their_source() {
+        let io = SampenError::from(io::Error::new(io::ErrorKind::NotFound, "gone"));
+        assert!(matches!(io, SampenError::Io(_)));
+        assert!(io.source().is_some());
+        assert_eq!("gone", io.to_string());
+
+        let pattern = SampenError::from(glob::Pattern::new("[").unwrap_err());
+        assert!(matches!(pattern, SampenError::Pattern(_)));
+        assert!(pattern.source().is_some());
+
+        assert!(matches!(
+            SampenError::from(Interrupted),
+            SampenError::Interrupted
+        ));
+        assert!(SampenError::MissingSampleRate.source().is_none());
+    }
+}
@@ -0,0 +1,82 @@
+//! Fractal dimension estimates, reported alongside sample entropy as
+//! complementary complexity measures.
+use crate::stats;
+
+/// Computes Higuchi's fractal dimension of a waveform.
+///
+/// From Higuchi, T. (1988) "Approach to an irregular time series on the basis
+/// of the fractal theory". For each delay k in 1..=k_max, the average length
+/// L(k) of the curve sampled every k points is computed (averaging over the k
+/// possible starting offsets, each normalized for the number of points it
+/// covers). If L(k) scales as k^-D, D is the fractal dimension, estimated as
+/// the OLS slope of ln L(k) against ln(1/k).
+///
+/// The result lies between 1 (a smooth curve) and 2 (white noise). `k_max`
+/// should be large enough to give the slope fit a few points, but small
+/// relative to the series length so each subsampled curve still has many
+/// points; values around 8 to 20 are typical for physiological signals of a
+/// few thousand samples. It is worth checking that the estimate has plateaued
+/// as `k_max` increases.
+///
+/// # Arguments
+/// * `data` - a vector containing the waveform data.
+/// * `k_max` - the largest delay used. Must be at least 2 and below
+///   `data.len()`.
+///
+pub fn higuchi_fractal_dimension(data: &[f32], k_max: usize) -> f32 {
+    let n = data.len();
+    let mut log_inverse_k: Vec<f32> = Vec::with_capacity(k_max);
+    let mut log_length: Vec<f32> = Vec::with_capacity(k_max);
+    for k in 1..=k_max {
+        let mean_length: f32 = (0..k)
+            .map(|offset| {
+                let num_steps = (n - 1 - offset) / k;
+                let total: f32 = (1..=num_steps)
+                    .map(|i| (data[offset + i * k] - data[offset + (i - 1) * k]).abs())
+                    .sum();
+                let normalization = (n - 1) as f32 / (num_steps * k) as f32;
+                total * normalization / k as f32
+            })
+            .sum::<f32>()
+            / k as f32;
+        log_inverse_k.push((1.0 / k as f32).ln());
+        log_length.push(mean_length.ln());
+    }
+    let (slope, _intercept) = stats::linear_regression(&log_inverse_k, &log_length);
+    slope
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::SplitMix64;
+
+    #[test]
+    fn test_higuchi_smooth_curve_is_one_dimensional() {
+        let line: Vec<f32> = (0..1000).map(|x| x as f32 * 0.3).collect();
+        assert!((higuchi_fractal_dimension(&line, 10) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_higuchi_weierstrass_function() {
+        // W(t) = sum a^n cos(b^n pi t) has dimension 2 + ln(a) / ln(b) = 1.5.
+        let (a, b): (f32, f32) = (0.5, 4.0);
+        let weierstrass: Vec<f32> = (0..4000)
+            .map(|t| {
+                let t = t as f32 / 4000.0;
+                (0..12)
+                    .map(|n| a.powi(n) * (b.powi(n) * std::f32::consts::PI * t).cos())
+                    .sum()
+            })
+            .collect();
+        let dimension = higuchi_fractal_dimension(&weierstrass, 10);
+        assert!((1.35..1.65).contains(&dimension), "{}", dimension);
+    }
+
+    #[test]
+    fn test_higuchi_noise_is_two_dimensional() {
+        let noise: Vec<f32> = SplitMix64::new(12345).uniform_vec(4000);
+        let dimension = higuchi_fractal_dimension(&noise, 10);
+        assert!((1.9..2.1).contains(&dimension), "{}", dimension);
+    }
+}
@@ -0,0 +1,112 @@
+//! Cooperative cancellation for long-running match counts.
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::error::SampenError;
+
+/// Conditions under which a long-running computation gives up early: a
+/// deadline, a cancel flag shared with whoever may cancel it, or both.
+///
+/// The match counting loop checks `is_triggered` once per template, i.e. once
+/// per row of the O(n^2) pair comparison, so a computation overruns its
+/// deadline, or runs on after being cancelled, by at most the time taken to
+/// compare one template against all the others. A check is a relaxed atomic
+/// load and at most a clock read, against the thousands of comparisons of a
+/// row, so it costs well under 1% even on short series. The default
+/// interrupt never triggers.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt {
+    deadline: Option<Instant>,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Interrupt {
+    /// An interrupt that triggers once `deadline` has passed.
+    pub fn at(deadline: Instant) -> Self {
+        Interrupt {
+            deadline: Some(deadline),
+            cancel: None,
+        }
+    }
+
+    /// An interrupt that triggers once `budget` has elapsed from now.
+    pub fn after(budget: Duration) -> Self {
+        Interrupt::at(Instant::now() + budget)
+    }
+
+    /// An interrupt that triggers once `cancel` is set, e.g. by another
+    /// thread when a user cancels a batch.
+    pub fn cancelled_by(cancel: Arc<AtomicBool>) -> Self {
+        Interrupt::default().with_cancel(cancel)
+    }
+
+    /// This interrupt, also triggering once `cancel` is set.
+    pub fn with_cancel(self, cancel: Arc<AtomicBool>) -> Self {
+        Interrupt {
+            cancel: Some(cancel),
+            ..self
+        }
+    }
+
+    /// Returns true if the cancel flag is set.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    /// Returns true if the computation should stop.
+    pub fn is_triggered(&self) -> bool {
+        self.is_cancelled()
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// The error for a computation this interrupt stopped:
+    /// `SampenError::Cancelled` if it was cancelled, else
+    /// `SampenError::Interrupted`.
+    pub fn error(&self) -> SampenError {
+        if self.is_cancelled() {
+            SampenError::Cancelled
+        } else {
+            SampenError::Interrupted
+        }
+    }
+}
+
+/// Returned when a computation was abandoned because its `Interrupt`
+/// triggered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "computation was interrupted before it finished")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interrupt() {
+        assert!(!Interrupt::default().is_triggered());
+        assert!(Interrupt::at(Instant::now()).is_triggered());
+        assert!(!Interrupt::after(Duration::from_secs(3600)).is_triggered());
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let interrupt = Interrupt::after(Duration::from_secs(3600)).with_cancel(cancel.clone());
+        assert!(!interrupt.is_triggered());
+        assert!(matches!(interrupt.error(), SampenError::Interrupted));
+        cancel.store(true, Ordering::Relaxed);
+        assert!(interrupt.is_triggered());
+        assert!(Interrupt::cancelled_by(cancel).is_cancelled());
+        assert!(matches!(interrupt.error(), SampenError::Cancelled));
+    }
+}
@@ -0,0 +1,30 @@
+//! Sample entropy for physiological waveforms.
+//!
+//! Everything required for computing sample entropy lives in [`stats`]; the
+//! binary in `main.rs` uses it to process a whole directory of VitalDB csvs.
+//! [`prelude`] gathers the commonly used items from every module.
+pub mod calibration;
+pub mod cost;
+pub mod dump;
+pub mod embedding;
+pub mod envelope;
+pub mod error;
+pub mod fractal;
+pub mod interrupt;
+pub mod matching;
+pub mod measures;
+pub mod multiscale;
+pub mod periodic;
+pub mod permutation;
+pub mod prelude;
+pub mod provenance;
+pub mod reader;
+mod rng;
+pub mod sketch;
+pub mod stats;
+pub mod surrogate;
+mod util;
+pub mod vital_entropies;
+pub mod vital_file;
+#[cfg(feature = "wasm")]
+pub mod wasm;
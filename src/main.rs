@@ -1,140 +1,2629 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use csv::Writer;
 use glob::glob;
 use indicatif::{ParallelProgressIterator, ProgressBar};
 use rayon::prelude::*;
-use std::error::Error;
-use std::time::Instant;
-mod stats;
-mod vital_entropies;
-use csv::Writer;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use sample_entropy::calibration::{default_cache_path, load_or_calibrate};
+use sample_entropy::cost::estimate_cost_of;
+use sample_entropy::envelope::Signal;
+use sample_entropy::error::SampenError;
+use sample_entropy::interrupt::{Interrupt, Interrupted};
+use sample_entropy::matching::{set_brute_force_threshold, MatchStrategy};
+use sample_entropy::provenance::provenance;
+use sample_entropy::reader::{reader_for_path, CsvReader};
+use sample_entropy::stats;
+use sample_entropy::stats::{Preprocess, Tolerance};
+use sample_entropy::surrogate::shuffle_surrogate;
+use sample_entropy::vital_entropies::{
+    summarize, DetrendingComparison, HalvesComparison, Status, ToLong, VitalEntropies,
+    WindowEntropy,
+};
+use sample_entropy::vital_file::{
+    read_csv, read_csv_with_layout, write_csv, Channel, CsvLayout, SampleRange, VitalFile,
+};
+
+/// Computes sample entropy for every VitalDB csv matching the glob pattern.
+#[derive(Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    entropy: EntropyArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Converts csvs with other column names into the canonical
+    /// `name,mbp,sbp,dbp` layout the entropy computation reads.
+    Prepare(PrepareArgs),
+    /// Computes the entropies of a single csv with the default settings and
+    /// prints them, without writing any output file.
+    One(OneArgs),
+}
+
+/// Options for the default entropy computation.
+#[derive(clap::Args)]
+struct EntropyArgs {
+    /// File format of the results.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Csv)]
+    format: OutputFormat,
+
+    /// Shape of the output csv.
+    #[arg(long, value_enum, default_value_t = OutputShape::Wide)]
+    output_shape: OutputShape,
+
+    /// How each wave is prepared before computing its entropy.
+    #[arg(long, value_enum, default_value_t = Preprocess::Detrend)]
+    preprocess: Preprocess,
+
+    /// Gives up on a file once its entropy computation has run this many
+    /// seconds, leaving NaN entropies and marking the row as timed out.
+    #[arg(long, value_parser = parse_seconds)]
+    max_seconds: Option<Duration>,
+
+    /// Floors both match counts at 1 so that waves with no length-(m+1)
+    /// matches report ln(B) instead of an infinite entropy.
+    #[arg(long)]
+    floor_counts: bool,
+
+    /// Algorithm used to find the matching templates. All give the same
+    /// entropies.
+    #[arg(long, value_enum, default_value_t = MatchStrategy::Auto)]
+    match_strategy: MatchStrategy,
+
+    /// Measures again where `--match-strategy auto` switches from brute force
+    /// to sorting, instead of reading the crossover measured by an earlier
+    /// run. It is cached in `sample_entropy/brute_force_threshold` under
+    /// `$XDG_CACHE_HOME` (else `%LOCALAPPDATA%`, else `~/.cache`; see
+    /// `calibration::default_cache_path`), and measured again automatically
+    /// by a new build.
+    #[arg(long)]
+    recalibrate: bool,
+
+    /// Reports each entropy divided by the mean entropy of this many shuffles
+    /// of the wave, for comparing records of different lengths (see
+    /// `surrogate::normalized_sample_entropy`).
+    #[arg(long, value_name = "NUM_SURROGATES")]
+    normalize: Option<usize>,
+
+    /// Marks a file as unreliable when any channel's entropy comes from fewer
+    /// than this many matches of either template length. 10 is a bare
+    /// minimum; 100 or more is preferable when comparing similar records.
+    #[arg(long, default_value_t = 10)]
+    min_matches: usize,
+
+    /// Which signal derived from each wave the entropy is computed on.
+    #[arg(long, value_enum, default_value_t = Signal::Wave)]
+    signal: Signal,
+
+    /// Uses this match threshold for every wave instead of 0.2 standard
+    /// deviations of the preprocessed wave. For data preprocessed by another
+    /// tool, combine with `--preprocess raw` so that neither the detrending
+    /// nor the standard deviation is recomputed; the caller is then
+    /// responsible for both having been done to each wave as wanted.
+    #[arg(long = "r", value_name = "R")]
+    absolute_r: Option<f32>,
+
+    /// Computes every entropy on both the raw and the detrended wave and
+    /// writes both, as `sbp_sampen_raw`, `sbp_sampen_detrended` and so on,
+    /// to show how much detrending changes each channel.
+    #[arg(long, conflicts_with = "preprocess")]
+    compare_detrending: bool,
+
+    /// Computes every entropy on the first and the second half of each
+    /// channel instead and writes both, their absolute difference and
+    /// whether it is above `THRESHOLD` (0.3 if not given), as
+    /// `sbp_sampen_first`, `sbp_sampen_second`, `sbp_difference`,
+    /// `sbp_nonstationary` and so on, to flag channels whose complexity
+    /// drifts over the recording. Each half is preprocessed and given its own
+    /// `r`.
+    #[arg(
+        long,
+        value_name = "THRESHOLD",
+        num_args = 0..=1,
+        default_missing_value = "0.3",
+        conflicts_with = "compare_detrending"
+    )]
+    split_halves: Option<f32>,
+
+    /// Number of threads computing entropies, in total: files, their
+    /// channels and the shuffles of `--normalize` share them, split as by
+    /// `Parallelism::split`. 0 uses every core; 1 runs single-threaded,
+    /// which is easier to debug and leaves the other cores of a shared node
+    /// alone. The results are the same either way.
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+
+    /// Prints per-channel statistics of the entropies across all files (see
+    /// `vital_entropies::summarize`) once they are computed.
+    #[arg(long)]
+    summary: bool,
 
-use vital_entropies::VitalEntropies;
+    /// Rounds the entropies in the output to this many decimals, so that
+    /// outputs from different machines diff cleanly despite differences in
+    /// the last few bits. This only affects the output: each entropy is
+    /// computed at full precision and rounded once, when written.
+    #[arg(long, value_name = "N")]
+    round_decimals: Option<u32>,
+
+    /// Analyzes only this range of each channel, cut out before any
+    /// preprocessing: `START:END` in samples, end exclusive, or
+    /// `STARTs:ENDs` in seconds for files with a sample rate. Every file must
+    /// contain the whole range. The range used is recorded in each row.
+    #[arg(long, value_name = "START:END")]
+    range: Option<SampleRange>,
+
+    /// Adds a composite column, the weighted average of each file's finite
+    /// channel entropies (see `VitalEntropies::composite_score`), with
+    /// weights given as e.g. `sbp=2,mbp=1,dbp=1`. Channels left out get no
+    /// weight.
+    #[arg(
+        long,
+        value_name = "CHANNEL=WEIGHT,...",
+        value_parser = parse_weights,
+        conflicts_with_all = ["compare_detrending", "split_halves"]
+    )]
+    weights: Option<HashMap<String, f32>>,
+
+    /// Whether the first row of each csv names its columns. Without a header
+    /// the columns are read by position, as `name,mbp,sbp,dbp`.
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    has_header: bool,
+
+    /// Splits simple csvs (unquoted, with a field for every column) on commas
+    /// directly instead of with the `csv` crate, which reads them faster and
+    /// the same (see `vital_file::read_simple_csv_from_reader`). Other csvs
+    /// are read as usual.
+    #[arg(long)]
+    simple_csv: bool,
+
+    /// Starts the output csv with a `#` comment line naming the crate version
+    /// and git commit that produced it (see `provenance::provenance`), so
+    /// results can be traced to the exact build. Readers then need to skip
+    /// comment lines, e.g. pandas' `comment="#"`. MessagePack output always
+    /// records the build.
+    #[arg(long)]
+    provenance: bool,
+
+    /// Also writes the entropy of every overlapping window of each channel to
+    /// `vitaldb_rolling_entropies_rust.csv`, a row per window, for windows of
+    /// `WINDOW` samples starting every `STEP` samples. Each window is
+    /// detrended and given its own `r` (see `stats::rolling_sample_entropy`),
+    /// whatever `--preprocess` says.
+    #[arg(
+        long,
+        value_name = "WINDOW:STEP",
+        value_parser = parse_rolling,
+        conflicts_with_all = ["compare_detrending", "split_halves"]
+    )]
+    rolling: Option<(usize, usize)>,
+
+    /// Appends to the output csvs instead of replacing them, writing the
+    /// header (and the `--provenance` comment) only to a new or empty file,
+    /// so that batches processed by separate runs end up in one file. The
+    /// appended rows must have the columns already there, i.e. the same
+    /// `--output-shape` and options. Only csv output can be appended to.
+    #[arg(long)]
+    append: bool,
+
+    /// With `--append`, skips the files already in the output csv, so that a
+    /// run on an overlapping set of files adds only the new ones. Files are
+    /// matched by the `name` column of the existing rows: the case name
+    /// recorded in the file or, for a file that couldn't be read, its file
+    /// name without the extension.
+    #[arg(long, requires = "append")]
+    skip_existing: bool,
+
+    /// Times the reading of each file and the preprocessing and match
+    /// counting of each channel, writes them to `vitaldb_profile_rust.csv`,
+    /// a row per file, and prints the totals and the slowest files. Without
+    /// it nothing is timed but the whole computation.
+    #[arg(long, conflicts_with_all = ["compare_detrending", "split_halves"])]
+    profile: bool,
+}
+
+/// Options for the `one` subcommand.
+#[derive(clap::Args)]
+struct OneArgs {
+    /// Path of the csv, in the canonical `name,mbp,sbp,dbp` layout.
+    path: PathBuf,
+}
+
+/// Options for the `prepare` subcommand.
+#[derive(clap::Args)]
+struct PrepareArgs {
+    /// Glob pattern of the csvs to convert.
+    input: String,
+
+    /// Directory the converted csvs are written to, under their original
+    /// file names.
+    output_dir: PathBuf,
+
+    /// Comma separated `field=column` mappings from the canonical fields
+    /// `name`, `mbp`, `sbp` and `dbp` to the columns holding them, e.g.
+    /// `sbp=ART_SBP,mbp=ART_MBP,dbp=ART_DBP`. Unmapped channels keep their
+    /// canonical name; without a `name` mapping each file is named after its
+    /// file stem. With `--has-header false`, give each column as its 0-based
+    /// index instead, e.g. `sbp=2,mbp=3,dbp=1`.
+    #[arg(long)]
+    columns: CsvLayout,
+
+    /// Whether the first row of each csv names its columns. Without a header
+    /// every row is read as data.
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = clap::ArgAction::Set)]
+    has_header: bool,
+}
+
+/// Parses channel weights given as comma separated `channel=weight` pairs.
+fn parse_weights(arg: &str) -> Result<HashMap<String, f32>, String> {
+    arg.split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let (channel, weight) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected channel=weight, got {}", pair))?;
+            let channel = channel.trim();
+            if !["sbp", "mbp", "dbp"].contains(&channel) {
+                return Err(format!("unknown channel {}", channel));
+            }
+            let weight: f32 = weight
+                .trim()
+                .parse()
+                .map_err(|error| format!("{}", error))?;
+            if !weight.is_finite() || weight < 0.0 {
+                return Err(format!("the weight of {} must be non-negative", channel));
+            }
+            Ok((channel.to_string(), weight))
+        })
+        .collect()
+}
+
+/// Parses a rolling window given as `window:step`, both positive numbers of
+/// samples.
+fn parse_rolling(arg: &str) -> Result<(usize, usize), String> {
+    let (window, step) = arg
+        .split_once(':')
+        .ok_or_else(|| format!("expected window:step, got {}", arg))?;
+    let parse = |samples: &str| -> Result<usize, String> {
+        match samples.trim().parse() {
+            Ok(0) => Err(String::from("window and step must be positive")),
+            Ok(samples) => Ok(samples),
+            Err(error) => Err(format!("{}: {}", samples, error)),
+        }
+    };
+    Ok((parse(window)?, parse(step)?))
+}
 
-fn main() -> std::io::Result<()> {
-    let glob_pattern: String = String::from("D:/datasets/vitaldb_individual_csvs/*.csv");
+/// Parses a non-negative number of seconds into a duration.
+fn parse_seconds(arg: &str) -> Result<Duration, String> {
+    let seconds: f64 = arg.parse().map_err(|error| format!("{}", error))?;
+    Duration::try_from_secs_f64(seconds).map_err(|error| format!("{}", error))
+}
+
+/// File format of the results.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// A csv in the shape given by `--output-shape`.
+    Csv,
+    /// A versioned MessagePack file of the wide rows (see
+    /// `vital_entropies::write_msgpack`).
+    #[cfg(feature = "msgpack")]
+    Msgpack,
+}
+
+/// Layout of the output csv.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputShape {
+    /// One row per file, one column per channel.
+    Wide,
+    /// One `name, channel, sampen` row per channel per file.
+    Long,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let result = match args.command {
+        Some(Command::Prepare(prepare)) => prepare_files(&prepare),
+        Some(Command::One(one)) => describe_one(&one.path).map(|table| print!("{}", table)),
+        None => compute_entropies(&args.entropy),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            ExitCode::from(exit_code(&error))
+        }
+    }
+}
+
+/// The process exit code for an error, following the BSD `sysexits.h`
+/// conventions so that scripts can tell bad input from a failing disk.
+fn exit_code(error: &SampenError) -> u8 {
+    match error {
+        SampenError::Pattern(_)
+        | SampenError::InvalidParameter(_)
+        | SampenError::InvalidTolerance(_) => 64,
+        SampenError::Csv(_)
+        | SampenError::MissingColumn(_)
+        | SampenError::InvalidValue { .. }
+        | SampenError::InvalidFile { .. }
+        | SampenError::UnsupportedVersion { .. }
+        | SampenError::InsufficientDataForMPlus1 { .. }
+        | SampenError::MissingSampleRate => 65,
+        #[cfg(feature = "msgpack")]
+        SampenError::MsgpackDecode(_) => 65,
+        #[cfg(feature = "ndjson")]
+        SampenError::Json(_) => 65,
+        SampenError::Io(_) | SampenError::Glob(_) => 74,
+        #[cfg(feature = "msgpack")]
+        SampenError::MsgpackEncode(_) => 74,
+        SampenError::Interrupted => 75,
+        // As for a process killed by Ctrl-C, 128 + SIGINT.
+        SampenError::Cancelled => 130,
+    }
+}
+
+/// Rounds a duration to a unit suiting its length, e.g. "~12 minutes".
+fn describe_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs_f64();
+    if seconds < 90.0 {
+        format!("~{} seconds", seconds.round())
+    } else if seconds < 90.0 * 60.0 {
+        format!("~{} minutes", (seconds / 60.0).round())
+    } else {
+        format!("~{:.1} hours", seconds / 3600.0)
+    }
+}
+
+/// Computes and saves the entropies of every file.
+fn compute_entropies(args: &EntropyArgs) -> Result<(), SampenError> {
+    let glob_pattern: String = String::from("D:/datasets/vitaldb_individual_csvs/*");
     println!("Reading vital files...");
-    let vital_files = read_glob_into_vitalfiles(&glob_pattern);
+    if args.append && args.format != OutputFormat::Csv {
+        return Err(SampenError::InvalidParameter(String::from(
+            "--append only writes csv",
+        )));
+    }
+    let csv = CsvReader {
+        has_header: args.has_header,
+        simple: args.simple_csv,
+    };
+    let mut read_times: Vec<Duration> = Vec::new();
+    let mut vital_files =
+        read_glob_into_vitalfiles(&glob_pattern, csv, args.profile.then_some(&mut read_times))?;
+    let read_times: HashMap<String, Duration> = vital_files
+        .iter()
+        .map(|file| file_name(file).to_string())
+        .zip(read_times)
+        .collect();
+    if args.skip_existing {
+        let output = if args.compare_detrending {
+            DETRENDING_CSV
+        } else if args.split_halves.is_some() {
+            HALVES_CSV
+        } else {
+            ENTROPIES_CSV
+        };
+        let existing = existing_names(output)?;
+        let num_files = vital_files.len();
+        vital_files.retain(|file| !existing.contains(file_name(file)));
+        println!(
+            "Skipping {} file(s) already in {}",
+            num_files - vital_files.len(),
+            output
+        );
+    }
+    let ranges = restrict_to_range(&mut vital_files, args.range)?;
+    if let Some(r) = args.absolute_r {
+        // An absolute `r` doesn't depend on the data, so it is checked once.
+        Tolerance::Absolute(r).checked_resolve(&[])?;
+    }
+    if args.match_strategy == MatchStrategy::Auto {
+        let cache = default_cache_path();
+        match load_or_calibrate(&cache, args.recalibrate) {
+            Ok(threshold) => set_brute_force_threshold(threshold),
+            // The default crossover gives the same entropies, only slower.
+            Err(err) => eprintln!(
+                "Warning: couldn't cache the match strategy calibration at {}: {}",
+                cache.display(),
+                err
+            ),
+        }
+    }
     const M: usize = 2;
+    let pool = thread_pool(args.threads)?;
+    let parallelism = Parallelism::split(
+        pool.current_num_threads(),
+        vital_files.len(),
+        3,
+        args.normalize.unwrap_or(0),
+    );
+    let config = EntropyConfig {
+        m: M,
+        preprocess: args.preprocess,
+        max_seconds: args.max_seconds,
+        floor_counts: args.floor_counts,
+        match_strategy: args.match_strategy,
+        normalize: args.normalize,
+        signal: args.signal,
+        min_matches: args.min_matches,
+        tolerance: args
+            .absolute_r
+            .map_or(Tolerance::default(), Tolerance::Absolute),
+        profile: args.profile,
+        channel_threads: parallelism.channels,
+        surrogate_threads: parallelism.surrogates,
+    };
+
+    if args.compare_detrending {
+        return pool.install(|| compare_detrending(args, &config, &vital_files, &ranges));
+    }
+    if let Some(max_difference) = args.split_halves {
+        return pool.install(|| split_halves(args, &config, &vital_files, &ranges, max_difference));
+    }
 
-    println!("Computing sample entropy...");
+    let estimate = estimate_cost_of(vital_files.iter().filter_map(|file| file.as_ref().ok()), M);
+    println!(
+        "Estimated runtime: {}",
+        describe_duration(estimate.total_time / pool.current_num_threads() as u32)
+    );
+    println!("Computing sample entropy... (Ctrl-C saves the files finished so far)");
+    let cancel = cancel_on_ctrl_c();
     let start = Instant::now();
-    let sample_entropies: Vec<VitalEntropies> = {
-        vital_files
-            .par_iter()
-            .progress()
-            .map(|vf| compute_sampen_for_vital_file(M, vf))
-            .collect::<Vec<VitalEntropies>>()
-    };
+    let timings = Mutex::new(Vec::new());
+    let profile = args.profile.then_some(&timings);
+    let rows = pool.install(|| compute_sampen_for_files(&config, &vital_files, &cancel, profile));
     let duration = start.elapsed();
-    println!("Sample entropy computation finished in: {:?}", duration);
+    let num_unfinished = rows.iter().filter(|row| row.is_none()).count();
+    let mut sample_entropies: Vec<VitalEntropies> = rows
+        .into_iter()
+        .zip(&ranges)
+        .filter_map(|(row, range)| {
+            let mut entropies = row?;
+            (entropies.range_start, entropies.range_end) = range_bounds(range);
+            Some(entropies)
+        })
+        .collect();
+    if num_unfinished > 0 {
+        println!(
+            "Cancelled after {:?}; saving the {} finished file(s) without the {} unfinished.",
+            duration,
+            sample_entropies.len(),
+            num_unfinished
+        );
+    } else {
+        println!("Sample entropy computation finished in: {:?}", duration);
+    }
+    if args.summary {
+        print!("{}", summarize(&sample_entropies));
+    }
+    if args.profile {
+        let mut timings = timings
+            .into_inner()
+            .unwrap_or_else(|error| error.into_inner());
+        for timing in timings.iter_mut() {
+            timing.read = read_times.get(&timing.name).copied().unwrap_or_default();
+        }
+        print!("{}", describe_profile(&timings, SLOWEST_FILES));
+        write_profile(
+            create_csv(PROFILE_CSV, args.provenance, args.append)?,
+            &timings,
+        )?
+        .sync_all()?;
+    }
+    if let Some(weights) = &args.weights {
+        for entropies in sample_entropies.iter_mut() {
+            entropies.composite = Some(entropies.composite_score(weights));
+        }
+    }
+    if let Some(decimals) = args.round_decimals {
+        for entropies in sample_entropies.iter_mut() {
+            entropies.round(decimals);
+        }
+    }
+
+    // A cancelled batch is saved as soon as possible, without its windows.
+    if let Some((window, step)) = args.rolling.filter(|_| num_unfinished == 0) {
+        println!("Computing rolling sample entropy...");
+        let mut rows = pool.install(|| rolling_rows(&config, &vital_files, &ranges, window, step));
+        if let Some(decimals) = args.round_decimals {
+            for row in rows.iter_mut() {
+                row.round(decimals);
+            }
+        }
+        let mut writer = create_csv(ROLLING_CSV, args.provenance, args.append)?;
+        for row in &rows {
+            writer.serialize(row)?;
+        }
+        writer
+            .into_inner()
+            .map_err(|error| error.into_error())?
+            .sync_all()?;
+    }
+
+    match args.format {
+        OutputFormat::Csv => {
+            println!("Saving to csv...");
+            let writer = create_csv(ENTROPIES_CSV, args.provenance, args.append)?;
+            let file = write_rows(writer, &sample_entropies, args.output_shape)?;
+            file.sync_all()?;
+        }
+        #[cfg(feature = "msgpack")]
+        OutputFormat::Msgpack => {
+            println!("Saving to msgpack...");
+            let mut writer =
+                std::io::BufWriter::new(File::create("vitaldb_entropies_rust.msgpack")?);
+            sample_entropy::vital_entropies::write_msgpack(&sample_entropies, &mut writer)?;
+            writer
+                .into_inner()
+                .map_err(|error| error.into_error())?
+                .sync_all()?;
+        }
+    }
+
+    if num_unfinished > 0 {
+        return Err(SampenError::Cancelled);
+    }
+    Ok(())
+}
+
+/// Returns a flag that Ctrl-C sets, to cancel a batch cleanly. A second
+/// Ctrl-C exits at once, for a computation that doesn't stop soon enough.
+fn cancel_on_ctrl_c() -> Arc<AtomicBool> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let flag = cancel.clone();
+    let handler = ctrlc::set_handler(move || {
+        if flag.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+    });
+    if let Err(error) = handler {
+        eprintln!("Warning: Ctrl-C will exit without saving: {}", error);
+    }
+    cancel
+}
+
+/// Cuts every readable file down to `range`, if one is given, returning the
+/// sample range each file was cut to (`None` for unreadable files).
+fn restrict_to_range(
+    vital_files: &mut [Result<VitalFile, String>],
+    range: Option<SampleRange>,
+) -> Result<Vec<Option<Range<usize>>>, SampenError> {
+    vital_files
+        .iter_mut()
+        .map(|file| match (file, range) {
+            (Ok(vitalf), Some(range)) => {
+                let used = range.resolve(vitalf)?;
+                vitalf.restrict(used.clone());
+                Ok(Some(used))
+            }
+            _ => Ok(None),
+        })
+        .collect()
+}
+
+/// The `range_start` and `range_end` of a row.
+fn range_bounds(range: &Option<Range<usize>>) -> (Option<usize>, Option<usize>) {
+    (
+        range.as_ref().map(|range| range.start),
+        range.as_ref().map(|range| range.end),
+    )
+}
+
+/// Builds the pool the entropies are computed in, with `threads` threads or,
+/// if 0, one per core.
+fn thread_pool(threads: usize) -> Result<rayon::ThreadPool, SampenError> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|error| {
+            SampenError::InvalidParameter(format!("could not start {} threads: {}", threads, error))
+        })
+}
+
+/// How many of the tasks of each level of the computation may run at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Parallelism {
+    files: usize,
+    /// The channels of one file.
+    channels: usize,
+    /// The shuffles of one channel with `--normalize`.
+    surrogates: usize,
+}
+
+impl Parallelism {
+    /// Splits a budget of threads over the files, their channels and the
+    /// shuffles of each channel, in that order.
+    ///
+    /// The outer levels go first, since a task spanning a whole file shares
+    /// nothing with its neighbours and costs no coordination. Each level
+    /// runs as many of its tasks at once as leave a whole share of the
+    /// budget to each, and hands the shares down: with 8 threads and 2
+    /// files, the files get 2 and each of their 3 channels runs at once,
+    /// leaving 8 / 6 = 1 thread for its shuffles. So a large batch runs a
+    /// file per thread, as without the inner levels, and the inner levels
+    /// only spread out once there are fewer files than threads, e.g. a
+    /// single long recording. The product of the levels never exceeds the
+    /// budget, so the tasks never outnumber the threads; the pool they run
+    /// in, sized to the budget, enforces the same.
+    fn split(budget: usize, num_files: usize, num_channels: usize, num_surrogates: usize) -> Self {
+        let budget = budget.max(1);
+        let files = num_files.clamp(1, budget);
+        let channels = num_channels.clamp(1, budget / files);
+        let surrogates = num_surrogates.clamp(1, budget / (files * channels));
+        Parallelism {
+            files,
+            channels,
+            surrogates,
+        }
+    }
+}
+
+/// Maps `f` over the items in the current rayon pool, running at most
+/// `width` items at once, and keeps their order. With a width of 1 the items
+/// are mapped in turn on the calling thread.
+fn map_at_most<T: Sync, U: Send>(
+    items: &[T],
+    width: usize,
+    f: impl Fn(&T) -> U + Sync + Send,
+) -> Vec<U> {
+    if width <= 1 {
+        return items.iter().map(f).collect();
+    }
+    // Each chunk is one task mapping its items in turn.
+    items
+        .par_chunks(items.len().div_ceil(width).max(1))
+        .flat_map_iter(|chunk| chunk.iter().map(&f))
+        .collect()
+}
+
+/// Computes the entropies of every file in parallel, in the current rayon
+/// pool, keeping the files' order.
+///
+/// Once `cancel` is set, files not yet started are skipped and those running
+/// stop within a template's comparisons (see `Interrupt`); both are `None`,
+/// since a file cut short has no meaningful row. The rows of the files that
+/// had finished are kept, so that they can still be saved.
+///
+/// With `config.profile` set, the channel timings of every finished file are
+/// pushed to `profile`, in the order the files finish and with their read
+/// time left at zero.
+fn compute_sampen_for_files(
+    config: &EntropyConfig,
+    vital_files: &[Result<VitalFile, String>],
+    cancel: &Arc<AtomicBool>,
+    profile: Option<&Mutex<Vec<FileTiming>>>,
+) -> Vec<Option<VitalEntropies>> {
+    vital_files
+        .par_iter()
+        .progress()
+        .map_with(WorkBuffer::default(), |work, file| {
+            let interrupt = config.interrupt().with_cancel(cancel.clone());
+            if interrupt.is_cancelled() {
+                return None;
+            }
+            work.timings.clear();
+            let entropies = match file {
+                Ok(vitalf) => compute_sampen_with_interrupt(config, vitalf, work, &interrupt),
+                Err(name) => VitalEntropies::parse_error(name.clone()),
+            };
+            if interrupt.is_cancelled() {
+                return None;
+            }
+            if let Some(profile) = profile {
+                let timing = FileTiming {
+                    name: entropies.name.clone(),
+                    read: Duration::ZERO,
+                    channels: std::mem::take(&mut work.timings),
+                };
+                profile
+                    .lock()
+                    .unwrap_or_else(|error| error.into_inner())
+                    .push(timing);
+            }
+            Some(entropies)
+        })
+        .collect()
+}
+
+/// Computes the entropy of every `window` sample window, starting every
+/// `step` samples, of each channel of the readable files. Windows start
+/// from the beginning of each file's range, if it was cut to one.
+fn rolling_rows(
+    config: &EntropyConfig,
+    vital_files: &[Result<VitalFile, String>],
+    ranges: &[Option<Range<usize>>],
+    window: usize,
+    step: usize,
+) -> Vec<WindowEntropy> {
+    vital_files
+        .par_iter()
+        .zip(ranges)
+        .filter_map(|(file, range)| Some((file.as_ref().ok()?, range)))
+        .flat_map_iter(|(vitalf, range)| {
+            let offset = range.as_ref().map_or(0, |range| range.start);
+            vitalf
+                .channels()
+                .into_iter()
+                .flat_map(move |(channel, data)| {
+                    stats::rolling_sample_entropy(config.m, config.tolerance, data, window, step)
+                        .into_iter()
+                        .enumerate()
+                        .map(move |(window_index, sampen)| WindowEntropy {
+                            name: vitalf.name.clone(),
+                            channel: channel.to_string(),
+                            window_index,
+                            start_sample: offset + window_index * step,
+                            sampen,
+                        })
+                })
+        })
+        .collect()
+}
+
+/// Computes and saves the entropies of every file on both the raw and the
+/// detrended waves, with the rest of `config` shared by both.
+fn compare_detrending(
+    args: &EntropyArgs,
+    config: &EntropyConfig,
+    vital_files: &[Result<VitalFile, String>],
+    ranges: &[Option<Range<usize>>],
+) -> Result<(), SampenError> {
+    if args.format != OutputFormat::Csv {
+        return Err(SampenError::InvalidParameter(String::from(
+            "--compare-detrending only writes csv",
+        )));
+    }
+    let raw_config = EntropyConfig {
+        preprocess: Preprocess::Raw,
+        ..*config
+    };
+    let detrended_config = EntropyConfig {
+        preprocess: Preprocess::Detrend,
+        ..*config
+    };
+
+    println!("Computing raw and detrended sample entropy...");
+    let start = Instant::now();
+    let mut comparisons: Vec<DetrendingComparison> = vital_files
+        .par_iter()
+        .progress()
+        .map_with(WorkBuffer::default(), |work, file| {
+            DetrendingComparison::new(
+                &compute_sampen_for_file(&raw_config, file, work),
+                &compute_sampen_for_file(&detrended_config, file, work),
+            )
+        })
+        .collect();
+    println!(
+        "Sample entropy computation finished in: {:?}",
+        start.elapsed()
+    );
+    for (comparison, range) in comparisons.iter_mut().zip(ranges) {
+        (comparison.range_start, comparison.range_end) = range_bounds(range);
+    }
+
+    if let Some(decimals) = args.round_decimals {
+        for comparison in comparisons.iter_mut() {
+            comparison.round(decimals);
+        }
+    }
+
+    println!("Saving to csv...");
+    let writer = create_csv(DETRENDING_CSV, args.provenance, args.append)?;
+    let file = write_rows(writer, &comparisons, args.output_shape)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Computes the entropies of the first and second halves of every file with
+/// `compute_sampen_for_halves`, and saves them.
+fn split_halves(
+    args: &EntropyArgs,
+    config: &EntropyConfig,
+    vital_files: &[Result<VitalFile, String>],
+    ranges: &[Option<Range<usize>>],
+    max_difference: f32,
+) -> Result<(), SampenError> {
+    if args.format != OutputFormat::Csv {
+        return Err(SampenError::InvalidParameter(String::from(
+            "--split-halves only writes csv",
+        )));
+    }
+    println!("Computing sample entropy of both halves...");
+    let start = Instant::now();
+    let mut comparisons: Vec<HalvesComparison> = vital_files
+        .par_iter()
+        .progress()
+        .map_with(WorkBuffer::default(), |work, file| {
+            compute_sampen_for_halves(config, file, work, max_difference)
+        })
+        .collect();
+    println!(
+        "Sample entropy computation finished in: {:?}",
+        start.elapsed()
+    );
+    for (comparison, range) in comparisons.iter_mut().zip(ranges) {
+        (comparison.range_start, comparison.range_end) = range_bounds(range);
+    }
+
+    if let Some(decimals) = args.round_decimals {
+        for comparison in comparisons.iter_mut() {
+            comparison.round(decimals);
+        }
+    }
 
     println!("Saving to csv...");
-    let mut writer = Writer::from_path("vitaldb_entropies_rust.csv")?;
-    for element in sample_entropies.iter() {
-        writer.serialize(element)?;
+    let writer = create_csv(HALVES_CSV, args.provenance, args.append)?;
+    let file = write_rows(writer, &comparisons, args.output_shape)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Computes the entropies of a file's first and second halves (see
+/// `VitalFile::halves`) with the same `config`, flagging the channels whose
+/// halves differ by more than `max_difference`. A file that couldn't be read
+/// gets `Status::ParseError` for both.
+fn compute_sampen_for_halves(
+    config: &EntropyConfig,
+    file: &Result<VitalFile, String>,
+    work: &mut WorkBuffer,
+    max_difference: f32,
+) -> HalvesComparison {
+    match file {
+        Ok(vitalf) => {
+            let (first, second) = vitalf.halves();
+            HalvesComparison::new(
+                &compute_sampen_for_vital_file(config, &first, work),
+                &compute_sampen_for_vital_file(config, &second, work),
+                max_difference,
+            )
+        }
+        Err(name) => {
+            let failed = VitalEntropies::parse_error(name.clone());
+            HalvesComparison::new(&failed, &failed, max_difference)
+        }
     }
-    writer.flush()?;
+}
+
+/// Computes the entropies of the csv at `path` on the current thread with
+/// `EntropyConfig::default()`, returning them as a small table.
+fn describe_one(path: &std::path::Path) -> Result<String, SampenError> {
+    let vital_file = read_csv(&path.to_string_lossy()).map_err(|error| match error {
+        // The io error alone doesn't say which file is missing.
+        SampenError::Io(error) => SampenError::Io(std::io::Error::new(
+            error.kind(),
+            format!("{}: {}", path.display(), error),
+        )),
+        error => error,
+    })?;
+    let entropies = compute_sampen_for_vital_file(
+        &EntropyConfig::default(),
+        &vital_file,
+        &mut WorkBuffer::default(),
+    );
+    let mut table = format!("{} ({:?}", entropies.name, entropies.status);
+    if entropies.unreliable {
+        table.push_str(", unreliable");
+    }
+    table.push_str(")\nchannel   sampen\n");
+    for row in entropies.to_long() {
+        table.push_str(&format!("{:<7} {:>8.4}\n", row.channel, row.sampen));
+    }
+    Ok(table)
+}
 
+/// Converts every file matching the input glob into the canonical layout.
+///
+/// Files that can't be read with the given columns are reported and skipped,
+/// so that one malformed export doesn't stop the rest from being converted.
+fn prepare_files(args: &PrepareArgs) -> Result<(), SampenError> {
+    std::fs::create_dir_all(&args.output_dir)?;
+    let layout = CsvLayout {
+        has_header: args.has_header,
+        ..args.columns.clone()
+    };
+    let mut skipped: usize = 0;
+    for path in glob(&args.input)? {
+        let path = path?;
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let vital_file = match read_csv_with_layout(File::open(&path)?, &layout, &stem) {
+            Ok(vital_file) => vital_file,
+            Err(error) => {
+                eprintln!("Skipping {}: {}", path.display(), error);
+                skipped += 1;
+                continue;
+            }
+        };
+        let output = args.output_dir.join(path.file_name().unwrap_or_default());
+        write_csv(&vital_file, File::create(&output)?)?;
+    }
+    if skipped > 0 {
+        eprintln!("Skipped {} file(s) that could not be converted.", skipped);
+    }
     Ok(())
 }
 
-/// Vital file struct for holding the data.
-pub struct VitalFile {
+/// The output csv of the entropies.
+const ENTROPIES_CSV: &str = "vitaldb_entropies_rust.csv";
+/// The output csv of `--rolling`.
+const ROLLING_CSV: &str = "vitaldb_rolling_entropies_rust.csv";
+/// The output csv of `--compare-detrending`.
+const DETRENDING_CSV: &str = "vitaldb_detrending_comparison_rust.csv";
+/// The output csv of `--split-halves`.
+const HALVES_CSV: &str = "vitaldb_halves_comparison_rust.csv";
+/// The output csv of `--profile`.
+const PROFILE_CSV: &str = "vitaldb_profile_rust.csv";
+
+/// Creates the csv at `path`, first writing the `provenance` of this build
+/// as a `#` comment line if asked to. With `append`, an existing file is
+/// appended to instead, and only gets the comment and the header row if it
+/// is empty.
+fn create_csv(
+    path: &str,
+    with_provenance: bool,
+    append: bool,
+) -> Result<Writer<File>, SampenError> {
+    let mut file = if append {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+    } else {
+        File::create(path)?
+    };
+    let is_new = file.metadata()?.len() == 0;
+    if with_provenance && is_new {
+        use std::io::Write;
+        writeln!(file, "# {}", provenance())?;
+    }
+    Ok(csv::WriterBuilder::new()
+        .has_headers(is_new)
+        .from_writer(file))
+}
+
+/// The `name` column of the rows of an existing output csv, or none if there
+/// is no file at `path`. Comment lines and the header row are skipped.
+fn existing_names(path: &str) -> Result<HashSet<String>, SampenError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(error) => return Err(error.into()),
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .flexible(true)
+        .from_reader(file);
+    let mut names = HashSet::new();
+    for record in reader.records() {
+        if let Some(name) = record?.get(0) {
+            names.insert(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// The name a file's rows are written under, also for unreadable files.
+fn file_name(file: &Result<VitalFile, String>) -> &str {
+    match file {
+        Ok(vitalf) => &vitalf.name,
+        Err(name) => name,
+    }
+}
+
+/// Writes the results to a csv writer in the requested shape.
+///
+/// Returns the underlying writer once everything has been flushed to it, so
+/// that a file can be synced to disk. Any error while writing or flushing is
+/// returned rather than leaving a silently truncated csv.
+fn write_rows<W: std::io::Write, T: Serialize + ToLong>(
+    mut writer: Writer<W>,
+    rows: &[T],
+    shape: OutputShape,
+) -> std::io::Result<W> {
+    for element in rows.iter() {
+        match shape {
+            OutputShape::Wide => writer.serialize(element)?,
+            OutputShape::Long => {
+                for row in element.to_long() {
+                    writer.serialize(row)?;
+                }
+            }
+        }
+    }
+    writer.into_inner().map_err(|error| error.into_error())
+}
+
+/// Settings shared by the entropy computation of every file.
+#[derive(Clone, Copy)]
+struct EntropyConfig {
+    m: usize,
+    preprocess: Preprocess,
+    max_seconds: Option<Duration>,
+    floor_counts: bool,
+    match_strategy: MatchStrategy,
+    normalize: Option<usize>,
+    signal: Signal,
+    min_matches: usize,
+    tolerance: Tolerance,
+    /// Whether each channel's timing is recorded in `WorkBuffer::timings`.
+    profile: bool,
+    /// How many of a file's channels are computed at once (see
+    /// `Parallelism`).
+    channel_threads: usize,
+    /// How many of a channel's `normalize` shuffles are computed at once.
+    surrogate_threads: usize,
+}
+
+impl EntropyConfig {
+    /// The interrupt of one file, triggering after `max_seconds`, if set.
+    fn interrupt(&self) -> Interrupt {
+        match self.max_seconds {
+            Some(budget) => Interrupt::after(budget),
+            None => Interrupt::default(),
+        }
+    }
+}
+
+impl Default for EntropyConfig {
+    /// The settings of a run without any options.
+    fn default() -> Self {
+        EntropyConfig {
+            m: 2,
+            preprocess: Preprocess::Detrend,
+            max_seconds: None,
+            floor_counts: false,
+            match_strategy: MatchStrategy::Auto,
+            normalize: None,
+            signal: Signal::Wave,
+            min_matches: 10,
+            tolerance: Tolerance::default(),
+            profile: false,
+            channel_threads: 1,
+            surrogate_threads: 1,
+        }
+    }
+}
+
+/// The entropy of a single wave and the match counts it was computed from.
+struct WaveEntropy {
+    sampen: f32,
+    counts: stats::MatchCounts,
+    /// The fraction of the wave's template pairs that matched.
+    match_fraction: f32,
+}
+
+/// Per-task scratch space, reused across all the files a rayon task handles
+/// so that preprocessing and template construction don't reallocate per file.
+#[derive(Default, Clone)]
+struct WorkBuffer {
+    preprocessed: Vec<f32>,
+    templates: stats::TemplateBuffer,
+    /// With `EntropyConfig::profile`, the timing of each channel computed
+    /// since it was last cleared.
+    timings: Vec<(&'static str, ChannelTiming)>,
+    /// With `EntropyConfig::profile`, when the last wave finished
+    /// preprocessing.
+    preprocessed_at: Option<Instant>,
+}
+
+/// The time a channel took, recorded with `--profile`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct ChannelTiming {
+    /// Deriving the signal, preprocessing it and resolving `r`.
+    preprocess: Duration,
+    /// Counting the matches, including those of any `--normalize`
+    /// surrogates.
+    matching: Duration,
+}
+
+/// The time a file took, recorded with `--profile`.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct FileTiming {
     name: String,
-    sbp: Vec<f32>,
-    mbp: Vec<f32>,
-    dbp: Vec<f32>,
+    read: Duration,
+    channels: Vec<(&'static str, ChannelTiming)>,
+}
+
+impl FileTiming {
+    /// The time spent on the file in every stage.
+    fn total(&self) -> Duration {
+        self.read
+            + self
+                .channels
+                .iter()
+                .map(|(_, timing)| timing.preprocess + timing.matching)
+                .sum::<Duration>()
+    }
+}
+
+/// The number of files `describe_profile` lists as the slowest.
+const SLOWEST_FILES: usize = 5;
+
+/// Describes where the time of a `--profile` run went: the total of each
+/// stage over all the files, and the `num_slowest` files that took longest.
+fn describe_profile(timings: &[FileTiming], num_slowest: usize) -> String {
+    let channels = || timings.iter().flat_map(|file| &file.channels);
+    let mut description = format!(
+        "Time spent reading: {:?}, preprocessing: {:?}, counting matches: {:?}\n",
+        timings.iter().map(|file| file.read).sum::<Duration>(),
+        channels()
+            .map(|(_, timing)| timing.preprocess)
+            .sum::<Duration>(),
+        channels()
+            .map(|(_, timing)| timing.matching)
+            .sum::<Duration>(),
+    );
+    let mut slowest: Vec<&FileTiming> = timings.iter().collect();
+    slowest.sort_by_key(|file| std::cmp::Reverse(file.total()));
+    description.push_str("Slowest files:\n");
+    for file in slowest.iter().take(num_slowest) {
+        description.push_str(&format!("  {}: {:?}\n", file.name, file.total()));
+    }
+    description
+}
+
+/// Writes the timings of a `--profile` run, a row per file with its read
+/// time and each channel's preprocessing and match counting times, in
+/// seconds. Returns the underlying writer once everything is flushed to it,
+/// like `write_rows`.
+fn write_profile<W: std::io::Write>(
+    mut writer: Writer<W>,
+    timings: &[FileTiming],
+) -> std::io::Result<W> {
+    let channels: Vec<&str> = timings
+        .iter()
+        .find(|file| !file.channels.is_empty())
+        .map(|file| file.channels.iter().map(|(channel, _)| *channel).collect())
+        .unwrap_or_default();
+    let mut header = vec![String::from("name"), String::from("read_seconds")];
+    for channel in &channels {
+        header.push(format!("{}_preprocess_seconds", channel));
+        header.push(format!("{}_match_seconds", channel));
+    }
+    header.push(String::from("total_seconds"));
+    writer.write_record(&header)?;
+    for file in timings {
+        let mut record = vec![file.name.clone(), file.read.as_secs_f64().to_string()];
+        for channel in &channels {
+            let timing = file
+                .channels
+                .iter()
+                .find(|(name, _)| name == channel)
+                .map(|(_, timing)| *timing)
+                .unwrap_or_default();
+            record.push(timing.preprocess.as_secs_f64().to_string());
+            record.push(timing.matching.as_secs_f64().to_string());
+        }
+        record.push(file.total().as_secs_f64().to_string());
+        writer.write_record(&record)?;
+    }
+    writer.into_inner().map_err(|error| error.into_error())
+}
+
+/// Computes the entropies of a file read by `read_glob_into_vitalfiles`, or
+/// the `Status::ParseError` row of a file that couldn't be read.
+fn compute_sampen_for_file(
+    config: &EntropyConfig,
+    file: &Result<VitalFile, String>,
+    work: &mut WorkBuffer,
+) -> VitalEntropies {
+    match file {
+        Ok(vitalf) => compute_sampen_for_vital_file(config, vitalf, work),
+        Err(name) => VitalEntropies::parse_error(name.clone()),
+    }
 }
 
 /// Computes sample entropy for a single VitalFile struct.
-fn compute_sampen_for_vital_file(m: usize, vitalf: &VitalFile) -> VitalEntropies {
-    let sbp_sampen: f32 = compute_sampen_for_wave(m, stats::detrend_data(&vitalf.sbp));
-    let mbp_sampen: f32 = compute_sampen_for_wave(m, stats::detrend_data(&vitalf.mbp));
-    let dbp_sampen: f32 = compute_sampen_for_wave(m, stats::detrend_data(&vitalf.dbp));
+///
+/// Channels that are flat, too short or missing from the file get a NaN
+/// entropy, as do those still running when the file runs past
+/// `config.max_seconds`; the rest keep theirs. The row's status is the worst
+/// of its channels'. The row is marked unreliable if any finished channel has
+/// fewer than `config.min_matches` matches.
+fn compute_sampen_for_vital_file(
+    config: &EntropyConfig,
+    vitalf: &VitalFile,
+    work: &mut WorkBuffer,
+) -> VitalEntropies {
+    compute_sampen_with_interrupt(config, vitalf, work, &config.interrupt())
+}
+
+/// Computes sample entropy for a single VitalFile struct like
+/// `compute_sampen_for_vital_file`, stopping the channels still running when
+/// `interrupt` triggers. With `config.channel_threads` above 1, the channels
+/// are computed at once in the current rayon pool, each with scratch space
+/// of its own.
+fn compute_sampen_with_interrupt(
+    config: &EntropyConfig,
+    vitalf: &VitalFile,
+    work: &mut WorkBuffer,
+    interrupt: &Interrupt,
+) -> VitalEntropies {
+    let channels = [
+        ("sbp", &vitalf.sbp),
+        ("mbp", &vitalf.mbp),
+        ("dbp", &vitalf.dbp),
+    ];
+    let entropies: Vec<Result<WaveEntropy, Status>> = if config.channel_threads > 1 {
+        let computed = map_at_most(&channels, config.channel_threads, |(channel, data)| {
+            let mut channel_work = WorkBuffer::default();
+            let entropy = compute_sampen_for_channel(
+                config,
+                vitalf,
+                channel,
+                data,
+                &mut channel_work,
+                interrupt,
+            );
+            (entropy, channel_work.timings)
+        });
+        computed
+            .into_iter()
+            .map(|(entropy, timings)| {
+                work.timings.extend(timings);
+                entropy
+            })
+            .collect()
+    } else {
+        channels
+            .iter()
+            .map(|(channel, data)| {
+                compute_sampen_for_channel(config, vitalf, channel, data, work, interrupt)
+            })
+            .collect()
+    };
 
+    let sampen = |channel: &Result<WaveEntropy, Status>| {
+        channel.as_ref().map_or(f32::NAN, |wave| wave.sampen)
+    };
+    let match_fraction = |channel: &Result<WaveEntropy, Status>| {
+        channel.as_ref().ok().map(|wave| wave.match_fraction)
+    };
     VitalEntropies {
         name: vitalf.name.clone(),
-        sbp_sampen,
-        mbp_sampen,
-        dbp_sampen,
+        sbp_sampen: sampen(&entropies[0]),
+        mbp_sampen: sampen(&entropies[1]),
+        dbp_sampen: sampen(&entropies[2]),
+        composite: None,
+        status: entropies
+            .iter()
+            .map(|channel| channel.as_ref().err().copied().unwrap_or_default())
+            .max()
+            .unwrap_or_default(),
+        unreliable: entropies.iter().any(|channel| {
+            channel
+                .as_ref()
+                .is_ok_and(|wave| !wave.counts.is_reliable(config.min_matches))
+        }),
+        range_start: None,
+        range_end: None,
+        missing_channels: vitalf.missing_channels().join(";"),
+        sbp_match_fraction: match_fraction(&entropies[0]),
+        mbp_match_fraction: match_fraction(&entropies[1]),
+        dbp_match_fraction: match_fraction(&entropies[2]),
     }
 }
 
-fn compute_sampen_for_wave(m: usize, data: Vec<f32>) -> f32 {
-    let stdev: f32 = stats::standard_deviation(&data);
-    let r: f32 = stdev * 0.2;
-    stats::sample_entropy(m, r, &data)
+/// Computes the entropy of one of a file's channels with
+/// `compute_sampen_for_wave`, recording its timing in `work` with
+/// `config.profile`. A missing channel is `Status::MissingChannel`.
+fn compute_sampen_for_channel(
+    config: &EntropyConfig,
+    vitalf: &VitalFile,
+    channel: &'static str,
+    data: &Channel,
+    work: &mut WorkBuffer,
+    interrupt: &Interrupt,
+) -> Result<WaveEntropy, Status> {
+    if data.missing {
+        return Err(Status::MissingChannel);
+    }
+    let data = &data.data;
+    let start = config.profile.then(Instant::now);
+    work.preprocessed_at = None;
+    let label = format!("{} {}", vitalf.name, channel);
+    let entropy = compute_sampen_for_wave(config, &label, data, work, interrupt);
+    if let Some(start) = start {
+        // A wave failing before its matches are counted spent its time
+        // preprocessing.
+        let timing = match work.preprocessed_at {
+            Some(preprocessed) => ChannelTiming {
+                preprocess: preprocessed - start,
+                matching: preprocessed.elapsed(),
+            },
+            None => ChannelTiming {
+                preprocess: start.elapsed(),
+                matching: Duration::ZERO,
+            },
+        };
+        work.timings.push((channel, timing));
+    }
+    entropy
 }
 
-/// Reads waveform data from a file into a vector.
+/// Derives the configured signal from a single wave, preprocesses it and
+/// computes its sample entropy, using `work` for all intermediate buffers.
 ///
-/// Due to waves being different length, they cannot be put into a single csv
-/// file without doing awkward things. For convenience, csv files for each
-/// vital filename was made. The vital_file struct holds this data.
+/// `r` comes from `config.tolerance`. With `Preprocess::Raw` and an absolute
+/// tolerance the wave is used exactly as given, which is the contract for
+/// callers that preprocess externally. A tolerance resolving to a
+/// non-positive `r`, as a fraction of the MAD or IQR does for a wave that is
+/// nearly all one value, fails with `Status::Flat`.
 ///
-/// # Arguments
-/// * `path` - a reference to a string filepath to a csv file.
+/// Fails with `Status::TooShort` or `Status::Flat` for waves with too few or
+/// no distinct samples, or with nothing but rounding error left after
+/// preprocessing, or whose instantaneous frequency is constant, and with
+/// `Status::Timeout` if interrupted.
 ///
+/// Prints a warning if the wave is quantized more coarsely than `r`, if `r`
+/// is so tight next to the wave's swing that few templates match, or if `r`
+/// is so loose that nearly everything matches, since the entropy is then
+/// meaningless. `label` identifies the wave in it. With
+/// `config.normalize` set, the entropy is divided by the mean entropy of
+/// that many shuffles of the preprocessed wave.
+fn compute_sampen_for_wave(
+    config: &EntropyConfig,
+    label: &str,
+    data: &[f32],
+    work: &mut WorkBuffer,
+    interrupt: &Interrupt,
+) -> Result<WaveEntropy, Status> {
+    let derived: Vec<f32>;
+    let data = match config.signal {
+        Signal::Wave => data,
+        signal => {
+            derived = signal.derive(data);
+            &derived
+        }
+    };
+    if data.len() < config.m + 2 {
+        return Err(Status::TooShort);
+    }
+    if config.signal == Signal::InstantaneousFrequency
+        && stats::standard_deviation(data) <= MIN_FREQUENCY_VARIATION * stats::mean(data).abs()
+    {
+        return Err(Status::Flat);
+    }
+    config.preprocess.apply_into(data, &mut work.preprocessed);
+    if work.preprocessed.iter().all(|x| *x == work.preprocessed[0])
+        || stats::standard_deviation(&work.preprocessed)
+            <= MAX_RESIDUAL_FRACTION * stats::standard_deviation(data)
+    {
+        return Err(Status::Flat);
+    }
+    let r: f32 = config
+        .tolerance
+        .checked_resolve(&work.preprocessed)
+        .map_err(|_| Status::Flat)?;
+    if config.profile {
+        work.preprocessed_at = Some(Instant::now());
+    }
+    if let Some(step) = quantization_warning(data, r) {
+        eprintln!(
+            "Warning: {} is quantized in steps of {}, which is larger than r = {}.",
+            label, step, r
+        );
+    }
+    if let Some(amplitude) = small_tolerance_warning(&work.preprocessed, r) {
+        eprintln!(
+            "Warning: r = {} is under {}% of the {} swing of {} about its mean of {}, so \
+             few of its templates match and its entropy is unstable.",
+            r,
+            100.0 * MIN_TOLERANCE_AC_FRACTION,
+            amplitude,
+            label,
+            stats::mean(&work.preprocessed)
+        );
+    }
+    let wave = sampen_of_preprocessed(
+        config,
+        r,
+        &work.preprocessed,
+        &mut work.templates,
+        interrupt,
+    )?;
+    if loose_tolerance_warning(&work.preprocessed, r, config.m, &wave.counts) {
+        eprintln!(
+            "Warning: r = {} is too loose for {}: {:.0}% of its template pairs match, so its \
+             entropy is near 0 whatever the signal.",
+            r,
+            label,
+            100.0 * wave.match_fraction
+        );
+    }
+    match config.normalize {
+        Some(num_surrogates) => {
+            let seeds: Vec<u64> = (0..num_surrogates as u64).collect();
+            let preprocessed = &work.preprocessed;
+            let surrogate_entropy = |seed: &u64, templates: &mut stats::TemplateBuffer| {
+                let shuffled = shuffle_surrogate(preprocessed, *seed);
+                sampen_of_preprocessed(config, r, &shuffled, templates, interrupt)
+                    .map(|wave| wave.sampen)
+            };
+            let surrogate_entropies: Vec<f32> = if config.surrogate_threads > 1 {
+                map_at_most(&seeds, config.surrogate_threads, |seed| {
+                    surrogate_entropy(seed, &mut stats::TemplateBuffer::default())
+                })
+                .into_iter()
+                .collect::<Result<_, _>>()?
+            } else {
+                seeds
+                    .iter()
+                    .map(|seed| surrogate_entropy(seed, &mut work.templates))
+                    .collect::<Result<_, _>>()?
+            };
+            Ok(WaveEntropy {
+                sampen: wave.sampen / stats::mean(&surrogate_entropies),
+                ..wave
+            })
+        }
+        None => Ok(wave),
+    }
+}
 
-fn read_csv(path: &str) -> Result<VitalFile, Box<dyn Error>> {
-    // Read data from path.
-    let mut reader = csv::Reader::from_path(path)?;
-
-    // Initialize vectors.
-    let mut record_names: Vec<String> = vec![];
-    let mut mean_blood_pressures: Vec<f32> = vec![];
-    let mut systolic_blood_pressures: Vec<f32> = vec![];
-    let mut diastolic_blood_pressures: Vec<f32> = vec![];
-    // Read the values into the arrays.
-    for result in reader.records() {
-        let record = result?;
-
-        let name = &record[0];
-        let mbp = record[1].parse::<f32>()?;
-        let sbp = record[2].parse::<f32>()?;
-        let dbp = record[3].parse::<f32>()?;
-
-        record_names.push(name.to_string());
-        mean_blood_pressures.push(mbp);
-        systolic_blood_pressures.push(sbp);
-        diastolic_blood_pressures.push(dbp);
-    }
-
-    let new_vital_file = VitalFile {
-        name: record_names[0].clone(),
-        sbp: systolic_blood_pressures,
-        mbp: mean_blood_pressures,
-        dbp: diastolic_blood_pressures,
+/// Computes the sample entropy of an already preprocessed wave with the
+/// configured match strategy and count floor.
+fn sampen_of_preprocessed(
+    config: &EntropyConfig,
+    r: f32,
+    data: &[f32],
+    templates: &mut stats::TemplateBuffer,
+    interrupt: &Interrupt,
+) -> Result<WaveEntropy, Interrupted> {
+    let counts = stats::match_counts_with_strategy(
+        config.m,
+        r,
+        data,
+        config.match_strategy,
+        templates,
+        interrupt,
+    )?;
+    let sampen = if config.floor_counts {
+        counts.floored_sample_entropy()
+    } else {
+        counts.sample_entropy()
     };
+    Ok(WaveEntropy {
+        sampen,
+        counts,
+        match_fraction: counts.match_fraction(config.m, data.len()),
+    })
+}
+
+/// Waves whose standard deviation after preprocessing is at most this
+/// fraction of the original are `Status::Flat`: preprocessing removed all of
+/// them but rounding error, as `Preprocess::Detrend` does to a straight line
+/// and `Preprocess::Periodic` to a pure sinusoid. f32 rounding is about 1e-7
+/// of the values, so this leaves room for large offsets.
+const MAX_RESIDUAL_FRACTION: f32 = 1e-4;
+
+/// Instantaneous frequencies whose standard deviation is at most this
+/// fraction of their mean are `Status::Flat`: the wave is a sinusoid, whose
+/// frequency only varies by the error locating its zero crossings, about
+/// 1e-4 of the mean at worst. The pulse rate of a living patient varies by
+/// percents.
+const MIN_FREQUENCY_VARIATION: f32 = 1e-3;
 
-    Ok(new_vital_file)
+/// Match fractions above this are flagged by `loose_tolerance_warning`, well
+/// above the 0.1 to 0.3 of a typical `r` (see `MatchCounts::match_fraction`).
+const MAX_MATCH_FRACTION: f32 = 0.9;
+
+/// Returns true if `r` is wider than the range of the data, so that every
+/// pair of templates matches, or if nearly every pair matched anyway.
+fn loose_tolerance_warning(data: &[f32], r: f32, m: usize, counts: &stats::MatchCounts) -> bool {
+    let finite = data.iter().filter(|x| x.is_finite());
+    let range = finite.clone().copied().fold(f32::NEG_INFINITY, f32::max)
+        - finite.copied().fold(f32::INFINITY, f32::min);
+    r > range || counts.match_fraction(m, data.len()) > MAX_MATCH_FRACTION
 }
 
-/// Reads all the files from the glob pattern into a vector of VitalFiles.
+/// Tolerances below this fraction of the AC amplitude are flagged by
+/// `small_tolerance_warning`.
+///
+/// For white noise the range after mean removal of a few thousand samples is
+/// about 6 to 8 standard deviations, so the usual `r` of 0.2 standard
+/// deviations is 3% or more of it. A signal whose `r` is below 2% has a
+/// standard deviation that is small next to its swing, e.g. a quiet baseline
+/// on a large DC offset with a few excursions, and so few of its templates
+/// match that its entropy is dominated by chance.
+const MIN_TOLERANCE_AC_FRACTION: f32 = 0.02;
+
+/// Returns the AC amplitude of the data (see `stats::ac_amplitude`) if `r`
+/// is below `MIN_TOLERANCE_AC_FRACTION` of it.
+fn small_tolerance_warning(data: &[f32], r: f32) -> Option<f32> {
+    let amplitude = stats::ac_amplitude(data);
+    (r < MIN_TOLERANCE_AC_FRACTION * amplitude).then_some(amplitude)
+}
+
+/// Returns the quantization step of the data if it is larger than `r`.
+fn quantization_warning(data: &[f32], r: f32) -> Option<f32> {
+    stats::detect_quantization(data).filter(|step| r < *step)
+}
+
+/// Reads all the files from the glob pattern into a vector of VitalFiles,
+/// each with the reader for its extension (see
+/// `reader::reader_for_path`).
+///
+/// Files in a format no reader handles are reported and skipped. A file that
+/// can't be read is reported and kept as the `Err` of its file stem, so that
+/// its row can still be written with `Status::ParseError`.
+///
+/// If given, `read_times` gets how long each file took to read, in order. A
+/// path holding several files splits its time between them evenly.
 ///
 /// # Arguments
 /// * `glob_pattern` - a String pattern for glob to use.
+/// * `csv` - how csvs are read.
+/// * `read_times` - where the read times are recorded, if anywhere.
 ///
+fn read_glob_into_vitalfiles(
+    glob_pattern: &str,
+    csv: CsvReader,
+    mut read_times: Option<&mut Vec<Duration>>,
+) -> Result<Vec<Result<VitalFile, String>>, SampenError> {
+    let bar = ProgressBar::new(glob(glob_pattern)?.count() as u64);
 
-fn read_glob_into_vitalfiles(glob_pattern: &str) -> Vec<VitalFile> {
-    let bar = {
-        let glob_files = glob(glob_pattern).expect("Failed to read glob pattern.");
-        ProgressBar::new(glob_files.count() as u64)
-    };
+    let mut vital_files: Vec<Result<VitalFile, String>> = Vec::new();
+    for file in glob(glob_pattern)? {
+        let path = file?;
+        bar.inc(1);
+        if path.is_dir() {
+            continue;
+        }
+        let reader = match reader_for_path(&path.to_string_lossy(), csv) {
+            Ok(reader) => reader,
+            Err(error) => {
+                eprintln!("Skipping {}", error);
+                continue;
+            }
+        };
+        let start = Instant::now();
+        let num_read = vital_files.len();
+        match reader.read(&path.to_string_lossy()) {
+            Ok(files) => vital_files.extend(files.into_iter().map(Ok)),
+            Err(error) => {
+                eprintln!("Could not read {}: {}", path.display(), error);
+                vital_files.push(Err(path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default()));
+            }
+        }
+        if let Some(read_times) = read_times.as_deref_mut() {
+            let num_files = vital_files.len() - num_read;
+            let elapsed = start.elapsed() / num_files.max(1) as u32;
+            read_times.extend(std::iter::repeat_n(elapsed, num_files));
+        }
+    }
+
+    Ok(vital_files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn example_entropies() -> Vec<VitalEntropies> {
+        (0..4)
+            .map(|ix| VitalEntropies {
+                name: format!("file_{}", ix),
+                sbp_sampen: 0.5,
+                mbp_sampen: 1.0,
+                dbp_sampen: 1.5,
+                composite: None,
+                status: Status::Ok,
+                unreliable: false,
+                range_start: None,
+                range_end: None,
+                missing_channels: String::new(),
+                sbp_match_fraction: Some(0.25),
+                mbp_match_fraction: Some(0.2),
+                dbp_match_fraction: None,
+            })
+            .collect()
+    }
+
+    fn written_rows(shape: OutputShape) -> Vec<String> {
+        let mut buffer: Vec<u8> = Vec::new();
+        write_rows(
+            Writer::from_writer(&mut buffer),
+            &example_entropies(),
+            shape,
+        )
+        .unwrap();
+        String::from_utf8(buffer)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    /// Records what is written to it and the largest single write.
+    #[derive(Debug, Default)]
+    struct RecordingWriter {
+        written: Vec<u8>,
+        largest_write: usize,
+    }
+
+    impl std::io::Write for RecordingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.largest_write = self.largest_write.max(buf.len());
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_output_is_streamed_and_matches_joined_rows() {
+        let rows: Vec<VitalEntropies> = (0..2000)
+            .map(|i| VitalEntropies {
+                name: format!("case_{}", i),
+                ..example_entropies().remove(i % 4)
+            })
+            .collect();
+        let mut recorder = RecordingWriter::default();
+        write_rows(Writer::from_writer(&mut recorder), &rows, OutputShape::Wide).unwrap();
+
+        // The output as it was built before streaming: every row serialized to
+        // its own String, then joined.
+        let joined: String = rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(i == 0)
+                    .from_writer(Vec::new());
+                writer.serialize(row).unwrap();
+                String::from_utf8(writer.into_inner().unwrap()).unwrap()
+            })
+            .collect::<Vec<String>>()
+            .join("");
+        assert_eq!(joined.as_bytes(), recorder.written.as_slice());
+        // Rows reach the file a buffer at a time, never the whole csv at once.
+        assert!(recorder.largest_write < recorder.written.len() / 4);
+    }
+
+    /// Accepts a fixed number of bytes, then fails every write as if the
+    /// disk were full.
+    #[derive(Debug)]
+    struct FailingWriter {
+        capacity: usize,
+    }
+
+    impl std::io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.capacity == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::StorageFull,
+                    "disk full",
+                ));
+            }
+            let written = buf.len().min(self.capacity);
+            self.capacity -= written;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_rounding_hides_last_digit_noise() {
+        let rows = |noise: f32| {
+            let mut entropies = example_entropies();
+            for entropy in entropies.iter_mut() {
+                entropy.sbp_sampen = 0.7312 + noise;
+                entropy.round(4);
+            }
+            let mut buffer: Vec<u8> = Vec::new();
+            write_rows(
+                Writer::from_writer(&mut buffer),
+                &entropies,
+                OutputShape::Wide,
+            )
+            .unwrap();
+            buffer
+        };
+        assert_eq!(rows(0.0000001), rows(0.0000004));
+        assert!(String::from_utf8(rows(0.0000001))
+            .unwrap()
+            .contains("file_0,0.7312,1.0,1.5,,ok,false"));
+    }
+
+    #[test]
+    fn test_parse_weights() {
+        let weights = parse_weights("sbp=2, dbp=0.5").unwrap();
+        assert_eq!(2, weights.len());
+        assert_eq!(Some(&2.0), weights.get("sbp"));
+        assert_eq!(Some(&0.5), weights.get("dbp"));
+        for invalid in ["hr=1", "sbp", "sbp=-1", "sbp=x"] {
+            assert!(parse_weights(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_long_output_has_composite_row() {
+        let mut entropies = example_entropies();
+        entropies[0].composite =
+            Some(entropies[0].composite_score(&parse_weights("sbp=1,dbp=1").unwrap()));
+        let mut buffer: Vec<u8> = Vec::new();
+        write_rows(
+            Writer::from_writer(&mut buffer),
+            &entropies,
+            OutputShape::Long,
+        )
+        .unwrap();
+        let written = String::from_utf8(buffer).unwrap();
+        let rows: Vec<&str> = written.lines().collect();
+        assert_eq!("file_0,composite,1.0,ok,false,,,false,", rows[4]);
+        assert_eq!(4 * 3 + 1, rows.len() - 1);
+    }
+
+    #[test]
+    fn test_one_prints_entropies_or_fails_clearly() {
+        let dir = std::env::temp_dir().join(format!("sampen_one_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let good = dir.join("good.csv");
+        write_csv(&example_vital_file(), File::create(&good).unwrap()).unwrap();
+        let malformed = dir.join("malformed.csv");
+        std::fs::write(&malformed, "name,mbp\nbad,x\n").unwrap();
+        let table = describe_one(&good);
+        let malformed_error = describe_one(&malformed).unwrap_err();
+        let missing_error = describe_one(&dir.join("missing.csv")).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let expected = compute_sampen_for_vital_file(
+            &example_config(None),
+            &example_vital_file(),
+            &mut WorkBuffer::default(),
+        );
+        let table = table.unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!("example (Ok)", lines[0]);
+        assert_eq!(format!("sbp     {:>8.4}", expected.sbp_sampen), lines[2]);
+        assert_eq!(5, lines.len());
+        assert_eq!(65, exit_code(&malformed_error));
+        assert_eq!(74, exit_code(&missing_error));
+        assert!(missing_error.to_string().contains("missing.csv"));
+    }
+
+    #[test]
+    fn test_provenance_comment_names_version() {
+        let dir = std::env::temp_dir().join(format!("sampen_provenance_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.csv");
+        let path = path.to_string_lossy();
+        let rows = example_entropies();
+        let file = write_rows(
+            create_csv(&path, true, false).unwrap(),
+            &rows,
+            OutputShape::Wide,
+        );
+        file.unwrap().sync_all().unwrap();
+        let with_comment = std::fs::read_to_string(path.as_ref()).unwrap();
+        write_rows(
+            create_csv(&path, false, false).unwrap(),
+            &rows,
+            OutputShape::Wide,
+        )
+        .unwrap();
+        let without_comment = std::fs::read_to_string(path.as_ref()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let (comment, rest) = with_comment.split_once('\n').unwrap();
+        assert!(comment.starts_with(&format!("# sample_entropy {}", env!("CARGO_PKG_VERSION"))));
+        assert_eq!(without_comment, rest);
+        assert!(without_comment.starts_with("name,"));
+    }
+
+    #[test]
+    fn test_append_skips_files_already_written() {
+        let dir = std::env::temp_dir().join(format!("sampen_append_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.csv");
+        let path = path.to_string_lossy();
+        let rows = example_entropies();
+        assert!(existing_names(&path).unwrap().is_empty());
+        let writer = create_csv(&path, true, true).unwrap();
+        write_rows(writer, &rows[..3], OutputShape::Wide).unwrap();
+
+        // The second batch overlaps the first on file_1 and file_2.
+        let existing = existing_names(&path).unwrap();
+        let second_batch: Vec<VitalEntropies> = example_entropies()
+            .into_iter()
+            .skip(1)
+            .filter(|entropies| !existing.contains(&entropies.name))
+            .collect();
+        let writer = create_csv(&path, true, true).unwrap();
+        write_rows(writer, &second_batch, OutputShape::Wide).unwrap();
+        let written = std::fs::read_to_string(path.as_ref()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(3, existing.len());
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(6, lines.len(), "{}", written);
+        assert!(lines[0].starts_with("# sample_entropy"));
+        assert!(lines[1].starts_with("name,"));
+        let names: Vec<&str> = lines[2..]
+            .iter()
+            .map(|line| line.split(',').next().unwrap())
+            .collect();
+        assert_eq!(vec!["file_0", "file_1", "file_2", "file_3"], names);
+        assert_eq!("missing", file_name(&Err(String::from("missing"))));
+    }
+
+    #[test]
+    fn test_rolling_rows_cover_every_window() {
+        assert_eq!(Ok((300, 50)), parse_rolling("300:50"));
+        assert!(parse_rolling("300").is_err());
+        assert!(parse_rolling("300:0").is_err());
+
+        let len = 1000;
+        let files: Vec<Result<VitalFile, String>> = vec![
+            Ok(VitalFile {
+                name: String::from("case1"),
+                sample_rate: None,
+                sbp: Channel::new((0..len).map(|x| ((x * 13) % 17) as f32).collect()),
+                mbp: Channel::new((0..len).map(|x| ((x * 7) % 11) as f32).collect()),
+                dbp: Channel::new((0..len).map(|x| ((x * 5) % 19) as f32).collect()),
+            }),
+            Err(String::from("unreadable")),
+        ];
+        let ranges = vec![Some(200..200 + len), None];
+        let (window, step) = (300, 70);
+        let rows = rolling_rows(&EntropyConfig::default(), &files, &ranges, window, step);
+        let num_windows = (len - window) / step + 1;
+        assert_eq!(3 * num_windows, rows.len());
+        let dbp: Vec<&WindowEntropy> = rows.iter().filter(|row| row.channel == "dbp").collect();
+        assert_eq!(num_windows, dbp.len());
+        assert_eq!(
+            (0..num_windows).collect::<Vec<usize>>(),
+            dbp.iter()
+                .map(|row| row.window_index)
+                .collect::<Vec<usize>>()
+        );
+        assert_eq!(200 + 2 * step, dbp[2].start_sample);
+        assert!(rows.iter().all(|row| row.name == "case1"));
+    }
+
+    #[test]
+    fn test_describe_duration() {
+        assert_eq!("~0 seconds", describe_duration(Duration::from_millis(20)));
+        assert_eq!("~45 seconds", describe_duration(Duration::from_secs(45)));
+        assert_eq!("~12 minutes", describe_duration(Duration::from_secs(725)));
+        assert_eq!("~2.5 hours", describe_duration(Duration::from_secs(9000)));
+    }
 
-    let glob_files = glob(glob_pattern).expect("Failed to read glob pattern.");
-    let mut vital_files: Vec<VitalFile> = Vec::new();
-    for file in glob_files {
-        let path: String = match file {
-            Ok(path) => path.into_os_string().into_string().unwrap(),
-            Err(error) => panic!("{:?}", error),
+    #[test]
+    fn test_write_error_surfaces() {
+        let error = write_rows(
+            Writer::from_writer(FailingWriter { capacity: 40 }),
+            &example_entropies(),
+            OutputShape::Long,
+        )
+        .unwrap_err();
+        assert_eq!(std::io::ErrorKind::StorageFull, error.kind());
+    }
+
+    #[test]
+    fn test_exit_codes_separate_bad_input_from_io() {
+        let missing = std::io::Error::new(std::io::ErrorKind::NotFound, "gone");
+        assert_eq!(74, exit_code(&SampenError::from(missing)));
+        let layout_error = "hr=HR".parse::<CsvLayout>().unwrap_err();
+        assert_eq!(64, exit_code(&layout_error));
+        assert_eq!(64, exit_code(&SampenError::InvalidTolerance(0.0)));
+        assert_eq!(
+            65,
+            exit_code(&SampenError::MissingColumn(String::from("dbp")))
+        );
+    }
+
+    fn example_config(max_seconds: Option<Duration>) -> EntropyConfig {
+        EntropyConfig {
+            max_seconds,
+            ..EntropyConfig::default()
+        }
+    }
+
+    fn example_vital_file() -> VitalFile {
+        let wave: Vec<f32> = (0..300).map(|x| ((x * 13) % 17) as f32).collect();
+        VitalFile {
+            name: String::from("example"),
+            sample_rate: None,
+            sbp: Channel::new(wave.clone()),
+            mbp: Channel::new(wave.clone()),
+            dbp: Channel::new(wave),
+        }
+    }
+
+    #[test]
+    fn test_externally_preprocessed_matches_integrated() {
+        let wave: Vec<f32> = (0..400)
+            .map(|x| ((x * 13) % 17) as f32 + x as f32 * 0.05)
+            .collect();
+        let integrated = compute_sampen_for_wave(
+            &example_config(None),
+            "integrated",
+            &wave,
+            &mut WorkBuffer::default(),
+            &Interrupt::default(),
+        )
+        .unwrap();
+
+        let detrended = stats::detrend_data(&wave);
+        let r = 0.2 * stats::standard_deviation(&detrended);
+        let config = EntropyConfig {
+            preprocess: Preprocess::Raw,
+            tolerance: Tolerance::Absolute(r),
+            ..example_config(None)
+        };
+        let external = compute_sampen_for_wave(
+            &config,
+            "external",
+            &detrended,
+            &mut WorkBuffer::default(),
+            &Interrupt::default(),
+        )
+        .unwrap();
+        assert!(integrated.sampen.is_finite());
+        assert_eq!(integrated.sampen.to_bits(), external.sampen.to_bits());
+        assert_eq!(integrated.counts, external.counts);
+    }
+
+    #[test]
+    fn test_range_restricts_channels() {
+        // Periodic for the first 300 samples, irregular after.
+        let wave: Vec<f32> = (0..300)
+            .map(|x| ((x * 13) % 17) as f32)
+            .chain((0..300).map(|x| ((x * x) as f32 * 0.37).sin() * 8.0))
+            .collect();
+        let file = || VitalFile {
+            name: String::from("example"),
+            sample_rate: None,
+            sbp: Channel::new(wave.clone()),
+            mbp: Channel::new(wave.clone()),
+            dbp: Channel::new(wave.clone()),
         };
-        let vital_file = match read_csv(&path) {
-            Ok(result) => result,
-            Err(error) => panic!("Problem opening the csv file: {:?}", error),
+        let compute = |range: Option<SampleRange>| {
+            let mut files = vec![Ok(file())];
+            let ranges = restrict_to_range(&mut files, range).unwrap();
+            let cancel = Arc::new(AtomicBool::new(false));
+            let mut entropies =
+                compute_sampen_for_files(&example_config(None), &files, &cancel, None);
+            let mut entropies = entropies.remove(0).unwrap();
+            (entropies.range_start, entropies.range_end) = range_bounds(&ranges[0]);
+            entropies
         };
-        vital_files.push(vital_file);
-        bar.inc(1);
+        let whole = compute(None);
+        assert_eq!((None, None), (whole.range_start, whole.range_end));
+        let full_range = compute(Some(SampleRange::Samples(0, 600)));
+        assert_eq!(whole.sbp_sampen, full_range.sbp_sampen);
+        assert_eq!(
+            (Some(0), Some(600)),
+            (full_range.range_start, full_range.range_end)
+        );
+
+        let periodic = compute(Some(SampleRange::Samples(0, 300)));
+        let mut presliced = file();
+        presliced.sbp.data.truncate(300);
+        let expected = compute_sampen_for_wave(
+            &example_config(None),
+            "presliced",
+            &presliced.sbp.data,
+            &mut WorkBuffer::default(),
+            &Interrupt::default(),
+        )
+        .unwrap();
+        assert_eq!(expected.sampen, periodic.sbp_sampen);
+        assert!(periodic.sbp_sampen < whole.sbp_sampen);
+
+        let mut past_end = vec![Ok(file())];
+        assert!(matches!(
+            restrict_to_range(&mut past_end, Some(SampleRange::Samples(500, 601))),
+            Err(SampenError::InvalidParameter(_))
+        ));
     }
 
-    vital_files
+    #[test]
+    fn test_detrending_comparison_diverges_only_on_trends() {
+        let compare = |vital_file: &VitalFile| {
+            let mut work = WorkBuffer::default();
+            let raw = EntropyConfig {
+                preprocess: Preprocess::Raw,
+                ..example_config(None)
+            };
+            DetrendingComparison::new(
+                &compute_sampen_for_vital_file(&raw, vital_file, &mut work),
+                &compute_sampen_for_vital_file(&example_config(None), vital_file, &mut work),
+            )
+        };
+        let noise: Vec<f32> = (0..1000).map(|x| ((x * x) as f32 * 0.37).sin()).collect();
+        let noisy_file = VitalFile {
+            name: String::from("noisy"),
+            sample_rate: None,
+            sbp: Channel::new(noise.clone()),
+            mbp: Channel::new(noise.clone()),
+            dbp: Channel::new(noise),
+        };
+        let stationary = compare(&noisy_file);
+        assert!((stationary.sbp_sampen_raw - stationary.sbp_sampen_detrended).abs() < 0.1);
+
+        let mut trending = noisy_file;
+        for (ix, x) in trending.sbp.data.iter_mut().enumerate() {
+            *x += ix as f32 * 0.01;
+        }
+        let trending = compare(&trending);
+        assert!((trending.sbp_sampen_raw - trending.sbp_sampen_detrended).abs() > 0.3);
+        assert_eq!(trending.dbp_sampen_raw, stationary.dbp_sampen_raw);
+
+        let rows = trending.to_long();
+        assert_eq!("sbp", rows[0].channel);
+        assert_eq!(trending.sbp_sampen_detrended, rows[0].sampen_detrended);
+    }
+
+    #[test]
+    fn test_halves_differ_only_on_regime_change() {
+        let noise: Vec<f32> = (0..2000).map(|x| ((x * x) as f32 * 0.37).sin()).collect();
+        let stationary_file = VitalFile {
+            name: String::from("stationary"),
+            sample_rate: None,
+            sbp: Channel::new(noise.clone()),
+            mbp: Channel::new(noise.clone()),
+            dbp: Channel::new(noise),
+        };
+        let (first, second) = stationary_file.halves();
+        assert_eq!(1000, first.num_samples());
+        assert_eq!(1000, second.num_samples());
+        assert_eq!(stationary_file.sbp.data[1000], second.sbp.data[0]);
+        let halves = |file: &VitalFile| {
+            compute_sampen_for_halves(
+                &example_config(None),
+                &Ok(file.clone()),
+                &mut WorkBuffer::default(),
+                0.3,
+            )
+        };
+        let stationary = halves(&stationary_file);
+        assert!(
+            stationary.sbp_difference < 0.3,
+            "{}",
+            stationary.sbp_difference
+        );
+        assert!(!stationary.sbp_nonstationary);
+
+        // The second half of sbp turns into a slow, regular rhythm.
+        let mut changing_file = stationary_file;
+        for (ix, x) in changing_file.sbp.data.iter_mut().enumerate().skip(1000) {
+            *x = (ix as f32 * 0.1).sin() + 0.05 * *x;
+        }
+        let changing = halves(&changing_file);
+        assert!(changing.sbp_difference > 1.0, "{}", changing.sbp_difference);
+        assert!(changing.sbp_nonstationary);
+        assert!(!changing.dbp_nonstationary);
+        assert_eq!(stationary.dbp_difference, changing.dbp_difference);
+
+        let rows = changing.to_long();
+        assert_eq!("sbp", rows[0].channel);
+        assert!(rows[0].nonstationary);
+        let unreadable = compute_sampen_for_halves(
+            &example_config(None),
+            &Err(String::from("broken")),
+            &mut WorkBuffer::default(),
+            0.3,
+        );
+        assert_eq!(Status::ParseError, unreadable.status);
+        assert!(!unreadable.sbp_nonstationary);
+    }
+
+    #[test]
+    fn test_tiny_timeout_produces_sentinel() {
+        let entropies = compute_sampen_for_vital_file(
+            &example_config(Some(Duration::ZERO)),
+            &example_vital_file(),
+            &mut WorkBuffer::default(),
+        );
+        assert_eq!(Status::Timeout, entropies.status);
+        assert!(entropies.sbp_sampen.is_nan());
+        assert!(entropies.dbp_sampen.is_nan());
+    }
+
+    #[test]
+    fn test_generous_timeout_computes_normally() {
+        let entropies = compute_sampen_for_vital_file(
+            &example_config(Some(Duration::from_secs(3600))),
+            &example_vital_file(),
+            &mut WorkBuffer::default(),
+        );
+        assert_eq!(Status::Ok, entropies.status);
+        assert!(entropies.sbp_sampen.is_finite());
+        assert!(parse_seconds("-1").is_err());
+        assert_eq!(Ok(Duration::from_millis(1500)), parse_seconds("1.5"));
+    }
+
+    #[test]
+    fn test_profile_records_channel_timings() {
+        let mut file = example_vital_file();
+        file.sbp.data = (0..3000).map(|x| ((x * 13) % 17) as f32).collect();
+        file.dbp.data.truncate(3);
+        let vital_files = vec![Ok(file), Err(String::from("unreadable"))];
+        let unset = Arc::new(AtomicBool::new(false));
+
+        let profiled = EntropyConfig {
+            profile: true,
+            ..example_config(None)
+        };
+        let timings = Mutex::new(Vec::new());
+        let rows = compute_sampen_for_files(&profiled, &vital_files, &unset, Some(&timings));
+        let mut timings = timings.into_inner().unwrap();
+        timings.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(2, timings.len());
+        assert_eq!("example", timings[0].name);
+        let channels: Vec<&str> = timings[0].channels.iter().map(|(name, _)| *name).collect();
+        assert_eq!(vec!["sbp", "mbp", "dbp"], channels);
+        let (_, sbp) = timings[0].channels[0];
+        assert!(sbp.preprocess > Duration::ZERO && sbp.matching > Duration::ZERO);
+        // The short channel fails before counting any matches.
+        assert_eq!(Duration::ZERO, timings[0].channels[2].1.matching);
+        assert!(timings[1].channels.is_empty());
+        assert!(timings[0].total() >= sbp.preprocess + sbp.matching);
+
+        let description = describe_profile(&timings, 1);
+        assert!(description.contains("Slowest files:\n  example: "));
+        assert!(!description.contains("unreadable"));
+        let written = write_profile(Writer::from_writer(Vec::new()), &timings).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        let header = written.lines().next().unwrap();
+        assert!(header.starts_with("name,read_seconds,sbp_preprocess_seconds,sbp_match_seconds"));
+        assert_eq!(3, written.lines().count());
+
+        // Without the flag the same rows come out and nothing is timed.
+        let mut work = WorkBuffer::default();
+        let entropies = compute_sampen_for_vital_file(
+            &example_config(None),
+            vital_files[0].as_ref().unwrap(),
+            &mut work,
+        );
+        assert!(work.timings.is_empty() && work.preprocessed_at.is_none());
+        assert_eq!(rows[0].as_ref().unwrap().sbp_sampen, entropies.sbp_sampen);
+    }
+
+    #[test]
+    fn test_flat_and_short_channels_set_status() {
+        let config = example_config(None);
+        let mut flat = example_vital_file();
+        flat.mbp.data.fill(80.0);
+        let entropies = compute_sampen_for_vital_file(&config, &flat, &mut WorkBuffer::default());
+        assert_eq!(Status::Flat, entropies.status);
+        assert!(entropies.mbp_sampen.is_nan());
+        assert!(entropies.sbp_sampen.is_finite());
+
+        // Nothing but rounding is left of a sinusoid without its period.
+        let periodic = EntropyConfig {
+            preprocess: Preprocess::Periodic,
+            ..example_config(None)
+        };
+        let mut sine = example_vital_file();
+        sine.sbp.data = (0..300)
+            .map(|t| 100.0 + 20.0 * (t as f32 * 0.37).sin())
+            .collect();
+        let entropies = compute_sampen_for_vital_file(&periodic, &sine, &mut WorkBuffer::default());
+        assert_eq!(Status::Flat, entropies.status);
+        assert!(entropies.sbp_sampen.is_nan());
+
+        // So is the frequency of a sinusoid, while a varying rhythm's isn't.
+        let frequency = EntropyConfig {
+            signal: Signal::InstantaneousFrequency,
+            ..example_config(None)
+        };
+        let entropies =
+            compute_sampen_for_vital_file(&frequency, &sine, &mut WorkBuffer::default());
+        assert_eq!(Status::Flat, entropies.status);
+        assert!(entropies.sbp_sampen.is_nan());
+        let mut rhythm = example_vital_file();
+        for data in [
+            &mut rhythm.sbp.data,
+            &mut rhythm.mbp.data,
+            &mut rhythm.dbp.data,
+        ] {
+            let mut phase = 0.0_f32;
+            *data = (0..3000)
+                .map(|t| {
+                    phase += 0.05 * (1.0 + 0.2 * ((t * 7919) % 101) as f32 / 101.0);
+                    100.0 + 20.0 * (std::f32::consts::TAU * phase).sin()
+                })
+                .collect();
+        }
+        let entropies =
+            compute_sampen_for_vital_file(&frequency, &rhythm, &mut WorkBuffer::default());
+        assert_eq!(Status::Ok, entropies.status);
+        assert!(entropies.sbp_sampen.is_finite());
+
+        let mut short = flat;
+        short.dbp.data.truncate(3);
+        let entropies = compute_sampen_for_vital_file(&config, &short, &mut WorkBuffer::default());
+        assert_eq!(Status::TooShort, entropies.status);
+        assert!(entropies.dbp_sampen.is_nan());
+    }
+
+    #[test]
+    fn test_zero_relative_tolerance_is_flat() {
+        let config = EntropyConfig {
+            preprocess: Preprocess::Raw,
+            tolerance: Tolerance::MadFraction(0.2),
+            ..example_config(None)
+        };
+        // Nine samples in ten are the same, so the MAD and with it r are 0.
+        let mut mostly_flat = example_vital_file();
+        mostly_flat.sbp.data.fill(120.0);
+        for x in mostly_flat.sbp.data.iter_mut().step_by(10) {
+            *x = 121.0;
+        }
+        let entropies =
+            compute_sampen_for_vital_file(&config, &mostly_flat, &mut WorkBuffer::default());
+        assert_eq!(Status::Flat, entropies.status);
+        assert!(entropies.sbp_sampen.is_nan());
+        assert!(entropies.mbp_sampen.is_finite());
+    }
+
+    #[test]
+    fn test_missing_channel_leaves_the_others_computed() {
+        let wave = (0..300).map(|x| ((x * 13) % 17).to_string());
+        let csv: String = std::iter::once(String::from("name,sbp,mbp"))
+            .chain(wave.map(|x| format!("case1,{},{}", x, x)))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let vitalf = sample_entropy::vital_file::read_csv_from_reader(csv.as_bytes()).unwrap();
+        let entropies = compute_sampen_for_vital_file(
+            &example_config(None),
+            &vitalf,
+            &mut WorkBuffer::default(),
+        );
+        let full = compute_sampen_for_vital_file(
+            &example_config(None),
+            &example_vital_file(),
+            &mut WorkBuffer::default(),
+        );
+        assert_eq!(Status::MissingChannel, entropies.status);
+        assert_eq!("dbp", entropies.missing_channels);
+        assert!(entropies.dbp_sampen.is_nan());
+        assert_eq!(full.sbp_sampen, entropies.sbp_sampen);
+        assert_eq!(full.mbp_sampen, entropies.mbp_sampen);
+        assert_eq!("", full.missing_channels);
+
+        // In long format only the missing channel's row is marked missing.
+        let mut buffer: Vec<u8> = Vec::new();
+        write_rows(
+            Writer::from_writer(&mut buffer),
+            std::slice::from_ref(&entropies),
+            OutputShape::Long,
+        )
+        .unwrap();
+        let written = String::from_utf8(buffer).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        let column = lines[0].split(',').position(|column| column == "missing");
+        let missing: Vec<(&str, &str)> = lines[1..]
+            .iter()
+            .map(|line| {
+                let fields: Vec<&str> = line.split(',').collect();
+                (fields[1], fields[column.unwrap()])
+            })
+            .collect();
+        assert_eq!(
+            vec![("sbp", "false"), ("mbp", "false"), ("dbp", "true")],
+            missing
+        );
+    }
+
+    #[test]
+    fn test_unreadable_files_get_parse_error_rows() {
+        let dir = std::env::temp_dir().join(format!("sampen_status_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_csv(
+            &example_vital_file(),
+            File::create(dir.join("good.csv")).unwrap(),
+        )
+        .unwrap();
+        std::fs::write(dir.join("bad.csv"), "name,mbp\nbad,x\n").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a vital file\n").unwrap();
+        let files =
+            read_glob_into_vitalfiles(&dir.join("*").to_string_lossy(), CsvReader::default(), None)
+                .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let statuses: Vec<(String, Status)> = files
+            .iter()
+            .map(|file| {
+                let entropies = compute_sampen_for_file(
+                    &example_config(None),
+                    file,
+                    &mut WorkBuffer::default(),
+                );
+                (entropies.name, entropies.status)
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (String::from("bad"), Status::ParseError),
+                (String::from("example"), Status::Ok)
+            ],
+            statuses
+        );
+    }
+
+    #[test]
+    fn test_thread_count_does_not_change_output() {
+        let vital_files: Vec<Result<VitalFile, String>> = (0..16)
+            .map(|ix| {
+                let mut vital_file = example_vital_file();
+                vital_file.name = format!("file_{}", ix);
+                vital_file.sbp.data.rotate_left(ix);
+                Ok(vital_file)
+            })
+            .collect();
+        let written = |threads: usize| -> Vec<u8> {
+            let cancel = Arc::new(AtomicBool::new(false));
+            let entropies: Vec<VitalEntropies> = thread_pool(threads)
+                .unwrap()
+                .install(|| {
+                    compute_sampen_for_files(&example_config(None), &vital_files, &cancel, None)
+                })
+                .into_iter()
+                .map(Option::unwrap)
+                .collect();
+            write_rows(
+                Writer::from_writer(Vec::new()),
+                &entropies,
+                OutputShape::Wide,
+            )
+            .unwrap()
+        };
+        let single_threaded = written(1);
+        assert_eq!(single_threaded, written(4));
+        assert_eq!(single_threaded, written(0));
+    }
+
+    #[test]
+    fn test_parallelism_split_stays_within_budget() {
+        for budget in 0..=16 {
+            for num_files in 0..=20 {
+                for num_surrogates in 0..=5 {
+                    let split = Parallelism::split(budget, num_files, 3, num_surrogates);
+                    let total = split.files * split.channels * split.surrogates;
+                    assert!((1..=budget.max(1)).contains(&total), "{:?}", split);
+                }
+            }
+        }
+        let split = |budget, num_files, num_surrogates| {
+            let split = Parallelism::split(budget, num_files, 3, num_surrogates);
+            (split.files, split.channels, split.surrogates)
+        };
+        assert_eq!((8, 1, 1), split(8, 100, 10));
+        assert_eq!((2, 3, 1), split(8, 2, 0));
+        assert_eq!((1, 3, 5), split(16, 1, 10));
+    }
+
+    #[test]
+    fn test_nested_maps_never_exceed_the_budget() {
+        let active = AtomicUsize::new(0);
+        let most_active = AtomicUsize::new(0);
+        let task = |x: &usize| {
+            let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+            most_active.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(2));
+            active.fetch_sub(1, Ordering::SeqCst);
+            x * 2
+        };
+        let items: Vec<usize> = (0..12).collect();
+        let pool = thread_pool(8).unwrap();
+        // One level, narrower than the pool.
+        let doubled = pool.install(|| map_at_most(&items, 3, task));
+        assert_eq!(items.iter().map(|x| x * 2).collect::<Vec<_>>(), doubled);
+        assert!(most_active.load(Ordering::SeqCst) <= 3);
+
+        // Two levels of a split budget.
+        most_active.store(0, Ordering::SeqCst);
+        let split = Parallelism::split(4, 2, 3, 0);
+        let nested = thread_pool(4).unwrap().install(|| {
+            map_at_most(&[0, 1], split.files, |_| {
+                map_at_most(&items, split.channels, task)
+            })
+        });
+        assert_eq!(vec![doubled.clone(), doubled], nested);
+        let most = most_active.load(Ordering::SeqCst);
+        assert!((1..=4).contains(&most), "{}", most);
+    }
+
+    #[test]
+    fn test_split_parallelism_matches_serial_computation() {
+        let vital_files: Vec<Result<VitalFile, String>> =
+            vec![Ok(example_vital_file()), Err(String::from("unreadable"))];
+        let serial = EntropyConfig {
+            normalize: Some(4),
+            ..example_config(None)
+        };
+        let split = Parallelism::split(16, vital_files.len(), 3, 4);
+        let parallel = EntropyConfig {
+            channel_threads: split.channels,
+            surrogate_threads: split.surrogates,
+            ..serial
+        };
+        assert!(parallel.channel_threads > 1 && parallel.surrogate_threads > 1);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let rows = |config: &EntropyConfig, threads: usize| -> Vec<u8> {
+            let entropies: Vec<VitalEntropies> = thread_pool(threads)
+                .unwrap()
+                .install(|| compute_sampen_for_files(config, &vital_files, &cancel, None))
+                .into_iter()
+                .map(Option::unwrap)
+                .collect();
+            write_rows(
+                Writer::from_writer(Vec::new()),
+                &entropies,
+                OutputShape::Wide,
+            )
+            .unwrap()
+        };
+        assert_eq!(rows(&serial, 1), rows(&parallel, 16));
+    }
+
+    #[test]
+    fn test_cancel_flag_skips_unfinished_files() {
+        let vital_files: Vec<Result<VitalFile, String>> =
+            vec![Ok(example_vital_file()), Err(String::from("unreadable"))];
+        let config = example_config(None);
+        let unset = Arc::new(AtomicBool::new(false));
+        let rows = compute_sampen_for_files(&config, &vital_files, &unset, None);
+        let expected = compute_sampen_for_vital_file(
+            &config,
+            vital_files[0].as_ref().unwrap(),
+            &mut WorkBuffer::default(),
+        );
+        assert_eq!(
+            Some(expected.sbp_sampen),
+            rows[0].as_ref().map(|row| row.sbp_sampen)
+        );
+        assert_eq!(
+            Some(Status::ParseError),
+            rows[1].as_ref().map(|row| row.status)
+        );
+
+        let set = Arc::new(AtomicBool::new(true));
+        let rows = compute_sampen_for_files(&config, &vital_files, &set, None);
+        assert!(rows.iter().all(Option::is_none));
+        assert_eq!(130, exit_code(&SampenError::Cancelled));
+    }
+
+    #[test]
+    fn test_few_matches_flag_unreliable() {
+        let config = example_config(None);
+        let long = example_vital_file();
+        let entropies = compute_sampen_for_vital_file(&config, &long, &mut WorkBuffer::default());
+        assert!(!entropies.unreliable);
+        let short = VitalFile {
+            name: String::from("short"),
+            sample_rate: None,
+            sbp: Channel::new(long.sbp.data[..12].to_vec()),
+            mbp: Channel::new(long.mbp.data[..12].to_vec()),
+            dbp: Channel::new(long.dbp.data[..12].to_vec()),
+        };
+        let entropies = compute_sampen_for_vital_file(&config, &short, &mut WorkBuffer::default());
+        assert!(entropies.unreliable);
+    }
+
+    #[test]
+    fn test_match_fraction_agrees_with_dumped_counts() {
+        let vital_file = example_vital_file();
+        let entropies = compute_sampen_for_vital_file(
+            &example_config(None),
+            &vital_file,
+            &mut WorkBuffer::default(),
+        );
+        let preprocessed = Preprocess::Detrend.apply(&vital_file.sbp.data);
+        let r = Tolerance::default().resolve(&preprocessed);
+        let dump = sample_entropy::dump::dump_matching(2, r, &preprocessed);
+        let num_templates = dump.templates.len();
+        let num_pairs = num_templates * (num_templates - 1) / 2;
+        let m_matches: usize = dump.templates.iter().map(|t| t.m_matches).sum::<usize>() / 2;
+        let fraction = entropies.sbp_match_fraction.unwrap();
+        assert!(
+            (fraction - m_matches as f32 / num_pairs as f32).abs() < 1e-6,
+            "{}",
+            fraction
+        );
+        assert!(fraction > 0.0 && fraction < 1.0);
+
+        let mut short = vital_file;
+        short.dbp.data.truncate(3);
+        let entropies = compute_sampen_for_vital_file(
+            &example_config(None),
+            &short,
+            &mut WorkBuffer::default(),
+        );
+        assert_eq!(None, entropies.dbp_match_fraction);
+        assert_eq!(None, entropies.to_long()[2].match_fraction);
+        assert_eq!(
+            entropies.mbp_match_fraction,
+            entropies.to_long()[1].match_fraction
+        );
+    }
+
+    #[test]
+    fn test_loose_tolerance_warning() {
+        let data = stats::detrend_data(&example_vital_file().sbp.data);
+        let counts = |r: f32| stats::match_counts(2, r, &data);
+        let r = 0.2 * stats::standard_deviation(&data);
+        assert!(!loose_tolerance_warning(&data, r, 2, &counts(r)));
+        assert!(loose_tolerance_warning(&data, 100.0, 2, &counts(100.0)));
+        // Narrower than the range, but still matching most pairs.
+        assert!(loose_tolerance_warning(&data, 15.0, 2, &counts(15.0)));
+    }
+
+    #[test]
+    fn test_quantization_warning() {
+        let integers: Vec<f32> = vec![80., 81., 80., 82., 81., 80.];
+        assert_eq!(Some(1.0), quantization_warning(&integers, 0.5));
+        assert_eq!(None, quantization_warning(&integers, 2.0));
+    }
+
+    #[test]
+    fn test_small_tolerance_warning_on_offset_signal() {
+        // A quiet baseline on a large offset, with a few artifacts.
+        let mut offset: Vec<f32> = (0..10000)
+            .map(|x| 1000.0 + 0.0001 * ((x * 7919) % 101) as f32)
+            .collect();
+        for ix in [1000, 4000, 7000, 9000, 9500] {
+            offset[ix] += 1.0;
+        }
+        let r = 0.2 * stats::standard_deviation(&offset);
+        let amplitude = small_tolerance_warning(&offset, r).unwrap();
+        assert!((amplitude - 1.0).abs() < 0.02, "{}", amplitude);
+
+        let sine: Vec<f32> = (0..10000).map(|x| (x as f32 * 0.1).sin()).collect();
+        let r = 0.2 * stats::standard_deviation(&sine);
+        assert_eq!(None, small_tolerance_warning(&sine, r));
+    }
+
+    #[test]
+    fn test_long_output_has_one_row_per_channel() {
+        let rows = written_rows(OutputShape::Long);
+        assert_eq!(
+            "name,channel,sampen,status,unreliable,range_start,range_end,missing,match_fraction",
+            rows[0]
+        );
+        assert_eq!(4 * 3, rows.len() - 1);
+        assert_eq!("file_0,sbp,0.5,ok,false,,,false,0.25", rows[1]);
+        assert_eq!("file_0,dbp,1.5,ok,false,,,false,", rows[3]);
+    }
+
+    #[test]
+    fn test_wide_output_has_one_row_per_file() {
+        let rows = written_rows(OutputShape::Wide);
+        assert_eq!(
+            "name,sbp_sampen,mbp_sampen,dbp_sampen,composite,status,unreliable,range_start,range_end,missing_channels,sbp_match_fraction,mbp_match_fraction,dbp_match_fraction",
+            rows[0]
+        );
+        assert_eq!(4, rows.len() - 1);
+    }
 }
@@ -0,0 +1,553 @@
+//! Algorithms for counting chebyshev template matches.
+//!
+//! Every strategy finds exactly the same pairs as the brute force O(n^2)
+//! comparison in `stats`; they differ only in how many pairs they can rule
+//! out without comparing them. Templates are never copied out of the data:
+//! each is identified by the index it starts at. The exception is
+//! `get_matches_lsh`, which trades exactness for speed at large m.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::interrupt::{Interrupt, Interrupted};
+use crate::stats::{is_finite_template, is_match, MatchCounts};
+
+/// The default number of templates below which `MatchStrategy::Auto` uses
+/// brute force. See `MatchStrategy::resolve`.
+pub const DEFAULT_BRUTE_FORCE_THRESHOLD: usize = 128;
+
+static BRUTE_FORCE_THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_BRUTE_FORCE_THRESHOLD);
+
+/// The number of templates below which `MatchStrategy::Auto` uses brute
+/// force, for the whole process.
+pub fn brute_force_threshold() -> usize {
+    BRUTE_FORCE_THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Sets the number of templates below which `MatchStrategy::Auto` uses brute
+/// force, for the whole process, e.g. to the crossover measured on this
+/// machine by `calibration::load_or_calibrate`. Every strategy finds the
+/// same matches, so this only changes how fast they are found.
+pub fn set_brute_force_threshold(threshold: usize) {
+    BRUTE_FORCE_THRESHOLD.store(threshold, Ordering::Relaxed);
+}
+
+/// How the unique pairs of matching templates are found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum MatchStrategy {
+    /// Compare every pair of templates. O(n^2) with no setup cost.
+    BruteForce,
+    /// Sort the templates by their first value and compare each one only to
+    /// the following templates whose first value is within `r`.
+    Sorted,
+    /// Hash the templates into buckets of width `r` by their first value and
+    /// compare each one only to the templates in its own and nearby buckets.
+    Bucketed,
+    /// Build a KD-tree over the templates and range-query a box of half-width
+    /// `r` around each one.
+    KdTree,
+    /// Pick one of the above from the number of templates and their length.
+    #[default]
+    Auto,
+}
+
+impl MatchStrategy {
+    /// Returns the concrete strategy used for `num_templates` templates of
+    /// length `m`. Only `Auto` is resolved; other strategies are returned
+    /// unchanged.
+    ///
+    /// Measured on a sine plus noise series with r of 0.2 standard
+    /// deviations: below about a hundred templates nothing beats brute force.
+    /// Above that, sorting is 3-5x faster than brute force, and only loses
+    /// to the KD-tree once there are tens of thousands of templates of
+    /// length 3 or more. Bucketing never won and is never picked. The
+    /// crossover to brute force depends on the machine, so it is
+    /// `brute_force_threshold`, which can be measured and set.
+    pub fn resolve(self, num_templates: usize, m: usize) -> MatchStrategy {
+        self.resolve_with_threshold(num_templates, m, brute_force_threshold())
+    }
+
+    /// Resolves the strategy like `resolve`, with brute force below
+    /// `threshold` templates.
+    pub(crate) fn resolve_with_threshold(
+        self,
+        num_templates: usize,
+        m: usize,
+        threshold: usize,
+    ) -> MatchStrategy {
+        match self {
+            MatchStrategy::Auto if num_templates < threshold || m == 0 => MatchStrategy::BruteForce,
+            MatchStrategy::Auto if m >= 3 && num_templates >= 10_000 => MatchStrategy::KdTree,
+            MatchStrategy::Auto => MatchStrategy::Sorted,
+            strategy => strategy,
+        }
+    }
+}
+
+/// Counts the matching pairs of length-m and length-(m+1) templates of the
+/// data in a single pass, using the given strategy.
+///
+/// Every length-(m+1) match is a length-m match whose templates also agree
+/// on the value after them, so only the length-m pairs are searched, and
+/// each match found is tested on that one extra value. Templates containing
+/// a non-finite value are skipped, as in `stats::get_matches`. The interrupt
+/// is checked once per template.
+///
+/// # Arguments
+///
+/// * `strategy` - the algorithm used to find the matches.
+/// * `data` - a vector containing the waveform data.
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `scratch` - scratch space for the finite templates and their order.
+/// * `interrupt` - when to give up.
+///
+pub(crate) fn count_match_pairs(
+    strategy: MatchStrategy,
+    data: &[f32],
+    m: usize,
+    r: f32,
+    scratch: &mut MatchScratch,
+    interrupt: &Interrupt,
+) -> Result<MatchCounts, Interrupted> {
+    let mut counts = MatchCounts {
+        m_matches: 0,
+        m_plus_1_matches: 0,
+    };
+    visit_match_pairs(strategy, data, m, r, scratch, interrupt, &mut |i, j| {
+        counts.m_matches += 1;
+        if let (Some(a), Some(b)) = (data.get(i + m), data.get(j + m)) {
+            if a.is_finite() && b.is_finite() && is_match(&[*a], &[*b], &r) {
+                counts.m_plus_1_matches += 1;
+            }
+        }
+    })?;
+    Ok(counts)
+}
+
+/// Calls `on_match` with the start indices of every unique pair of matching
+/// finite length-m templates, found with the given strategy.
+///
+/// # Arguments
+///
+/// * `strategy` - the algorithm used to find the matches.
+/// * `data` - a vector containing the waveform data.
+/// * `m` - the template size.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `scratch` - scratch space for the finite templates and their order.
+/// * `interrupt` - when to give up.
+/// * `on_match` - called once per matching pair.
+///
+pub(crate) fn visit_match_pairs(
+    strategy: MatchStrategy,
+    data: &[f32],
+    m: usize,
+    r: f32,
+    scratch: &mut MatchScratch,
+    interrupt: &Interrupt,
+    on_match: &mut OnMatch,
+) -> Result<(), Interrupted> {
+    let MatchScratch { starts, order } = scratch;
+    starts.clear();
+    if data.len() >= m {
+        starts.extend((0..=data.len() - m).filter(|i| is_finite_template(&data[*i..*i + m])));
+    }
+    let templates = Templates { data, m, starts };
+    let strategy = match strategy.resolve(starts.len(), m) {
+        // The pruning strategies need at least one value to prune on.
+        _ if m == 0 => MatchStrategy::BruteForce,
+        strategy => strategy,
+    };
+    // Nothing is closer than a non-positive (or NaN) threshold, and the
+    // pruning strategies need a positive one to work with.
+    if strategy != MatchStrategy::BruteForce && (r.is_nan() || r <= 0.0) {
+        return Ok(());
+    }
+    match strategy {
+        MatchStrategy::Sorted => visit_sorted(&templates, order, r, interrupt, on_match),
+        MatchStrategy::Bucketed => visit_bucketed(&templates, r, interrupt, on_match),
+        MatchStrategy::KdTree => visit_kd_tree(&templates, order, r, interrupt, on_match),
+        MatchStrategy::BruteForce | MatchStrategy::Auto => {
+            visit_brute_force(&templates, r, interrupt, on_match)
+        }
+    }
+}
+
+/// Scratch space for finding the matching pairs, kept between calls so that
+/// its vectors are only allocated once.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct MatchScratch {
+    /// The start indices of the finite templates.
+    pub(crate) starts: Vec<usize>,
+    /// The templates, by their index in `starts`, in the order a strategy
+    /// visits them.
+    pub(crate) order: Vec<usize>,
+}
+
+/// The finite length-m templates of the data, by their start indices.
+struct Templates<'a> {
+    data: &'a [f32],
+    m: usize,
+    starts: &'a [usize],
+}
+
+impl Templates<'_> {
+    /// The `k`th template.
+    fn get(&self, k: usize) -> &[f32] {
+        &self.data[self.starts[k]..self.starts[k] + self.m]
+    }
+
+    fn len(&self) -> usize {
+        self.starts.len()
+    }
+}
+
+/// Calls `on_match` with the start indices of every unique matching pair.
+type OnMatch<'a> = dyn FnMut(usize, usize) + 'a;
+
+fn visit_brute_force(
+    templates: &Templates,
+    r: f32,
+    interrupt: &Interrupt,
+    on_match: &mut OnMatch,
+) -> Result<(), Interrupted> {
+    for i in 0..templates.len() {
+        if interrupt.is_triggered() {
+            return Err(Interrupted);
+        }
+        for j in i + 1..templates.len() {
+            if is_match(templates.get(i), templates.get(j), &r) {
+                on_match(templates.starts[i], templates.starts[j]);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Once the templates are sorted by their first value, the first values of
+/// the templates after `i` only grow, so the scan can stop at the first one
+/// that is already `r` or more away from template `i`.
+fn visit_sorted(
+    templates: &Templates,
+    order: &mut Vec<usize>,
+    r: f32,
+    interrupt: &Interrupt,
+    on_match: &mut OnMatch,
+) -> Result<(), Interrupted> {
+    order.clear();
+    order.extend(0..templates.len());
+    order.sort_unstable_by(|a, b| templates.get(*a)[0].total_cmp(&templates.get(*b)[0]));
+    for i in 0..order.len() {
+        if interrupt.is_triggered() {
+            return Err(Interrupted);
+        }
+        let template = templates.get(order[i]);
+        for j in order[i + 1..].iter() {
+            let other = templates.get(*j);
+            if other[0] - template[0] >= r {
+                break;
+            }
+            if is_match(template, other, &r) {
+                on_match(templates.starts[order[i]], templates.starts[*j]);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Two first values less than `r` apart fall into buckets at most one apart,
+/// but rounding in the division can push them one bucket further, so each
+/// template is compared to the two buckets after its own as well.
+fn visit_bucketed(
+    templates: &Templates,
+    r: f32,
+    interrupt: &Interrupt,
+    on_match: &mut OnMatch,
+) -> Result<(), Interrupted> {
+    let bucket_of = |template: &[f32]| (template[0] as f64 / r as f64).floor() as i64;
+    let mut buckets: HashMap<i64, Vec<usize>> = HashMap::new();
+    for k in 0..templates.len() {
+        buckets
+            .entry(bucket_of(templates.get(k)))
+            .or_default()
+            .push(k);
+    }
+    for i in 0..templates.len() {
+        if interrupt.is_triggered() {
+            return Err(Interrupted);
+        }
+        let template = templates.get(i);
+        let bucket = bucket_of(template);
+        let own = buckets[&bucket].iter().filter(|j| **j > i);
+        let later = (1..=2)
+            .filter_map(|offset| bucket.checked_add(offset))
+            .filter_map(|key| buckets.get(&key))
+            .flatten();
+        for j in own.chain(later) {
+            if is_match(template, templates.get(*j), &r) {
+                on_match(templates.starts[i], templates.starts[*j]);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The tree is stored implicitly: `order` is arranged so that for every
+/// subrange, the template at its midpoint splits the rest of the range on
+/// the axis `depth % m`, with lesser or equal values before it and greater or
+/// equal values after it.
+fn visit_kd_tree(
+    templates: &Templates,
+    order: &mut Vec<usize>,
+    r: f32,
+    interrupt: &Interrupt,
+    on_match: &mut OnMatch,
+) -> Result<(), Interrupted> {
+    order.clear();
+    order.extend(0..templates.len());
+    build_kd_tree(templates, order, 0);
+    for i in 0..templates.len() {
+        if interrupt.is_triggered() {
+            return Err(Interrupted);
+        }
+        query_kd_tree(templates, order, 0, i, r, on_match);
+    }
+    Ok(())
+}
+
+fn build_kd_tree(templates: &Templates, order: &mut [usize], depth: usize) {
+    if order.len() <= 1 {
+        return;
+    }
+    let axis = depth % templates.m;
+    let mid = order.len() / 2;
+    order.select_nth_unstable_by(mid, |a, b| {
+        templates.get(*a)[axis].total_cmp(&templates.get(*b)[axis])
+    });
+    let (before, rest) = order.split_at_mut(mid);
+    build_kd_tree(templates, before, depth + 1);
+    build_kd_tree(templates, &mut rest[1..], depth + 1);
+}
+
+/// Visits the templates in the subtree `order` matching template `query`
+/// that come after it, so that each pair is visited once.
+///
+/// A half of the subtree is skipped only if its split value alone is already
+/// `r` or more away from the query, which rules out every template in it.
+fn query_kd_tree(
+    templates: &Templates,
+    order: &[usize],
+    depth: usize,
+    query: usize,
+    r: f32,
+    on_match: &mut OnMatch,
+) {
+    if order.is_empty() {
+        return;
+    }
+    let axis = depth % templates.m;
+    let mid = order.len() / 2;
+    let (query_template, split) = (templates.get(query), templates.get(order[mid]));
+    if order[mid] > query && is_match(query_template, split, &r) {
+        on_match(templates.starts[query], templates.starts[order[mid]]);
+    }
+    if !(query_template[axis] > split[axis] && query_template[axis] - split[axis] >= r) {
+        query_kd_tree(templates, &order[..mid], depth + 1, query, r, on_match);
+    }
+    if !(split[axis] > query_template[axis] && split[axis] - query_template[axis] >= r) {
+        query_kd_tree(templates, &order[mid + 1..], depth + 1, query, r, on_match);
+    }
+}
+
+/// Counts the unique pairs of matching templates approximately, by hashing
+/// them on every coordinate.
+///
+/// Each template is hashed to the cell of `r`-wide bins its coordinates
+/// fall in, and compared only to the templates in the same cell or in the
+/// cells next to it along the first `probes` coordinates. Two matching
+/// templates are less than `r` apart in every coordinate, so they always
+/// share a bin or sit in neighboring ones, but a pair that straddles a bin
+/// boundary in a coordinate past the probed ones is never compared. The
+/// count is therefore a lower bound, exact once `probes` reaches the
+/// template length. A matching pair straddles a given boundary about half
+/// the time, so each unprobed coordinate roughly halves the matches found,
+/// while each probed one triples the cells looked up per template. With
+/// high m and few probes the cells are sparse, which is where this is fast;
+/// use it to explore parameters, not for reported entropies. Templates
+/// containing NaN or infinite values are skipped.
+///
+/// # Arguments
+///
+/// * `templates` - an immutable reference to a vector of all templates.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `probes` - how many leading coordinates to probe neighboring bins along.
+///
+pub fn get_matches_lsh(templates: &[Vec<f32>], r: f32, probes: usize) -> usize {
+    if r.is_nan() || r <= 0.0 {
+        return 0;
+    }
+    let bin_of = |x: f32| (x as f64 / r as f64).floor() as i64;
+    let finite: Vec<&Vec<f32>> = templates
+        .iter()
+        .filter(|template| is_finite_template(template))
+        .collect();
+    let mut cells: HashMap<Vec<i64>, Vec<usize>> = HashMap::new();
+    for (k, template) in finite.iter().enumerate() {
+        cells
+            .entry(template.iter().map(|x| bin_of(*x)).collect())
+            .or_default()
+            .push(k);
+    }
+
+    let mut matches: usize = 0;
+    let mut cell: Vec<i64> = Vec::new();
+    for (i, template) in finite.iter().enumerate() {
+        let home: Vec<i64> = template.iter().map(|x| bin_of(*x)).collect();
+        let probes = probes.min(home.len());
+        // Every offset in {-1, 0, 1} along each probed coordinate, counted
+        // in base 3. Distinct offsets reach distinct cells, so no pair is
+        // counted twice.
+        for offset in 0..3_usize.pow(probes as u32) {
+            cell.clone_from(&home);
+            let mut digits = offset;
+            for bin in cell.iter_mut().take(probes) {
+                *bin += (digits % 3) as i64 - 1;
+                digits /= 3;
+            }
+            let Some(others) = cells.get(&cell) else {
+                continue;
+            };
+            matches += others
+                .iter()
+                .filter(|j| **j > i && is_match(template, finite[**j], &r))
+                .count();
+        }
+    }
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::SplitMix64;
+
+    const STRATEGIES: [MatchStrategy; 5] = [
+        MatchStrategy::BruteForce,
+        MatchStrategy::Sorted,
+        MatchStrategy::Bucketed,
+        MatchStrategy::KdTree,
+        MatchStrategy::Auto,
+    ];
+
+    #[test]
+    fn test_forced_strategies_ignore_the_threshold() {
+        for threshold in [1, 128, 100_000] {
+            for strategy in [
+                MatchStrategy::BruteForce,
+                MatchStrategy::Sorted,
+                MatchStrategy::Bucketed,
+                MatchStrategy::KdTree,
+            ] {
+                assert_eq!(strategy, strategy.resolve_with_threshold(500, 2, threshold));
+            }
+        }
+        let auto = MatchStrategy::Auto;
+        assert_eq!(
+            MatchStrategy::BruteForce,
+            auto.resolve_with_threshold(500, 2, 1000)
+        );
+        assert_eq!(
+            MatchStrategy::Sorted,
+            auto.resolve_with_threshold(500, 2, 100)
+        );
+        assert_eq!(DEFAULT_BRUTE_FORCE_THRESHOLD, 128);
+    }
+
+    #[test]
+    fn test_lsh_converges_to_exact_count() {
+        let mut rng = SplitMix64::new(11);
+        let walk: Vec<f32> = (0..1500)
+            .scan(0.0, |x, _| {
+                *x += rng.next_f32() - 0.5;
+                Some(*x)
+            })
+            .collect();
+        let m = 6;
+        let templates: Vec<Vec<f32>> = walk.windows(m).map(|window| window.to_vec()).collect();
+        let r = 0.5;
+        let exact = count_match_pairs(
+            MatchStrategy::BruteForce,
+            &walk,
+            m,
+            r,
+            &mut MatchScratch::default(),
+            &Interrupt::default(),
+        )
+        .unwrap()
+        .m_matches;
+        let counts: Vec<usize> = (0..=m)
+            .map(|probes| get_matches_lsh(&templates, r, probes))
+            .collect();
+        assert!(counts[0] < exact, "{:?} of {}", counts, exact);
+        for pair in counts.windows(2) {
+            assert!(pair[0] <= pair[1], "{:?}", counts);
+        }
+        assert_eq!(exact, counts[m]);
+        assert_eq!(exact, get_matches_lsh(&templates, r, m + 3));
+        assert_eq!(0, get_matches_lsh(&templates, 0.0, m));
+    }
+
+    #[test]
+    fn test_strategies_count_identically() {
+        let mut rng = SplitMix64::new(7);
+        // Integer-valued data makes exact distance ties with r common.
+        let mut ties: Vec<f32> = (0..400).map(|_| rng.next_below(9) as f32).collect();
+        ties[17] = f32::NAN;
+        let smooth: Vec<f32> = (0..400).map(|x| (x as f32 * 0.05).sin()).collect();
+        let count = |strategy, data: &[f32], m, r| {
+            count_match_pairs(
+                strategy,
+                data,
+                m,
+                r,
+                &mut MatchScratch::default(),
+                &Interrupt::default(),
+            )
+        };
+        for data in [&ties, &smooth] {
+            for m in 1..=4 {
+                for r in [0.0, 0.1, 0.5, 1.0, 2.0, 20.0] {
+                    let expected = count(MatchStrategy::BruteForce, data, m, r);
+                    for strategy in STRATEGIES {
+                        assert_eq!(
+                            expected,
+                            count(strategy, data, m, r),
+                            "{:?} with m = {}, r = {}",
+                            strategy,
+                            m,
+                            r
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_auto_resolves_to_concrete_strategy() {
+        assert_eq!(
+            MatchStrategy::BruteForce,
+            MatchStrategy::Auto.resolve(10, 2)
+        );
+        assert_eq!(MatchStrategy::Sorted, MatchStrategy::Auto.resolve(5000, 2));
+        assert_eq!(MatchStrategy::Sorted, MatchStrategy::Auto.resolve(5000, 4));
+        assert_eq!(
+            MatchStrategy::KdTree,
+            MatchStrategy::Auto.resolve(20_000, 4)
+        );
+        assert_eq!(
+            MatchStrategy::Bucketed,
+            MatchStrategy::Bucketed.resolve(10, 2)
+        );
+    }
+}
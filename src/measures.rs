@@ -0,0 +1,169 @@
+//! Several entropy measures of one waveform, computed together.
+//!
+//! Sample entropy and approximate entropy are both built from the matches
+//! between length-m and length-(m+1) templates, so `compute_measures` finds
+//! those matches once and derives both from them. Permutation entropy looks
+//! at the ordinal patterns of the samples rather than at template distances,
+//! so it shares no work with the other two and costs the same as calling
+//! `permutation_entropy` directly.
+use crate::interrupt::Interrupt;
+use crate::permutation::{self, TieRule};
+use crate::stats::{self, Tolerance};
+
+/// Which measures `compute_measures` computes, and with which parameters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeasureConfig {
+    /// The smaller of the two template sizes of sample and approximate
+    /// entropy.
+    pub m: usize,
+    /// How `r` is derived from the data.
+    pub tolerance: Tolerance,
+    /// Whether to compute `stats::sample_entropy`.
+    pub sample_entropy: bool,
+    /// Whether to compute `stats::approximate_entropy`.
+    pub approximate_entropy: bool,
+    /// The parameters of `permutation::permutation_entropy`, or `None` to
+    /// skip it.
+    pub permutation_entropy: Option<PermutationConfig>,
+}
+
+impl Default for MeasureConfig {
+    /// Every measure, with m = 2, the default tolerance and the default
+    /// permutation parameters.
+    fn default() -> Self {
+        MeasureConfig {
+            m: 2,
+            tolerance: Tolerance::default(),
+            sample_entropy: true,
+            approximate_entropy: true,
+            permutation_entropy: Some(PermutationConfig::default()),
+        }
+    }
+}
+
+/// The parameters of permutation entropy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PermutationConfig {
+    /// The length of the delay vectors.
+    pub order: usize,
+    /// The number of samples between the values of a vector.
+    pub delay: usize,
+    /// How equal values are ordered.
+    pub ties: TieRule,
+}
+
+impl Default for PermutationConfig {
+    /// Order 3 with consecutive samples, the usual choice for short records.
+    fn default() -> Self {
+        PermutationConfig {
+            order: 3,
+            delay: 1,
+            ties: TieRule::default(),
+        }
+    }
+}
+
+/// The measures of one waveform. A measure that wasn't asked for is `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MeasureSet {
+    /// See `stats::sample_entropy`.
+    pub sample_entropy: Option<f32>,
+    /// See `stats::approximate_entropy`.
+    pub approximate_entropy: Option<f32>,
+    /// See `permutation::permutation_entropy`.
+    pub permutation_entropy: Option<f32>,
+}
+
+/// Computes the measures in `config` for a waveform.
+///
+/// When both sample entropy and approximate entropy are asked for, their
+/// template matches are found in a single pass; each field equals what its
+/// standalone function returns for the same parameters.
+///
+/// # Arguments
+/// * `config` - which measures to compute, and how.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn compute_measures(config: &MeasureConfig, data: &[f32]) -> MeasureSet {
+    let mut measures = MeasureSet::default();
+    if config.sample_entropy || config.approximate_entropy {
+        let r = config.tolerance.resolve(data);
+        if config.approximate_entropy {
+            let matches = stats::template_matches(config.m, r, data, &Interrupt::default())
+                .unwrap_or_else(|_| unreachable!("the default interrupt never triggers"));
+            measures.approximate_entropy = Some(matches.approximate_entropy(config.m, data));
+            if config.sample_entropy {
+                measures.sample_entropy = Some(matches.counts.sample_entropy());
+            }
+        } else {
+            // Without approximate entropy there is nothing to share, and
+            // `match_counts` skips the per-template bookkeeping.
+            measures.sample_entropy = Some(stats::match_counts(config.m, r, data).sample_entropy());
+        }
+    }
+    if let Some(permutation) = config.permutation_entropy {
+        measures.permutation_entropy = Some(permutation::permutation_entropy(
+            permutation.order,
+            permutation.delay,
+            data,
+            permutation.ties,
+        ));
+    }
+    measures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal() -> Vec<f32> {
+        (0..1500)
+            .map(|x| (x as f32 * 0.21).sin() + ((x * 37) % 11) as f32 * 0.1)
+            .collect()
+    }
+
+    #[test]
+    fn test_measures_equal_standalone_functions() {
+        let data = signal();
+        let config = MeasureConfig::default();
+        let r = config.tolerance.resolve(&data);
+        let measures = compute_measures(&config, &data);
+        assert_eq!(
+            Some(stats::sample_entropy(2, r, &data)),
+            measures.sample_entropy
+        );
+        assert_eq!(
+            Some(stats::approximate_entropy(2, r, &data)),
+            measures.approximate_entropy
+        );
+        assert_eq!(
+            Some(permutation::permutation_entropy(
+                3,
+                1,
+                &data,
+                TieRule::ByIndex
+            )),
+            measures.permutation_entropy
+        );
+    }
+
+    #[test]
+    fn test_only_requested_measures_are_computed() {
+        let data = signal();
+        let config = MeasureConfig {
+            m: 3,
+            approximate_entropy: false,
+            permutation_entropy: None,
+            ..MeasureConfig::default()
+        };
+        let r = config.tolerance.resolve(&data);
+        assert_eq!(
+            MeasureSet {
+                sample_entropy: Some(stats::sample_entropy(3, r, &data)),
+                approximate_entropy: None,
+                permutation_entropy: None,
+            },
+            compute_measures(&config, &data)
+        );
+    }
+}
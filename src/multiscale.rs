@@ -0,0 +1,861 @@
+//! Multiscale entropy: sample entropy of a series coarse-grained at a range
+//! of scales.
+//!
+//! The coarse-graining step is pluggable through the `CoarseGrain` trait, so
+//! every multiscale measure works with any grain, including user-defined
+//! ones. The composite variants, which coarse-grain every scale at every
+//! offset, use the prefix sums of `PrefixSums` instead.
+use std::ops::RangeInclusive;
+
+use crate::envelope::Signal;
+use crate::interrupt::Interrupt;
+use crate::stats::{
+    match_counts_interruptible, mean, sample_entropy_with_buffer, MatchCounts, TemplateBuffer,
+};
+
+/// Reduces a series to a coarser time scale.
+pub trait CoarseGrain {
+    /// Returns the series coarse-grained at `scale`, where scale 1 should
+    /// return the series unchanged.
+    fn grain(&self, data: &[f32], scale: usize) -> Vec<f32>;
+}
+
+/// The original coarse-graining of Costa et al.: the means of consecutive,
+/// non-overlapping windows of `scale` samples. A trailing partial window is
+/// dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MeanGrain;
+
+impl CoarseGrain for MeanGrain {
+    fn grain(&self, data: &[f32], scale: usize) -> Vec<f32> {
+        coarse_grain(data, scale)
+    }
+}
+
+/// Coarse-grains a series at `scale` as `MeanGrain` does, for use outside
+/// the multiscale entropies: the means of consecutive, non-overlapping
+/// windows of `scale` samples, `len / scale` of them rounded down, with a
+/// trailing partial window dropped. Scale 1 returns a copy of the series.
+/// Panics if `scale` is 0, which has no windows.
+///
+/// # Arguments
+/// * `data` - a vector containing the waveform data.
+/// * `scale` - the number of samples averaged into each value, at least 1.
+///
+pub fn coarse_grain(data: &[f32], scale: usize) -> Vec<f32> {
+    assert!(scale >= 1, "scale must be at least 1");
+    data.chunks_exact(scale).map(mean).collect()
+}
+
+/// The mean coarse-graining of `MeanGrain` with overlapping windows: the
+/// means of every window of `scale` samples starting a multiple of the step
+/// `stride.min(scale)` in. A stride of at least the scale gives `MeanGrain`'s
+/// non-overlapping windows, and a stride of 1 the full moving average.
+///
+/// Overlap trades independence for length. At step s a series of n samples
+/// has `(n - scale) / s + 1` grains instead of `n / scale`, so the templates
+/// of large scales stop running out; but each grain still averages `scale`
+/// samples, so its variance is no smaller than with `MeanGrain` (σ² / scale
+/// for white noise), and consecutive grains share `scale - s` of them, so
+/// they are correlated and match each other more often than independent
+/// grains would. The closer the step is to 1 the lower the entropy of a
+/// coarse-grained white noise, and since `r` is fixed across scales the
+/// curves of different strides aren't comparable. Each grain is averaged
+/// directly, so the cost grows as `scale / s`; `PrefixSums::strided_grain`
+/// takes O(1) per grain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverlappingMeanGrain {
+    pub stride: usize,
+}
+
+impl CoarseGrain for OverlappingMeanGrain {
+    fn grain(&self, data: &[f32], scale: usize) -> Vec<f32> {
+        data.windows(scale)
+            .step_by(self.stride.clamp(1, scale))
+            .map(mean)
+            .collect()
+    }
+}
+
+/// How `GapAwareMeanGrain` treats windows containing gaps, i.e. NaN or
+/// infinite samples standing for masked or missing data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GapPolicy {
+    /// A window containing any gap becomes a gap (NaN) itself.
+    #[default]
+    Drop,
+    /// A window is averaged over its finite samples only, and only becomes a
+    /// gap if every sample in it is one.
+    ValidSubset,
+}
+
+/// The mean coarse-graining of `MeanGrain`, with an explicit policy for
+/// windows that straddle a gap.
+///
+/// Either way, a window is never removed from the coarse-grained series: a
+/// dropped window is kept in place as NaN, whose templates are then skipped
+/// by the match counting. This keeps grain `i` covering samples
+/// `i * scale..(i + 1) * scale` at every scale, so coarse-grained channels of
+/// the same file stay aligned with each other even when their gaps differ.
+/// `Drop` gives the same grains as `MeanGrain`; `ValidSubset` keeps more of
+/// them, at the cost of grains averaged over fewer samples being noisier
+/// than the rest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GapAwareMeanGrain {
+    pub policy: GapPolicy,
+}
+
+impl CoarseGrain for GapAwareMeanGrain {
+    fn grain(&self, data: &[f32], scale: usize) -> Vec<f32> {
+        data.chunks_exact(scale)
+            .map(|window| match self.policy {
+                GapPolicy::Drop if window.iter().all(|x| x.is_finite()) => mean(window),
+                GapPolicy::Drop => f32::NAN,
+                GapPolicy::ValidSubset => {
+                    let valid: Vec<f32> =
+                        window.iter().copied().filter(|x| x.is_finite()).collect();
+                    if valid.is_empty() {
+                        f32::NAN
+                    } else {
+                        mean(&valid)
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// How the multiscale entropies treat the scales whose coarse-grained series
+/// is too short for a sample entropy.
+///
+/// Coarse-graining at scale `tau` leaves about `n / tau` samples, and a
+/// series of fewer than `m + 2` has no pair of length-(m+1) templates to
+/// compare, so its entropy is undefined; the coarse-grained series only get
+/// shorter as the scale grows, so every scale after the first such one is
+/// too short as well. Either way, no entropy is computed for those scales.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShortScales {
+    /// Keeps the scales in the curve, with NaN as their entropy, so that the
+    /// curve always has `max_scale` entries. `complexity_index` stops at
+    /// the first of them.
+    #[default]
+    Undefined,
+    /// Ends the curve at the last scale long enough, so that it may have
+    /// fewer than `max_scale` entries, none of them undefined for being too
+    /// short.
+    Truncate,
+}
+
+impl ShortScales {
+    /// Whether a scale whose coarse-grained series has `len` samples is
+    /// computed (`Some(true)`), kept as NaN (`Some(false)`), or ends the
+    /// curve (`None`).
+    fn admit(self, m: usize, len: usize) -> Option<bool> {
+        match self {
+            _ if len >= m + 2 => Some(true),
+            ShortScales::Undefined => Some(false),
+            ShortScales::Truncate => None,
+        }
+    }
+}
+
+/// How the multiscale entropies coarse-grain and which scales they keep,
+/// beyond the scales and the grain themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MultiscaleOptions {
+    /// For the composite measures, the number of samples between the starts
+    /// of consecutive windows, at most the scale: at each scale the grains
+    /// step by `stride.min(scale)`, and the sample entropies of the series
+    /// starting at each offset of the first step are combined. A stride of
+    /// at least `max_scale` gives the non-overlapping windows of Wu et al.,
+    /// and a stride of 1 the multiscale entropy of the full moving average,
+    /// as `OverlappingMeanGrain`. `multiscale_entropy` ignores it, its grain
+    /// choosing the windows.
+    pub stride: usize,
+    /// Whether scales coarse-grained too short are NaN or end the curve.
+    pub short: ShortScales,
+}
+
+impl Default for MultiscaleOptions {
+    /// Non-overlapping windows, with the scales too short kept as NaN.
+    fn default() -> Self {
+        MultiscaleOptions {
+            stride: usize::MAX,
+            short: ShortScales::default(),
+        }
+    }
+}
+
+/// Computes multiscale entropy for scales 1 through `max_scale`.
+///
+/// Follows Costa, M.; Goldberger, A.L.; Peng, C.-K. (2002) "Multiscale
+/// entropy analysis of complex physiologic time series": the series is
+/// coarse-grained at each scale and the sample entropy of every
+/// coarse-grained series is computed with the same `r`. As in the paper, `r`
+/// should be derived from the original series, not recomputed per scale.
+/// Pass `&MeanGrain` for the paper's coarse-graining, and
+/// `MultiscaleOptions::default()` to keep the scales coarse-grained to fewer
+/// than `m + 2` samples as NaN.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+/// * `max_scale` - the largest coarse-graining scale to compute.
+/// * `grain` - how the series is coarse-grained at each scale.
+/// * `options` - which scales too short are kept; the stride is the grain's.
+///
+pub fn multiscale_entropy(
+    m: usize,
+    r: f32,
+    data: &[f32],
+    max_scale: usize,
+    grain: &dyn CoarseGrain,
+    options: MultiscaleOptions,
+) -> Vec<f32> {
+    let mut buffer = TemplateBuffer::default();
+    (1..=max_scale)
+        .map_while(|scale| {
+            let coarse = grain.grain(data, scale);
+            options
+                .short
+                .admit(m, coarse.len())
+                .map(|long_enough| match long_enough {
+                    true => sample_entropy_with_buffer(m, r, &coarse, &mut buffer),
+                    false => f32::NAN,
+                })
+        })
+        .collect()
+}
+
+/// Running sums of a series, from which the mean of any window takes two
+/// subtractions.
+///
+/// Composite multiscale entropy coarse-grains the series at every offset of
+/// every scale, `max_scale (max_scale + 1) / 2` series in all. Averaging each
+/// window directly costs `O(n)` per series, `O(n max_scale²)` overall; with
+/// the sums computed once, every series costs only its own `n / scale`
+/// windows. Windows containing NaN or infinite samples are NaN, as with
+/// `GapAwareMeanGrain`'s `GapPolicy::Drop`.
+///
+/// The sums are accumulated in f64, and a window's mean is the difference of
+/// two of them, so its error grows with the magnitude of the sums rather than
+/// of the window: about `f64::EPSILON` times the sum of the absolute values
+/// up to the window's end. For a day of arterial pressure at 500 Hz that is
+/// about 1e-6 mmHg, below the f32 resolution of the means themselves, but
+/// the grains can differ from `MeanGrain`'s in the last bit, so results
+/// agree with `multiscale_entropy` only up to rounding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefixSums {
+    /// Entry `k` is the sum of the finite samples before sample `k`.
+    sums: Vec<f64>,
+    /// Entry `k` is the number of non-finite samples before sample `k`.
+    gaps: Vec<usize>,
+}
+
+impl PrefixSums {
+    /// Computes the running sums of the data.
+    pub fn new(data: &[f32]) -> Self {
+        let mut sums: Vec<f64> = Vec::with_capacity(data.len() + 1);
+        let mut gaps: Vec<usize> = Vec::with_capacity(data.len() + 1);
+        let (mut sum, mut num_gaps) = (0.0, 0);
+        sums.push(sum);
+        gaps.push(num_gaps);
+        for x in data {
+            if x.is_finite() {
+                sum += *x as f64;
+            } else {
+                num_gaps += 1;
+            }
+            sums.push(sum);
+            gaps.push(num_gaps);
+        }
+        PrefixSums { sums, gaps }
+    }
+
+    /// The number of samples summed.
+    pub fn len(&self) -> usize {
+        self.sums.len() - 1
+    }
+
+    /// Returns true if no samples were summed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The mean of samples `start..end`, or NaN if any of them is non-finite.
+    pub fn window_mean(&self, start: usize, end: usize) -> f32 {
+        if self.gaps[end] > self.gaps[start] {
+            return f32::NAN;
+        }
+        ((self.sums[end] - self.sums[start]) / (end - start) as f64) as f32
+    }
+
+    /// The means of consecutive, non-overlapping windows of `scale` samples
+    /// starting at sample `offset`. A trailing partial window is dropped. With
+    /// offset 0 this is `MeanGrain`'s coarse-graining.
+    pub fn grain(&self, scale: usize, offset: usize) -> Vec<f32> {
+        self.strided_grain(scale, offset, scale)
+    }
+
+    /// The number of grains `strided_grain` returns.
+    fn num_windows(&self, scale: usize, offset: usize, stride: usize) -> usize {
+        let span = self.len().saturating_sub(offset);
+        if span < scale {
+            0
+        } else {
+            (span - scale) / stride.max(1) + 1
+        }
+    }
+
+    /// The means of the windows of `scale` samples starting at sample
+    /// `offset` and every `stride` samples after it, as
+    /// `OverlappingMeanGrain`. A trailing partial window is dropped.
+    pub fn strided_grain(&self, scale: usize, offset: usize, stride: usize) -> Vec<f32> {
+        let stride = stride.max(1);
+        (0..self.num_windows(scale, offset, stride))
+            .map(|ix| {
+                let start = offset + ix * stride;
+                self.window_mean(start, start + scale)
+            })
+            .collect()
+    }
+}
+
+/// Returns, for each scale, the match counts of the series coarse-grained at
+/// each offset of its first step, with the step `options.stride.min(scale)`
+/// of `OverlappingMeanGrain`. A scale is too short for `options.short` if any
+/// of its offsets is, and None if it is kept undefined; the last offset's
+/// series is the shortest.
+fn offset_match_counts(
+    m: usize,
+    r: f32,
+    data: &[f32],
+    max_scale: usize,
+    options: MultiscaleOptions,
+) -> Vec<Option<Vec<MatchCounts>>> {
+    let sums = PrefixSums::new(data);
+    let mut buffer = TemplateBuffer::default();
+    (1..=max_scale)
+        .map_while(|scale| {
+            let step = options.stride.clamp(1, scale);
+            let long_enough = options
+                .short
+                .admit(m, sums.num_windows(scale, step - 1, step))?;
+            Some(long_enough.then(|| {
+                (0..step)
+                    .map(|offset| {
+                        let grain = sums.strided_grain(scale, offset, step);
+                        match_counts_interruptible(m, r, &grain, &mut buffer, &Interrupt::default())
+                            .unwrap_or_else(|_| {
+                                unreachable!("the default interrupt never triggers")
+                            })
+                    })
+                    .collect()
+            }))
+        })
+        .collect()
+}
+
+/// Computes composite multiscale entropy for scales 1 through `max_scale`.
+///
+/// From Wu, S.-D. et al. (2013) "Composite multiscale entropy and its
+/// application to the fault diagnosis of rotating machinery". At scale
+/// `tau`, the mean grain is taken at each of the `tau` offsets of the first
+/// window, and the sample entropies of the `tau` series are averaged, which
+/// uses every sample at every scale and so varies less than
+/// `multiscale_entropy` on short records. An offset with no matches makes
+/// its scale infinite or NaN; `refined_composite_multiscale_entropy` avoids
+/// that. The grains come from `PrefixSums`, and overlap as
+/// `options.stride` says. A scale is coarse-grained too short for
+/// `options.short` if the series of any of its offsets is.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+/// * `max_scale` - the largest coarse-graining scale to compute.
+/// * `options` - how far apart the windows start, and which scales too
+///   short are kept.
+///
+pub fn composite_multiscale_entropy(
+    m: usize,
+    r: f32,
+    data: &[f32],
+    max_scale: usize,
+    options: MultiscaleOptions,
+) -> Vec<f32> {
+    offset_match_counts(m, r, data, max_scale, options)
+        .iter()
+        .map(|counts| match counts {
+            Some(counts) => {
+                counts.iter().map(MatchCounts::sample_entropy).sum::<f32>() / counts.len() as f32
+            }
+            None => f32::NAN,
+        })
+        .collect()
+}
+
+/// Computes refined composite multiscale entropy for scales 1 through
+/// `max_scale`.
+///
+/// From Wu, S.-D. et al. (2014) "Analysis of complex time series using
+/// refined composite multiscale entropy". As
+/// `composite_multiscale_entropy`, but the match counts of the `tau` offsets
+/// are pooled before taking -ln(A/B), so a scale is only undefined if no
+/// offset has any matches, or if it is coarse-grained too short as for
+/// `composite_multiscale_entropy`.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+/// * `max_scale` - the largest coarse-graining scale to compute.
+/// * `options` - how far apart the windows start, and which scales too
+///   short are kept.
+///
+pub fn refined_composite_multiscale_entropy(
+    m: usize,
+    r: f32,
+    data: &[f32],
+    max_scale: usize,
+    options: MultiscaleOptions,
+) -> Vec<f32> {
+    offset_match_counts(m, r, data, max_scale, options)
+        .into_iter()
+        .map(|counts| match counts {
+            Some(counts) => counts.into_iter().sum::<MatchCounts>().sample_entropy(),
+            None => f32::NAN,
+        })
+        .collect()
+}
+
+/// Computes the complexity index of a multiscale entropy curve, the area
+/// under it over all its scales.
+///
+/// From Costa, M.; Goldberger, A.L.; Peng, C.-K. (2005) "Multiscale entropy
+/// analysis of biological signals". `mse_curve[i]` is the entropy at scale
+/// `i + 1`, as returned by `multiscale_entropy` and its variants, and the
+/// area is integrated with the trapezoidal rule, one unit per scale, so a
+/// flat curve of height h over k scales has area h (k - 1). Integration
+/// stops at the first NaN or infinite entry: an undefined scale means the
+/// coarse-grained series had too few matches, and the scales above it have
+/// even fewer samples, so the area covers the scales up to the last defined
+/// one before it. Curves whose index stopped early aren't comparable to
+/// complete ones; compare the index over a range of scales defined for all
+/// of the curves instead, with `complexity_index_over`. A curve with fewer
+/// than two defined scales has area 0.
+///
+/// # Arguments
+/// * `mse_curve` - the entropies at scales 1, 2, and so on.
+///
+pub fn complexity_index(mse_curve: &[f32]) -> f32 {
+    complexity_index_over(mse_curve, 1..=mse_curve.len())
+}
+
+/// Computes the complexity index of a multiscale entropy curve like
+/// `complexity_index`, over only the given scales. Scales past the end of
+/// the curve are ignored, and integration still stops at the first NaN or
+/// infinite entry within the range.
+///
+/// # Arguments
+/// * `mse_curve` - the entropies at scales 1, 2, and so on.
+/// * `scales` - the first and last scales integrated over, counting from 1.
+///
+pub fn complexity_index_over(mse_curve: &[f32], scales: RangeInclusive<usize>) -> f32 {
+    let first = (*scales.start()).max(1) - 1;
+    let end = (*scales.end()).min(mse_curve.len());
+    let defined: Vec<f32> = mse_curve
+        .get(first..end)
+        .unwrap_or_default()
+        .iter()
+        .copied()
+        .take_while(|entropy| entropy.is_finite())
+        .collect();
+    defined
+        .windows(2)
+        .map(|pair| (pair[0] + pair[1]) / 2.0)
+        .sum()
+}
+
+/// The order in which `envelope_multiscale_entropy` extracts the envelope
+/// and coarse-grains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EnvelopeOrder {
+    /// Coarse-grains the envelope of the original series. Each scale sees the
+    /// modulation averaged over longer stretches, as `multiscale_entropy` of
+    /// the envelope, so this shows how complex the modulation is at each
+    /// time scale.
+    #[default]
+    EnvelopeFirst,
+    /// Takes the envelope of each coarse-grained series. Coarse-graining
+    /// smooths away the carrier's fastest detail first, so this shows the
+    /// modulation of the oscillations still resolved at each scale; as the
+    /// scale approaches the carrier's period the oscillation averages out
+    /// and the envelope no longer follows the modulation.
+    GrainFirst,
+}
+
+/// Computes the multiscale entropy of a signal's envelope, for scales 1
+/// through `max_scale`.
+///
+/// For a pulsatile wave such as arterial pressure, the envelope follows the
+/// beat-to-beat amplitude, so its entropy measures the complexity of the
+/// modulation rather than of the pulse shape (see `crate::envelope`). The
+/// envelope comes from `Signal::derive` and the coarse-graining from `grain`,
+/// in the order given by `order`; at scale 1 both orders give the sample
+/// entropy of the envelope itself. `r` is used at every scale, and should be
+/// derived from the envelope of the original series rather than from the
+/// wave, whose spread is mostly the carrier's. Scales whose coarse-grained
+/// envelope has fewer than `m + 2` samples are NaN, as with
+/// `ShortScales::Undefined`.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+/// * `max_scale` - the largest coarse-graining scale to compute.
+/// * `grain` - how the series is coarse-grained at each scale.
+/// * `envelope` - which envelope is taken. `Signal::Wave` gives the
+///   ordinary `multiscale_entropy`.
+/// * `order` - whether the envelope is taken before or after coarse-graining.
+///
+pub fn envelope_multiscale_entropy(
+    m: usize,
+    r: f32,
+    data: &[f32],
+    max_scale: usize,
+    grain: &dyn CoarseGrain,
+    envelope: Signal,
+    order: EnvelopeOrder,
+) -> Vec<f32> {
+    match order {
+        EnvelopeOrder::EnvelopeFirst => multiscale_entropy(
+            m,
+            r,
+            &envelope.derive(data),
+            max_scale,
+            grain,
+            MultiscaleOptions::default(),
+        ),
+        EnvelopeOrder::GrainFirst => {
+            let mut buffer = TemplateBuffer::default();
+            (1..=max_scale)
+                .map(|scale| {
+                    let coarse = envelope.derive(&grain.grain(data, scale));
+                    match ShortScales::Undefined.admit(m, coarse.len()) {
+                        Some(true) => sample_entropy_with_buffer(m, r, &coarse, &mut buffer),
+                        _ => f32::NAN,
+                    }
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::upper_envelope;
+    use crate::rng::SplitMix64;
+    use crate::stats::{sample_entropy, standard_deviation};
+
+    #[test]
+    fn test_multiscale_entropy() {
+        let data: Vec<f32> = (0..120).map(|x| ((x * 7) % 13) as f32).collect();
+        let mse = multiscale_entropy(2, 2.0, &data, 3, &MeanGrain, Default::default());
+        assert_eq!(3, mse.len());
+        assert_eq!(sample_entropy(2, 2.0, &data), mse[0]);
+        assert_eq!(sample_entropy(2, 2.0, &MeanGrain.grain(&data, 3)), mse[2]);
+    }
+
+    #[test]
+    fn test_mean_grain_matches_original_coarse_graining() {
+        assert_eq!(
+            vec![0.5_f32, 2.5_f32],
+            MeanGrain.grain(&[0., 1., 2., 3., 4.], 2)
+        );
+        // The hardcoded coarse-graining averaged each window by hand.
+        let data: Vec<f32> = (0..50).map(|x| ((x * 11) % 17) as f32).collect();
+        for scale in 1..=6 {
+            let by_hand: Vec<f32> = (0..data.len() / scale)
+                .map(|i| data[i * scale..(i + 1) * scale].iter().sum::<f32>() / scale as f32)
+                .collect();
+            assert_eq!(by_hand, MeanGrain.grain(&data, scale));
+        }
+    }
+
+    #[test]
+    fn test_short_scales_are_undefined_or_truncated() {
+        let data: Vec<f32> = (0..30).map(|x| ((x * 7) % 13) as f32).collect();
+        let truncate = MultiscaleOptions {
+            short: ShortScales::Truncate,
+            ..MultiscaleOptions::default()
+        };
+        // Scale 7 leaves 4 grains, m + 2, and scale 8 only 3.
+        let undefined = multiscale_entropy(2, 2.0, &data, 20, &MeanGrain, Default::default());
+        assert_eq!(
+            multiscale_entropy(2, 2.0, &data, 7, &MeanGrain, Default::default()),
+            undefined[..7]
+        );
+        assert!(undefined[7..].iter().all(|entropy| entropy.is_nan()));
+        let truncated = multiscale_entropy(2, 2.0, &data, 20, &MeanGrain, truncate);
+        assert_eq!(7, truncated.len());
+        assert_eq!(undefined[..7], truncated);
+
+        // The last offset of scale 7 leaves only 3 grains.
+        let composite = composite_multiscale_entropy(2, 2.0, &data, 20, truncate);
+        assert_eq!(6, composite.len());
+        assert_eq!(
+            composite_multiscale_entropy(2, 2.0, &data, 6, Default::default()),
+            composite
+        );
+        let refined = refined_composite_multiscale_entropy(2, 2.0, &data, 20, Default::default());
+        assert_eq!(20, refined.len());
+        assert!(refined[6..].iter().all(|entropy| entropy.is_nan()));
+
+        let moving = MultiscaleOptions {
+            stride: 1,
+            ..truncate
+        };
+        for tiny in [&[][..], &[1.0], &[1.0, 2.0, 3.0]] {
+            assert!(multiscale_entropy(2, 2.0, tiny, 5, &MeanGrain, truncate).is_empty());
+            assert!(refined_composite_multiscale_entropy(2, 2.0, tiny, 5, moving).is_empty());
+            assert_eq!(
+                5,
+                multiscale_entropy(2, 2.0, tiny, 5, &MeanGrain, Default::default()).len()
+            );
+        }
+    }
+
+    #[test]
+    fn test_coarse_grain_of_ramp() {
+        let ramp: Vec<f32> = (0..7).map(|x| x as f32).collect();
+        assert_eq!(vec![0.5, 2.5, 4.5], coarse_grain(&ramp, 2));
+        for scale in 1..=8 {
+            assert_eq!(ramp.len() / scale, coarse_grain(&ramp, scale).len());
+        }
+        assert_eq!(ramp, coarse_grain(&ramp, 1));
+        assert!(std::panic::catch_unwind(|| coarse_grain(&ramp, 0)).is_err());
+    }
+
+    #[test]
+    fn test_stride_extremes() {
+        let mut rng = SplitMix64::new(12);
+        let data: Vec<f32> = rng.uniform_vec(600);
+        let sums = PrefixSums::new(&data);
+        for scale in 1..=5 {
+            let non_overlapping = OverlappingMeanGrain { stride: scale };
+            assert_eq!(
+                MeanGrain.grain(&data, scale),
+                non_overlapping.grain(&data, scale)
+            );
+            let moving_average: Vec<f32> = data.windows(scale).map(mean).collect();
+            let full = OverlappingMeanGrain { stride: 1 };
+            assert_eq!(moving_average, full.grain(&data, scale));
+            assert_eq!(moving_average.len(), sums.strided_grain(scale, 0, 1).len());
+            assert_eq!(sums.grain(scale, 0), sums.strided_grain(scale, 0, scale));
+        }
+        assert_eq!(
+            multiscale_entropy(2, 0.15, &data, 4, &MeanGrain, Default::default()),
+            multiscale_entropy(
+                2,
+                0.15,
+                &data,
+                4,
+                &OverlappingMeanGrain { stride: 4 },
+                Default::default()
+            )
+        );
+        // Half-overlapping windows, between the two.
+        let half = OverlappingMeanGrain { stride: 2 }.grain(&data, 4);
+        assert_eq!(299, half.len());
+        assert_eq!(mean(&data[2..6]), half[1]);
+
+        let stride = |stride| MultiscaleOptions {
+            stride,
+            ..MultiscaleOptions::default()
+        };
+        assert_eq!(
+            composite_multiscale_entropy(2, 0.15, &data, 4, Default::default()),
+            composite_multiscale_entropy(2, 0.15, &data, 4, stride(4))
+        );
+        assert_eq!(
+            refined_composite_multiscale_entropy(2, 0.15, &data, 4, Default::default()),
+            refined_composite_multiscale_entropy(2, 0.15, &data, 4, stride(4))
+        );
+        let moving = composite_multiscale_entropy(2, 0.15, &data, 4, stride(1));
+        let refined_moving = refined_composite_multiscale_entropy(2, 0.15, &data, 4, stride(1));
+        assert_eq!(moving, refined_moving);
+        let grain = sums.strided_grain(4, 0, 1);
+        assert_eq!(crate::stats::sample_entropy(2, 0.15, &grain), moving[3]);
+    }
+
+    #[test]
+    fn test_gap_aware_mean_grain() {
+        let gappy = [1.0, f32::NAN, 3.0, 5.0, f32::NAN, f32::NAN];
+        let dropped = GapAwareMeanGrain {
+            policy: GapPolicy::Drop,
+        }
+        .grain(&gappy, 2);
+        assert!(dropped[0].is_nan() && dropped[2].is_nan());
+        assert_eq!(4.0, dropped[1]);
+        let subset = GapAwareMeanGrain {
+            policy: GapPolicy::ValidSubset,
+        }
+        .grain(&gappy, 2);
+        assert_eq!(1.0, subset[0]);
+        assert_eq!(4.0, subset[1]);
+        assert!(subset[2].is_nan());
+
+        // Without gaps, both policies reproduce the standard MSE.
+        let data: Vec<f32> = (0..120).map(|x| ((x * 7) % 13) as f32).collect();
+        let standard = multiscale_entropy(2, 2.0, &data, 4, &MeanGrain, Default::default());
+        for policy in [GapPolicy::Drop, GapPolicy::ValidSubset] {
+            let grain = GapAwareMeanGrain { policy };
+            assert_eq!(
+                standard,
+                multiscale_entropy(2, 2.0, &data, 4, &grain, Default::default())
+            );
+        }
+    }
+
+    #[test]
+    fn test_custom_grain() {
+        // Decimation: keep every `scale`-th sample.
+        struct Decimate;
+        impl CoarseGrain for Decimate {
+            fn grain(&self, data: &[f32], scale: usize) -> Vec<f32> {
+                data.iter().step_by(scale).copied().collect()
+            }
+        }
+        let data: Vec<f32> = (0..120).map(|x| ((x * 7) % 13) as f32).collect();
+        let mse = multiscale_entropy(2, 2.0, &data, 2, &Decimate, Default::default());
+        assert_eq!(sample_entropy(2, 2.0, &Decimate.grain(&data, 2)), mse[1]);
+    }
+
+    #[test]
+    fn test_prefix_sum_grains_match_direct_means() {
+        let mut rng = SplitMix64::new(8);
+        // A large offset makes the prefix sums large relative to the windows.
+        let data: Vec<f32> = (0..5000).map(|_| 90.0 + rng.next_f32() * 40.0).collect();
+        let sums = PrefixSums::new(&data);
+        assert_eq!(data.len(), sums.len());
+        for scale in 1..=8 {
+            for offset in 0..scale {
+                let direct = MeanGrain.grain(&data[offset..], scale);
+                let prefix = sums.grain(scale, offset);
+                assert_eq!(direct.len(), prefix.len());
+                for (a, b) in direct.iter().zip(&prefix) {
+                    assert!((a - b).abs() < 1e-4, "{} {}", a, b);
+                }
+            }
+        }
+
+        let gappy = [1.0, f32::NAN, 3.0, 5.0, 7.0];
+        let grains = PrefixSums::new(&gappy).grain(2, 0);
+        assert!(grains[0].is_nan());
+        assert_eq!(4.0, grains[1]);
+        assert!(PrefixSums::new(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_composite_multiscale_entropy() {
+        let mut rng = SplitMix64::new(10);
+        let data: Vec<f32> = rng.uniform_vec(1200);
+        let cmse = composite_multiscale_entropy(2, 0.15, &data, 3, Default::default());
+        let rcmse = refined_composite_multiscale_entropy(2, 0.15, &data, 3, Default::default());
+        assert_eq!(3, cmse.len());
+        assert_eq!(sample_entropy(2, 0.15, &data), cmse[0]);
+        assert_eq!(cmse[0], rcmse[0]);
+
+        let sums = PrefixSums::new(&data);
+        let offsets: Vec<MatchCounts> = (0..3)
+            .map(|offset| crate::stats::match_counts(2, 0.15, &sums.grain(3, offset)))
+            .collect();
+        let mean_entropy = offsets.iter().map(|c| c.sample_entropy()).sum::<f32>() / 3.0;
+        assert_eq!(mean_entropy, cmse[2]);
+        let pooled: MatchCounts = offsets.into_iter().sum();
+        assert_eq!(pooled.sample_entropy(), rcmse[2]);
+    }
+
+    #[test]
+    fn test_complexity_index() {
+        let flat = [0.8; 10];
+        assert!((0.8 * 9.0 - complexity_index(&flat)).abs() < 1e-5);
+        assert!((0.8 * 4.0 - complexity_index_over(&flat, 3..=7)).abs() < 1e-5);
+        assert!((0.8 * 2.0 - complexity_index_over(&flat, 8..=20)).abs() < 1e-5);
+        assert_eq!(2.0, complexity_index(&[0.0, 1.0, 2.0]));
+
+        let mut undefined = flat;
+        undefined[5] = f32::NAN;
+        undefined[7] = f32::INFINITY;
+        assert!((0.8 * 4.0 - complexity_index(&undefined)).abs() < 1e-5);
+        assert_eq!(0.0, complexity_index_over(&undefined, 6..=10));
+        assert_eq!(0.0, complexity_index(&[1.5]));
+        assert_eq!(0.0, complexity_index(&[]));
+    }
+
+    /// A 20 sample period carrier whose amplitude follows `modulation`.
+    fn carrier(modulation: &[f32]) -> Vec<f32> {
+        modulation
+            .iter()
+            .enumerate()
+            .map(|(t, a)| a * (std::f32::consts::TAU * t as f32 / 20.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_envelope_multiscale_entropy_tracks_modulation() {
+        let len = 3000;
+        let regular: Vec<f32> = (0..len)
+            .map(|t| 1.0 + 0.5 * (std::f32::consts::TAU * t as f32 / 400.0).sin())
+            .collect();
+        // Random levels every 60 samples, joined by straight lines.
+        let mut rng = SplitMix64::new(6);
+        let knots: Vec<f32> = (0..=len / 60).map(|_| 0.5 + rng.next_f32()).collect();
+        let irregular: Vec<f32> = (0..len)
+            .map(|t| {
+                let (k, frac) = (t / 60, (t % 60) as f32 / 60.0);
+                knots[k] + (knots[k + 1] - knots[k]) * frac
+            })
+            .collect();
+
+        let envelope = Signal::UpperEnvelope;
+        for order in [EnvelopeOrder::EnvelopeFirst, EnvelopeOrder::GrainFirst] {
+            let entropies: Vec<Vec<f32>> = [&regular, &irregular]
+                .iter()
+                .map(|modulation| {
+                    let wave = carrier(modulation);
+                    let r = 0.2 * standard_deviation(&upper_envelope(&wave));
+                    let mse =
+                        envelope_multiscale_entropy(2, r, &wave, 3, &MeanGrain, envelope, order);
+                    assert_eq!(sample_entropy(2, r, &upper_envelope(&wave)), mse[0]);
+                    mse
+                })
+                .collect();
+            for scale in 0..3 {
+                assert!(
+                    entropies[0][scale] < entropies[1][scale],
+                    "{:?} {:?}",
+                    order,
+                    entropies
+                );
+            }
+        }
+
+        let wave = carrier(&irregular);
+        assert_eq!(
+            multiscale_entropy(2, 0.1, &wave, 3, &MeanGrain, Default::default()),
+            envelope_multiscale_entropy(
+                2,
+                0.1,
+                &wave,
+                3,
+                &MeanGrain,
+                Signal::Wave,
+                EnvelopeOrder::GrainFirst
+            )
+        );
+    }
+}
@@ -0,0 +1,303 @@
+//! Removing the dominant periodic component of a waveform.
+//!
+//! Arterial pressure is dominated by the cardiac cycle, which sample entropy
+//! mostly sees as regularity. Subtracting a sinusoid fitted at the dominant
+//! period leaves the residual, whose entropy measures what the cycle doesn't
+//! explain: the beat-to-beat variation and whatever else rides on the wave.
+use crate::stats::{autocorrelation, first_zero_crossing};
+
+/// The autocorrelation a peak needs for its lag to count as a period. Below
+/// it the series has no clear periodicity, and `dominant_period` gives None.
+pub const MIN_PERIODIC_AUTOCORRELATION: f32 = 0.5;
+
+/// The largest lag searched for a period, 4 s of a 1 kHz recording and far
+/// beyond any cardiac cycle.
+pub const MAX_PERIOD: usize = 4096;
+
+/// The number of samples the autocorrelation is estimated from. Without the
+/// `spectral` feature its cost is the number of samples times the number of
+/// lags, so long recordings are estimated from their start.
+const MAX_AUTOCORRELATION_SAMPLES: usize = 1 << 16;
+
+/// The number of golden section steps refining the frequency, each shrinking
+/// the search interval by a factor of 0.618, enough to reach f64 precision.
+const REFINEMENT_STEPS: usize = 60;
+
+/// Estimates the dominant period of a series, in samples, or None if it has
+/// no clear periodicity.
+///
+/// The period is the lag of the highest peak of the `stats::autocorrelation`
+/// after its first zero crossing, up to `MAX_PERIOD` or half the series,
+/// refined to a fraction of a sample by fitting a parabola through the peak
+/// and its neighbours. The highest peak rather than `stats::first_peak`, so
+/// that a harmonic such as the dicrotic notch's doesn't pass for the period;
+/// the biased normalization shrinks the peaks of longer lags, so a multiple
+/// of the period never beats the period itself. The autocorrelation of the
+/// first 65536 samples is used.
+///
+/// It fails, returning None, when the series has no period to find: if the
+/// autocorrelation never reaches zero, as for a trend or a slow random walk
+/// (detrend first), or if its highest peak is below
+/// `MIN_PERIODIC_AUTOCORRELATION`, as for noise or a rhythm whose period
+/// wanders too much to line up with itself one cycle later. A period longer
+/// than `MAX_PERIOD` or half the series is not found either.
+///
+/// # Arguments
+/// * `data` - a vector containing the waveform data.
+///
+pub fn dominant_period(data: &[f32]) -> Option<f32> {
+    let data = &data[..data.len().min(MAX_AUTOCORRELATION_SAMPLES)];
+    let max_lag = MAX_PERIOD.min(data.len() / 2);
+    let acf = autocorrelation(data, max_lag);
+    let (lag, peak) = acf
+        .iter()
+        .enumerate()
+        .skip(first_zero_crossing(&acf)?)
+        .max_by(|a, b| a.1.total_cmp(b.1))?;
+    if *peak < MIN_PERIODIC_AUTOCORRELATION || lag + 1 >= acf.len() {
+        return None;
+    }
+    let (before, after) = (acf[lag - 1], acf[lag + 1]);
+    let curvature = before - 2.0 * peak + after;
+    let shift = if curvature < 0.0 {
+        0.5 * (before - after) / curvature
+    } else {
+        0.0
+    };
+    Some(lag as f32 + shift)
+}
+
+/// A line plus a sinusoid, `intercept + slope t + a cos(ωt) + b sin(ωt)`,
+/// at angular frequency ω.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sinusoid {
+    omega: f64,
+    /// The intercept, slope, and cosine and sine amplitudes.
+    coefficients: [f64; 4],
+}
+
+impl Sinusoid {
+    fn basis(omega: f64, t: usize) -> [f64; 4] {
+        let phase = omega * t as f64;
+        [1.0, t as f64, phase.cos(), phase.sin()]
+    }
+
+    fn at(&self, t: usize) -> f64 {
+        Sinusoid::basis(self.omega, t)
+            .iter()
+            .zip(&self.coefficients)
+            .map(|(basis, coefficient)| basis * coefficient)
+            .sum()
+    }
+}
+
+/// Fits a line plus a sinusoid at angular frequency `omega` to the finite
+/// samples by least squares, returning it and its sum of squared residuals.
+/// The line is fitted with the sinusoid rather than before it, since over a
+/// fractional number of cycles the sinusoid itself has a trend.
+fn fit_sinusoid(data: &[f32], omega: f64) -> (Sinusoid, f64) {
+    // The normal equations of the four coefficients.
+    let mut gram = [[0.0_f64; 4]; 4];
+    let mut moments = [0.0_f64; 4];
+    for (t, x) in data.iter().enumerate().filter(|(_, x)| x.is_finite()) {
+        let basis = Sinusoid::basis(omega, t);
+        for i in 0..4 {
+            for j in 0..4 {
+                gram[i][j] += basis[i] * basis[j];
+            }
+            moments[i] += basis[i] * *x as f64;
+        }
+    }
+    let sinusoid = Sinusoid {
+        omega,
+        coefficients: solve(gram, moments),
+    };
+    let residual = data
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| x.is_finite())
+        .map(|(t, x)| (*x as f64 - sinusoid.at(t)).powi(2))
+        .sum();
+    (sinusoid, residual)
+}
+
+/// Solves the system `a x = b` by Gaussian elimination with partial
+/// pivoting, or returns zeros if it is singular.
+fn solve<const N: usize>(mut a: [[f64; N]; N], mut b: [f64; N]) -> [f64; N] {
+    for column in 0..N {
+        let pivot = (column..N)
+            .max_by(|i, j| a[*i][column].abs().total_cmp(&a[*j][column].abs()))
+            .unwrap_or(column);
+        if a[pivot][column] == 0.0 || !a[pivot][column].is_finite() {
+            return [0.0; N];
+        }
+        a.swap(column, pivot);
+        b.swap(column, pivot);
+        let pivot_row = a[column];
+        for row in column + 1..N {
+            let factor = a[row][column] / pivot_row[column];
+            for (value, pivot) in a[row].iter_mut().zip(&pivot_row).skip(column) {
+                *value -= factor * pivot;
+            }
+            b[row] -= factor * b[column];
+        }
+    }
+    let mut x = [0.0; N];
+    for row in (0..N).rev() {
+        let known: f64 = (row + 1..N).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - known) / a[row][row];
+    }
+    x
+}
+
+/// Detrends the series like `stats::detrend_into`, with the line fitted to
+/// the finite samples only, so that a gap doesn't make every sample NaN. The
+/// non-finite samples are passed through. A series without any is detrended
+/// by `stats::detrend_into` itself, to the bit.
+fn detrend_finite_into(data: &[f32], out: &mut Vec<f32>) {
+    if data.iter().all(|x| x.is_finite()) {
+        crate::stats::detrend_into(data, out);
+        return;
+    }
+    let (x, y): (Vec<f32>, Vec<f32>) = data
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| x.is_finite())
+        .map(|(t, x)| ((t + 1) as f32, *x))
+        .unzip();
+    let (slope, intercept) = crate::stats::linear_regression(&x, &y);
+    out.clear();
+    out.extend(
+        data.iter()
+            .enumerate()
+            .map(|(t, x)| x - intercept - slope * (t + 1) as f32),
+    );
+}
+
+/// Subtracts a line and a sinusoid fitted at the dominant period from the
+/// series.
+///
+/// The dominant period is that of the series linearly detrended as by
+/// `Preprocess::Detrend`, though over its finite samples, from
+/// `dominant_period`. The frequency of the sinusoid is then refined by a
+/// golden section search within a sample of that period for the fit leaving
+/// the smallest residual, since over many cycles even a small error in the
+/// period leaves a residual that grows with the phase drift. If no period is
+/// found, the detrended series is returned. Only the fundamental is removed:
+/// a cycle that isn't sinusoidal, such as a pressure pulse with its dicrotic
+/// notch, leaves its harmonics in the residual. Non-finite samples are kept
+/// in place and left out of the fit.
+///
+/// Returns the period removed, if any.
+///
+/// # Arguments
+/// * `data` - an immutable vector slice of waveform data.
+/// * `out` - the vector the residual is written into.
+///
+pub fn remove_periodic_into(data: &[f32], out: &mut Vec<f32>) -> Option<f32> {
+    detrend_finite_into(data, out);
+    let period = dominant_period(out)?;
+    let omega = |period: f64| std::f64::consts::TAU / period;
+    // The residual is unimodal in the frequency near the true one.
+    let (mut low, mut high) = (
+        omega(period as f64 + 1.0),
+        omega((period as f64 - 1.0).max(1.5)),
+    );
+    let golden = (5.0_f64.sqrt() - 1.0) / 2.0;
+    let residual = |omega: f64| fit_sinusoid(data, omega).1;
+    let (mut a, mut b) = (high - golden * (high - low), low + golden * (high - low));
+    let (mut residual_a, mut residual_b) = (residual(a), residual(b));
+    for _ in 0..REFINEMENT_STEPS {
+        if residual_a < residual_b {
+            high = b;
+            (b, residual_b) = (a, residual_a);
+            a = high - golden * (high - low);
+            residual_a = residual(a);
+        } else {
+            low = a;
+            (a, residual_a) = (b, residual_b);
+            b = low + golden * (high - low);
+            residual_b = residual(b);
+        }
+    }
+    let (sinusoid, _) = fit_sinusoid(data, (low + high) / 2.0);
+    out.clear();
+    out.extend(
+        data.iter()
+            .enumerate()
+            .map(|(t, x)| (*x as f64 - sinusoid.at(t)) as f32),
+    );
+    Some((std::f64::consts::TAU / sinusoid.omega) as f32)
+}
+
+/// Subtracts a sinusoid fitted at the dominant period like
+/// `remove_periodic_into`, returning a new vector.
+///
+/// # Arguments
+/// * `data` - an immutable vector slice of waveform data.
+///
+pub fn remove_periodic(data: &[f32]) -> Vec<f32> {
+    let mut out: Vec<f32> = Vec::with_capacity(data.len());
+    remove_periodic_into(data, &mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::SplitMix64;
+    use crate::stats::standard_deviation;
+
+    /// A sine of the period, with a drift and an offset.
+    fn sine(period: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|t| {
+                90.0 + 0.001 * t as f32 + 20.0 * (std::f32::consts::TAU * t as f32 / period).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pure_sinusoid_leaves_no_residual() {
+        let data = sine(47.3, 5000);
+        let period = dominant_period(&crate::stats::detrend_data(&data)).unwrap();
+        assert!((period - 47.3).abs() < 0.5, "{}", period);
+
+        let mut residual = Vec::new();
+        let removed = remove_periodic_into(&data, &mut residual).unwrap();
+        assert!((removed - 47.3).abs() < 1e-3, "{}", removed);
+        assert_eq!(data.len(), residual.len());
+        let ratio = standard_deviation(&residual) / standard_deviation(&data);
+        assert!(ratio < 1e-4, "{}", ratio);
+    }
+
+    #[test]
+    fn test_gap_keeps_the_period_and_the_finite_residual() {
+        let mut data = sine(47.3, 5000);
+        data[1234] = f32::NAN;
+        data[4000] = f32::INFINITY;
+        let mut residual = Vec::new();
+        let removed = remove_periodic_into(&data, &mut residual).unwrap();
+        assert!((removed - 47.3).abs() < 1e-2, "{}", removed);
+        assert!(residual[1234].is_nan());
+        assert!(!residual[4000].is_finite());
+        let finite: Vec<f32> = residual.iter().copied().filter(|x| x.is_finite()).collect();
+        assert_eq!(data.len() - 2, finite.len());
+        assert!(
+            standard_deviation(&finite) < 1e-2,
+            "{}",
+            standard_deviation(&finite)
+        );
+    }
+
+    #[test]
+    fn test_no_period_in_noise_or_trend() {
+        let mut rng = SplitMix64::new(3);
+        let noise = rng.uniform_vec(5000);
+        assert_eq!(None, dominant_period(&noise));
+        let ramp: Vec<f32> = (0..5000).map(|t| t as f32).collect();
+        assert_eq!(None, dominant_period(&ramp));
+        // Without a period, only the trend is removed.
+        assert_eq!(crate::stats::detrend_data(&noise), remove_periodic(&noise));
+    }
+}
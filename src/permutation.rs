@@ -0,0 +1,249 @@
+//! Permutation entropy, the entropy of the ordinal patterns of a waveform.
+//!
+//! From Bandt, C. & Pompe, B. (2002) "Permutation entropy: a natural
+//! complexity measure for time series". Each delay vector is reduced to the
+//! order of its values, so the measure ignores amplitude and needs no
+//! tolerance, but it has to decide what order equal values are in.
+use std::collections::HashMap;
+
+use crate::rng::SplitMix64;
+use crate::util;
+
+/// How equal values within a delay vector are ordered.
+///
+/// Neither rule is neutral. `ByIndex` puts every tie in ascending order, so a
+/// flat stretch always reads as the same pattern (e.g. `0 1 2` at order 3)
+/// and that pattern's probability is inflated, pulling the entropy down on
+/// coarsely quantized signals. `AddJitter` spreads each tied vector over the
+/// patterns consistent with its distinct values at random, which pushes the
+/// entropy up towards that of noise. Only vectors containing a tie are
+/// affected: if a fraction q of them do, the pattern distributions of the two
+/// rules are at most q apart in total variation, so their unnormalized
+/// permutation entropies differ by at most `q ln(K - 1) + h(q)`, where `K =
+/// order!` and `h(q) = -q ln q - (1 - q) ln(1 - q)` (the Fannes-Audenaert
+/// bound), and the normalized entropies by that divided by `ln K`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TieRule {
+    /// Of two equal values, the earlier one is the smaller.
+    #[default]
+    ByIndex,
+    /// Adds uniform noise of a quarter of the smallest gap between distinct
+    /// values to every sample before ordering, drawn from this seed. The
+    /// noise is too small to reorder distinct values. Ties it can't separate
+    /// (when a quarter gap is below float precision) fall back to `ByIndex`.
+    AddJitter(u64),
+}
+
+/// Computes the normalized permutation entropy of a waveform.
+///
+/// Every delay vector `(x[i], x[i + delay], ..., x[i + (order-1) * delay])`
+/// is mapped to the permutation that sorts it, with ties ordered by `ties`,
+/// and the Shannon entropy of the permutations' frequencies is divided by
+/// its maximum `ln(order!)`. The result lies between 0 (a monotonic series)
+/// and 1 (every pattern equally likely, as in white noise). Vectors
+/// containing NaN or infinite values are skipped.
+///
+/// # Arguments
+/// * `order` - the length of the delay vectors. Must be at least 2.
+/// * `delay` - the number of samples between the values of a vector.
+/// * `data` - a vector containing the waveform data.
+/// * `ties` - how equal values are ordered.
+///
+pub fn permutation_entropy(order: usize, delay: usize, data: &[f32], ties: TieRule) -> f32 {
+    normalized_entropy(order, &pattern_weights(order, delay, data, ties, |_| 1.0))
+}
+
+/// Computes the normalized weighted permutation entropy of a waveform.
+///
+/// From Fadlallah, B. et al. (2013) "Weighted-permutation entropy: a
+/// complexity measure for time series incorporating amplitude information".
+/// As `permutation_entropy`, but each delay vector counts in proportion to
+/// its variance, so small fluctuations (which are mostly noise) weigh less
+/// than large ones. Vectors with no variance, e.g. all ties, don't count at
+/// all, so the tie rule matters less than for `permutation_entropy`.
+///
+/// # Arguments
+/// * `order` - the length of the delay vectors. Must be at least 2.
+/// * `delay` - the number of samples between the values of a vector.
+/// * `data` - a vector containing the waveform data.
+/// * `ties` - how equal values are ordered.
+///
+pub fn weighted_permutation_entropy(
+    order: usize,
+    delay: usize,
+    data: &[f32],
+    ties: TieRule,
+) -> f32 {
+    let variance = |vector: &[f32]| -> f64 {
+        let mean = vector.iter().map(|x| *x as f64).sum::<f64>() / vector.len() as f64;
+        vector
+            .iter()
+            .map(|x| (*x as f64 - mean).powi(2))
+            .sum::<f64>()
+            / vector.len() as f64
+    };
+    normalized_entropy(order, &pattern_weights(order, delay, data, ties, variance))
+}
+
+/// Sums the weight of the delay vectors of each ordinal pattern, keyed by the
+/// pattern's Lehmer code. Weights are computed from the original values, not
+/// the jittered ones.
+fn pattern_weights(
+    order: usize,
+    delay: usize,
+    data: &[f32],
+    ties: TieRule,
+    weight: impl Fn(&[f32]) -> f64,
+) -> HashMap<usize, f64> {
+    let jittered: Vec<f32>;
+    let ordered = match ties {
+        TieRule::ByIndex => data,
+        TieRule::AddJitter(seed) => {
+            jittered = jitter(data, seed);
+            &jittered
+        }
+    };
+    let span = order.saturating_sub(1) * delay;
+    let mut weights: HashMap<usize, f64> = HashMap::new();
+    let mut vector: Vec<f32> = Vec::with_capacity(order);
+    let mut ranks: Vec<f32> = Vec::with_capacity(order);
+    let mut permutation: Vec<usize> = Vec::with_capacity(order);
+    for start in 0..data.len().saturating_sub(span) {
+        vector.clear();
+        vector.extend((0..order).map(|k| data[start + k * delay]));
+        if !vector.iter().all(|x| x.is_finite()) {
+            continue;
+        }
+        ranks.clear();
+        ranks.extend((0..order).map(|k| ordered[start + k * delay]));
+        permutation.clear();
+        permutation.extend(0..order);
+        // A stable sort keeps equal values in index order.
+        permutation.sort_by(|a, b| ranks[*a].total_cmp(&ranks[*b]));
+        *weights.entry(lehmer_code(&permutation)).or_default() += weight(&vector);
+    }
+    weights
+}
+
+/// Adds noise of up to a quarter of the smallest gap between distinct finite
+/// values to every sample. See `TieRule::AddJitter`.
+fn jitter(data: &[f32], seed: u64) -> Vec<f32> {
+    let mut distinct: Vec<f32> = util::sorted_finite(data);
+    distinct.dedup();
+    let gap = distinct
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .fold(f32::INFINITY, f32::min);
+    let amplitude = if gap.is_finite() { gap / 4.0 } else { 1.0 };
+    let mut rng = SplitMix64::new(seed);
+    data.iter()
+        .map(|x| x + (rng.next_f32() - 0.5) * amplitude)
+        .collect()
+}
+
+/// The index of the permutation among all permutations of its length, in
+/// lexicographic order.
+fn lehmer_code(permutation: &[usize]) -> usize {
+    permutation.iter().enumerate().fold(0, |code, (ix, value)| {
+        let smaller_later = permutation[ix + 1..]
+            .iter()
+            .filter(|other| *other < value)
+            .count();
+        code * (permutation.len() - ix) + smaller_later
+    })
+}
+
+/// The Shannon entropy of the pattern weights, divided by `ln(order!)`.
+fn normalized_entropy(order: usize, weights: &HashMap<usize, f64>) -> f32 {
+    let total: f64 = weights.values().sum();
+    let entropy: f64 = weights
+        .values()
+        .filter(|weight| **weight > 0.0)
+        .map(|weight| {
+            let p = weight / total;
+            -p * p.ln()
+        })
+        .sum();
+    let max_entropy: f64 = (2..=order).map(|k| (k as f64).ln()).sum();
+    (entropy / max_entropy) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lehmer_code_enumerates_permutations() {
+        let codes: Vec<usize> = [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ]
+        .iter()
+        .map(|permutation| lehmer_code(permutation))
+        .collect();
+        assert_eq!(vec![0, 1, 2, 3, 4, 5], codes);
+    }
+
+    #[test]
+    fn test_permutation_entropy_extremes() {
+        let ramp: Vec<f32> = (0..500).map(|x| x as f32).collect();
+        assert_eq!(0.0, permutation_entropy(3, 1, &ramp, TieRule::ByIndex));
+        let mut rng = SplitMix64::new(2);
+        let noise = rng.uniform_vec(20000);
+        let entropy = permutation_entropy(3, 1, &noise, TieRule::ByIndex);
+        assert!(entropy > 0.99 && entropy <= 1.0, "{}", entropy);
+        let weighted = weighted_permutation_entropy(3, 1, &noise, TieRule::ByIndex);
+        assert!(weighted > 0.98 && weighted <= 1.0, "{}", weighted);
+    }
+
+    #[test]
+    fn test_tie_rules_on_quantized_signal() {
+        let mut rng = SplitMix64::new(4);
+        let quantized: Vec<f32> = (0..5000)
+            .map(|x| ((x as f32 * 0.3).sin() * 4.0 + rng.next_f32() * 2.0).round())
+            .collect();
+        let order = 3;
+        let by_index = permutation_entropy(order, 1, &quantized, TieRule::ByIndex);
+        assert_eq!(
+            by_index,
+            permutation_entropy(order, 1, &quantized, TieRule::ByIndex)
+        );
+        let jittered = permutation_entropy(order, 1, &quantized, TieRule::AddJitter(9));
+        assert_eq!(
+            jittered,
+            permutation_entropy(order, 1, &quantized, TieRule::AddJitter(9))
+        );
+        assert!(jittered > by_index);
+
+        let num_vectors = quantized.len() - (order - 1);
+        let num_tied = quantized
+            .windows(order)
+            .filter(|vector| {
+                vector[0] == vector[1] || vector[1] == vector[2] || vector[0] == vector[2]
+            })
+            .count();
+        let q = num_tied as f64 / num_vectors as f64;
+        assert!(q > 0.3, "{}", q);
+        let num_patterns: f64 = 6.0;
+        let bound = (q * (num_patterns - 1.0).ln() - q * q.ln() - (1.0 - q) * (1.0 - q).ln())
+            / num_patterns.ln();
+        assert!(((jittered - by_index) as f64) <= bound);
+    }
+
+    #[test]
+    fn test_jitter_preserves_order_of_distinct_values() {
+        let data: Vec<f32> = vec![1.0, 2.0, 2.0, 3.0, 1.0, 5.0];
+        let jittered = jitter(&data, 3);
+        for i in 0..data.len() {
+            for j in 0..data.len() {
+                if data[i] < data[j] {
+                    assert!(jittered[i] < jittered[j]);
+                }
+            }
+        }
+    }
+}
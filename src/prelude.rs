@@ -0,0 +1,44 @@
+//! The items most uses of the crate need, so that
+//! `use sample_entropy::prelude::*;` is enough to read a file and compute
+//! its entropies without knowing which module each item lives in.
+//!
+//! The distance between templates is chosen with a `MatchPredicate`, of
+//! which `ChebyshevThreshold` is the standard one.
+pub use crate::error::SampenError;
+pub use crate::matching::MatchStrategy;
+pub use crate::measures::{compute_measures, MeasureConfig, MeasureSet};
+pub use crate::multiscale::{multiscale_entropy, CoarseGrain, MeanGrain, MultiscaleOptions};
+pub use crate::permutation::{permutation_entropy, TieRule};
+pub use crate::stats::{
+    approximate_entropy, checked_sample_entropy, match_counts, sample_entropy, ChebyshevThreshold,
+    MatchCounts, MatchPredicate, Preprocess, Tolerance,
+};
+pub use crate::vital_entropies::{Status, VitalEntropies};
+pub use crate::vital_file::{read_csv, Channel, VitalFile};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prelude_covers_typical_use() {
+        let vital_file = crate::vital_file::read_csv_from_reader(
+            "name,mbp,sbp,dbp\ncase1,90,120,70\n".as_bytes(),
+        )
+        .unwrap();
+        let data: Vec<f32> = (0..200).map(|x| ((x * 13) % 17) as f32).collect();
+        let detrended = Preprocess::default().apply(&data);
+        let r = Tolerance::default().resolve(&detrended);
+        let entropy: f32 = sample_entropy(2, r, &detrended);
+        assert_eq!(match_counts(2, r, &detrended).sample_entropy(), entropy);
+        let mse = multiscale_entropy(2, r, &detrended, 1, &MeanGrain, Default::default());
+        assert_eq!(1, mse.len());
+        let error: SampenError = checked_sample_entropy(2, r, &data[..2]).unwrap_err();
+        assert!(matches!(
+            error,
+            SampenError::InsufficientDataForMPlus1 { .. }
+        ));
+        let channel: &Channel = &vital_file.sbp;
+        assert_eq!(vec![120.0], channel.data);
+    }
+}
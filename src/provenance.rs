@@ -0,0 +1,42 @@
+//! Which build of the crate produced a set of results.
+//!
+//! Entropies depend on details such as the match counting and detrending,
+//! which may change between versions, so results kept for research need to
+//! be traceable to the exact binary that computed them.
+
+/// The crate version, from `Cargo.toml`.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The abbreviated hash of the git commit the crate was built from, or
+/// `None` when it was built outside a git checkout or without git installed.
+pub const GIT_HASH: Option<&str> = option_env!("SAMPLE_ENTROPY_GIT_HASH");
+
+/// Describes this build, e.g. `sample_entropy 0.1.0 (git 1a2b3c4d5e6f)`, or
+/// `sample_entropy 0.1.0` without a git hash.
+pub fn provenance() -> String {
+    match GIT_HASH {
+        Some(hash) => format!("{} {} (git {})", env!("CARGO_PKG_NAME"), VERSION, hash),
+        None => format!("{} {}", env!("CARGO_PKG_NAME"), VERSION),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_matches_manifest() {
+        let manifest = include_str!("../Cargo.toml");
+        let version_line = manifest
+            .lines()
+            .find(|line| line.starts_with("version"))
+            .unwrap();
+        assert_eq!(format!("version = \"{}\"", VERSION), version_line);
+        let description = provenance();
+        assert!(description.starts_with(&format!("sample_entropy {}", VERSION)));
+        if let Some(hash) = GIT_HASH {
+            assert!(description.ends_with(&format!("(git {})", hash)));
+            assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+        }
+    }
+}
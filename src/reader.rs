@@ -0,0 +1,137 @@
+//! A common interface over the vital file readers, so that a pipeline can
+//! read a directory of mixed formats without special-casing each one.
+use std::fs::File;
+use std::path::Path;
+
+use crate::error::SampenError;
+use crate::vital_file::{self, VitalFile};
+
+/// A format vital files can be read from.
+pub trait WaveformReader {
+    /// Reads every vital file stored at `path`. Formats holding a single
+    /// file per path return one.
+    fn read(&self, path: &str) -> Result<Vec<VitalFile>, SampenError>;
+}
+
+/// Reads csvs in the canonical layout with
+/// `vital_file::read_csv_from_reader_with_header`.
+#[derive(Debug, Clone, Copy)]
+pub struct CsvReader {
+    /// Whether the first row of each csv names the columns.
+    pub has_header: bool,
+    /// Whether to split simple csvs without the `csv` crate, with
+    /// `vital_file::read_simple_csv_from_reader`. The files read the same
+    /// either way.
+    pub simple: bool,
+}
+
+impl Default for CsvReader {
+    /// Reads csvs with a header, like `vital_file::read_csv`.
+    fn default() -> Self {
+        CsvReader {
+            has_header: true,
+            simple: false,
+        }
+    }
+}
+
+impl WaveformReader for CsvReader {
+    fn read(&self, path: &str) -> Result<Vec<VitalFile>, SampenError> {
+        let file = File::open(path)?;
+        let vital_file = if self.simple {
+            vital_file::read_simple_csv_from_reader(file, self.has_header)
+        } else {
+            vital_file::read_csv_from_reader_with_header(file, self.has_header)
+        };
+        vital_file.map(|vital_file| vec![vital_file])
+    }
+}
+
+/// Reads NDJSON with `vital_file::read_ndjson`, skipping malformed lines.
+#[cfg(feature = "ndjson")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NdjsonReader;
+
+#[cfg(feature = "ndjson")]
+impl WaveformReader for NdjsonReader {
+    fn read(&self, path: &str) -> Result<Vec<VitalFile>, SampenError> {
+        vital_file::read_ndjson(path)
+    }
+}
+
+/// Returns the reader for a path from its extension, ignoring case: `csv`,
+/// read with the given `CsvReader`, and with the `ndjson` feature `ndjson`
+/// and `jsonl`. Other formats name their fields in every record, so `csv`
+/// doesn't affect them.
+///
+/// Fails with `SampenError::InvalidFile` for any other extension, or none.
+///
+/// # Arguments
+/// * `path` - the path of the file to be read.
+/// * `csv` - how csvs are read. `CsvReader::default()` reads them like
+///   `vital_file::read_csv`.
+///
+pub fn reader_for_path(path: &str, csv: CsvReader) -> Result<Box<dyn WaveformReader>, SampenError> {
+    let extension = Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("csv") => Ok(Box::new(csv)),
+        #[cfg(feature = "ndjson")]
+        Some("ndjson" | "jsonl") => Ok(Box::new(NdjsonReader)),
+        _ => Err(SampenError::InvalidFile {
+            name: path.to_string(),
+            reason: String::from("no reader for this file extension"),
+        }),
+    }
+}
+
+/// Reads the vital files at `path` with the reader for its extension.
+///
+/// # Arguments
+/// * `path` - the path of the file to be read.
+///
+pub fn read_any(path: &str) -> Result<Vec<VitalFile>, SampenError> {
+    reader_for_path(path, CsvReader::default())?.read(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vital_file::{write_csv, Channel};
+
+    #[test]
+    fn test_dispatch_on_extension() {
+        let dir = std::env::temp_dir().join(format!("sampen_readers_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let case = VitalFile {
+            name: String::from("case1"),
+            sample_rate: None,
+            sbp: Channel::new(vec![120.0, 121.0]),
+            mbp: Channel::new(vec![90.0, 91.0]),
+            dbp: Channel::new(vec![70.0, 71.0]),
+        };
+        let csv = dir.join("case1.CSV");
+        write_csv(&case, File::create(&csv).unwrap()).unwrap();
+        let read = read_any(&csv.to_string_lossy());
+
+        #[cfg(feature = "ndjson")]
+        let ndjson_read = {
+            let ndjson = dir.join("cases.jsonl");
+            let files = [case];
+            vital_file::write_ndjson(&files, File::create(&ndjson).unwrap()).unwrap();
+            read_any(&ndjson.to_string_lossy())
+        };
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(vec![120.0, 121.0], read.unwrap()[0].sbp.data);
+        #[cfg(feature = "ndjson")]
+        assert_eq!(vec![90.0, 91.0], ndjson_read.unwrap()[0].mbp.data);
+        for unknown in ["case1.edf", "case1", "data/case1.csv.gz"] {
+            assert!(matches!(
+                reader_for_path(unknown, CsvReader::default()),
+                Err(SampenError::InvalidFile { .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Approximate quantiles of a series in one pass and bounded memory.
+//!
+//! An exact quantile needs the whole series sorted, a copy as large as the
+//! series itself; for a multi-gigabyte recording that copy is the problem.
+//! `PercentileSketch` is a merging t-digest, from Dunning, T. (2019)
+//! "Computing extremely accurate quantiles using t-digests": samples are
+//! summarized by weighted centroids, small near the extremes and largest
+//! around the median, so the memory is fixed by the compression rather than
+//! by the number of samples.
+
+/// The compression `PercentileSketch::default` uses.
+pub const DEFAULT_COMPRESSION: f64 = 200.0;
+
+/// How many samples are buffered per unit of compression before they are
+/// merged into the centroids.
+const BUFFER_PER_COMPRESSION: f64 = 5.0;
+
+/// A weighted mean of adjacent samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+/// Estimates quantiles of the samples it has seen, in memory that doesn't
+/// grow with their number.
+///
+/// With compression δ the sketch holds at most about `π δ / 2` centroids and
+/// `5 δ` buffered samples, about 13 KB at the default of 200. A centroid near
+/// quantile q summarizes at most about `π sqrt(q (1 - q)) / δ` of the
+/// samples, and an estimate is off by at most half a centroid in rank: at
+/// the default, half a percent of the samples at the median, a tenth of one
+/// at the 1st and 99th percentiles, and nearly exact at the extremes, which
+/// are kept exactly. Interpolating between centroids usually does much
+/// better than the bound. Doubling δ halves the error and doubles the memory.
+/// Estimates don't depend on the order the samples arrive in beyond that
+/// error, but aren't bit-for-bit reproducible across orders.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PercentileSketch {
+    compression: f64,
+    /// Sorted by mean.
+    centroids: Vec<Centroid>,
+    /// Samples not yet merged into the centroids.
+    buffer: Vec<f64>,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for PercentileSketch {
+    /// A sketch with `DEFAULT_COMPRESSION`.
+    fn default() -> Self {
+        PercentileSketch::new(DEFAULT_COMPRESSION)
+    }
+}
+
+impl PercentileSketch {
+    /// An empty sketch with compression δ, at least 10. See
+    /// `PercentileSketch` for how δ trades memory for accuracy.
+    pub fn new(compression: f64) -> Self {
+        let compression = compression.max(10.0);
+        PercentileSketch {
+            compression,
+            centroids: Vec::new(),
+            buffer: Vec::with_capacity((compression * BUFFER_PER_COMPRESSION) as usize),
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// The number of samples the sketch has seen, excluding those skipped.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Adds a sample. NaN and infinite samples are skipped.
+    pub fn insert(&mut self, x: f32) {
+        if !x.is_finite() {
+            return;
+        }
+        let x = x as f64;
+        self.count += 1;
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
+        self.buffer.push(x);
+        if self.buffer.len() as f64 >= self.compression * BUFFER_PER_COMPRESSION {
+            self.centroids = self.merged();
+            self.buffer.clear();
+        }
+    }
+
+    /// Adds every sample of the data, like `insert`.
+    pub fn extend(&mut self, data: &[f32]) {
+        for x in data {
+            self.insert(*x);
+        }
+    }
+
+    /// Estimates the q-th quantile, for q between 0 and 1: the value below
+    /// which a fraction q of the samples lie, interpolated linearly between
+    /// ranks as in `stats::median`. NaN if the sketch is empty or q is
+    /// outside [0, 1].
+    pub fn quantile(&self, q: f64) -> f32 {
+        if self.count == 0 || !(0.0..=1.0).contains(&q) {
+            return f32::NAN;
+        }
+        let centroids = self.merged();
+        // Each centroid sits at the middle of the ranks it covers, between the
+        // minimum at rank 0 and the maximum at rank `count`.
+        let target = q * self.count as f64;
+        let mut previous = (0.0, self.min);
+        let mut covered = 0.0;
+        for centroid in centroids {
+            let position = covered + centroid.weight / 2.0;
+            if target <= position {
+                return interpolate(previous, (position, centroid.mean), target) as f32;
+            }
+            covered += centroid.weight;
+            previous = (position, centroid.mean);
+        }
+        interpolate(previous, (self.count as f64, self.max), target) as f32
+    }
+
+    /// The centroids with the buffered samples merged in, each as large as
+    /// the scale function allows.
+    fn merged(&self) -> Vec<Centroid> {
+        let mut points: Vec<Centroid> = self
+            .buffer
+            .iter()
+            .map(|x| Centroid {
+                mean: *x,
+                weight: 1.0,
+            })
+            .chain(self.centroids.iter().copied())
+            .collect();
+        points.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+        let total = self.count as f64;
+        let mut merged: Vec<Centroid> = Vec::with_capacity(self.compression as usize);
+        let mut points = points.into_iter();
+        let Some(mut current) = points.next() else {
+            return merged;
+        };
+        let mut covered = 0.0;
+        let mut limit = total * self.next_quantile_limit(0.0);
+        for point in points {
+            if covered + current.weight + point.weight <= limit {
+                let weight = current.weight + point.weight;
+                current.mean += (point.mean - current.mean) * point.weight / weight;
+                current.weight = weight;
+            } else {
+                covered += current.weight;
+                merged.push(current);
+                limit = total * self.next_quantile_limit(covered / total);
+                current = point;
+            }
+        }
+        merged.push(current);
+        merged
+    }
+
+    /// The quantile one unit of the scale function `k(q) = δ asin(2q - 1) /
+    /// 2π` above q, the furthest a centroid starting at q may extend.
+    fn next_quantile_limit(&self, q: f64) -> f64 {
+        use std::f64::consts::PI;
+        let k = self.compression * (2.0 * q - 1.0).clamp(-1.0, 1.0).asin() / (2.0 * PI) + 1.0;
+        let angle = (2.0 * PI * k / self.compression).min(PI / 2.0);
+        (angle.sin() + 1.0) / 2.0
+    }
+}
+
+/// The value at `x` on the line through two (position, value) points.
+fn interpolate(left: (f64, f64), right: (f64, f64), x: f64) -> f64 {
+    if right.0 <= left.0 {
+        return right.1;
+    }
+    left.1 + (right.1 - left.1) * (x - left.0) / (right.0 - left.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::SplitMix64;
+
+    #[test]
+    fn test_quantiles_are_close_to_exact() {
+        let mut rng = SplitMix64::new(17);
+        // Skewed, so that the quantiles are unevenly spaced.
+        let data: Vec<f32> = (0..200_000).map(|_| rng.next_f32().powi(3)).collect();
+        let mut sketch = PercentileSketch::default();
+        sketch.extend(&data);
+        assert_eq!(data.len() as u64, sketch.count());
+        assert!(sketch.centroids.len() < 400);
+
+        let mut sorted = data.clone();
+        sorted.sort_by(f32::total_cmp);
+        for q in [0.0, 0.001, 0.01, 0.1, 0.25, 0.5, 0.75, 0.9, 0.99, 1.0] {
+            let estimate = sketch.quantile(q);
+            // The fraction of samples below the estimate is off by little.
+            let rank = sorted.partition_point(|x| *x < estimate) as f64 / sorted.len() as f64;
+            assert!((rank - q).abs() < 0.005, "q {}: rank {}", q, rank);
+        }
+        assert_eq!(sorted[0], sketch.quantile(0.0));
+        assert_eq!(sorted[sorted.len() - 1], sketch.quantile(1.0));
+    }
+
+    #[test]
+    fn test_small_sketches_are_exact() {
+        let mut sketch = PercentileSketch::default();
+        assert!(sketch.quantile(0.5).is_nan());
+        sketch.extend(&[3.0, f32::NAN, 1.0, 4.0, f32::INFINITY, 2.0]);
+        assert_eq!(4, sketch.count());
+        assert_eq!(
+            crate::stats::median(&[3.0, 1.0, 4.0, 2.0]),
+            sketch.quantile(0.5)
+        );
+        assert_eq!(1.0, sketch.quantile(0.0));
+        assert_eq!(4.0, sketch.quantile(1.0));
+        assert!(sketch.quantile(1.5).is_nan());
+    }
+}
@@ -1,3 +1,12 @@
+use std::collections::HashMap;
+
+use crate::error::SampenError;
+use crate::interrupt::{Interrupt, Interrupted};
+use crate::matching::{self, MatchStrategy};
+use crate::rng::SplitMix64;
+use crate::sketch::PercentileSketch;
+use crate::util;
+
 /// Constructs the template vectors for a given time series.
 ///
 /// # Arguments
@@ -5,37 +14,301 @@
 /// * `window_size` - the window size for a single template.
 /// * `ts_data` - the time series data.
 ///
-fn construct_templates(window_size: usize, ts_data: &Vec<f32>) -> Vec<Vec<f32>> {
-    let num_windows = ts_data.len() - window_size + 1;
-    (0..num_windows)
-        .map(|x| ts_data[x..x + window_size].to_vec())
-        .collect::<Vec<Vec<f32>>>()
+pub fn construct_templates(window_size: usize, ts_data: &[f32]) -> Vec<Vec<f32>> {
+    let mut templates: Vec<Vec<f32>> = Vec::new();
+    construct_templates_into(window_size, ts_data, &mut templates);
+    templates
+}
+
+/// Constructs the template vectors for a given time series into an existing
+/// vector, reusing the allocations of any templates already stored in it.
+///
+/// # Arguments
+///
+/// * `window_size` - the window size for a single template.
+/// * `ts_data` - the time series data.
+/// * `templates` - the vector the templates are written into.
+///
+fn construct_templates_into(window_size: usize, ts_data: &[f32], templates: &mut Vec<Vec<f32>>) {
+    let num_windows = (ts_data.len() + 1).saturating_sub(window_size);
+    templates.truncate(num_windows);
+    let reused = templates.len();
+    for (x, template) in templates.iter_mut().enumerate() {
+        template.clear();
+        template.extend_from_slice(&ts_data[x..x + window_size]);
+    }
+    templates.extend((reused..num_windows).map(|x| ts_data[x..x + window_size].to_vec()));
+}
+
+/// The default memory cap of a `TemplateBuffer`, 64 MiB.
+///
+/// A series of 10 minutes at 500 Hz copied into length-2 and length-3
+/// templates takes about 20 MiB, so typical records stay well below it.
+pub const DEFAULT_TEMPLATE_MEMORY_CAP: usize = 64 << 20;
+
+/// Scratch space for template vectors.
+///
+/// Passing the same buffer to `sample_entropy_with_buffer` for many series
+/// lets the template vectors be reused instead of reallocated every call.
+///
+/// Functions that compare templates with a `MatchPredicate` copy every
+/// template into the buffer, which costs `template_memory` bytes and for a
+/// long series can exhaust memory. When the copies would exceed the
+/// buffer's memory cap, they instead compare slices of the series located
+/// by their start indices, 8 bytes per template, and free any copies left
+/// from earlier series. Both give identical counts. The cap is per buffer:
+/// a parallel run keeping one buffer per rayon task, as the binary does,
+/// can hold up to the number of threads times the cap.
+#[derive(Debug, Clone)]
+pub struct TemplateBuffer {
+    size_m: Vec<Vec<f32>>,
+    size_m_plus_k: Vec<Vec<f32>>,
+    match_scratch: matching::MatchScratch,
+    memory_cap: usize,
+}
+
+impl Default for TemplateBuffer {
+    /// An empty buffer capped at `DEFAULT_TEMPLATE_MEMORY_CAP`.
+    fn default() -> Self {
+        TemplateBuffer::with_memory_cap(DEFAULT_TEMPLATE_MEMORY_CAP)
+    }
+}
+
+impl TemplateBuffer {
+    /// An empty buffer that holds at most `memory_cap` bytes of template
+    /// copies.
+    pub fn with_memory_cap(memory_cap: usize) -> Self {
+        TemplateBuffer {
+            size_m: Vec::new(),
+            size_m_plus_k: Vec::new(),
+            match_scratch: matching::MatchScratch::default(),
+            memory_cap,
+        }
+    }
+
+    /// The most bytes of template copies the buffer holds.
+    pub fn memory_cap(&self) -> usize {
+        self.memory_cap
+    }
+}
+
+/// The bytes taken by copies of every template of `window_size` samples of a
+/// series of `len` samples, including each copy's `Vec` header.
+///
+/// # Arguments
+/// * `window_size` - the window size for a single template.
+/// * `len` - the length of the series.
+///
+pub fn template_memory(window_size: usize, len: usize) -> usize {
+    let per_template = std::mem::size_of::<Vec<f32>>() + window_size * std::mem::size_of::<f32>();
+    (len + 1).saturating_sub(window_size) * per_template
 }
 
-/// Returns the number of unique pairs of template vectors where the
-/// chebyshev distance between each pair of vectors is less than the given
-/// threshold.
+/// Decides whether two templates of equal length match.
+///
+/// `sample_entropy` uses `ChebyshevThreshold`, but any similarity rule can be
+/// plugged into `sample_entropy_with_predicate` by implementing this trait.
+pub trait MatchPredicate {
+    /// Returns true if template `a` matches template `b`.
+    fn matches(&self, a: &[f32], b: &[f32]) -> bool;
+}
+
+/// The standard sample entropy match: two templates match when their
+/// chebyshev distance is less than the wrapped threshold `r`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChebyshevThreshold(pub f32);
+
+impl MatchPredicate for ChebyshevThreshold {
+    fn matches(&self, a: &[f32], b: &[f32]) -> bool {
+        is_match(a, b, &self.0)
+    }
+}
+
+/// A chebyshev threshold with its own `r` for each coordinate of a
+/// template: two templates match when every coordinate differs by less than
+/// its threshold.
+///
+/// Coordinate i of a template is the i-th oldest of its samples, so this can
+/// e.g. demand closer agreement on the most recent samples. A coordinate past
+/// the end of the thresholds uses the last one, so `m` thresholds also cover
+/// the extension of a length-(m+1) template, and `m + 1` set it separately.
+/// Thresholds of one value reproduce `ChebyshevThreshold`. It is only
+/// meaningful when the coordinates differ in scale or in importance, as for
+/// templates built from several series or with a delay embedding;
+/// coordinates of one stationary series all have the same spread, and uneven
+/// thresholds then merely make the entropy harder to compare with the
+/// literature.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PerDimensionThreshold<'a>(pub &'a [f32]);
+
+impl MatchPredicate for PerDimensionThreshold<'_> {
+    fn matches(&self, a: &[f32], b: &[f32]) -> bool {
+        is_match_per_dimension(a, b, self.0)
+    }
+}
+
+/// Returns the number of unique pairs of template vectors that match
+/// according to the given predicate.
 ///
 /// This function technically returns exactly half the matches, but since
 /// sample entropy is -ln(A/B), it doesn't matter if we divide both A and B
 /// by two.
 ///
+/// Templates containing a non-finite value (NaN or +/-inf) are skipped
+/// entirely: they are neither compared nor counted. Since
+/// `(NaN - x).abs() < r` is always false, such a template could never match
+/// anyway, but skipping it up front makes that behavior explicit rather than
+/// an accident of float comparison semantics.
+///
+/// The templates can be built any way, e.g. from a delay embedding, and held
+/// as `Vec<f32>`s or as slices of a series, but must all have the same
+/// length; this panics otherwise.
+///
 /// # Arguments
 ///
 /// * `templates` - an immutable reference to the a vector containing all templates.
-/// * `threshold` - the distance threshold over which a match does not occur.
+/// * `predicate` - the rule deciding whether two templates match.
+/// * `interrupt` - when to give up, checked once per template.
 ///
-fn get_matches(templates: &[Vec<f32>], threshold: &f32) -> usize {
-    let mut matches: u32 = 0;
+pub fn get_matches<T: AsRef<[f32]>, P: MatchPredicate>(
+    templates: &[T],
+    predicate: &P,
+    interrupt: &Interrupt,
+) -> Result<usize, Interrupted> {
+    let finite_templates: Vec<&[f32]> = equal_length_templates(templates)
+        .filter(|template| is_finite_template(template))
+        .collect();
+    let mut matches: usize = 0;
+
+    for i in 0..finite_templates.len() {
+        if interrupt.is_triggered() {
+            return Err(Interrupted);
+        }
+        for j in i + 1..finite_templates.len() {
+            if predicate.matches(finite_templates[i], finite_templates[j]) {
+                matches += 1;
+            }
+        }
+    }
+    Ok(matches)
+}
 
+/// Counts both the length-m and the length-(m+1) matches of templates built
+/// by the caller, as `get_matches` does for one length.
+///
+/// Each template holds m+1 samples: B counts the pairs whose first m samples
+/// match, and A those of them whose whole templates match. So every template
+/// counted needs its extension, and templates from `data.windows(m + 1)`
+/// leave out the last length-m template, which `match_counts` includes in B.
+/// A template whose first m samples aren't finite is skipped, and one whose
+/// last sample isn't finite counts towards B only. The templates must all
+/// have m+1 samples; this panics otherwise.
+///
+/// # Arguments
+///
+/// * `m` - the smaller of the two template sizes.
+/// * `templates` - the templates, each of m+1 samples.
+/// * `predicate` - the rule deciding whether two templates match.
+/// * `interrupt` - when to give up, checked once per template.
+///
+pub fn get_match_counts<T: AsRef<[f32]>, P: MatchPredicate>(
+    m: usize,
+    templates: &[T],
+    predicate: &P,
+    interrupt: &Interrupt,
+) -> Result<MatchCounts, Interrupted> {
+    let templates: Vec<&[f32]> = equal_length_templates(templates)
+        .inspect(|template| assert_eq!(m + 1, template.len(), "templates must have m+1 samples"))
+        .filter(|template| is_finite_template(&template[..m]))
+        .collect();
+    let mut counts = MatchCounts {
+        m_matches: 0,
+        m_plus_1_matches: 0,
+    };
     for i in 0..templates.len() {
+        if interrupt.is_triggered() {
+            return Err(Interrupted);
+        }
         for j in i + 1..templates.len() {
-            if is_match(&templates[i], &templates[j], threshold) {
+            let (a, b) = (templates[i], templates[j]);
+            if !predicate.matches(&a[..m], &b[..m]) {
+                continue;
+            }
+            counts.m_matches += 1;
+            if is_finite_template(&a[m..]) && is_finite_template(&b[m..]) && predicate.matches(a, b)
+            {
+                counts.m_plus_1_matches += 1;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+/// The templates as slices, panicking if they differ in length.
+fn equal_length_templates<T: AsRef<[f32]>>(templates: &[T]) -> impl Iterator<Item = &[f32]> {
+    let len = templates.first().map(|template| template.as_ref().len());
+    templates.iter().map(move |template| {
+        let template = template.as_ref();
+        assert_eq!(
+            len,
+            Some(template.len()),
+            "templates must have equal length"
+        );
+        template
+    })
+}
+
+/// Counts the matching pairs like `get_matches`, but compares the templates
+/// in place in `data` rather than copies of them, recording the starts of
+/// the finite templates in `starts`.
+fn get_matches_in_place<P: MatchPredicate>(
+    window_size: usize,
+    data: &[f32],
+    predicate: &P,
+    starts: &mut Vec<usize>,
+    interrupt: &Interrupt,
+) -> Result<usize, Interrupted> {
+    starts.clear();
+    starts.extend(
+        (0..(data.len() + 1).saturating_sub(window_size))
+            .filter(|start| is_finite_template(&data[*start..*start + window_size])),
+    );
+    let template = |ix: usize| &data[starts[ix]..starts[ix] + window_size];
+    let mut matches: usize = 0;
+    for i in 0..starts.len() {
+        if interrupt.is_triggered() {
+            return Err(Interrupted);
+        }
+        for j in i + 1..starts.len() {
+            if predicate.matches(template(i), template(j)) {
                 matches += 1;
             }
         }
     }
-    matches.try_into().unwrap()
+    Ok(matches)
+}
+
+/// Unwraps the result of a computation that was given an interrupt that
+/// never triggers.
+fn uninterrupted<T>(result: Result<T, Interrupted>) -> T {
+    result.unwrap_or_else(|_| unreachable!("the default interrupt never triggers"))
+}
+
+/// Returns -ln(probability), the entropy of a conditional probability, with
+/// a probability of exactly 1 giving 0.0 rather than the -0.0 of negating
+/// `ln(1.0)`.
+fn entropy_of(probability: f32) -> f32 {
+    let entropy = -probability.ln();
+    if entropy == 0.0 {
+        0.0
+    } else {
+        entropy
+    }
+}
+
+/// Determines if every value in a template is finite.
+pub(crate) fn is_finite_template(template: &[f32]) -> bool {
+    template.iter().all(|x| x.is_finite())
 }
 
 /// Determines if two templates match.
@@ -52,105 +325,3395 @@ fn get_matches(templates: &[Vec<f32>], threshold: &f32) -> usize {
 /// * `vec_2` - another immutable reference to a template vector.
 /// * `r` - the distance threshold over which a match does not occur.
 ///
-fn is_match(vec_1: &[f32], vec_2: &Vec<f32>, r: &f32) -> bool {
+pub(crate) fn is_match(vec_1: &[f32], vec_2: &[f32], r: &f32) -> bool {
     let threshold = *r;
-    return vec_1
+    vec_1
+        .iter()
+        .zip(vec_2)
+        .all(|x: (&f32, &f32)| (x.0 - x.1).abs() < threshold)
+}
+
+/// The chebyshev distance between two templates, the largest elementwise
+/// difference between them, as `is_match` compares against `r`. NaN if
+/// either template holds a NaN.
+///
+/// # Arguments
+///
+/// * `vec_1` - an immutable reference to a template vector.
+/// * `vec_2` - another immutable reference to a template vector.
+///
+pub fn chebyshev_distance(vec_1: &[f32], vec_2: &[f32]) -> f32 {
+    vec_1
+        .iter()
+        .zip(vec_2)
+        .map(|(a, b)| (a - b).abs())
+        .fold(0.0, |distance, difference| {
+            if difference > distance || difference.is_nan() {
+                difference
+            } else {
+                distance
+            }
+        })
+}
+
+/// The number of templates `distance_matrix` includes at most, so that the
+/// matrix takes at most 64 MiB.
+pub const MAX_DISTANCE_MATRIX_TEMPLATES: usize = 4096;
+
+/// Computes the chebyshev distance between every pair of length-m templates
+/// of a series, for looking at how the matches `sample_entropy` counts come
+/// about.
+///
+/// Entry `[i][j]` is the `chebyshev_distance` between the templates starting
+/// at samples i and j, so the matrix is symmetric with a zero diagonal, and
+/// templates i and j match at tolerance r exactly when it is below r. The
+/// matrix holds n² distances for n templates, so only the templates starting
+/// in the first `MAX_DISTANCE_MATRIX_TEMPLATES` samples are included: the
+/// matrix is for short series or the start of a long one, and the counts of
+/// a whole long series are better left to `match_counts`. Templates holding
+/// a NaN have NaN distances.
+///
+/// # Arguments
+/// * `m` - the template size.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn distance_matrix(m: usize, data: &[f32]) -> Vec<Vec<f32>> {
+    let num_templates = (data.len() + 1)
+        .saturating_sub(m)
+        .min(MAX_DISTANCE_MATRIX_TEMPLATES);
+    let template = |ix: usize| &data[ix..ix + m];
+    (0..num_templates)
+        .map(|i| {
+            (0..num_templates)
+                .map(|j| chebyshev_distance(template(i), template(j)))
+                .collect()
+        })
+        .collect()
+}
+
+/// Determines if two templates match like `is_match`, comparing coordinate i
+/// against `r[i]`, or the last threshold past the end of `r`. Stops at the
+/// first coordinate that differs by too much.
+///
+/// # Arguments
+///
+/// * `vec_1` - an immutable reference to a template vector.
+/// * `vec_2` - another immutable reference to a template vector.
+/// * `r` - the distance threshold of each coordinate. Must not be empty.
+///
+pub(crate) fn is_match_per_dimension(vec_1: &[f32], vec_2: &[f32], r: &[f32]) -> bool {
+    let last = r[r.len() - 1];
+    vec_1
         .iter()
         .zip(vec_2)
-        .all(|x: (&f32, &f32)| (x.0 - x.1).abs() < threshold);
+        .zip(r.iter().chain(std::iter::repeat(&last)))
+        .all(|((a, b), threshold)| (a - b).abs() < *threshold)
 }
 
 /// Computes sample entropy for a waveform.
 ///
+/// Templates containing NaN or infinite values are excluded from both the m
+/// and m+1 match counts (see `get_matches`). With m = 2 the counts come from
+/// `sample_entropy_m2`'s specialized path.
+///
+/// `r` must be positive: matches are strictly closer than `r`, so nothing
+/// matches otherwise and the result is NaN. `checked_sample_entropy` reports
+/// that as an error instead, and `exact_sample_entropy` is the way to count
+/// only identical templates as matching.
+///
 /// # Arguments
 /// * `m` - the smaller of the two template sizes.
 /// * `r` - the distance threshold over which a match does not occur.
 /// * `data` - a vector containing the waveform data.
 ///
-pub fn sample_entropy(m: usize, r: f32, data: &Vec<f32>) -> f32 {
-    let templates_size_m: Vec<Vec<f32>> = construct_templates(m, data);
-    let m_plus_one = m + 1;
-    let templates_size_m_plus_1: Vec<Vec<f32>> = construct_templates(m_plus_one, data);
-    let length_m_template_matches: f32 = get_matches(&templates_size_m, &r) as f32;
-    let length_m_plus_1_template_matches: f32 = get_matches(&templates_size_m_plus_1, &r) as f32;
-    let ratio: f32 = length_m_plus_1_template_matches / length_m_template_matches;
-    let sampen: f32 = -(ratio).ln();
-    sampen
+pub fn sample_entropy(m: usize, r: f32, data: &[f32]) -> f32 {
+    sample_entropy_with_buffer(m, r, data, &mut TemplateBuffer::default())
 }
 
-/// Vectorized one liner for computing the mean of a vector.
-pub fn mean(data: &[f32]) -> f32 {
-    data.iter().sum::<f32>() / data.len() as f32
+/// Computes sample entropy for a waveform like `sample_entropy`, but returns
+/// `SampenError::InsufficientDataForMPlus1` instead of a degenerate NaN or
+/// +inf when the series is too short to have two length-(m+1) templates,
+/// i.e. has fewer than m+2 samples, and `SampenError::InvalidTolerance` when
+/// `r` is not a positive number, which can never match.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn checked_sample_entropy(m: usize, r: f32, data: &[f32]) -> Result<f32, SampenError> {
+    if r.is_nan() || r <= 0.0 {
+        return Err(SampenError::InvalidTolerance(r));
+    }
+    if data.len() < m + 2 {
+        return Err(SampenError::InsufficientDataForMPlus1 { m, len: data.len() });
+    }
+    Ok(sample_entropy(m, r, data))
 }
 
-/// Vectorized read-only code that computes standard deviation.
-pub fn standard_deviation(data: &[f32]) -> f32 {
-    let xbar: f32 = mean(data);
-    let squared_err_sum: f32 = data
-        .iter()
-        .fold(0_f32, |acc, x| acc + ((x - xbar).powf(2.0)));
-    (squared_err_sum / (data.len() as f32)).sqrt()
+/// Computes sample entropy for a waveform, finding the matching templates
+/// with the given strategy.
+///
+/// Every strategy gives the same result; `sample_entropy` uses
+/// `MatchStrategy::Auto`.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+/// * `strategy` - the algorithm used to find the matches.
+///
+pub fn sample_entropy_with_strategy(
+    m: usize,
+    r: f32,
+    data: &[f32],
+    strategy: MatchStrategy,
+) -> f32 {
+    uninterrupted(match_counts_with_strategy(
+        m,
+        r,
+        data,
+        strategy,
+        &mut TemplateBuffer::default(),
+        &Interrupt::default(),
+    ))
+    .sample_entropy()
 }
 
-/// Detrends the data via a linear detrending.
+/// Computes sample entropy for a waveform with a threshold for each
+/// coordinate of the templates (see `PerDimensionThreshold`).
 ///
-/// Fits an ordinary least squares regression line to the data, then subtracts
-/// the estimation from the model to detrend the data. This is done at the
-/// suggestion of the 1994 paper by Pincus, S.M.; Goldberger, A.L. titled:
-/// "Physiological time-series analysis: what does regularity quantify?"
+/// `sample_entropy(m, r, data)` equals `sample_entropy_per_dimension(m,
+/// &[r; m], data)`. Panics if `r` doesn't have m or m+1 thresholds.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold of each coordinate, m of them, or m+1 to
+///   set the extension's separately.
+/// * `data` - a vector containing the waveform data.
 ///
-/// In theory there is a nice closed form expression for denominator. It might
-/// be useful to speed the program up, but honestly it is already fairly fast.
+pub fn sample_entropy_per_dimension(m: usize, r: &[f32], data: &[f32]) -> f32 {
+    assert!(
+        r.len() == m || r.len() == m + 1,
+        "r must have m or m+1 thresholds"
+    );
+    sample_entropy_with_predicate(m, &PerDimensionThreshold(r), data)
+}
+
+/// Computes sample entropy for a waveform using a custom rule for deciding
+/// whether two templates match.
+///
+/// `sample_entropy(m, r, data)` is equivalent to
+/// `sample_entropy_with_predicate(m, &ChebyshevThreshold(r), data)`.
 ///
 /// # Arguments
-/// `data` - an immutable vector slice of waveform data.
+/// * `m` - the smaller of the two template sizes.
+/// * `predicate` - the rule deciding whether two templates match.
+/// * `data` - a vector containing the waveform data.
 ///
-pub fn detrend_data(data: &[f32]) -> Vec<f32> {
-    let xbar: f32 = (data.len() + 1) as f32 / 2.0;
-    let ybar: f32 = mean(data);
-    // beta hat is the estimate of the slope parameter.
-    let beta_hat: f32 = {
-        let (numerator, denominator): (f32, f32) =
-            data.iter()
-                .enumerate()
-                .fold((0_f32, 0_f32), |acc, (index, value)| {
-                    let temp = (index + 1) as f32 - xbar;
-                    let num_acc = acc.0 + (temp * (value - ybar));
-                    let den_acc = acc.1 + (temp.powf(2.0));
-                    (num_acc, den_acc)
-                });
-        numerator / denominator
+pub fn sample_entropy_with_predicate<P: MatchPredicate>(
+    m: usize,
+    predicate: &P,
+    data: &[f32],
+) -> f32 {
+    uninterrupted(sample_entropy_generalized_with_buffer(
+        m,
+        1,
+        predicate,
+        data,
+        &mut TemplateBuffer::default(),
+        &Interrupt::default(),
+    ))
+}
+
+/// Computes sample entropy for a waveform, building the templates in the
+/// given buffer rather than allocating new ones.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+/// * `buffer` - scratch space for the templates, reused across calls.
+///
+pub fn sample_entropy_with_buffer(
+    m: usize,
+    r: f32,
+    data: &[f32],
+    buffer: &mut TemplateBuffer,
+) -> f32 {
+    uninterrupted(sample_entropy_interruptible(
+        m,
+        r,
+        data,
+        buffer,
+        &Interrupt::default(),
+    ))
+}
+
+/// Computes sample entropy for a waveform like `sample_entropy_with_buffer`,
+/// but gives up with `Interrupted` as soon as `interrupt` triggers.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+/// * `buffer` - scratch space for the templates, reused across calls.
+/// * `interrupt` - when to abandon the computation.
+///
+pub fn sample_entropy_interruptible(
+    m: usize,
+    r: f32,
+    data: &[f32],
+    buffer: &mut TemplateBuffer,
+    interrupt: &Interrupt,
+) -> Result<f32, Interrupted> {
+    match_counts_interruptible(m, r, data, buffer, interrupt).map(|counts| counts.sample_entropy())
+}
+
+/// The pair counts sample entropy is computed from.
+///
+/// Counts from several windows or segments of the same record can be pooled
+/// by adding them (`+` or `.sum()`), giving the conditional probability of
+/// the whole. Averaging the per-window entropies or probabilities instead
+/// weights a window with few matches as heavily as one with many.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatchCounts {
+    /// The number of matching pairs of length-m templates, usually called B.
+    pub m_matches: usize,
+    /// The number of matching pairs of length-(m+1) templates, usually
+    /// called A.
+    pub m_plus_1_matches: usize,
+}
+
+impl MatchCounts {
+    /// The conditional probability A/B that two templates matching for m
+    /// points also match for m+1.
+    ///
+    /// This is NaN when no length-m templates match.
+    pub fn conditional_probability(&self) -> f32 {
+        self.m_plus_1_matches as f32 / self.m_matches as f32
+    }
+
+    /// Sample entropy, -ln(A/B).
+    ///
+    /// This is +inf when no length-(m+1) templates match and NaN when no
+    /// length-m templates match either. When A = B it is exactly 0.0, never
+    /// -0.0; see `is_perfectly_regular`.
+    pub fn sample_entropy(&self) -> f32 {
+        entropy_of(self.conditional_probability())
+    }
+
+    /// Returns true if some length-m templates match and every one of those
+    /// pairs also matches for m+1, so that the sample entropy is exactly 0.
+    ///
+    /// The counts of a single series never do this: of its matching pairs,
+    /// the one whose later template starts last either involves the last
+    /// template, which has no m+1 extension, or extends to a matching pair
+    /// starting one sample later. So A < B whenever B > 0, and a perfectly
+    /// periodic series gives a small positive ln(B/A) rather than 0. Counts
+    /// assembled another way, e.g. leaving out the last template, can be
+    /// perfectly regular, and then most likely come from a periodic signal
+    /// or an `r` wider than its range (see `match_fraction`).
+    pub fn is_perfectly_regular(&self) -> bool {
+        self.m_matches > 0 && self.m_plus_1_matches == self.m_matches
+    }
+
+    /// Returns true if both A and B are at least `min_matches`.
+    ///
+    /// The standard error of sample entropy is roughly 1/sqrt(A) (Richman,
+    /// J.S.; Moorman, J.R. 2000), so an estimate from 3 matches is little
+    /// better than noise. A minimum of 10 keeps the error near a third of a
+    /// nat at worst; 100 or more is preferable for comparing similar
+    /// records.
+    pub fn is_reliable(&self, min_matches: usize) -> bool {
+        self.m_matches >= min_matches && self.m_plus_1_matches >= min_matches
+    }
+
+    /// The fraction of all pairs of length-m templates of a `len` sample
+    /// series that match, B / (T(T-1)/2) for its T = len - m + 1 templates.
+    ///
+    /// This shows whether `r` suits the data. Fractions of roughly 0.1 to 0.3
+    /// are typical of a well chosen `r`. Near 1, almost everything matches,
+    /// A/B tends to 1 and the entropy to 0 whatever the signal; near 0, too
+    /// few pairs match for a stable estimate (see `is_reliable`). NaN if the
+    /// series has fewer than two templates.
+    pub fn match_fraction(&self, m: usize, len: usize) -> f32 {
+        let num_templates = (len + 1).saturating_sub(m);
+        let num_pairs = num_templates * num_templates.saturating_sub(1) / 2;
+        self.m_matches as f32 / num_pairs as f32
+    }
+
+    /// Sample entropy with each count floored at 1, -ln(max(A,1)/max(B,1)).
+    ///
+    /// This is the convention of Lake, D.E. et al. (2002) "Sample entropy
+    /// analysis of neonatal heart rate variability": when no length-(m+1)
+    /// templates match, substituting a single match gives ln(B), the largest
+    /// finite value the record could have produced, rather than +inf. It is a
+    /// convention for keeping downstream statistics finite, not an estimate,
+    /// and the resulting upper bound grows with the record length.
+    pub fn floored_sample_entropy(&self) -> f32 {
+        let a = self.m_plus_1_matches.max(1) as f32;
+        let b = self.m_matches.max(1) as f32;
+        entropy_of(a / b)
+    }
+
+    /// A `confidence` level interval `(lower, upper)` around
+    /// `sample_entropy`. See `sample_entropy_bounds` for the model.
+    pub fn sample_entropy_interval(&self, confidence: f32) -> (f32, f32) {
+        if !(confidence > 0.0 && confidence < 1.0) || self.m_matches == 0 {
+            return (f32::NAN, f32::NAN);
+        }
+        let z = normal_quantile(0.5 + confidence as f64 / 2.0);
+        let n = self.m_matches as f64;
+        let p = self.m_plus_1_matches as f64 / n;
+        // The Wilson score interval, which unlike p +- z SE stays within
+        // [0, 1] and has a non-zero width at p = 0 or 1.
+        let scale = 1.0 + z * z / n;
+        let center = (p + z * z / (2.0 * n)) / scale;
+        let half_width = z / scale * (p * (1.0 - p) / n + z * z / (4.0 * n * n)).sqrt();
+        let p_low = (center - half_width).max(0.0);
+        let p_high = (center + half_width).min(1.0);
+        (entropy_of(p_high as f32), entropy_of(p_low as f32))
+    }
+}
+
+impl std::ops::Add for MatchCounts {
+    type Output = MatchCounts;
+
+    fn add(self, other: MatchCounts) -> MatchCounts {
+        MatchCounts {
+            m_matches: self.m_matches + other.m_matches,
+            m_plus_1_matches: self.m_plus_1_matches + other.m_plus_1_matches,
+        }
+    }
+}
+
+impl std::iter::Sum for MatchCounts {
+    fn sum<I: Iterator<Item = MatchCounts>>(iter: I) -> MatchCounts {
+        iter.fold(
+            MatchCounts {
+                m_matches: 0,
+                m_plus_1_matches: 0,
+            },
+            |total, counts| total + counts,
+        )
+    }
+}
+
+/// Computes sample entropy for a waveform with a confidence interval,
+/// returning `(lower, point, upper)`.
+///
+/// The model treats each of the B matching length-m pairs as an independent
+/// trial that also matches at m+1 with probability p, so A is binomial and
+/// A/B estimates p. The Wilson score interval for p at the given confidence
+/// level is mapped through -ln, giving bounds that are always ordered and
+/// non-negative; when no length-(m+1) pairs match, the upper bound is +inf
+/// like the point estimate. Pairs sharing templates are not really
+/// independent, so the interval is somewhat too narrow, most of all for
+/// regular signals where matches come in runs; Richman and Moorman (2000)
+/// give an estimator of that extra variance. The width shrinks with
+/// 1/sqrt(A), so longer series give narrower intervals. NaN bounds if no
+/// length-m pairs match or `confidence` is not strictly between 0 and 1.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+/// * `confidence` - the confidence level of the interval, e.g. 0.95.
+///
+pub fn sample_entropy_bounds(m: usize, r: f32, data: &[f32], confidence: f32) -> (f32, f32, f32) {
+    let counts = match_counts(m, r, data);
+    let (lower, upper) = counts.sample_entropy_interval(confidence);
+    (lower, counts.sample_entropy(), upper)
+}
+
+/// The quantile function of the standard normal distribution, from the
+/// rational approximation of Acklam (relative error below 1.2e-9). `p` must
+/// be strictly between 0 and 1.
+fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e1,
+        2.209460984245205e2,
+        -2.759285104469687e2,
+        1.38357751867269e2,
+        -3.066479806614716e1,
+        2.506628277459239,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e1,
+        1.615858368580409e2,
+        -1.556989798598866e2,
+        6.680131188771972e1,
+        -1.328068155288572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-3,
+        -3.223964580411365e-1,
+        -2.400758277161838,
+        -2.549732539343734,
+        4.374664141464968,
+        2.938163982698783,
+    ];
+    const D: [f64; 4] = [
+        7.784695709041462e-3,
+        3.224671290700398e-1,
+        2.445134137142996,
+        3.754408661907416,
+    ];
+    const P_LOW: f64 = 0.02425;
+    let tail = |q: f64| {
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
     };
-    // alpha hat is the estimate of the intercept parameter.
-    let alpha_hat: f32 = ybar - beta_hat * xbar;
+    if p < P_LOW {
+        tail((-2.0 * p.ln()).sqrt())
+    } else if p > 1.0 - P_LOW {
+        -tail((-2.0 * (1.0 - p).ln()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    }
+}
 
-    data.iter()
-        .enumerate()
-        .map(|(ix, val)| val - alpha_hat - (beta_hat * ((ix as f32) + 1.0)))
-        .collect::<Vec<f32>>()
+/// Computes the conditional probability A/B that two templates of a waveform
+/// matching for m points also match for m+1. `sample_entropy` is -ln of it.
+///
+/// To combine several windows of a record, pool their `match_counts` rather
+/// than the per-window probabilities (see `MatchCounts`).
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn conditional_probability(m: usize, r: f32, data: &[f32]) -> f32 {
+    match_counts(m, r, data).conditional_probability()
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Counts the matching pairs of length-m and length-(m+1) templates.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn match_counts(m: usize, r: f32, data: &[f32]) -> MatchCounts {
+    uninterrupted(match_counts_interruptible(
+        m,
+        r,
+        data,
+        &mut TemplateBuffer::default(),
+        &Interrupt::default(),
+    ))
+}
 
-    #[test]
-    fn test_constuct_templates_1() {
-        let expected: Vec<Vec<f32>> = vec![vec![1_f32], vec![2f32], vec![3_f32]];
-        assert_eq!(expected, construct_templates(1, &vec![1_f32, 2_f32, 3_f32]));
+/// Counts matches like `match_counts`, with the templates built in `buffer`,
+/// giving up with `Interrupted` as soon as `interrupt` triggers.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+/// * `buffer` - scratch space for the templates, reused across calls.
+/// * `interrupt` - when to abandon the computation.
+///
+pub fn match_counts_interruptible(
+    m: usize,
+    r: f32,
+    data: &[f32],
+    buffer: &mut TemplateBuffer,
+    interrupt: &Interrupt,
+) -> Result<MatchCounts, Interrupted> {
+    match_counts_with_strategy(m, r, data, MatchStrategy::Auto, buffer, interrupt)
+}
+
+/// Counts matches like `match_counts_interruptible`, finding the matching
+/// templates with the given strategy. `MatchStrategy::Auto` with m = 2 uses
+/// `match_counts_m2`, which beats sorting at every length measured.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+/// * `strategy` - the algorithm used to find the matches.
+/// * `buffer` - scratch space for the templates, reused across calls.
+/// * `interrupt` - when to abandon the computation.
+///
+pub fn match_counts_with_strategy(
+    m: usize,
+    r: f32,
+    data: &[f32],
+    strategy: MatchStrategy,
+    buffer: &mut TemplateBuffer,
+    interrupt: &Interrupt,
+) -> Result<MatchCounts, Interrupted> {
+    if m == 2 && strategy == MatchStrategy::Auto {
+        return match_counts_m2(r, data, interrupt);
     }
+    matching::count_match_pairs(strategy, data, m, r, &mut buffer.match_scratch, interrupt)
+}
 
-    #[test]
-    fn test_constuct_templates_2() {
-        let expected: Vec<Vec<f32>> = vec![
-            vec![1_f32, 2_f32],
-            vec![2f32, 3_f32],
-            vec![3_f32, 4f32],
-            vec![4_f32, 5_f32],
-        ];
-        assert_eq!(
-            expected,
-            construct_templates(2, &vec![1_f32, 2_f32, 3_f32, 4_f32, 5_f32])
-        );
+/// Computes sample entropy counting only identical templates as matches,
+/// the limit of `sample_entropy` as `r` goes to 0. See `exact_match_counts`.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn exact_sample_entropy(m: usize, data: &[f32]) -> f32 {
+    exact_match_counts(m, data).sample_entropy()
+}
+
+/// Counts the pairs of identical length-m and length-(m+1) templates.
+///
+/// For coarsely quantized data, e.g. integer mmHg, every difference is a
+/// multiple of the quantization step, so any `r` at or below the step counts
+/// exactly these pairs; `match_counts` with such an `r` gives the same
+/// counts. Instead of comparing every pair, templates are grouped by value,
+/// which takes linear time, and a group of k identical templates holds
+/// k(k-1)/2 matching pairs. 0.0 and -0.0 are identical; templates containing
+/// NaN or infinite values are left out, as in `get_matches`.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn exact_match_counts(m: usize, data: &[f32]) -> MatchCounts {
+    // Adding 0.0 turns -0.0 into 0.0, so both share a key.
+    let bits: Vec<u32> = data.iter().map(|x| (x + 0.0).to_bits()).collect();
+    identical_pair_counts(m, &bits, |template| {
+        template.iter().all(|x| f32::from_bits(*x).is_finite())
+    })
+}
+
+/// Computes sample entropy of a sequence of symbols, counting templates as
+/// matching when every symbol is equal. See `symbolic_match_counts`.
+///
+/// For symbols, A/B is the probability that two length-(m+1) patterns are
+/// equal given that their first m symbols are: the collision probability of
+/// the next symbol given the m before it. Its negative log is the order 2
+/// Rényi counterpart of the conditional Shannon entropy `H(X_{m+1} | X_1,
+/// ..., X_m)` that symbolic dynamics estimates from pattern frequencies.
+/// Both are 0 for a sequence the past determines, and `ln k` for
+/// independent uniform symbols from an alphabet of k; for independent
+/// symbols of any distribution the sample entropy is at most the Shannon
+/// entropy, as collisions weigh the frequent symbols more. Unlike the
+/// plug-in Shannon estimate it needs no alphabet or pattern probabilities,
+/// only counts of pairs, and like `sample_entropy` it is a small positive
+/// number rather than 0 on a periodic sequence (see
+/// `MatchCounts::is_perfectly_regular`).
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `data` - the symbol sequence.
+///
+pub fn symbolic_sample_entropy(m: usize, data: &[u32]) -> f32 {
+    symbolic_match_counts(m, data).sample_entropy()
+}
+
+/// Counts the pairs of identical length-m and length-(m+1) symbol patterns,
+/// grouping the templates by value as `exact_match_counts` does, in linear
+/// time.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `data` - the symbol sequence.
+///
+pub fn symbolic_match_counts(m: usize, data: &[u32]) -> MatchCounts {
+    identical_pair_counts(m, data, |_| true)
+}
+
+/// Counts the pairs of identical length-m and length-(m+1) templates of
+/// `data` for which `is_valid` holds; a group of k identical templates holds
+/// k(k - 1)/2 pairs.
+fn identical_pair_counts(m: usize, data: &[u32], is_valid: impl Fn(&[u32]) -> bool) -> MatchCounts {
+    let identical_pairs = |length: usize| -> usize {
+        let mut groups: HashMap<&[u32], usize> = HashMap::new();
+        let templates =
+            (0..(data.len() + 1).saturating_sub(length)).map(|start| &data[start..start + length]);
+        for template in templates.filter(|template| is_valid(template)) {
+            *groups.entry(template).or_default() += 1;
+        }
+        groups.values().map(|k| k * (k - 1) / 2).sum()
+    };
+    MatchCounts {
+        m_matches: identical_pairs(m),
+        m_plus_1_matches: identical_pairs(m + 1),
+    }
+}
+
+/// Computes sample entropy over a grid of template sizes and tolerances.
+///
+/// The result has a row per entry of `m_values` and a column per entry of
+/// `r_values`, in the order given: `surface[i][j]` equals
+/// `sample_entropy(m_values[i], r_values[j], data)`. See
+/// `match_counts_surface` for how the grid shares its work.
+///
+/// # Arguments
+/// * `m_values` - the template sizes, one per row.
+/// * `r_values` - the distance thresholds, one per column.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn sample_entropy_surface(m_values: &[usize], r_values: &[f32], data: &[f32]) -> Vec<Vec<f32>> {
+    match_counts_surface(m_values, r_values, data)
+        .iter()
+        .map(|row| row.iter().map(MatchCounts::sample_entropy).collect())
+        .collect()
+}
+
+/// Counts the matching pairs of templates over a grid of template sizes and
+/// tolerances, laid out as in `sample_entropy_surface`.
+///
+/// Every pair of templates is visited once, whatever the size of the grid:
+/// the chebyshev distance of the pair over its first k samples is built up
+/// for k up to `max(m_values) + 1`, so the distances at every m and m+1 come
+/// from one pass, and each distance is located among the sorted `r_values`
+/// by binary search, counting it as a match for all the larger ones at
+/// once. That costs `O(n² (max m + |m_values| log |r_values|))` against
+/// `O(n² |m_values| |r_values|)` for separate brute force counts, but
+/// unlike `match_counts` it can't prune pairs, so for a handful of cells on
+/// a long series separate calls are faster. A NaN `r` matches nothing.
+///
+/// # Arguments
+/// * `m_values` - the template sizes, one per row.
+/// * `r_values` - the distance thresholds, one per column.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn match_counts_surface(
+    m_values: &[usize],
+    r_values: &[f32],
+    data: &[f32],
+) -> Vec<Vec<MatchCounts>> {
+    let mut order: Vec<usize> = (0..r_values.len())
+        .filter(|ix| !r_values[*ix].is_nan())
+        .collect();
+    order.sort_by(|a, b| r_values[*a].total_cmp(&r_values[*b]));
+    let sorted_r: Vec<f32> = order.iter().map(|ix| r_values[*ix]).collect();
+    // The number of thresholds a distance doesn't match, i.e. the first one
+    // it matches in `sorted_r`.
+    let first_match = |distance: f32| sorted_r.partition_point(|r| *r <= distance);
+
+    // `[m, m + 1]` pairs whose first match is at each position of `sorted_r`,
+    // or at its end for a pair matching nothing.
+    let mut first_matches = vec![
+        [
+            vec![0_usize; sorted_r.len() + 1],
+            vec![0; sorted_r.len() + 1]
+        ];
+        m_values.len()
+    ];
+    let max_len = m_values.iter().max().map_or(0, |m| m + 1);
+    // `distances[k]` is the distance of the pair over its first k samples.
+    let mut distances = vec![0_f32; max_len + 1];
+    let n = data.len();
+    for i in 0..n {
+        for j in i + 1..n {
+            let len = max_len.min(n - j);
+            for k in 0..len {
+                let difference = (data[i + k] - data[j + k]).abs();
+                // A non-finite sample matches nothing.
+                let difference = if difference.is_nan() {
+                    f32::INFINITY
+                } else {
+                    difference
+                };
+                distances[k + 1] = distances[k].max(difference);
+            }
+            for (&m, counts) in m_values.iter().zip(first_matches.iter_mut()) {
+                if m <= len {
+                    counts[0][first_match(distances[m])] += 1;
+                }
+                if m < len {
+                    counts[1][first_match(distances[m + 1])] += 1;
+                }
+            }
+        }
+    }
+
+    first_matches
+        .iter()
+        .map(|[m_first, m_plus_1_first]| {
+            // A NaN `r` has no position, and keeps these zero counts.
+            let no_matches = MatchCounts {
+                m_matches: 0,
+                m_plus_1_matches: 0,
+            };
+            let mut row = vec![no_matches; r_values.len()];
+            let (mut m_matches, mut m_plus_1_matches) = (0, 0);
+            for (position, ix) in order.iter().enumerate() {
+                m_matches += m_first[position];
+                m_plus_1_matches += m_plus_1_first[position];
+                row[*ix] = MatchCounts {
+                    m_matches,
+                    m_plus_1_matches,
+                };
+            }
+            row
+        })
+        .collect()
+}
+
+/// Computes sample entropy for m = 2.
+///
+/// Equivalent to `sample_entropy(2, r, data)`, which dispatches here. See
+/// `match_counts_m2` for how the counts are found.
+///
+/// # Arguments
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn sample_entropy_m2(r: f32, data: &[f32]) -> f32 {
+    uninterrupted(match_counts_m2(r, data, &Interrupt::default())).sample_entropy()
+}
+
+/// Counts the length-2 and length-3 template matches without building any
+/// templates.
+///
+/// Templates starting at `i` and `i + d` match for 2 points when samples
+/// `i, i + d` and `i + 1, i + 1 + d` are both within `r`, and for 3 points
+/// when `i + 2, i + 2 + d` are too. So walking each diagonal `d` of the
+/// pairwise comparison once, every sample pair is compared a single time
+/// and the last three comparisons decide both matches. Non-finite samples
+/// never compare as within `r`, so templates containing them never match,
+/// as in `get_matches`. The interrupt is checked once per diagonal.
+fn match_counts_m2(
+    r: f32,
+    data: &[f32],
+    interrupt: &Interrupt,
+) -> Result<MatchCounts, Interrupted> {
+    let n = data.len();
+    let mut counts = MatchCounts {
+        m_matches: 0,
+        m_plus_1_matches: 0,
+    };
+    for d in 1..n.saturating_sub(1) {
+        if interrupt.is_triggered() {
+            return Err(Interrupted);
+        }
+        let (mut before_last, mut last) = (false, false);
+        for (x, y) in data.iter().zip(&data[d..]) {
+            let close = (x - y).abs() < r;
+            counts.m_matches += usize::from(last && close);
+            counts.m_plus_1_matches += usize::from(before_last && last && close);
+            (before_last, last) = (last, close);
+        }
+    }
+    Ok(counts)
+}
+
+/// Computes sample entropy for a signal assembled from separate segments,
+/// e.g. the clean stretches left after cutting out artifacts. See
+/// `segmented_match_counts`.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `segments` - the segments of waveform data, in any order.
+///
+pub fn segmented_sample_entropy(m: usize, r: f32, segments: &[Vec<f32>]) -> f32 {
+    segmented_match_counts(m, r, segments).sample_entropy()
+}
+
+/// Counts the matches of a signal assembled from separate segments, building
+/// templates only within each segment.
+///
+/// Concatenating the segments and calling `match_counts` would also build
+/// templates spanning each joint, out of samples that were never adjacent.
+/// Here no template crosses a joint, and a template ending at the last
+/// sample of its segment has no m+1 extension, exactly as at the end of a
+/// single series. The counts are pooled over every pair of templates,
+/// including pairs from different segments, since they all sample the same
+/// signal; a single segment gives the same counts as `match_counts`. To pool
+/// only pairs within each segment instead, sum the segments' `match_counts`.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `segments` - the segments of waveform data, in any order.
+///
+pub fn segmented_match_counts(m: usize, r: f32, segments: &[Vec<f32>]) -> MatchCounts {
+    // Templates containing a non-finite value never match, so a NaN between
+    // segments keeps templates from spanning them.
+    let mut joined: Vec<f32> =
+        Vec::with_capacity(segments.iter().map(|segment| segment.len() + 1).sum());
+    for (ix, segment) in segments.iter().enumerate() {
+        if ix > 0 {
+            joined.push(f32::NAN);
+        }
+        joined.extend_from_slice(segment);
+    }
+    uninterrupted(matching::count_match_pairs(
+        MatchStrategy::Auto,
+        &joined,
+        m,
+        r,
+        &mut matching::MatchScratch::default(),
+        &Interrupt::default(),
+    ))
+}
+
+/// Returns, for each length-m template, the number of matching pairs it
+/// is part of.
+///
+/// Entry `k` belongs to the template starting at sample `k`, and the entries
+/// sum to twice the m match count B. Most templates of an irregular signal
+/// match a similar few others, so a run of templates matching far more than
+/// their neighbors marks a repeated pattern, such as a recurring artifact,
+/// that is pulling the entropy down. Templates containing NaN or infinite
+/// values match nothing.
+///
+/// # Arguments
+/// * `m` - the template size.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn template_influence(m: usize, r: f32, data: &[f32]) -> Vec<f32> {
+    let mut influence: Vec<f32> = vec![0.0; (data.len() + 1).saturating_sub(m)];
+    uninterrupted(matching::visit_match_pairs(
+        MatchStrategy::Auto,
+        data,
+        m,
+        r,
+        &mut matching::MatchScratch::default(),
+        &Interrupt::default(),
+        &mut |i, j| {
+            influence[i] += 1.0;
+            influence[j] += 1.0;
+        },
+    ));
+    influence
+}
+
+/// Computes approximate entropy for a waveform.
+///
+/// From Pincus, S.M. (1991) "Approximate entropy as a measure of system
+/// complexity". For each length-m template, C_i is the fraction of the
+/// templates it matches, counting itself, and Phi(m) is the mean of ln C_i;
+/// approximate entropy is Phi(m) - Phi(m+1). Counting the self-match keeps
+/// every logarithm finite but biases the result towards regularity,
+/// especially on short series, which is what sample entropy was designed to
+/// avoid. Templates containing NaN or infinite values are left out, as in
+/// `sample_entropy`. NaN if there are no length-(m+1) templates.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn approximate_entropy(m: usize, r: f32, data: &[f32]) -> f32 {
+    uninterrupted(template_matches(m, r, data, &Interrupt::default())).approximate_entropy(m, data)
+}
+
+/// The matches of every template of a series, from which both sample entropy
+/// and approximate entropy follow.
+pub(crate) struct TemplateMatches {
+    /// The pair counts of sample entropy.
+    pub(crate) counts: MatchCounts,
+    /// Entry `k` is how many other length-m templates the one starting at
+    /// sample `k` matches.
+    m_matches: Vec<usize>,
+    /// As `m_matches`, for the length-(m+1) templates.
+    m_plus_1_matches: Vec<usize>,
+}
+
+impl TemplateMatches {
+    /// Approximate entropy, Phi(m) - Phi(m+1). See `approximate_entropy`.
+    pub(crate) fn approximate_entropy(&self, m: usize, data: &[f32]) -> f32 {
+        let phi = |length: usize, matches: &[usize]| -> f64 {
+            let finite: Vec<usize> = (0..(data.len() + 1).saturating_sub(length))
+                .filter(|k| is_finite_template(&data[*k..*k + length]))
+                .collect();
+            let num_templates = finite.len() as f64;
+            finite
+                .iter()
+                .map(|k| ((matches[*k] + 1) as f64 / num_templates).ln())
+                .sum::<f64>()
+                / num_templates
+        };
+        (phi(m, &self.m_matches) - phi(m + 1, &self.m_plus_1_matches)) as f32
+    }
+}
+
+/// Counts the matches of every length-m and length-(m+1) template, along
+/// with their totals.
+///
+/// Two templates can only match for m+1 points if they match for the first
+/// m, so a single pass over the matching length-m pairs finds both.
+pub(crate) fn template_matches(
+    m: usize,
+    r: f32,
+    data: &[f32],
+    interrupt: &Interrupt,
+) -> Result<TemplateMatches, Interrupted> {
+    let num_templates = (data.len() + 1).saturating_sub(m);
+    let mut matches = TemplateMatches {
+        counts: MatchCounts {
+            m_matches: 0,
+            m_plus_1_matches: 0,
+        },
+        m_matches: vec![0; num_templates],
+        m_plus_1_matches: vec![0; num_templates.saturating_sub(1)],
+    };
+    matching::visit_match_pairs(
+        MatchStrategy::Auto,
+        data,
+        m,
+        r,
+        &mut matching::MatchScratch::default(),
+        interrupt,
+        &mut |i, j| {
+            matches.counts.m_matches += 1;
+            matches.m_matches[i] += 1;
+            matches.m_matches[j] += 1;
+            if let (Some(a), Some(b)) = (data.get(i + m), data.get(j + m)) {
+                if a.is_finite() && b.is_finite() && is_match(&[*a], &[*b], &r) {
+                    matches.counts.m_plus_1_matches += 1;
+                    matches.m_plus_1_matches[i] += 1;
+                    matches.m_plus_1_matches[j] += 1;
+                }
+            }
+        },
+    )?;
+    Ok(matches)
+}
+
+/// Computes a generalized sample entropy comparing templates of size m and
+/// m+k rather than m and m+1.
+///
+/// The result is -ln(count_{m+k}/count_m)/k. Dividing by k turns it into a
+/// per-step conditional entropy: it is the average information gained from
+/// each of the k extra samples, given the preceding m, so values for
+/// different k are on the same scale as ordinary sample entropy. With k=1
+/// this is exactly `sample_entropy`.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `k` - how many samples longer the larger template is, at least 1.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+///
+pub fn sample_entropy_generalized(m: usize, k: usize, r: f32, data: &[f32]) -> f32 {
+    let predicate = ChebyshevThreshold(r);
+    uninterrupted(sample_entropy_generalized_with_buffer(
+        m,
+        k,
+        &predicate,
+        data,
+        &mut TemplateBuffer::default(),
+        &Interrupt::default(),
+    ))
+}
+
+/// Computes a generalized sample entropy like `sample_entropy_generalized`,
+/// with a custom match rule like `sample_entropy_with_predicate`, building
+/// the templates in `buffer` within its memory cap and giving up with
+/// `Interrupted` as soon as `interrupt` triggers.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `k` - how many samples longer the larger template is, at least 1.
+/// * `predicate` - the rule deciding whether two templates match.
+/// * `data` - a vector containing the waveform data.
+/// * `buffer` - scratch space for the templates, reused across calls.
+/// * `interrupt` - when to abandon the computation.
+///
+pub fn sample_entropy_generalized_with_buffer<P: MatchPredicate>(
+    m: usize,
+    k: usize,
+    predicate: &P,
+    data: &[f32],
+    buffer: &mut TemplateBuffer,
+    interrupt: &Interrupt,
+) -> Result<f32, Interrupted> {
+    let (m_matches, m_plus_k_matches) =
+        count_matches_generalized(m, k, predicate, data, buffer, interrupt)?;
+    let length_m_template_matches: f32 = m_matches as f32;
+    let length_m_plus_k_template_matches: f32 = m_plus_k_matches as f32;
+    let ratio: f32 = length_m_plus_k_template_matches / length_m_template_matches;
+    let sampen: f32 = entropy_of(ratio) / k as f32;
+    Ok(sampen)
+}
+
+/// Counts the matching pairs of length-m and length-(m+k) templates.
+fn count_matches_generalized<P: MatchPredicate>(
+    m: usize,
+    k: usize,
+    predicate: &P,
+    data: &[f32],
+    buffer: &mut TemplateBuffer,
+    interrupt: &Interrupt,
+) -> Result<(usize, usize), Interrupted> {
+    let m_plus_k = m + k;
+    if template_memory(m, data.len()) + template_memory(m_plus_k, data.len()) > buffer.memory_cap {
+        buffer.size_m = Vec::new();
+        buffer.size_m_plus_k = Vec::new();
+        let starts = &mut buffer.match_scratch.starts;
+        let m_matches = get_matches_in_place(m, data, predicate, starts, interrupt)?;
+        let m_plus_k_matches = get_matches_in_place(m_plus_k, data, predicate, starts, interrupt)?;
+        return Ok((m_matches, m_plus_k_matches));
+    }
+    construct_templates_into(m, data, &mut buffer.size_m);
+    construct_templates_into(m_plus_k, data, &mut buffer.size_m_plus_k);
+    let m_matches = get_matches(&buffer.size_m, predicate, interrupt)?;
+    let m_plus_k_matches = get_matches(&buffer.size_m_plus_k, predicate, interrupt)?;
+    Ok((m_matches, m_plus_k_matches))
+}
+
+/// Computes sample entropy separately for each consecutive, non-overlapping
+/// epoch of a fixed duration.
+///
+/// The epoch length in samples is `sample_rate * epoch_seconds`, rounded to
+/// the nearest sample. A trailing partial epoch shorter than that is dropped,
+/// so every value is computed from the same amount of data. The same `r` is
+/// used for every epoch.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+/// * `sample_rate` - the sampling rate of `data`, in Hz.
+/// * `epoch_seconds` - the duration of each epoch, in seconds.
+///
+pub fn epoch_sample_entropy(
+    m: usize,
+    r: f32,
+    data: &[f32],
+    sample_rate: f32,
+    epoch_seconds: f32,
+) -> Vec<f32> {
+    let epoch_len = (sample_rate * epoch_seconds).round() as usize;
+    if epoch_len == 0 {
+        return vec![];
+    }
+    let mut buffer = TemplateBuffer::default();
+    data.chunks_exact(epoch_len)
+        .map(|epoch| sample_entropy_with_buffer(m, r, epoch, &mut buffer))
+        .collect()
+}
+
+/// Computes sample entropy on overlapping windows of the data, each detrended
+/// and given its own `r` as the pipeline does for a whole wave.
+///
+/// Windows start every `step` samples and hold `window` samples, so there are
+/// `(data.len() - window) / step + 1` of them (none if the data is shorter
+/// than a window). The detrending fits are updated as the window slides (see
+/// `RollingDetrend`) instead of refit from scratch for every window.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `tolerance` - how each window's `r` is chosen.
+/// * `data` - a vector containing the waveform data.
+/// * `window` - the number of samples in each window.
+/// * `step` - the number of samples between the starts of consecutive
+///   windows. Must be positive.
+///
+pub fn rolling_sample_entropy(
+    m: usize,
+    tolerance: Tolerance,
+    data: &[f32],
+    window: usize,
+    step: usize,
+) -> Vec<f32> {
+    assert!(step > 0, "step must be positive");
+    if window == 0 || data.len() < window {
+        return vec![];
+    }
+    let mut detrend = RollingDetrend::new(data, window);
+    let mut detrended: Vec<f32> = Vec::with_capacity(window);
+    let mut buffer = TemplateBuffer::default();
+    let mut entropies: Vec<f32> = Vec::with_capacity((data.len() - window) / step + 1);
+    loop {
+        detrend.detrend_into(&mut detrended);
+        let r = tolerance.resolve(&detrended);
+        entropies.push(sample_entropy_with_buffer(m, r, &detrended, &mut buffer));
+        if !(0..step).all(|_| detrend.advance()) {
+            return entropies;
+        }
+    }
+}
+
+/// Returns the number of pairs (one template from each set) that match.
+///
+/// Unlike `get_matches`, every ordered pair is counted, since a template from
+/// one series is never compared with itself. Templates containing non-finite
+/// values are skipped as in `get_matches`.
+fn get_cross_matches<P: MatchPredicate>(
+    templates_u: &[Vec<f32>],
+    templates_v: &[Vec<f32>],
+    predicate: &P,
+) -> usize {
+    let finite_v: Vec<&Vec<f32>> = templates_v
+        .iter()
+        .filter(|template| is_finite_template(template))
+        .collect();
+    templates_u
+        .iter()
+        .filter(|template| is_finite_template(template))
+        .map(|u| finite_v.iter().filter(|v| predicate.matches(u, v)).count())
+        .sum()
+}
+
+/// Computes the cross-sample entropy of two waveforms.
+///
+/// From Richman, J.S.; Moorman, J.R. (2000) "Physiological time-series
+/// analysis using approximate entropy and sample entropy": the conditional
+/// probability that templates of `u` and `v` that match for m points also
+/// match for m+1 is estimated by comparing every template of `u` against
+/// every template of `v`. Low values indicate tightly coupled series.
+///
+/// With the same `r` for both and series of equal length, the pair counts are
+/// symmetric, so swapping `u` and `v` gives the same value. In practice it is
+/// usually not symmetric because each series is normalized, or has `r`
+/// derived from its own standard deviation, separately.
+///
+/// A series shorter than m+1 samples has no templates to compare, and the
+/// result is NaN.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `u` - a vector containing the first waveform.
+/// * `v` - a vector containing the second waveform.
+///
+pub fn cross_sample_entropy(m: usize, r: f32, u: &[f32], v: &[f32]) -> f32 {
+    if u.len().min(v.len()) < m + 1 {
+        return f32::NAN;
+    }
+    let predicate = ChebyshevThreshold(r);
+    let length_m_template_matches: f32 = get_cross_matches(
+        &construct_templates(m, u),
+        &construct_templates(m, v),
+        &predicate,
+    ) as f32;
+    let length_m_plus_1_template_matches: f32 = get_cross_matches(
+        &construct_templates(m + 1, u),
+        &construct_templates(m + 1, v),
+        &predicate,
+    ) as f32;
+    entropy_of(length_m_plus_1_template_matches / length_m_template_matches)
+}
+
+/// Estimates sample entropy from a uniform random sample of at most
+/// `reservoir_size` templates, drawn in a single pass over the data.
+///
+/// The start positions of the N-m templates of length m+1 are fed through a
+/// reservoir (Vitter's algorithm R), so memory is bounded by `reservoir_size`
+/// and time by O(N + `reservoir_size`^2 * m), however long the record. The
+/// length-m and length-(m+1) matches are then counted among the sampled pairs
+/// only. Each sampled count is an unbiased estimate of the full count scaled
+/// by the fraction of pairs sampled; that fraction is the same for A and B,
+/// so it cancels in the ratio and no explicit rescaling is needed.
+///
+/// Bias: the ratio of the two estimated counts is consistent but not
+/// unbiased, and taking -ln of it adds a further upward bias (Jensen's
+/// inequality) that grows as the number of sampled m+1 matches shrinks. With
+/// too small a reservoir there may be no m+1 matches at all and the estimate
+/// is +inf. Like the original definition of Richman and Moorman, the length-m
+/// templates are only taken at the N-m positions where a length-(m+1)
+/// template also starts, so even a reservoir holding every template differs
+/// slightly from `sample_entropy`. The same seed always gives the same
+/// estimate.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `data` - a vector containing the waveform data.
+/// * `reservoir_size` - the largest number of templates to sample.
+/// * `seed` - the seed for the random number generator.
+///
+pub fn sample_entropy_reservoir(
+    m: usize,
+    r: f32,
+    data: &[f32],
+    reservoir_size: usize,
+    seed: u64,
+) -> f32 {
+    let mut rng = SplitMix64::new(seed);
+    let mut reservoir: Vec<usize> = Vec::with_capacity(reservoir_size);
+    for start in 0..data.len().saturating_sub(m) {
+        if reservoir.len() < reservoir_size {
+            reservoir.push(start);
+        } else {
+            let slot = rng.next_below(start + 1);
+            if slot < reservoir_size {
+                reservoir[slot] = start;
+            }
+        }
+    }
+    let templates: Vec<&[f32]> = reservoir
+        .iter()
+        .map(|start| &data[*start..*start + m + 1])
+        .collect();
+    let mut length_m_template_matches: usize = 0;
+    let mut length_m_plus_1_template_matches: usize = 0;
+    for i in 0..templates.len() {
+        for j in i + 1..templates.len() {
+            let (a, b) = (templates[i], templates[j]);
+            if !is_finite_template(&a[..m])
+                || !is_finite_template(&b[..m])
+                || !is_match(&a[..m], &b[..m], &r)
+            {
+                continue;
+            }
+            length_m_template_matches += 1;
+            if is_finite_template(&a[m..])
+                && is_finite_template(&b[m..])
+                && is_match(&a[m..], &b[m..], &r)
+            {
+                length_m_plus_1_template_matches += 1;
+            }
+        }
+    }
+    entropy_of(length_m_plus_1_template_matches as f32 / length_m_template_matches as f32)
+}
+
+/// The match counts of a series that grows, updated as samples are appended
+/// rather than recounted from scratch.
+///
+/// The series and its counts are kept, and `update` counts only the pairs a
+/// new template forms, with every template before it, old or new. Appending
+/// k samples to a series of n therefore costs O(k (n + k)) comparisons,
+/// against O((n + k)²) for `match_counts` on the whole, but no pruning: for
+/// a few large batches, recounting with `match_counts` can be faster. The
+/// counts are exactly those of `match_counts` on everything appended so far,
+/// with non-finite templates left out as there, whatever the chunks. `r` is
+/// fixed when the accumulator is built, so a tolerance relative to the data
+/// is that of whatever data it was resolved from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncrementalSampleEntropy {
+    m: usize,
+    r: f32,
+    data: Vec<f32>,
+    counts: MatchCounts,
+}
+
+impl IncrementalSampleEntropy {
+    /// An accumulator with no samples yet, counting templates of m and m+1
+    /// samples as matching within `r`.
+    pub fn new(m: usize, r: f32) -> Self {
+        IncrementalSampleEntropy {
+            m,
+            r,
+            data: Vec::new(),
+            counts: MatchCounts {
+                m_matches: 0,
+                m_plus_1_matches: 0,
+            },
+        }
+    }
+
+    /// Appends samples to the series, adding the matches of the templates
+    /// they complete.
+    pub fn update(&mut self, new_samples: &[f32]) {
+        let old_len = self.data.len();
+        self.data.extend_from_slice(new_samples);
+        let new_matches = |length: usize| -> usize {
+            let template = |start: usize| &self.data[start..start + length];
+            let first_new = (old_len + 1).saturating_sub(length);
+            let num_templates = (self.data.len() + 1).saturating_sub(length);
+            (first_new..num_templates)
+                .filter(|later| is_finite_template(template(*later)))
+                .map(|later| {
+                    // A non-finite earlier template never matches.
+                    (0..later)
+                        .filter(|earlier| is_match(template(*earlier), template(later), &self.r))
+                        .count()
+                })
+                .sum()
+        };
+        self.counts.m_matches += new_matches(self.m);
+        self.counts.m_plus_1_matches += new_matches(self.m + 1);
+    }
+
+    /// The samples appended so far.
+    pub fn data(&self) -> &[f32] {
+        &self.data
+    }
+
+    /// The match counts of the samples appended so far.
+    pub fn counts(&self) -> MatchCounts {
+        self.counts
+    }
+
+    /// The sample entropy of the samples appended so far, as
+    /// `MatchCounts::sample_entropy`.
+    pub fn sample_entropy(&self) -> f32 {
+        self.counts.sample_entropy()
+    }
+}
+
+/// Single-pass mean and variance accumulator using Welford's algorithm.
+///
+/// Unlike `standard_deviation`, which makes one pass for the mean and another
+/// for the squared errors, this updates the mean and the sum of squared
+/// errors as each sample arrives, so it can follow a stream without storing
+/// it. The accumulators are kept in f64: with millions of samples of a large
+/// baseline (e.g. pressures around 100 mmHg) each update is far smaller than
+/// the f32 precision of the running mean. Variance is the population
+/// variance, matching `standard_deviation`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunningStats {
+    count: u64,
+    mean: f64,
+    squared_error_sum: f64,
+}
+
+impl RunningStats {
+    /// Adds a sample.
+    pub fn push(&mut self, x: f32) {
+        let x = x as f64;
+        self.count += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f64;
+        self.squared_error_sum += delta * (x - self.mean);
+    }
+
+    /// The number of samples pushed so far.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The mean of the samples so far, NaN if there are none.
+    pub fn mean(&self) -> f32 {
+        if self.count == 0 {
+            f32::NAN
+        } else {
+            self.mean as f32
+        }
+    }
+
+    /// The population variance of the samples so far, NaN if there are none.
+    pub fn variance(&self) -> f32 {
+        (self.squared_error_sum / self.count as f64) as f32
+    }
+
+    /// The population standard deviation of the samples so far.
+    pub fn std(&self) -> f32 {
+        self.variance().sqrt()
+    }
+}
+
+/// Estimates the quantization step of a signal, if it has one.
+///
+/// Many monitors report pressures rounded to whole mmHg, so every pair of
+/// samples either coincides or differs by at least one step. The step is
+/// estimated as the smallest nonzero difference between distinct values, and
+/// is only returned if every other gap between distinct values is (within
+/// float tolerance) a whole multiple of it. Continuous data fails that check
+/// and returns None, as does data with fewer than two distinct finite values.
+///
+/// If the tolerance `r` is smaller than the step, template matching reduces
+/// to exact equality and sample entropy stops measuring anything meaningful.
+///
+/// # Arguments
+/// `data` - an immutable vector slice of waveform data.
+///
+pub fn detect_quantization(data: &[f32]) -> Option<f32> {
+    let mut values: Vec<f32> = util::sorted_finite(data);
+    values.dedup();
+    let gaps: Vec<f32> = values.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    let step: f32 = gaps.iter().copied().reduce(f32::min)?;
+    let is_multiple = |gap: &f32| {
+        let steps = gap / step;
+        (steps - steps.round()).abs() < 1e-2
+    };
+    if gaps.iter().all(is_multiple) {
+        Some(step)
+    } else {
+        None
+    }
+}
+
+/// Vectorized one liner for computing the mean of a vector.
+///
+/// The mean of no data is undefined, so an empty slice returns NaN.
+pub fn mean(data: &[f32]) -> f32 {
+    if data.is_empty() {
+        return f32::NAN;
+    }
+    data.iter().sum::<f32>() / data.len() as f32
+}
+
+/// Vectorized read-only code that computes standard deviation.
+///
+/// Like `mean`, an empty slice returns NaN.
+pub fn standard_deviation(data: &[f32]) -> f32 {
+    if data.is_empty() {
+        return f32::NAN;
+    }
+    let xbar: f32 = mean(data);
+    let squared_err_sum: f32 = data
+        .iter()
+        .fold(0_f32, |acc, x| acc + ((x - xbar).powf(2.0)));
+    (squared_err_sum / (data.len() as f32)).sqrt()
+}
+
+/// Computes the AC amplitude of a signal: the range of its finite values
+/// after the mean is removed, computed in f64 so that a large DC offset
+/// doesn't round away the variation around it. NaN if there are no finite
+/// values.
+///
+/// # Arguments
+/// `data` - an immutable vector slice of waveform data.
+///
+pub fn ac_amplitude(data: &[f32]) -> f32 {
+    let finite: Vec<f32> = data.iter().copied().filter(|x| x.is_finite()).collect();
+    let offset = mean(&finite) as f64;
+    let (low, high) = finite
+        .iter()
+        .map(|x| *x as f64 - offset)
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(low, high), x| {
+            (low.min(x), high.max(x))
+        });
+    if finite.is_empty() {
+        return f32::NAN;
+    }
+    (high - low) as f32
+}
+
+/// With the `spectral` feature, `autocorrelation` uses the FFT from this
+/// many lags, below which the direct sums are faster.
+#[cfg(feature = "spectral")]
+const FFT_AUTOCORRELATION_MIN_LAG: usize = 64;
+
+/// Computes the autocorrelation of a series at lags 0 through `max_lag`.
+///
+/// The series is centered on its `mean`, and the sum of the products of
+/// samples `lag` apart is divided by the sum of squares, so lag 0 is 1 and
+/// every lag is divided by the same full-length normalization rather than by
+/// its own number of pairs. That is the biased estimator: it shrinks longer
+/// lags towards 0 by a factor of `1 - lag / n`, but unlike the unbiased one
+/// it never exceeds 1 in magnitude, and of two lags with equally strong
+/// correlation the shorter one has the higher peak. Non-finite samples count
+/// as the mean, i.e. add nothing to the sums. Lags past the end of the
+/// series are left out, and a series with no variance is NaN at every lag.
+/// The sums are accumulated in f64.
+///
+/// With the `spectral` feature and a `max_lag` of 64 or more, the sums come
+/// from the FFT of the zero-padded series, in `O(n log n)` rather than
+/// `O(n max_lag)`; they agree with the direct sums up to rounding.
+///
+/// # Arguments
+/// * `data` - a vector containing the waveform data.
+/// * `max_lag` - the largest lag computed.
+///
+pub fn autocorrelation(data: &[f32], max_lag: usize) -> Vec<f32> {
+    let finite: Vec<f32> = data.iter().copied().filter(|x| x.is_finite()).collect();
+    let xbar = mean(&finite) as f64;
+    let centered: Vec<f64> = data
+        .iter()
+        .map(|x| if x.is_finite() { *x as f64 - xbar } else { 0.0 })
+        .collect();
+    let max_lag = max_lag.min(data.len().saturating_sub(1));
+    if data.is_empty() {
+        return Vec::new();
+    }
+    #[cfg(feature = "spectral")]
+    let covariances = if max_lag >= FFT_AUTOCORRELATION_MIN_LAG {
+        fft_lagged_products(&centered, max_lag)
+    } else {
+        lagged_products(&centered, max_lag)
+    };
+    #[cfg(not(feature = "spectral"))]
+    let covariances = lagged_products(&centered, max_lag);
+    covariances
+        .iter()
+        .map(|covariance| (covariance / covariances[0]) as f32)
+        .collect()
+}
+
+/// The sums of the products of samples `lag` apart, for lags 0 through
+/// `max_lag`.
+fn lagged_products(centered: &[f64], max_lag: usize) -> Vec<f64> {
+    (0..=max_lag)
+        .map(|lag| {
+            centered
+                .iter()
+                .zip(&centered[lag..])
+                .map(|(a, b)| a * b)
+                .sum()
+        })
+        .collect()
+}
+
+/// The sums of `lagged_products`, from the inverse FFT of the power
+/// spectrum. Padding to at least `n + max_lag` keeps the circular
+/// correlation from wrapping around.
+#[cfg(feature = "spectral")]
+fn fft_lagged_products(centered: &[f64], max_lag: usize) -> Vec<f64> {
+    use rustfft::num_complex::Complex;
+    use rustfft::FftPlanner;
+
+    let n = (centered.len() + max_lag).next_power_of_two();
+    let mut planner = FftPlanner::<f64>::new();
+    let mut spectrum: Vec<Complex<f64>> = centered
+        .iter()
+        .map(|x| Complex::new(*x, 0.0))
+        .chain(std::iter::repeat(Complex::new(0.0, 0.0)))
+        .take(n)
+        .collect();
+    planner.plan_fft_forward(n).process(&mut spectrum);
+    for value in spectrum.iter_mut() {
+        *value = Complex::new(value.norm_sqr(), 0.0);
+    }
+    planner.plan_fft_inverse(n).process(&mut spectrum);
+    spectrum[..=max_lag]
+        .iter()
+        .map(|x| x.re / n as f64)
+        .collect()
+}
+
+/// Returns the first lag at which an autocorrelation (see `autocorrelation`)
+/// reaches zero or goes negative, or None if it stays positive.
+///
+/// A common choice of embedding delay: samples that far apart are, to first
+/// order, uncorrelated.
+///
+/// # Arguments
+/// * `acf` - the autocorrelation at lags 0, 1, and so on.
+///
+pub fn first_zero_crossing(acf: &[f32]) -> Option<usize> {
+    acf.iter().position(|rho| *rho <= 0.0)
+}
+
+/// Returns the lag of the first peak of an autocorrelation (see
+/// `autocorrelation`) after its first zero crossing, or None if it has none.
+///
+/// A peak is a lag whose autocorrelation is above the one before it and at
+/// least the one after it. Looking only past the zero crossing skips the
+/// ripples that noise puts on the initial decay, so for a periodic series
+/// this is its period, rounded to a whole lag. The lags at the ends can't be
+/// peaks.
+///
+/// # Arguments
+/// * `acf` - the autocorrelation at lags 0, 1, and so on.
+///
+pub fn first_peak(acf: &[f32]) -> Option<usize> {
+    let start = first_zero_crossing(acf)?.max(1);
+    (start..acf.len().saturating_sub(1))
+        .find(|lag| acf[*lag] > acf[lag - 1] && acf[*lag] >= acf[lag + 1])
+}
+
+/// Scales the median absolute deviation of normally distributed data to its
+/// standard deviation.
+const MAD_TO_STD: f32 = 1.4826;
+
+/// Computes the median absolute deviation from the median, scaled by 1.4826
+/// so that it estimates the standard deviation of normally distributed data.
+///
+/// Clinical waveforms often carry artifacts such as flushes, line zeroing or
+/// motion spikes. A handful of those can inflate the standard deviation, and
+/// so an `r` derived from it, many times over, while the median absolute
+/// deviation ignores them as long as they are fewer than half the samples.
+/// Returns NaN if the data is empty or contains NaN.
+pub fn median_absolute_deviation(data: &[f32]) -> f32 {
+    let sorted = match util::sorted_without_nan(data) {
+        Some(sorted) if !sorted.is_empty() => sorted,
+        _ => return f32::NAN,
+    };
+    let center = median_of_sorted(&sorted);
+    let mut deviations: Vec<f32> = sorted.iter().map(|x| (x - center).abs()).collect();
+    deviations.sort_by(f32::total_cmp);
+    MAD_TO_STD * median_of_sorted(&deviations)
+}
+
+/// The number of samples above which `Tolerance::MadFraction` estimates the
+/// median absolute deviation with `approximate_median_absolute_deviation`,
+/// and `Tolerance::IqrFraction` the interquartile range with
+/// `approximate_interquartile_range`, rather than sorting a copy of the
+/// series; 16M samples, a 64 MB copy.
+pub const MAD_SKETCH_THRESHOLD: usize = 1 << 24;
+
+/// Estimates `median_absolute_deviation` in bounded memory, from two passes
+/// over the data with a `PercentileSketch`: one for the median, one for the
+/// deviations from it.
+///
+/// Each median is off by at most about half a percent of the samples in
+/// rank (see `PercentileSketch`), so for smooth distributions the estimate is
+/// within about 1% of the exact value, which moves `r` far less than the
+/// choice of fraction does. Infinite samples are skipped rather than counted
+/// at the ends. Returns NaN if the data is empty or contains NaN.
+pub fn approximate_median_absolute_deviation(data: &[f32]) -> f32 {
+    if data.is_empty() || data.iter().any(|x| x.is_nan()) {
+        return f32::NAN;
+    }
+    let mut values = PercentileSketch::default();
+    values.extend(data);
+    let center = values.quantile(0.5);
+    let mut deviations = PercentileSketch::default();
+    for x in data {
+        deviations.insert((x - center).abs());
+    }
+    MAD_TO_STD * deviations.quantile(0.5)
+}
+
+/// Computes the interquartile range, the distance between the first and
+/// third quartiles, each interpolated linearly between ranks as the median
+/// is.
+///
+/// Unlike the median absolute deviation, which measures the spread
+/// symmetrically about the median, the quartiles are placed independently,
+/// so a skewed distribution's long side widens the range only as far as its
+/// quarter of the samples reaches. It ignores artifacts as long as they are
+/// fewer than a quarter of the samples on either side, half the share the
+/// median absolute deviation tolerates. For normally distributed data it is
+/// about 1.349 standard deviations. Returns NaN if the data is empty or
+/// contains NaN.
+pub fn interquartile_range(data: &[f32]) -> f32 {
+    match util::sorted_without_nan(data) {
+        Some(sorted) if !sorted.is_empty() => {
+            quantile_of_sorted(&sorted, 0.75) - quantile_of_sorted(&sorted, 0.25)
+        }
+        _ => f32::NAN,
+    }
+}
+
+/// Estimates `interquartile_range` in bounded memory, from one pass over the
+/// data with a `PercentileSketch`, as `approximate_median_absolute_deviation`
+/// does the median absolute deviation. Each quartile is off by at most about
+/// half a percent of the samples in rank, less near the tails. Infinite
+/// samples are skipped. Returns NaN if the data is empty or contains NaN.
+pub fn approximate_interquartile_range(data: &[f32]) -> f32 {
+    if data.is_empty() || data.iter().any(|x| x.is_nan()) {
+        return f32::NAN;
+    }
+    let mut values = PercentileSketch::default();
+    values.extend(data);
+    values.quantile(0.75) - values.quantile(0.25)
+}
+
+/// Computes the median of the data, NaN if it is empty or contains NaN.
+pub fn median(data: &[f32]) -> f32 {
+    match util::sorted_without_nan(data) {
+        Some(sorted) if !sorted.is_empty() => median_of_sorted(&sorted),
+        _ => f32::NAN,
+    }
+}
+
+/// The q-th quantile of non-empty sorted data, interpolated linearly between
+/// the samples around rank `q (len - 1)`, so that q = 0.5 is the median.
+fn quantile_of_sorted(sorted: &[f32], q: f32) -> f32 {
+    let position = q * (sorted.len() - 1) as f32;
+    let below = position.floor() as usize;
+    let above = (below + 1).min(sorted.len() - 1);
+    let weight = position - below as f32;
+    sorted[below] + (sorted[above] - sorted[below]) * weight
+}
+
+/// The median of non-empty sorted data.
+fn median_of_sorted(sorted: &[f32]) -> f32 {
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+/// Fits a polynomial of the given degree to the points by ordinary least
+/// squares, returning its coefficients from the constant term up, so that
+/// `y ≈ c[0] + c[1] x + ... + c[degree] x^degree`.
+///
+/// The normal equations are solved in f64, in x shifted to its midpoint and
+/// scaled to [-1, 1] and y centered on its mean, which keeps them well
+/// conditioned at low degree whatever the scale of x. Even so, the
+/// conditioning of the powers grows about sixfold per degree: past degree 10
+/// or so the fit loses digits, and it is refused, with every coefficient NaN,
+/// once the system can't be told apart from a singular one. The coefficients
+/// are returned in powers of x itself, which at high degree, or for x far
+/// from 0 next to its spread (e.g. sample numbers in the millions), are large
+/// and cancel when evaluated, so that f32 loses more of them still. Center x
+/// first in that case.
+///
+/// Every coefficient is NaN if x has fewer than `degree + 1` distinct
+/// values, or if a point isn't finite.
+///
+/// # Arguments
+/// `x` - the independent variable.
+/// `y` - the dependent variable, the same length as `x`.
+/// `degree` - the degree of the polynomial.
+///
+pub fn ols_polyfit(x: &[f32], y: &[f32], degree: usize) -> Vec<f32> {
+    let mut coefficients = vec![0.0; degree + 1];
+    let mut scratch = vec![0.0; polyfit_scratch_len(degree)];
+    polyfit(
+        x.iter().zip(y).map(|(x, y)| (*x as f64, *y as f64)),
+        &mut coefficients,
+        &mut scratch,
+    );
+    coefficients
+        .into_iter()
+        .map(|coefficient| coefficient as f32)
+        .collect()
+}
+
+/// The length of the scratch space `polyfit` works in for a fit of the given
+/// degree: the normal equations, the power sums and the solution.
+const fn polyfit_scratch_len(degree: usize) -> usize {
+    let n = degree + 1;
+    n * n + 4 * n - 1
+}
+
+/// Fits a polynomial to the points like `ols_polyfit`, in f64 and without
+/// collecting the points, which are iterated twice: once for their range
+/// and mean and once for the normal equations. A constant y fits exactly,
+/// with every coefficient but the mean 0.
+///
+/// The `degree + 1` coefficients are written into `coefficients`, and the
+/// fit works in `scratch`, `polyfit_scratch_len(degree)` long, so that it
+/// allocates nothing itself.
+fn polyfit(
+    points: impl Iterator<Item = (f64, f64)> + Clone,
+    coefficients: &mut [f64],
+    scratch: &mut [f64],
+) {
+    let degree = coefficients.len() - 1;
+    let n = degree + 1;
+    coefficients.fill(f64::NAN);
+    let (low, high, sum_y, count) = points.clone().fold(
+        (f64::INFINITY, f64::NEG_INFINITY, 0.0, 0.0),
+        |(low, high, sum_y, count), (x, y)| (low.min(x), high.max(x), sum_y + y, count + 1.0),
+    );
+    let ybar = sum_y / count;
+    let center = (low + high) / 2.0;
+    let half_range = (high - low) / 2.0;
+    if !center.is_finite() || (degree > 0 && half_range <= 0.0) {
+        return;
+    }
+    let scale = if half_range > 0.0 { half_range } else { 1.0 };
+
+    // The normal equations of the powers of t = (x - center) / scale, with
+    // the gram matrix stored row by row.
+    let (gram, rest) = scratch.split_at_mut(n * n);
+    let (moments, rest) = rest.split_at_mut(n);
+    let (powers, rest) = rest.split_at_mut(2 * degree + 1);
+    let scaled = &mut rest[..n];
+    moments.fill(0.0);
+    powers.fill(0.0);
+    for (x, y) in points {
+        let t = (x - center) / scale;
+        let mut power = 1.0;
+        for (k, entry) in powers.iter_mut().enumerate() {
+            *entry += power;
+            if k <= degree {
+                moments[k] += power * (y - ybar);
+            }
+            power *= t;
+        }
+    }
+    for (i, row) in gram.chunks_exact_mut(n).enumerate() {
+        row.copy_from_slice(&powers[i..i + n]);
+    }
+    if !solve_symmetric(gram, moments, scaled) {
+        return;
+    }
+
+    // Expands the polynomial in t into powers of x by Horner's rule, each
+    // step multiplying by (x - center) / scale and adding a coefficient.
+    coefficients.fill(0.0);
+    for a in scaled.iter().rev() {
+        for j in (0..=degree).rev() {
+            let shifted = if j > 0 { coefficients[j - 1] } else { 0.0 };
+            coefficients[j] = (shifted - center * coefficients[j]) / scale;
+        }
+        coefficients[0] += a;
+    }
+    coefficients[0] += ybar;
+}
+
+/// Solves the normal equations `gram c = moments`, with `gram` stored row by
+/// row, by Gaussian elimination with partial pivoting, writing c into
+/// `solution`. Returns false if a pivot is negligible next to the number of
+/// points, the first entry of `gram`, or isn't finite. Both `gram` and
+/// `moments` are overwritten.
+fn solve_symmetric(gram: &mut [f64], moments: &mut [f64], solution: &mut [f64]) -> bool {
+    let n = moments.len();
+    let negligible = 1e-12 * gram[0];
+    for column in 0..n {
+        let Some(pivot) = (column..n).max_by(|i, j| {
+            gram[i * n + column]
+                .abs()
+                .total_cmp(&gram[j * n + column].abs())
+        }) else {
+            return false;
+        };
+        let pivot_value = gram[pivot * n + column];
+        if !pivot_value.is_finite() || pivot_value.abs() <= negligible {
+            return false;
+        }
+        for k in 0..n {
+            gram.swap(column * n + k, pivot * n + k);
+        }
+        moments.swap(column, pivot);
+        let (above, below) = gram.split_at_mut((column + 1) * n);
+        let pivot_row = &above[column * n..];
+        for (offset, row) in below.chunks_exact_mut(n).enumerate() {
+            let factor = row[column] / pivot_row[column];
+            for (value, pivot) in row.iter_mut().zip(pivot_row).skip(column) {
+                *value -= factor * pivot;
+            }
+            moments[column + 1 + offset] -= factor * moments[column];
+        }
+    }
+    for row in (0..n).rev() {
+        let known: f64 = (row + 1..n).map(|k| gram[row * n + k] * solution[k]).sum();
+        solution[row] = (moments[row] - known) / gram[row * n + row];
+    }
+    true
+}
+
+/// Fits an ordinary least squares regression line y = alpha + beta * x.
+///
+/// This is `ols_polyfit` at degree 1, the same estimator `detrend_data`
+/// uses, for callers with explicit x values. Returns `(beta_hat,
+/// alpha_hat)`, the slope and intercept.
+///
+/// # Arguments
+/// `x` - the independent variable.
+/// `y` - the dependent variable, the same length as `x`.
+///
+pub fn linear_regression(x: &[f32], y: &[f32]) -> (f32, f32) {
+    let coefficients = ols_polyfit(x, y, 1);
+    (coefficients[1], coefficients[0])
+}
+
+/// How a series is prepared before its sample entropy is computed.
+///
+/// Note that chebyshev distances are shift-invariant and centering does not
+/// change the standard deviation, so `Center` gives the same sample entropy
+/// as `Raw`; it is offered for completeness. Linear detrending, on the other
+/// hand, changes both the distances and the derived `r` on a trending series.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Preprocess {
+    /// Use the series as-is.
+    Raw,
+    /// Subtract the mean of the series.
+    Center,
+    /// Subtract an ordinary least squares regression line (see `detrend_data`).
+    #[default]
+    Detrend,
+    /// Detrend, then subtract a sinusoid fitted at the dominant period, if
+    /// there is one (see `periodic::remove_periodic`).
+    Periodic,
+}
+
+impl Preprocess {
+    /// Applies the preprocessing, returning a new vector.
+    pub fn apply(&self, data: &[f32]) -> Vec<f32> {
+        let mut out: Vec<f32> = Vec::with_capacity(data.len());
+        self.apply_into(data, &mut out);
+        out
+    }
+
+    /// Applies the preprocessing, writing the result into `out`.
+    pub fn apply_into(&self, data: &[f32], out: &mut Vec<f32>) {
+        match self {
+            Preprocess::Raw => {
+                out.clear();
+                out.extend_from_slice(data);
+            }
+            Preprocess::Center => {
+                let xbar: f32 = mean(data);
+                out.clear();
+                out.extend(data.iter().map(|x| x - xbar));
+            }
+            Preprocess::Detrend => {
+                detrend_into(data, out);
+            }
+            Preprocess::Periodic => {
+                crate::periodic::remove_periodic_into(data, out);
+            }
+        }
+    }
+}
+
+/// How the match threshold `r` is chosen for a series.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tolerance {
+    /// This fraction of the standard deviation of the (preprocessed) series.
+    StdFraction(f32),
+    /// This fraction of the scaled median absolute deviation of the series
+    /// (see `median_absolute_deviation`), which artifacts distort far less
+    /// than the standard deviation.
+    MadFraction(f32),
+    /// This fraction of the interquartile range of the series (see
+    /// `interquartile_range`). For skewed or heavy-tailed series, where the
+    /// standard deviation follows the tails and the median absolute
+    /// deviation assumes they are symmetric, the quartiles follow each side
+    /// separately; but the range tolerates artifacts in only a quarter of
+    /// the samples against the median absolute deviation's half, so prefer
+    /// `MadFraction` for artifact-ridden but symmetric series and
+    /// `StdFraction` for clean ones, whose `r` stays comparable with the
+    /// literature. The range of normal data is 1.349 standard deviations,
+    /// so a fraction of 0.15 gives about the conventional 0.2 of them.
+    IqrFraction(f32),
+    /// This threshold, whatever the series. The caller is responsible for
+    /// having scaled it to the series.
+    Absolute(f32),
+}
+
+impl Default for Tolerance {
+    /// The conventional 0.2 standard deviations.
+    fn default() -> Self {
+        Tolerance::StdFraction(0.2)
+    }
+}
+
+impl Tolerance {
+    /// Returns `r` for the series. `Absolute` doesn't look at the data.
+    /// `MadFraction` and `IqrFraction` estimate the spread with a sketch on
+    /// series longer than `MAD_SKETCH_THRESHOLD`, so that they needn't be
+    /// copied.
+    pub fn resolve(&self, data: &[f32]) -> f32 {
+        match self {
+            Tolerance::StdFraction(fraction) => fraction * standard_deviation(data),
+            Tolerance::MadFraction(fraction) if data.len() > MAD_SKETCH_THRESHOLD => {
+                fraction * approximate_median_absolute_deviation(data)
+            }
+            Tolerance::MadFraction(fraction) => fraction * median_absolute_deviation(data),
+            Tolerance::IqrFraction(fraction) if data.len() > MAD_SKETCH_THRESHOLD => {
+                fraction * approximate_interquartile_range(data)
+            }
+            Tolerance::IqrFraction(fraction) => fraction * interquartile_range(data),
+            Tolerance::Absolute(r) => *r,
+        }
+    }
+
+    /// Returns `r` for the series like `resolve`, or
+    /// `SampenError::InvalidTolerance` if it isn't positive, e.g. for a
+    /// fraction of the spread of a flat series or a non-positive `Absolute`.
+    pub fn checked_resolve(&self, data: &[f32]) -> Result<f32, SampenError> {
+        let r = self.resolve(data);
+        if r > 0.0 {
+            Ok(r)
+        } else {
+            Err(SampenError::InvalidTolerance(r))
+        }
+    }
+}
+
+/// Detrends the data via a linear detrending.
+///
+/// Fits an ordinary least squares regression line to the data, then subtracts
+/// the estimation from the model to detrend the data. This is done at the
+/// suggestion of the 1994 paper by Pincus, S.M.; Goldberger, A.L. titled:
+/// "Physiological time-series analysis: what does regularity quantify?"
+///
+/// The line is fitted by `ols_polyfit` at degree 1 against the sample
+/// numbers.
+///
+/// An empty slice detrends to an empty vector. Fewer than `MIN_DETREND_LEN`
+/// samples are only centered on their mean.
+///
+/// # Arguments
+/// `data` - an immutable vector slice of waveform data.
+///
+pub fn detrend_data(data: &[f32]) -> Vec<f32> {
+    detrend_data_with_fit(data).0
+}
+
+/// Detrends the data like `detrend_data`, also returning the slope and
+/// intercept of the regression line that was subtracted.
+///
+/// The line is fitted against the 1-based sample number, so the trend at
+/// `data[i]` is `intercept + slope * (i + 1)`. A steep slope can point to a
+/// drifting sensor.
+///
+/// # Arguments
+/// `data` - an immutable vector slice of waveform data.
+///
+pub fn detrend_data_with_fit(data: &[f32]) -> (Vec<f32>, f32, f32) {
+    let mut detrended: Vec<f32> = Vec::with_capacity(data.len());
+    let (slope, intercept) = detrend_into(data, &mut detrended);
+    (detrended, slope, intercept)
+}
+
+/// The fewest samples a line is fitted to when detrending. A line through
+/// two samples fits both exactly, whatever their noise, so it would detrend
+/// any pair to zeros; fewer samples than this are centered on their mean
+/// instead, as if the slope were 0.
+pub const MIN_DETREND_LEN: usize = 3;
+
+/// Detrends the data via a linear detrending, writing the result into `out`.
+///
+/// `out` is cleared first, so a single vector can be reused to detrend many
+/// series without reallocating. Returns the slope and intercept of the
+/// subtracted line, as in `detrend_data_with_fit`. An empty slice has no
+/// line to fit: `out` is left empty and the slope and intercept are NaN.
+/// Below `MIN_DETREND_LEN` samples, the slope is 0 and the intercept the
+/// mean.
+///
+/// # Arguments
+/// `data` - an immutable vector slice of waveform data.
+/// `out` - the vector the detrended data is written into.
+///
+pub fn detrend_into(data: &[f32], out: &mut Vec<f32>) -> (f32, f32) {
+    out.clear();
+    if data.is_empty() {
+        return (f32::NAN, f32::NAN);
+    }
+    if data.len() < MIN_DETREND_LEN {
+        let ybar: f32 = mean(data);
+        out.extend(data.iter().map(|val| val - ybar));
+        return (0.0, ybar);
+    }
+    let mut fit = [0.0; 2];
+    polyfit(
+        data.iter()
+            .enumerate()
+            .map(|(index, value)| ((index + 1) as f64, *value as f64)),
+        &mut fit,
+        &mut [0.0; polyfit_scratch_len(1)],
+    );
+    // alpha hat and beta hat are the estimates of the intercept and slope.
+    let (alpha_hat, beta_hat) = (fit[0], fit[1]);
+
+    out.extend(
+        data.iter()
+            .enumerate()
+            .map(|(ix, val)| (*val as f64 - alpha_hat - beta_hat * (ix + 1) as f64) as f32),
+    );
+    (beta_hat as f32, alpha_hat as f32)
+}
+
+/// The `detrend_data` fit of a window sliding over a series, updated in O(1)
+/// per sample it slides.
+///
+/// Within a window the samples are regressed on their 1-based position, so
+/// the sums of x and x^2 are the same for every window and only the sums of
+/// y and xy change. Sliding by one sample drops the first y and shifts every
+/// remaining position down by one, so `sum_xy` loses `sum_y` and gains
+/// `window * y_new`, and `sum_y` swaps the old and new y.
+///
+/// Running sums accumulate the rounding error of every update, unlike a fit
+/// from scratch. They are kept in f64, which keeps the drift far below f32
+/// precision over millions of steps for waveform-sized values, but a series
+/// with a very large offset relative to its variation (e.g. a raw timestamp)
+/// should be centered first, or the window refit with `detrend_data`.
+/// Non-finite samples enter the sums as zero and make the fit NaN while they
+/// are in the window, matching `detrend_data`, rather than poisoning the sums
+/// for every later window.
+#[derive(Debug, Clone)]
+pub struct RollingDetrend<'a> {
+    data: &'a [f32],
+    window: usize,
+    start: usize,
+    sum_y: f64,
+    sum_xy: f64,
+    non_finite: usize,
+}
+
+impl<'a> RollingDetrend<'a> {
+    /// Fits the first window of the data. `window` must be between 1 and the
+    /// length of the data.
+    pub fn new(data: &'a [f32], window: usize) -> Self {
+        assert!(
+            window > 0 && window <= data.len(),
+            "window must fit in the data"
+        );
+        let mut rolling = RollingDetrend {
+            data,
+            window,
+            start: 0,
+            sum_y: 0.0,
+            sum_xy: 0.0,
+            non_finite: 0,
+        };
+        for (ix, y) in data[..window].iter().enumerate() {
+            let y = rolling.add(*y);
+            rolling.sum_y += y;
+            rolling.sum_xy += (ix + 1) as f64 * y;
+        }
+        rolling
+    }
+
+    /// Counts a sample entering the window, returning its value in the sums.
+    fn add(&mut self, y: f32) -> f64 {
+        if y.is_finite() {
+            y as f64
+        } else {
+            self.non_finite += 1;
+            0.0
+        }
+    }
+
+    /// The index in the data of the first sample of the current window.
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// The samples of the current window.
+    pub fn window(&self) -> &'a [f32] {
+        &self.data[self.start..self.start + self.window]
+    }
+
+    /// Slides the window forward by one sample. Returns false, leaving the
+    /// window in place, if it already ends at the end of the data.
+    pub fn advance(&mut self) -> bool {
+        let end = self.start + self.window;
+        if end >= self.data.len() {
+            return false;
+        }
+        let old = self.data[self.start];
+        let old = if old.is_finite() {
+            old as f64
+        } else {
+            self.non_finite -= 1;
+            0.0
+        };
+        let new = self.add(self.data[end]);
+        self.sum_xy += self.window as f64 * new - self.sum_y;
+        self.sum_y += new - old;
+        self.start += 1;
+        true
+    }
+
+    /// The slope and intercept of the current window's fit, as returned by
+    /// `detrend_data_with_fit`, including its fallback to the mean for
+    /// windows under `MIN_DETREND_LEN`.
+    pub fn fit(&self) -> (f32, f32) {
+        if self.non_finite > 0 {
+            return (f32::NAN, f32::NAN);
+        }
+        let n = self.window as f64;
+        if self.window < MIN_DETREND_LEN {
+            return (0.0, (self.sum_y / n) as f32);
+        }
+        let xbar = (n + 1.0) / 2.0;
+        let centered_sum_xx = n * (n * n - 1.0) / 12.0;
+        let beta_hat = (self.sum_xy - xbar * self.sum_y) / centered_sum_xx;
+        let alpha_hat = self.sum_y / n - beta_hat * xbar;
+        (beta_hat as f32, alpha_hat as f32)
+    }
+
+    /// Detrends the current window with its fit, writing the result into
+    /// `out` like `detrend_into`.
+    pub fn detrend_into(&self, out: &mut Vec<f32>) {
+        let (beta_hat, alpha_hat) = self.fit();
+        out.clear();
+        out.extend(
+            self.window()
+                .iter()
+                .enumerate()
+                .map(|(ix, val)| val - alpha_hat - (beta_hat * ((ix as f32) + 1.0))),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constuct_templates_1() {
+        let expected: Vec<Vec<f32>> = vec![vec![1_f32], vec![2f32], vec![3_f32]];
+        assert_eq!(expected, construct_templates(1, &[1_f32, 2_f32, 3_f32]));
+    }
+
+    #[test]
+    fn test_constuct_templates_2() {
+        let expected: Vec<Vec<f32>> = vec![
+            vec![1_f32, 2_f32],
+            vec![2f32, 3_f32],
+            vec![3_f32, 4f32],
+            vec![4_f32, 5_f32],
+        ];
+        assert_eq!(
+            expected,
+            construct_templates(2, &[1_f32, 2_f32, 3_f32, 4_f32, 5_f32])
+        );
+    }
+
+    #[test]
+    fn test_get_matches_skips_nan_templates() {
+        let data: Vec<f32> = vec![1_f32, 2_f32, 1_f32, 2_f32, f32::NAN, 2_f32, 1_f32, 2_f32];
+        let templates = construct_templates(2, &data);
+        let finite_only: Vec<Vec<f32>> = templates
+            .iter()
+            .filter(|t| t.iter().all(|x| x.is_finite()))
+            .cloned()
+            .collect();
+        // The two templates overlapping the NaN are dropped, not miscounted.
+        assert_eq!(templates.len() - 2, finite_only.len());
+        let predicate = ChebyshevThreshold(0.5);
+        let interrupt = Interrupt::default();
+        assert_eq!(
+            get_matches(&finite_only, &predicate, &interrupt),
+            get_matches(&templates, &predicate, &interrupt)
+        );
+        assert_eq!(Ok(4), get_matches(&templates, &predicate, &interrupt));
+    }
+
+    #[test]
+    fn test_matches_of_caller_built_templates() {
+        let mut rng = SplitMix64::new(12);
+        let mut data: Vec<f32> = (0..300).map(|_| rng.next_below(8) as f32).collect();
+        data[40] = f32::NAN;
+        let predicate = ChebyshevThreshold(1.5);
+        let interrupt = Interrupt::default();
+        for m in [1, 2, 3] {
+            let counts = match_counts(m, 1.5, &data);
+            let slices: Vec<&[f32]> = data.windows(m).collect();
+            assert_eq!(
+                Ok(counts.m_matches),
+                get_matches(&slices, &predicate, &interrupt)
+            );
+            assert_eq!(
+                get_matches(&construct_templates(m, &data), &predicate, &interrupt),
+                get_matches(&slices, &predicate, &interrupt)
+            );
+
+            // Without the last length-m template, B drops by its matches.
+            let extended: Vec<&[f32]> = data.windows(m + 1).collect();
+            let detailed = get_match_counts(m, &extended, &predicate, &interrupt).unwrap();
+            assert_eq!(counts.m_plus_1_matches, detailed.m_plus_1_matches);
+            let last = &data[data.len() - m..];
+            let last_matches = slices[..slices.len() - 1]
+                .iter()
+                .filter(|t| is_finite_template(t) && predicate.matches(t, last))
+                .count();
+            assert_eq!(counts.m_matches - last_matches, detailed.m_matches);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "templates must have equal length")]
+    fn test_get_matches_rejects_unequal_templates() {
+        let templates: [&[f32]; 2] = [&[1.0, 2.0], &[1.0]];
+        let _ = get_matches(&templates, &ChebyshevThreshold(0.5), &Interrupt::default());
+    }
+
+    #[test]
+    fn test_non_positive_tolerance_is_rejected() {
+        let data: Vec<f32> = (0..100).map(|x| ((x * 7) % 5) as f32).collect();
+        for r in [0.0, -1.0] {
+            assert!(matches!(
+                checked_sample_entropy(2, r, &data),
+                Err(SampenError::InvalidTolerance(rejected)) if rejected == r
+            ));
+            assert!(matches!(
+                Tolerance::Absolute(r).checked_resolve(&data),
+                Err(SampenError::InvalidTolerance(_))
+            ));
+        }
+        assert!(matches!(
+            Tolerance::default().checked_resolve(&[3.0; 50]),
+            Err(SampenError::InvalidTolerance(_))
+        ));
+        assert_eq!(
+            Tolerance::default().resolve(&data),
+            Tolerance::default().checked_resolve(&data).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_symbolic_entropy_of_periodic_and_random_symbols() {
+        let periodic: Vec<u32> = (0..4000).map(|x| [0, 1, 2, 1][x % 4]).collect();
+        let regular = symbolic_sample_entropy(2, &periodic);
+        assert!(regular > 0.0 && regular < 0.01, "{}", regular);
+
+        let mut rng = SplitMix64::new(8);
+        let random: Vec<u32> = (0..4000).map(|_| rng.next_below(4) as u32).collect();
+        let entropy = symbolic_sample_entropy(2, &random);
+        assert!((entropy - 4.0_f32.ln()).abs() < 0.05, "{}", entropy);
+
+        // The same symbols as floats match exactly as often.
+        let as_floats: Vec<f32> = random.iter().map(|x| *x as f32).collect();
+        assert_eq!(
+            exact_match_counts(2, &as_floats),
+            symbolic_match_counts(2, &random)
+        );
+    }
+
+    #[test]
+    fn test_exact_matches_equal_sub_step_tolerance() {
+        let mut rng = SplitMix64::new(14);
+        let mut data: Vec<f32> = (0..500).map(|_| rng.next_below(6) as f32).collect();
+        data[17] = f32::NAN;
+        data[30] = -0.0;
+        for m in [1, 2, 3] {
+            assert_eq!(match_counts(m, 0.5, &data), exact_match_counts(m, &data));
+        }
+        assert_eq!(
+            sample_entropy(2, 0.5, &data),
+            exact_sample_entropy(2, &data)
+        );
+    }
+
+    #[test]
+    fn test_perfectly_regular_counts_give_exactly_zero() {
+        let repeating: Vec<f32> = [1.0, 4.0, 2.0, 8.0, 5.0]
+            .iter()
+            .copied()
+            .cycle()
+            .take(200)
+            .collect();
+        for m in [1, 2, 3] {
+            // Every pair of templates that matches for m also matches for
+            // m+1, except the pairs with the last template, which has no
+            // extension: one per earlier template in the same phase.
+            let counts = match_counts(m, 0.5, &repeating);
+            let last_template_matches = (repeating.len() - m) / 5;
+            assert_eq!(
+                last_template_matches,
+                counts.m_matches - counts.m_plus_1_matches
+            );
+            assert!(!counts.is_perfectly_regular());
+            let without_last_template = MatchCounts {
+                m_matches: counts.m_plus_1_matches,
+                ..counts
+            };
+            assert!(without_last_template.is_perfectly_regular());
+            let entropy = without_last_template.sample_entropy();
+            assert_eq!(0.0, entropy);
+            assert!(entropy.is_sign_positive(), "{}", m);
+        }
+        let no_matches = MatchCounts {
+            m_matches: 0,
+            m_plus_1_matches: 0,
+        };
+        assert!(!no_matches.is_perfectly_regular());
+        assert!(no_matches.floored_sample_entropy().is_sign_positive());
+        let all_extend = MatchCounts {
+            m_matches: 7,
+            m_plus_1_matches: 7,
+        };
+        assert!(all_extend
+            .sample_entropy_interval(0.95)
+            .0
+            .is_sign_positive());
+    }
+
+    #[test]
+    fn test_distance_matrix_is_symmetric_with_zero_diagonal() {
+        let data: Vec<f32> = vec![1.0, 3.0, 2.0, 5.0, 3.0, 1.0, 2.5, 4.0];
+        let matrix = distance_matrix(2, &data);
+        assert_eq!(7, matrix.len());
+        for (i, row) in matrix.iter().enumerate() {
+            assert_eq!(7, row.len());
+            assert_eq!(0.0, row[i]);
+            for (j, distance) in row.iter().enumerate() {
+                assert_eq!(matrix[j][i], *distance);
+            }
+        }
+        // Templates [1, 3] and [3, 1].
+        assert_eq!(2.0, matrix[0][4]);
+
+        // The pairs below r are the matches counted.
+        let r = 1.5;
+        let below = matrix.iter().flatten().filter(|d| **d < r).count();
+        let matches = get_matches(
+            &construct_templates(2, &data),
+            &ChebyshevThreshold(r),
+            &Interrupt::default(),
+        )
+        .unwrap();
+        assert_eq!(matrix.len() + 2 * matches, below);
+
+        let long = vec![0.0; MAX_DISTANCE_MATRIX_TEMPLATES + 10];
+        assert_eq!(
+            MAX_DISTANCE_MATRIX_TEMPLATES,
+            distance_matrix(2, &long).len()
+        );
+        assert!(distance_matrix(3, &[1.0, f32::NAN, 2.0])[0][0].is_nan());
+    }
+
+    #[test]
+    fn test_memory_cap_compares_templates_in_place() {
+        let data: Vec<f32> = (0..3000)
+            .map(|x| (x as f32 * 0.13).sin() + ((x * 31) % 17) as f32 * 0.05)
+            .collect();
+        let cap = 64 << 10;
+        assert!(template_memory(2, data.len()) + template_memory(3, data.len()) > cap);
+        let predicate = ChebyshevThreshold(0.2);
+        let mut capped = TemplateBuffer::with_memory_cap(cap);
+        // Copies left from a short series are freed once a long one is capped.
+        sample_entropy_generalized_with_buffer(
+            2,
+            1,
+            &predicate,
+            &data[..100],
+            &mut capped,
+            &Interrupt::default(),
+        )
+        .unwrap();
+        assert!(!capped.size_m.is_empty());
+        let in_place = sample_entropy_generalized_with_buffer(
+            2,
+            1,
+            &predicate,
+            &data,
+            &mut capped,
+            &Interrupt::default(),
+        )
+        .unwrap();
+
+        let held = |buffer: &TemplateBuffer| {
+            buffer.size_m.capacity() * std::mem::size_of::<Vec<f32>>()
+                + buffer
+                    .size_m
+                    .iter()
+                    .map(|t| t.capacity() * 4)
+                    .sum::<usize>()
+                + buffer.size_m_plus_k.capacity() * std::mem::size_of::<Vec<f32>>()
+                + buffer
+                    .size_m_plus_k
+                    .iter()
+                    .map(|t| t.capacity() * 4)
+                    .sum::<usize>()
+                + buffer.match_scratch.starts.capacity() * std::mem::size_of::<usize>()
+                + buffer.match_scratch.order.capacity() * std::mem::size_of::<usize>()
+        };
+        assert!(held(&capped) <= cap, "{}", held(&capped));
+        let mut uncapped = TemplateBuffer::default();
+        let copied = sample_entropy_generalized_with_buffer(
+            2,
+            1,
+            &predicate,
+            &data,
+            &mut uncapped,
+            &Interrupt::default(),
+        )
+        .unwrap();
+        assert!(held(&uncapped) > cap);
+        assert_eq!(copied, in_place);
+        assert_eq!(sample_entropy(2, 0.2, &data), in_place);
+        assert_eq!(
+            DEFAULT_TEMPLATE_MEMORY_CAP,
+            TemplateBuffer::default().memory_cap()
+        );
+    }
+
+    #[test]
+    fn test_construct_templates_into_reuses_buffer() {
+        let mut templates: Vec<Vec<f32>> = construct_templates(2, &[9_f32; 10]);
+        construct_templates_into(2, &[1_f32, 2_f32, 3_f32], &mut templates);
+        assert_eq!(construct_templates(2, &[1_f32, 2_f32, 3_f32]), templates);
+        construct_templates_into(2, &[1_f32, 2_f32, 3_f32, 4_f32, 5_f32], &mut templates);
+        assert_eq!(
+            construct_templates(2, &[1_f32, 2_f32, 3_f32, 4_f32, 5_f32]),
+            templates
+        );
+    }
+
+    #[test]
+    fn test_buffered_functions_match_allocating_versions() {
+        let data: Vec<f32> = (0..50)
+            .map(|x| ((x * 7) % 11) as f32 + x as f32 * 0.1)
+            .collect();
+        let mut detrended: Vec<f32> = vec![1_f32; 3];
+        detrend_into(&data, &mut detrended);
+        assert_eq!(detrend_data(&data), detrended);
+
+        let mut buffer = TemplateBuffer::default();
+        let first = sample_entropy_with_buffer(2, 1.5, &detrended, &mut buffer);
+        let second = sample_entropy_with_buffer(2, 1.5, &detrended[..30], &mut buffer);
+        assert_eq!(sample_entropy(2, 1.5, &detrended), first);
+        assert_eq!(sample_entropy(2, 1.5, &detrended[..30]), second);
+    }
+
+    #[test]
+    fn test_generalized_sample_entropy_k1_matches_standard() {
+        let data: Vec<f32> = (0..80).map(|x| ((x * 13) % 17) as f32).collect();
+        for m in 1..4 {
+            assert_eq!(
+                sample_entropy(m, 3.0, &data),
+                sample_entropy_generalized(m, 1, 3.0, &data)
+            );
+        }
+    }
+
+    #[test]
+    fn test_generalized_sample_entropy_k2_on_alternating_signal() {
+        // With period 2, templates only match others of the same phase.
+        // m=1: 10 templates in two groups of 5 -> 20 matching pairs.
+        // m+k=3: 8 templates in two groups of 4 -> 12 matching pairs.
+        let data: Vec<f32> = (0..10).map(|x| (x % 2) as f32).collect();
+        let expected: f32 = -(12_f32 / 20_f32).ln() / 2.0;
+        let result = sample_entropy_generalized(1, 2, 0.5, &data);
+        assert!((expected - result).abs() < 1e-6);
+    }
+
+    /// Chebyshev matching with a separate threshold per template position.
+    struct PerPositionThreshold(Vec<f32>);
+
+    impl MatchPredicate for PerPositionThreshold {
+        fn matches(&self, a: &[f32], b: &[f32]) -> bool {
+            a.iter()
+                .zip(b)
+                .zip(&self.0)
+                .all(|((x, y), r)| (x - y).abs() < *r)
+        }
+    }
+
+    #[test]
+    fn test_custom_match_predicate() {
+        let data: Vec<f32> = (0..90).map(|x| ((x * x * 7 + x * 3) % 23) as f32).collect();
+        let uniform = PerPositionThreshold(vec![4.0; 3]);
+        let expected = sample_entropy(2, 4.0, &data);
+        assert!(expected.is_finite() && expected > 0.0);
+        assert_eq!(expected, sample_entropy_with_predicate(2, &uniform, &data));
+        assert_eq!(
+            expected,
+            sample_entropy_with_predicate(2, &ChebyshevThreshold(4.0), &data)
+        );
+        // Loosening only the m+1-th position can only add m+1 matches.
+        let loose_last = PerPositionThreshold(vec![4.0, 4.0, 100.0]);
+        assert!(sample_entropy_with_predicate(2, &loose_last, &data) < expected);
+    }
+
+    #[test]
+    fn test_surface_cells_equal_standalone_calls() {
+        let mut rng = SplitMix64::new(21);
+        let mut data: Vec<f32> = (0..400)
+            .map(|x| (x as f32 * 0.2).sin() + rng.next_f32() * 0.5)
+            .collect();
+        data[150] = f32::NAN;
+        let m_values = [2, 1, 3];
+        let r_values = [0.3, 0.1, f32::NAN, 0.5, 0.05];
+        let surface = sample_entropy_surface(&m_values, &r_values, &data);
+        let counts = match_counts_surface(&m_values, &r_values, &data);
+        assert_eq!(3, surface.len());
+        for (i, m) in m_values.iter().enumerate() {
+            assert_eq!(5, surface[i].len());
+            for (j, r) in r_values.iter().enumerate() {
+                if r.is_nan() {
+                    assert_eq!(0, counts[i][j].m_matches);
+                    assert!(surface[i][j].is_nan());
+                    continue;
+                }
+                assert_eq!(match_counts(*m, *r, &data), counts[i][j]);
+                assert_eq!(
+                    sample_entropy(*m, *r, &data).to_bits(),
+                    surface[i][j].to_bits()
+                );
+            }
+        }
+        assert!(surface[0][0].is_finite() && surface[0][0] > 0.0);
+        assert!(sample_entropy_surface(&[2], &[0.2], &[1.0, 2.0])[0][0].is_nan());
+    }
+
+    #[test]
+    fn test_per_dimension_thresholds() {
+        let data: Vec<f32> = (0..300)
+            .map(|x| (x as f32 * 0.3).sin() * 3.0 + ((x * 7) % 5) as f32)
+            .collect();
+        for m in [1, 2, 3] {
+            let uniform = vec![1.5; m];
+            assert_eq!(
+                sample_entropy(m, 1.5, &data),
+                sample_entropy_per_dimension(m, &uniform, &data)
+            );
+        }
+        let tight_recent = sample_entropy_per_dimension(2, &[1.5, 0.5], &data);
+        assert_ne!(sample_entropy(2, 1.5, &data), tight_recent);
+        // The extension uses the last threshold unless given its own.
+        assert_eq!(
+            tight_recent,
+            sample_entropy_per_dimension(2, &[1.5, 0.5, 0.5], &data)
+        );
+        assert!(is_match_per_dimension(
+            &[0.0, 0.0, 0.0],
+            &[1.0, 0.1, 0.1],
+            &[1.5, 0.5]
+        ));
+        assert!(!is_match_per_dimension(
+            &[0.0, 0.0, 0.0],
+            &[1.0, 0.1, 0.6],
+            &[1.5, 0.5]
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "r must have m or m+1 thresholds")]
+    fn test_per_dimension_thresholds_need_one_per_coordinate() {
+        sample_entropy_per_dimension(2, &[1.0], &[1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_ac_amplitude_ignores_offset() {
+        let wave: Vec<f32> = vec![-1.0, 0.5, 1.0, f32::NAN, 0.0];
+        let offset: Vec<f32> = wave.iter().map(|x| x + 1000.0).collect();
+        assert_eq!(2.0, ac_amplitude(&wave));
+        assert_eq!(2.0, ac_amplitude(&offset));
+        assert!(ac_amplitude(&[f32::NAN]).is_nan());
+    }
+
+    #[test]
+    fn test_autocorrelation_recovers_sine_period() {
+        let sine: Vec<f32> = (0..2000)
+            .map(|t| 50.0 + (std::f32::consts::TAU * t as f32 / 25.0).sin())
+            .collect();
+        let acf = autocorrelation(&sine, 100);
+        assert_eq!(101, acf.len());
+        assert_eq!(1.0, acf[0]);
+        assert!(acf.iter().all(|rho| rho.abs() <= 1.0 + 1e-6));
+        assert_eq!(Some(7), first_zero_crossing(&acf));
+        assert_eq!(Some(25), first_peak(&acf));
+        // The biased estimator shrinks the later peaks.
+        assert!(acf[25] > acf[50] && acf[50] > acf[75]);
+
+        let direct = lagged_products(&[1.0, -2.0, 1.0], 2);
+        assert_eq!(vec![6.0, -4.0, 1.0], direct);
+        assert_eq!(3, autocorrelation(&[1.0, 2.0, 3.0], 10).len());
+        assert!(autocorrelation(&[2.0; 5], 2).iter().all(|rho| rho.is_nan()));
+        assert_eq!(None, first_peak(&[1.0, 0.5, 0.2]));
+    }
+
+    #[cfg(feature = "spectral")]
+    #[test]
+    fn test_fft_autocorrelation_matches_direct_sums() {
+        let mut rng = SplitMix64::new(21);
+        let data: Vec<f64> = rng
+            .uniform_vec(3000)
+            .iter()
+            .map(|x| *x as f64 - 0.5)
+            .collect();
+        let direct = lagged_products(&data, 500);
+        let fft = fft_lagged_products(&data, 500);
+        for (a, b) in direct.iter().zip(&fft) {
+            assert!((a - b).abs() < 1e-9 * direct[0], "{} {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_detect_quantization() {
+        let integers: Vec<f32> = vec![80., 82., 81., 81., 85., 79., 90., 80.];
+        assert_eq!(Some(1.0), detect_quantization(&integers));
+        let halves: Vec<f32> = (0..40).map(|x| 80.0 + ((x * 7) % 9) as f32 * 0.5).collect();
+        assert_eq!(Some(0.5), detect_quantization(&halves));
+        let continuous: Vec<f32> = (0..200).map(|x| (x as f32 * 0.37).sin() * 10.0).collect();
+        assert_eq!(None, detect_quantization(&continuous));
+        assert_eq!(None, detect_quantization(&[3.0, 3.0, 3.0]));
+    }
+
+    #[test]
+    fn test_centering_does_not_change_sample_entropy() {
+        let noise: Vec<f32> = (0..150)
+            .map(|x| ((x * x * 7 + x * 3) % 23) as f32)
+            .collect();
+        let r: f32 = 0.2 * standard_deviation(&noise);
+        let centered = Preprocess::Center.apply(&noise);
+        assert!(mean(&centered).abs() < 1e-4);
+        assert_eq!(
+            sample_entropy(2, r, &noise),
+            sample_entropy(2, r, &centered)
+        );
+
+        // A linear trend is untouched by centering but removed by detrending.
+        let trending: Vec<f32> = noise
+            .iter()
+            .enumerate()
+            .map(|(ix, x)| x + ix as f32 * 0.25)
+            .collect();
+        let raw = sample_entropy(2, r, &trending);
+        assert_eq!(
+            raw,
+            sample_entropy(2, r, &Preprocess::Center.apply(&trending))
+        );
+        assert_ne!(
+            raw,
+            sample_entropy(2, r, &Preprocess::Detrend.apply(&trending))
+        );
+        assert_eq!(trending, Preprocess::Raw.apply(&trending));
+    }
+
+    #[test]
+    fn test_polyfit_degree_one_matches_closed_form() {
+        let x: Vec<f32> = (0..40).map(|x| x as f32 * 0.25 + 10.0).collect();
+        let y: Vec<f32> = x
+            .iter()
+            .map(|x| 3.0 - 0.5 * x + ((x * 7.0) % 3.0 - 1.5))
+            .collect();
+        // The closed form `detrend_data` used to compute by hand.
+        let (xbar, ybar) = (mean(&x), mean(&y));
+        let numerator: f32 = x
+            .iter()
+            .zip(&y)
+            .map(|(xi, yi)| (xi - xbar) * (yi - ybar))
+            .sum();
+        let denominator: f32 = x.iter().map(|xi| (xi - xbar).powi(2)).sum();
+        let beta_hat = numerator / denominator;
+        let alpha_hat = ybar - beta_hat * xbar;
+        let fit = ols_polyfit(&x, &y, 1);
+        assert!(
+            (fit[1] - beta_hat).abs() < 1e-5,
+            "{:?} vs {}",
+            fit,
+            beta_hat
+        );
+        assert!(
+            (fit[0] - alpha_hat).abs() < 1e-4,
+            "{:?} vs {}",
+            fit,
+            alpha_hat
+        );
+        assert_eq!((fit[1], fit[0]), linear_regression(&x, &y));
+
+        let samples: Vec<f32> = (1..=y.len()).map(|x| x as f32).collect();
+        let (_, slope, intercept) = detrend_data_with_fit(&y);
+        let by_sample = ols_polyfit(&samples, &y, 1);
+        assert_eq!((by_sample[1], by_sample[0]), (slope, intercept));
+    }
+
+    #[test]
+    fn test_polyfit_recovers_polynomials() {
+        let x: Vec<f32> = (0..100).map(|x| x as f32 * 0.1 - 3.0).collect();
+        let cubic = [2.0, -1.0, 0.5, 0.25];
+        let y: Vec<f32> = x
+            .iter()
+            .map(|x| cubic.iter().rev().fold(0.0, |acc, c| acc * x + c))
+            .collect();
+        for (fitted, exact) in ols_polyfit(&x, &y, 3).iter().zip(cubic) {
+            assert!((fitted - exact).abs() < 1e-4, "{} vs {}", fitted, exact);
+        }
+        assert_eq!(vec![5.0, 0.0, 0.0], ols_polyfit(&x, &[5.0; 100], 2));
+        let constant = ols_polyfit(&x, &y, 0);
+        assert!((constant[0] - mean(&y)).abs() < 1e-4);
+        // Two distinct x values can't pin down a parabola.
+        assert!(ols_polyfit(&[1.0, 1.0, 2.0, 2.0], &[1.0, 2.0, 3.0, 4.0], 2)
+            .iter()
+            .all(|c| c.is_nan()));
+        assert!(ols_polyfit(&[], &[], 1).iter().all(|c| c.is_nan()));
+        assert_eq!(vec![2.5], ols_polyfit(&[4.0, 4.0], &[2.0, 3.0], 0));
+    }
+
+    #[test]
+    fn test_linear_regression() {
+        let x: Vec<f32> = (0..20).map(|x| x as f32).collect();
+        let y: Vec<f32> = x.iter().map(|x| 3.0 - 0.5 * x).collect();
+        let (beta_hat, alpha_hat) = linear_regression(&x, &y);
+        assert!((beta_hat + 0.5).abs() < 1e-5);
+        assert!((alpha_hat - 3.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_sample_entropy_interruptible() {
+        let data: Vec<f32> = (0..200).map(|x| ((x * 13) % 17) as f32).collect();
+        let mut buffer = TemplateBuffer::default();
+        assert_eq!(
+            Ok(sample_entropy(2, 3.0, &data)),
+            sample_entropy_interruptible(2, 3.0, &data, &mut buffer, &Interrupt::default())
+        );
+        let expired = Interrupt::at(std::time::Instant::now());
+        assert_eq!(
+            Err(Interrupted),
+            sample_entropy_interruptible(2, 3.0, &data, &mut buffer, &expired)
+        );
+    }
+
+    #[test]
+    fn test_cancel_flag_stops_match_counting() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        // Long enough that brute force takes seconds, far past the cancel.
+        let data = SplitMix64::new(3).uniform_vec(100_000);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let interrupt = Interrupt::cancelled_by(cancel.clone());
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            cancel.store(true, Ordering::Relaxed);
+        });
+        let start = std::time::Instant::now();
+        let counts = match_counts_with_strategy(
+            2,
+            0.1,
+            &data,
+            MatchStrategy::BruteForce,
+            &mut TemplateBuffer::default(),
+            &interrupt,
+        );
+        canceller.join().unwrap();
+        assert_eq!(Err(Interrupted), counts);
+        assert!(matches!(interrupt.error(), SampenError::Cancelled));
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+
+        let unset = Interrupt::cancelled_by(Arc::new(AtomicBool::new(false)));
+        let short = &data[..500];
+        assert_eq!(
+            Ok(match_counts(2, 0.1, short)),
+            match_counts_interruptible(2, 0.1, short, &mut TemplateBuffer::default(), &unset)
+        );
+    }
+
+    #[test]
+    fn test_running_stats_matches_batch() {
+        let data: Vec<f32> = SplitMix64::new(7).uniform_vec(1000);
+        let mut stats = RunningStats::default();
+        data.iter().for_each(|x| stats.push(*x));
+        assert_eq!(1000, stats.count());
+        assert!((mean(&data) - stats.mean()).abs() < 1e-5);
+        assert!((standard_deviation(&data) - stats.std()).abs() < 1e-5);
+        assert!(RunningStats::default().mean().is_nan());
+    }
+
+    #[test]
+    fn test_running_stats_is_accurate_on_large_magnitudes() {
+        let data: Vec<f32> = SplitMix64::new(11)
+            .uniform_vec(200_000)
+            .iter()
+            .map(|x| 10_000.0 + x)
+            .collect();
+        let exact_mean: f64 = data.iter().map(|x| *x as f64).sum::<f64>() / data.len() as f64;
+        let exact_std: f64 = (data
+            .iter()
+            .map(|x| (*x as f64 - exact_mean).powi(2))
+            .sum::<f64>()
+            / data.len() as f64)
+            .sqrt();
+        let mut stats = RunningStats::default();
+        data.iter().for_each(|x| stats.push(*x));
+        let running_error = (stats.std() as f64 - exact_std).abs();
+        let batch_error = (standard_deviation(&data) as f64 - exact_std).abs();
+        assert!(running_error < 1e-5);
+        assert!(running_error < batch_error);
+    }
+
+    #[test]
+    fn test_epoch_sample_entropy() {
+        let data: Vec<f32> = SplitMix64::new(3).uniform_vec(500);
+        // A single 5 second epoch at 100 Hz covers the whole signal.
+        assert_eq!(
+            vec![sample_entropy(2, 0.2, &data)],
+            epoch_sample_entropy(2, 0.2, &data, 100.0, 5.0)
+        );
+        // 1.5 second epochs: three full epochs, the last 50 samples dropped.
+        let epochs = epoch_sample_entropy(2, 0.2, &data, 100.0, 1.5);
+        assert_eq!(3, epochs.len());
+        assert_eq!(sample_entropy(2, 0.2, &data[150..300]), epochs[1]);
+    }
+
+    #[test]
+    fn test_cross_sample_entropy() {
+        let u: Vec<f32> = SplitMix64::new(1).uniform_vec(200);
+        let v: Vec<f32> = SplitMix64::new(2).uniform_vec(200);
+        let uv = cross_sample_entropy(2, 0.2, &u, &v);
+        assert!(uv.is_finite());
+        assert_eq!(uv, cross_sample_entropy(2, 0.2, &v, &u));
+        // A series is perfectly coupled with a slightly shifted copy of itself.
+        let shifted: Vec<f32> = u.iter().map(|x| x + 0.01).collect();
+        assert!(cross_sample_entropy(2, 0.2, &u, &shifted) < uv);
+    }
+
+    #[test]
+    fn test_cross_sample_entropy_of_a_short_series_is_nan() {
+        let u: Vec<f32> = SplitMix64::new(1).uniform_vec(200);
+        assert!(cross_sample_entropy(2, 0.2, &u, &[0.5, 0.25]).is_nan());
+        assert!(cross_sample_entropy(2, 0.2, &[0.5], &u).is_nan());
+        assert!(cross_sample_entropy(2, 0.2, &u, &[]).is_nan());
+    }
+
+    #[test]
+    fn test_floored_sample_entropy() {
+        // Consecutive integers with r below 1 never match at any length.
+        let ramp: Vec<f32> = (0..20).map(|x| x as f32).collect();
+        let counts = match_counts(2, 0.5, &ramp);
+        assert_eq!(0, counts.m_matches);
+        assert!(counts.sample_entropy().is_nan());
+        assert_eq!(0.0, counts.floored_sample_entropy());
+
+        // Period 3 with each period slightly shifted: the length-2 templates
+        // match one period later, but the third points drift apart too far.
+        let drifting: Vec<f32> = (0..30)
+            .map(|x| [0.0, 10.0, 20.0][x % 3] + (x / 3) as f32 * [0.0, 0.0, 2.0][x % 3])
+            .collect();
+        let counts = match_counts(2, 1.0, &drifting);
+        assert_eq!(0, counts.m_plus_1_matches);
+        assert!(counts.m_matches > 0);
+        assert_eq!(f32::INFINITY, counts.sample_entropy());
+        assert_eq!(
+            (counts.m_matches as f32).ln(),
+            counts.floored_sample_entropy()
+        );
+    }
+
+    #[test]
+    fn test_sample_entropy_reservoir() {
+        let mut rng = SplitMix64::new(11);
+        let data: Vec<f32> = (0..3000)
+            .map(|x| (x as f32 * 0.1).sin() + 0.5 * rng.next_f32())
+            .collect();
+        let r = 0.2 * standard_deviation(&data);
+        let estimate = sample_entropy_reservoir(2, r, &data, 800, 5);
+        assert_eq!(estimate, sample_entropy_reservoir(2, r, &data, 800, 5));
+        let exact = sample_entropy(2, r, &data);
+        assert!((estimate - exact).abs() < 0.1, "{} vs {}", estimate, exact);
+        // A reservoir holding every template only differs from the exact
+        // value by the one length-m template without a successor.
+        let full = sample_entropy_reservoir(2, r, &data, data.len(), 5);
+        assert!((full - exact).abs() < 1e-3, "{} vs {}", full, exact);
+    }
+
+    #[test]
+    fn test_detrend_data_with_fit() {
+        let line: Vec<f32> = (0..50).map(|x| 3.0 + 2.0 * (x + 1) as f32).collect();
+        let (detrended, slope, intercept) = detrend_data_with_fit(&line);
+        assert!((slope - 2.0).abs() < 1e-4, "{}", slope);
+        assert!((intercept - 3.0).abs() < 1e-3, "{}", intercept);
+        assert!(detrended.iter().all(|x| x.abs() < 1e-3));
+        assert_eq!(detrend_data(&line), detrended);
+    }
+
+    #[test]
+    fn test_short_series_are_centered_rather_than_fitted() {
+        let (pair, slope, intercept) = detrend_data_with_fit(&[1.0, 4.0]);
+        assert_eq!((0.0, 2.5), (slope, intercept));
+        assert_eq!(vec![-1.5, 1.5], pair);
+        assert_eq!(vec![0.0], detrend_data(&[7.0]));
+
+        let (triple, slope, _) = detrend_data_with_fit(&[1.0, 4.0, 7.0]);
+        assert!((slope - 3.0).abs() < 1e-6, "{}", slope);
+        assert!(triple.iter().all(|x| x.abs() < 1e-5), "{:?}", triple);
+
+        let data = [1.0, 4.0, 2.0, 8.0];
+        for window in 1..=data.len() {
+            let rolling = RollingDetrend::new(&data, window);
+            let (_, slope, intercept) = detrend_data_with_fit(&data[..window]);
+            let (rolling_slope, rolling_intercept) = rolling.fit();
+            assert!((slope - rolling_slope).abs() < 1e-5, "{}", window);
+            assert!((intercept - rolling_intercept).abs() < 1e-5, "{}", window);
+        }
+    }
+
+    #[test]
+    fn test_incremental_counts_equal_batch_counts() {
+        let mut rng = SplitMix64::new(31);
+        let mut data: Vec<f32> = (0..400).map(|_| rng.next_below(10) as f32).collect();
+        data[150] = f32::NAN;
+        for m in [1, 2, 3] {
+            let batch = match_counts(m, 2.5, &data);
+            for split in [0, 1, 150, 251, 399, 400] {
+                let mut incremental = IncrementalSampleEntropy::new(m, 2.5);
+                incremental.update(&data[..split]);
+                incremental.update(&data[split..]);
+                assert_eq!(batch, incremental.counts(), "m {} split {}", m, split);
+                assert_eq!(data.len(), incremental.data().len());
+            }
+        }
+        let mut incremental = IncrementalSampleEntropy::new(2, 2.5);
+        for chunk in data.chunks(7) {
+            incremental.update(chunk);
+        }
+        assert_eq!(sample_entropy(2, 2.5, &data), incremental.sample_entropy());
+    }
+
+    #[test]
+    fn test_match_counts_is_reliable() {
+        let counts = MatchCounts {
+            m_matches: 40,
+            m_plus_1_matches: 9,
+        };
+        assert!(counts.is_reliable(9));
+        assert!(!counts.is_reliable(10));
+    }
+
+    #[test]
+    fn test_single_pass_counts_match_two_pass() {
+        let mut rng = SplitMix64::new(21);
+        for trial in 0..20 {
+            let len = 20 + rng.next_below(200);
+            let mut data: Vec<f32> = (0..len).map(|_| rng.next_below(12) as f32).collect();
+            if trial % 3 == 0 {
+                data[rng.next_below(len)] = f32::NAN;
+            }
+            let m = 1 + trial % 3;
+            let r = [0.5, 1.0, 2.5][trial % 3];
+            let predicate = ChebyshevThreshold(r);
+            let two_pass = MatchCounts {
+                m_matches: uninterrupted(get_matches(
+                    &construct_templates(m, &data),
+                    &predicate,
+                    &Interrupt::default(),
+                )),
+                m_plus_1_matches: uninterrupted(get_matches(
+                    &construct_templates(m + 1, &data),
+                    &predicate,
+                    &Interrupt::default(),
+                )),
+            };
+            assert_eq!(two_pass, match_counts(m, r, &data), "trial {}", trial);
+        }
+    }
+
+    #[test]
+    fn test_tolerance_resolve() {
+        let data: Vec<f32> = (0..100).map(|x| ((x * 7) % 11) as f32).collect();
+        assert_eq!(
+            0.2 * standard_deviation(&data),
+            Tolerance::default().resolve(&data)
+        );
+        assert_eq!(0.5, Tolerance::Absolute(0.5).resolve(&data));
+        assert_eq!(0.5, Tolerance::Absolute(0.5).resolve(&[]));
+    }
+
+    #[test]
+    fn test_rolling_detrend_matches_batch() {
+        let mut rng = SplitMix64::new(8);
+        let mut data: Vec<f32> = (0..3000)
+            .map(|x| 80.0 + x as f32 * 0.01 + (x as f32 * 0.03).sin() * 5.0 + rng.next_f32())
+            .collect();
+        data[1234] = f32::NAN;
+        let window = 250;
+        let mut rolling = RollingDetrend::new(&data, window);
+        let mut incremental: Vec<f32> = Vec::new();
+        loop {
+            let start = rolling.start();
+            let (batch, beta_hat, alpha_hat) = detrend_data_with_fit(&data[start..start + window]);
+            rolling.detrend_into(&mut incremental);
+            let (rolling_beta, rolling_alpha) = rolling.fit();
+            if data[start..start + window].iter().any(|x| x.is_nan()) {
+                assert!(rolling_beta.is_nan() && incremental.iter().all(|x| x.is_nan()));
+            } else {
+                assert!((beta_hat - rolling_beta).abs() < 1e-5, "{}", start);
+                assert!((alpha_hat - rolling_alpha).abs() < 1e-3, "{}", start);
+                for (a, b) in batch.iter().zip(&incremental) {
+                    assert!((a - b).abs() < 1e-3, "{}", start);
+                }
+            }
+            if !rolling.advance() {
+                break;
+            }
+        }
+        assert_eq!(data.len() - window, rolling.start());
+    }
+
+    #[test]
+    fn test_rolling_sample_entropy_windows() {
+        let data: Vec<f32> = (0..1000)
+            .map(|x| ((x * x * 7 + x * 3) % 23) as f32 + x as f32 * 0.02)
+            .collect();
+        let entropies = rolling_sample_entropy(2, Tolerance::default(), &data, 300, 70);
+        assert_eq!((1000 - 300) / 70 + 1, entropies.len());
+        let last = &data[630..930];
+        let detrended = detrend_data(last);
+        let batch = sample_entropy(2, Tolerance::default().resolve(&detrended), &detrended);
+        assert!((batch - entropies[9]).abs() < 1e-3);
+        assert!(rolling_sample_entropy(2, Tolerance::default(), &data[..10], 20, 1).is_empty());
+    }
+
+    #[test]
+    fn test_template_influence_flags_repeated_pattern() {
+        let mut rng = SplitMix64::new(13);
+        let mut data: Vec<f32> = (0..1000).map(|_| rng.next_f32()).collect();
+        let motif: Vec<f32> = (0..10).map(|_| rng.next_f32()).collect();
+        let copies = [100, 300, 550, 800];
+        for start in copies {
+            data[start..start + 10].copy_from_slice(&motif);
+        }
+        let m = 5;
+        let r = 0.2 * standard_deviation(&data);
+        let influence = template_influence(m, r, &data);
+        assert_eq!(data.len() - m + 1, influence.len());
+        assert_eq!(
+            2 * match_counts(m, r, &data).m_matches,
+            influence.iter().sum::<f32>() as usize
+        );
+        let in_motif = |k: &usize| {
+            copies
+                .iter()
+                .any(|start| (*start..=start + 10 - m).contains(k))
+        };
+        let (motif, other): (Vec<usize>, Vec<usize>) = (0..influence.len()).partition(in_motif);
+        let mean_influence =
+            |ks: &[usize]| ks.iter().map(|k| influence[*k]).sum::<f32>() / ks.len() as f32;
+        assert!(mean_influence(&motif) >= 3.0, "{}", mean_influence(&motif));
+        assert!(mean_influence(&other) < 0.5, "{}", mean_influence(&other));
+    }
+
+    #[test]
+    fn test_match_fraction() {
+        let data: Vec<f32> = (0..50).map(|x| ((x * 7) % 11) as f32).collect();
+        assert_eq!(
+            1.0,
+            match_counts(2, 100.0, &data).match_fraction(2, data.len())
+        );
+        assert_eq!(
+            0.0,
+            match_counts(2, 0.5, &[1.0, 2.0, 3.0, 4.0]).match_fraction(2, 4)
+        );
+        let fraction = match_counts(2, 2.0, &data).match_fraction(2, data.len());
+        assert!(fraction > 0.0 && fraction < 0.5, "{}", fraction);
+        assert!(match_counts(2, 1.0, &[1.0, 2.0])
+            .match_fraction(2, 2)
+            .is_nan());
+    }
+
+    #[test]
+    fn test_segmented_counts_skip_joints() {
+        let mut rng = SplitMix64::new(17);
+        let signal: Vec<f32> = (0..300).map(|_| rng.next_below(6) as f32).collect();
+        assert_eq!(
+            match_counts(2, 1.5, &signal),
+            segmented_match_counts(2, 1.5, std::slice::from_ref(&signal))
+        );
+        assert_eq!(
+            sample_entropy(2, 1.5, &signal),
+            segmented_sample_entropy(2, 1.5, std::slice::from_ref(&signal))
+        );
+
+        let segments: Vec<Vec<f32>> = vec![
+            signal[..100].to_vec(),
+            signal[100..180].to_vec(),
+            signal[180..].to_vec(),
+        ];
+        // By hand: every pair of templates from within any one segment.
+        let templates = |size: usize| -> Vec<&[f32]> {
+            segments
+                .iter()
+                .flat_map(|segment| segment.windows(size))
+                .collect()
+        };
+        let pairs = |size: usize| -> usize {
+            let templates = templates(size);
+            (0..templates.len())
+                .map(|i| {
+                    (i + 1..templates.len())
+                        .filter(|j| is_match(templates[i], templates[*j], &1.5))
+                        .count()
+                })
+                .sum()
+        };
+        let counts = segmented_match_counts(2, 1.5, &segments);
+        assert_eq!(pairs(2), counts.m_matches);
+        // A template at the end of a segment has no extension.
+        assert_eq!(pairs(3), counts.m_plus_1_matches);
+        assert_ne!(counts, match_counts(2, 1.5, &signal));
+    }
+
+    #[test]
+    fn test_approximate_mad_is_close_to_exact() {
+        let mut rng = SplitMix64::new(8);
+        let data: Vec<f32> = (0..300_000)
+            .map(|x| (x as f32 * 0.001).sin() * 3.0 + rng.next_f32() + rng.next_f32())
+            .collect();
+        let exact = median_absolute_deviation(&data);
+        let approximate = approximate_median_absolute_deviation(&data);
+        assert!(
+            ((approximate - exact) / exact).abs() < 0.01,
+            "{} vs {}",
+            approximate,
+            exact
+        );
+        assert!(approximate_median_absolute_deviation(&[1.0, f32::NAN]).is_nan());
+        assert!(approximate_median_absolute_deviation(&[]).is_nan());
+    }
+
+    #[test]
+    fn test_mad_resists_spikes() {
+        assert_eq!(2.5, median(&[4.0, 1.0, 3.0, 2.0]));
+        assert!(median(&[1.0, f32::NAN]).is_nan());
+        assert_eq!(
+            1.4826,
+            median_absolute_deviation(&[1.0, 2.0, 3.0, 4.0, 5.0])
+        );
+        assert!(median_absolute_deviation(&[]).is_nan());
+
+        let mut rng = SplitMix64::new(5);
+        let clean: Vec<f32> = (0..2000)
+            .map(|x| (x as f32 * 0.05).sin() + rng.next_f32() - 0.5)
+            .collect();
+        let mut spiked = clean.clone();
+        for ix in (0..spiked.len()).step_by(100) {
+            spiked[ix] += 50.0;
+        }
+        let relative_change = |tolerance: Tolerance| {
+            let before = tolerance.resolve(&clean);
+            (tolerance.resolve(&spiked) - before) / before
+        };
+        let std_change = relative_change(Tolerance::StdFraction(0.2));
+        let mad_change = relative_change(Tolerance::MadFraction(0.2));
+        assert!(std_change > 2.0, "{}", std_change);
+        assert!(mad_change.abs() < 0.05, "{}", mad_change);
+    }
+
+    #[test]
+    fn test_iqr_resists_outliers_on_heavy_tails() {
+        assert_eq!(2.0, interquartile_range(&[1.0, 2.0, 3.0, 4.0, 5.0]));
+        assert_eq!(1.5, interquartile_range(&[4.0, 1.0, 3.0, 2.0]));
+        assert_eq!(0.0, interquartile_range(&[7.0]));
+        assert!(interquartile_range(&[]).is_nan());
+        assert!(interquartile_range(&[1.0, f32::NAN]).is_nan());
+
+        // Laplace noise, whose tails are heavier than a normal's.
+        let mut rng = SplitMix64::new(8);
+        let clean: Vec<f32> = (0..5000)
+            .map(|_| {
+                let u = rng.next_f32() - 0.5;
+                -u.signum() * (1.0 - 2.0 * u.abs()).max(f32::MIN_POSITIVE).ln()
+            })
+            .collect();
+        let mut spiked = clean.clone();
+        for ix in (0..spiked.len()).step_by(100) {
+            spiked[ix] += if ix % 200 == 0 { 50.0 } else { -50.0 };
+        }
+        let relative_change = |tolerance: Tolerance| {
+            let before = tolerance.resolve(&clean);
+            (tolerance.resolve(&spiked) - before) / before
+        };
+        let std_change = relative_change(Tolerance::StdFraction(0.2));
+        let iqr_change = relative_change(Tolerance::IqrFraction(0.15));
+        assert!(std_change > 1.0, "{}", std_change);
+        assert!(iqr_change.abs() < 0.05, "{}", iqr_change);
+
+        let approximate = approximate_interquartile_range(&clean);
+        let exact = interquartile_range(&clean);
+        assert!(
+            (approximate - exact).abs() < 0.02 * exact,
+            "{} vs {}",
+            approximate,
+            exact
+        );
+    }
+
+    #[test]
+    fn test_empty_input() {
+        assert!(mean(&[]).is_nan());
+        assert!(standard_deviation(&[]).is_nan());
+        assert_eq!(Vec::<f32>::new(), detrend_data(&[]));
+        let mut out: Vec<f32> = vec![1.0, 2.0];
+        let (slope, intercept) = detrend_into(&[], &mut out);
+        assert!(out.is_empty());
+        assert!(slope.is_nan() && intercept.is_nan());
+    }
+
+    #[test]
+    fn test_normal_quantile() {
+        assert!(normal_quantile(0.5).abs() < 1e-9);
+        assert!((normal_quantile(0.975) - 1.959964).abs() < 1e-6);
+        assert!((normal_quantile(0.005) + 2.575829).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_sample_entropy_bounds() {
+        let mut rng = SplitMix64::new(8);
+        let noise = rng.uniform_vec(2000);
+        let r = 0.2 * standard_deviation(&noise);
+        let width = |len: usize| {
+            let (lower, point, upper) = sample_entropy_bounds(2, r, &noise[..len], 0.95);
+            assert!(
+                lower < point && point < upper,
+                "{} {} {}",
+                lower,
+                point,
+                upper
+            );
+            assert_eq!(sample_entropy(2, r, &noise[..len]), point);
+            upper - lower
+        };
+        assert!(width(500) > width(2000));
+
+        let (narrow_lower, _, narrow_upper) = sample_entropy_bounds(2, r, &noise, 0.5);
+        let (wide_lower, _, wide_upper) = sample_entropy_bounds(2, r, &noise, 0.99);
+        assert!(wide_lower < narrow_lower && narrow_upper < wide_upper);
+        assert!(sample_entropy_bounds(2, r, &noise, 1.0).0.is_nan());
+    }
+
+    #[test]
+    fn test_approximate_entropy() {
+        // A period-2 series is perfectly regular: every template matches
+        // exactly the half of the templates in phase with it.
+        let alternating: Vec<f32> = (0..400).map(|x| (x % 2) as f32).collect();
+        let regular = approximate_entropy(2, 0.5, &alternating);
+        assert!(regular.abs() < 0.01, "{}", regular);
+
+        let mut rng = SplitMix64::new(6);
+        let noise = rng.uniform_vec(1000);
+        let r = 0.2 * standard_deviation(&noise);
+        let irregular = approximate_entropy(2, r, &noise);
+        assert!(irregular > 1.0, "{}", irregular);
+        // The self-matches bias approximate entropy below sample entropy.
+        assert!(irregular < sample_entropy(2, r, &noise));
+
+        let matches = uninterrupted(template_matches(2, r, &noise, &Interrupt::default()));
+        assert_eq!(match_counts(2, r, &noise), matches.counts);
+        assert!(approximate_entropy(2, r, &noise[..2]).is_nan());
+    }
+
+    #[test]
+    fn test_m2_matches_general_path() {
+        let mut rng = SplitMix64::new(37);
+        let mut signals: Vec<Vec<f32>> = Vec::new();
+        for trial in 0..30 {
+            let len = rng.next_below(300);
+            let mut data: Vec<f32> = if trial % 2 == 0 {
+                (0..len).map(|_| rng.next_below(8) as f32).collect()
+            } else {
+                (0..len).map(|_| rng.next_f32() * 4.0 - 2.0).collect()
+            };
+            if trial % 5 == 0 && len > 0 {
+                data[rng.next_below(len)] = f32::NAN;
+                data[rng.next_below(len)] = f32::INFINITY;
+            }
+            signals.push(data);
+        }
+        signals.push((0..1000).map(|x| (x as f32 * 0.1).sin()).collect());
+        signals.push((0..500).map(|x| ((x * 13) % 17) as f32).collect());
+        signals.push(vec![1.0; 50]);
+        signals.push(vec![1.0, 2.0, 3.0]);
+        for (i, data) in signals.iter().enumerate() {
+            for r in [0.0, 0.3, 1.0, 2.5, f32::NAN] {
+                let brute = uninterrupted(matching::count_match_pairs(
+                    MatchStrategy::BruteForce,
+                    data,
+                    2,
+                    r,
+                    &mut matching::MatchScratch::default(),
+                    &Interrupt::default(),
+                ));
+                let m2 = uninterrupted(match_counts_m2(r, data, &Interrupt::default()));
+                assert_eq!(brute, m2, "signal {} r {}", i, r);
+                let general = sample_entropy_with_strategy(2, r, data, MatchStrategy::BruteForce);
+                let specialized = sample_entropy_m2(r, data);
+                assert!(
+                    general.to_bits() == specialized.to_bits()
+                        || (general.is_nan() && specialized.is_nan()),
+                    "signal {} r {}",
+                    i,
+                    r
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_conditional_probability() {
+        let data: Vec<f32> = (0..200)
+            .map(|x| ((x * x * 7 + x * 3) % 23) as f32)
+            .collect();
+        let probability = conditional_probability(2, 3.0, &data);
+        assert!(probability > 0.0 && probability < 1.0);
+        assert_eq!(sample_entropy(2, 3.0, &data), -probability.ln());
+
+        let halves: MatchCounts = data
+            .chunks(100)
+            .map(|half| match_counts(2, 3.0, half))
+            .sum();
+        let first = match_counts(2, 3.0, &data[..100]);
+        let second = match_counts(2, 3.0, &data[100..]);
+        assert_eq!(first + second, halves);
+    }
+
+    #[test]
+    fn test_checked_sample_entropy_needs_two_m_plus_1_templates() {
+        let data = [1.0, 2.0, 1.0];
+        assert!(matches!(
+            checked_sample_entropy(2, 0.5, &data),
+            Err(SampenError::InsufficientDataForMPlus1 { m: 2, len: 3 })
+        ));
+        let data = [1.0, 2.0, 1.0, 2.0, 1.0, 2.0];
+        assert_eq!(
+            sample_entropy(2, 0.5, &data),
+            checked_sample_entropy(2, 0.5, &data).unwrap()
+        );
+        for r in [0.0, -1.0, f32::NAN] {
+            assert!(matches!(
+                checked_sample_entropy(2, r, &data),
+                Err(SampenError::InvalidTolerance(_))
+            ));
+        }
     }
 }
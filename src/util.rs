@@ -0,0 +1,46 @@
+//! Sorting helpers for floats, which are not `Ord` because of NaN.
+//!
+//! NaN policy: a NaN has no place in an ordering, so none of these helpers
+//! ever sorts one into its output. `sorted_without_nan` rejects data that
+//! contains a NaN, for statistics like the median where dropping samples
+//! would silently change the answer; `sorted_finite` drops NaN and the
+//! infinities, for uses that only care about the finite values present.
+//! Neither relies on `partial_cmp().unwrap()`, and neither panics.
+
+/// Returns the data sorted in increasing order, or None if it contains a
+/// NaN. Infinities sort to the ends.
+pub(crate) fn sorted_without_nan(data: &[f32]) -> Option<Vec<f32>> {
+    if data.iter().any(|x| x.is_nan()) {
+        return None;
+    }
+    let mut sorted: Vec<f32> = data.to_vec();
+    sorted.sort_by(f32::total_cmp);
+    Some(sorted)
+}
+
+/// Returns the finite values of the data sorted in increasing order,
+/// leaving out NaN and infinite values.
+pub(crate) fn sorted_finite(data: &[f32]) -> Vec<f32> {
+    let mut sorted: Vec<f32> = data.iter().copied().filter(|x| x.is_finite()).collect();
+    sorted.sort_by(f32::total_cmp);
+    sorted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nan_is_rejected_or_filtered() {
+        let data = [3.0, f32::NAN, -1.0, f32::INFINITY, 2.0];
+        assert_eq!(None, sorted_without_nan(&data));
+        assert_eq!(vec![-1.0, 2.0, 3.0], sorted_finite(&data));
+
+        let no_nan = [3.0, f32::NEG_INFINITY, -0.0, 0.0, 2.0];
+        let sorted = sorted_without_nan(&no_nan).unwrap();
+        assert_eq!(vec![f32::NEG_INFINITY, 0.0, 0.0, 2.0, 3.0], sorted);
+        assert!(sorted[1].is_sign_negative());
+        assert_eq!(Some(Vec::new()), sorted_without_nan(&[]));
+        assert!(sorted_finite(&[f32::NAN, f32::INFINITY]).is_empty());
+    }
+}
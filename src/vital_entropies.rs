@@ -1,10 +1,850 @@
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::interrupt::Interrupted;
+use crate::stats;
+
+#[cfg(feature = "msgpack")]
+use crate::error::SampenError;
+
+/// The outcome of computing the entropies of a file.
+///
+/// Variants are ordered from best to worst, and a file's status is the worst
+/// of its channels' statuses. The entropies of channels that didn't compute
+/// are NaN.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// Every channel computed.
+    #[default]
+    Ok,
+    /// A channel has the same value throughout after preprocessing, or only
+    /// rounding error is left of it, so its entropy says nothing (and an `r`
+    /// relative to its spread is zero or measures the rounding). For the
+    /// instantaneous frequency, the channel is a sinusoid, whose frequency
+    /// is constant.
+    Flat,
+    /// A channel has fewer than m+2 samples, too few for two length-(m+1)
+    /// templates, or too few cycles to derive the signal from.
+    TooShort,
+    /// A channel isn't in the file (see `VitalFile::missing_channels`), so
+    /// it has no entropy, while the others were computed.
+    MissingChannel,
+    /// The file ran past its time budget before every channel finished.
+    Timeout,
+    /// The file could not be read, so no channel was computed.
+    ParseError,
+}
+
+impl From<Interrupted> for Status {
+    fn from(_: Interrupted) -> Self {
+        Status::Timeout
+    }
+}
 
 /// Struct to store the name along with the entropy values.
+///
+/// `status` says whether every entropy was computed, and if not why (see
+/// `Status`). `unreliable` is set when a channel's entropy was computed from
+/// fewer matches than the configured minimum, so it is too noisy to trust.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VitalEntropies {
     pub name: String,
     pub sbp_sampen: f32,
     pub mbp_sampen: f32,
     pub dbp_sampen: f32,
+    /// The weighted average of the channels' entropies, if weights were
+    /// given (see `VitalEntropies::composite_score`).
+    #[serde(default)]
+    pub composite: Option<f32>,
+    pub status: Status,
+    pub unreliable: bool,
+    /// The range of samples analyzed, if only part of each channel was
+    /// (see `vital_file::SampleRange`): the first sample, and one past the
+    /// last.
+    #[serde(default)]
+    pub range_start: Option<usize>,
+    #[serde(default)]
+    pub range_end: Option<usize>,
+    /// The channels the file doesn't have, separated by `;`, empty if it has
+    /// them all.
+    #[serde(default)]
+    pub missing_channels: String,
+    /// The fraction of each channel's pairs of length-m templates that
+    /// matched (see `MatchCounts::match_fraction`), None for a channel whose
+    /// matches weren't counted. Between about 0.1 and 0.3 `r` suits the
+    /// channel; near 0 too few pairs match for a stable entropy, and near 1
+    /// nearly all do and the entropy tends to 0 whatever the signal, so rows
+    /// outside that range are worth filtering out.
+    #[serde(default)]
+    pub sbp_match_fraction: Option<f32>,
+    #[serde(default)]
+    pub mbp_match_fraction: Option<f32>,
+    #[serde(default)]
+    pub dbp_match_fraction: Option<f32>,
+}
+
+/// A single channel's entropy, used when writing results in long format.
+/// `status` and the range are those of the whole file, so `missing` tells
+/// the row of a channel the file doesn't have from those it computed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChannelEntropy {
+    pub name: String,
+    pub channel: String,
+    pub sampen: f32,
+    pub status: Status,
+    pub unreliable: bool,
+    #[serde(default)]
+    pub range_start: Option<usize>,
+    #[serde(default)]
+    pub range_end: Option<usize>,
+    /// Whether the file doesn't have the channel, whose entropy is then NaN.
+    #[serde(default)]
+    pub missing: bool,
+    /// The channel's match fraction, None for the composite row.
+    #[serde(default)]
+    pub match_fraction: Option<f32>,
+}
+
+/// The entropy of one window of a channel, a row of the rolling output.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WindowEntropy {
+    pub name: String,
+    pub channel: String,
+    /// The position of the window among the channel's windows, from 0.
+    pub window_index: usize,
+    /// The index of the window's first sample in the file as read, before
+    /// any range was cut out.
+    pub start_sample: usize,
+    pub sampen: f32,
+}
+
+impl WindowEntropy {
+    /// Rounds the entropy to `decimals` decimal places, like
+    /// `VitalEntropies::round`.
+    pub fn round(&mut self, decimals: u32) {
+        self.sampen = round_to(self.sampen, decimals);
+    }
+}
+
+/// Rounds to the given number of decimals, leaving NaN and infinities as
+/// they are.
+fn round_to(value: f32, decimals: u32) -> f32 {
+    let scale = 10_f64.powi(decimals as i32);
+    ((value as f64 * scale).round() / scale) as f32
+}
+
+/// Results written either as one wide row per file or, unpivoted, as one
+/// long row per channel.
+pub trait ToLong {
+    /// A single channel's row in long format.
+    type Row: Serialize;
+
+    /// Unpivots the per-channel columns into one row per channel.
+    fn to_long(&self) -> Vec<Self::Row>;
+}
+
+impl VitalEntropies {
+    /// Rounds every entropy to `decimals` decimal places.
+    ///
+    /// This is only for stable output: entropies that differ in the last few
+    /// bits between platforms, e.g. from a different summation order, round
+    /// to the same value and so print identically. The computation itself is
+    /// unaffected.
+    pub fn round(&mut self, decimals: u32) {
+        for sampen in [
+            &mut self.sbp_sampen,
+            &mut self.mbp_sampen,
+            &mut self.dbp_sampen,
+        ] {
+            *sampen = round_to(*sampen, decimals);
+        }
+        if let Some(composite) = self.composite.as_mut() {
+            *composite = round_to(*composite, decimals);
+        }
+    }
+
+    /// The weighted average of the channels' entropies, as a single score
+    /// for the file.
+    ///
+    /// `weights` maps channel names (`sbp`, `mbp`, `dbp`) to their weights.
+    /// Channels whose entropy is NaN or infinite, e.g. because they were
+    /// flat, too short or timed out, are left out, as are channels without a
+    /// weight, and the remaining weights are renormalized to sum to 1. So a
+    /// file whose sbp failed scores the weighted average of its mbp and dbp
+    /// rather than a pulled-down or NaN score. NaN if no weighted channel is
+    /// finite, or their weights sum to zero.
+    pub fn composite_score(&self, weights: &HashMap<String, f32>) -> f32 {
+        let (weighted_sum, total_weight) = [
+            ("sbp", self.sbp_sampen),
+            ("mbp", self.mbp_sampen),
+            ("dbp", self.dbp_sampen),
+        ]
+        .into_iter()
+        .filter(|(_, sampen)| sampen.is_finite())
+        .filter_map(|(channel, sampen)| weights.get(channel).map(|weight| (sampen, *weight)))
+        .fold((0.0, 0.0), |(sum, total), (sampen, weight)| {
+            (sum + weight * sampen, total + weight)
+        });
+        weighted_sum / total_weight
+    }
+
+    /// The row of a file that could not be read.
+    pub fn parse_error(name: String) -> Self {
+        VitalEntropies {
+            name,
+            sbp_sampen: f32::NAN,
+            mbp_sampen: f32::NAN,
+            dbp_sampen: f32::NAN,
+            composite: None,
+            status: Status::ParseError,
+            unreliable: false,
+            range_start: None,
+            range_end: None,
+            missing_channels: String::new(),
+            sbp_match_fraction: None,
+            mbp_match_fraction: None,
+            dbp_match_fraction: None,
+        }
+    }
+}
+
+impl ToLong for VitalEntropies {
+    type Row = ChannelEntropy;
+
+    /// Unpivots the per-channel columns into one row per channel, followed by
+    /// a `composite` row if the composite score was computed.
+    fn to_long(&self) -> Vec<Self::Row> {
+        [
+            ("sbp", Some(self.sbp_sampen), self.sbp_match_fraction),
+            ("mbp", Some(self.mbp_sampen), self.mbp_match_fraction),
+            ("dbp", Some(self.dbp_sampen), self.dbp_match_fraction),
+            ("composite", self.composite, None),
+        ]
+        .into_iter()
+        .filter_map(|(channel, sampen, fraction)| sampen.map(|sampen| (channel, sampen, fraction)))
+        .map(|(channel, sampen, match_fraction)| ChannelEntropy {
+            name: self.name.clone(),
+            channel: channel.to_string(),
+            sampen,
+            status: self.status,
+            unreliable: self.unreliable,
+            range_start: self.range_start,
+            range_end: self.range_end,
+            missing: self
+                .missing_channels
+                .split(';')
+                .any(|missing| missing == channel),
+            match_fraction,
+        })
+        .collect()
+    }
+}
+
+/// The entropies of a file computed on both its raw and its detrended waves,
+/// showing how much detrending changes each channel's result.
+///
+/// `status` is the worse of the two computations' statuses, and `unreliable`
+/// is set if either computation set it. Both computations analyze the same
+/// range.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DetrendingComparison {
+    pub name: String,
+    pub sbp_sampen_raw: f32,
+    pub sbp_sampen_detrended: f32,
+    pub mbp_sampen_raw: f32,
+    pub mbp_sampen_detrended: f32,
+    pub dbp_sampen_raw: f32,
+    pub dbp_sampen_detrended: f32,
+    pub status: Status,
+    pub unreliable: bool,
+    #[serde(default)]
+    pub range_start: Option<usize>,
+    #[serde(default)]
+    pub range_end: Option<usize>,
+}
+
+/// A single channel's raw and detrended entropies, used when writing a
+/// comparison in long format.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChannelComparison {
+    pub name: String,
+    pub channel: String,
+    pub sampen_raw: f32,
+    pub sampen_detrended: f32,
+    pub status: Status,
+    pub unreliable: bool,
+    #[serde(default)]
+    pub range_start: Option<usize>,
+    #[serde(default)]
+    pub range_end: Option<usize>,
+}
+
+impl DetrendingComparison {
+    /// Rounds every entropy to `decimals` decimal places, like
+    /// `VitalEntropies::round`.
+    pub fn round(&mut self, decimals: u32) {
+        for sampen in [
+            &mut self.sbp_sampen_raw,
+            &mut self.sbp_sampen_detrended,
+            &mut self.mbp_sampen_raw,
+            &mut self.mbp_sampen_detrended,
+            &mut self.dbp_sampen_raw,
+            &mut self.dbp_sampen_detrended,
+        ] {
+            *sampen = round_to(*sampen, decimals);
+        }
+    }
+
+    /// Pairs the results of the same file computed without and with
+    /// detrending.
+    pub fn new(raw: &VitalEntropies, detrended: &VitalEntropies) -> Self {
+        DetrendingComparison {
+            name: raw.name.clone(),
+            sbp_sampen_raw: raw.sbp_sampen,
+            sbp_sampen_detrended: detrended.sbp_sampen,
+            mbp_sampen_raw: raw.mbp_sampen,
+            mbp_sampen_detrended: detrended.mbp_sampen,
+            dbp_sampen_raw: raw.dbp_sampen,
+            dbp_sampen_detrended: detrended.dbp_sampen,
+            status: raw.status.max(detrended.status),
+            unreliable: raw.unreliable || detrended.unreliable,
+            range_start: raw.range_start,
+            range_end: raw.range_end,
+        }
+    }
+}
+
+impl ToLong for DetrendingComparison {
+    type Row = ChannelComparison;
+
+    fn to_long(&self) -> Vec<Self::Row> {
+        [
+            ("sbp", self.sbp_sampen_raw, self.sbp_sampen_detrended),
+            ("mbp", self.mbp_sampen_raw, self.mbp_sampen_detrended),
+            ("dbp", self.dbp_sampen_raw, self.dbp_sampen_detrended),
+        ]
+        .into_iter()
+        .map(
+            |(channel, sampen_raw, sampen_detrended)| ChannelComparison {
+                name: self.name.clone(),
+                channel: channel.to_string(),
+                sampen_raw,
+                sampen_detrended,
+                status: self.status,
+                unreliable: self.unreliable,
+                range_start: self.range_start,
+                range_end: self.range_end,
+            },
+        )
+        .collect()
+    }
+}
+
+/// The entropies of the first and second halves of a file, for spotting
+/// channels whose complexity drifts over the recording.
+///
+/// A channel is `nonstationary` when its halves' entropies differ by more
+/// than the threshold given to `HalvesComparison::new`. The difference of a
+/// channel with a NaN half is NaN, and never flagged. `status` and
+/// `unreliable` combine the halves' like `DetrendingComparison`'s; the range
+/// is the one split in two.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HalvesComparison {
+    pub name: String,
+    pub sbp_sampen_first: f32,
+    pub sbp_sampen_second: f32,
+    pub sbp_difference: f32,
+    pub sbp_nonstationary: bool,
+    pub mbp_sampen_first: f32,
+    pub mbp_sampen_second: f32,
+    pub mbp_difference: f32,
+    pub mbp_nonstationary: bool,
+    pub dbp_sampen_first: f32,
+    pub dbp_sampen_second: f32,
+    pub dbp_difference: f32,
+    pub dbp_nonstationary: bool,
+    pub status: Status,
+    pub unreliable: bool,
+    #[serde(default)]
+    pub range_start: Option<usize>,
+    #[serde(default)]
+    pub range_end: Option<usize>,
+}
+
+/// A single channel's entropies of both halves, used when writing a halves
+/// comparison in long format.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChannelHalves {
+    pub name: String,
+    pub channel: String,
+    pub sampen_first: f32,
+    pub sampen_second: f32,
+    pub difference: f32,
+    pub nonstationary: bool,
+    pub status: Status,
+    pub unreliable: bool,
+    #[serde(default)]
+    pub range_start: Option<usize>,
+    #[serde(default)]
+    pub range_end: Option<usize>,
+}
+
+impl HalvesComparison {
+    /// Pairs the results of a file's first and second halves (see
+    /// `VitalFile::halves`), flagging the channels whose entropies differ by
+    /// more than `max_difference`.
+    pub fn new(first: &VitalEntropies, second: &VitalEntropies, max_difference: f32) -> Self {
+        let difference = |first: f32, second: f32| (first - second).abs();
+        let (sbp, mbp, dbp) = (
+            difference(first.sbp_sampen, second.sbp_sampen),
+            difference(first.mbp_sampen, second.mbp_sampen),
+            difference(first.dbp_sampen, second.dbp_sampen),
+        );
+        HalvesComparison {
+            name: first.name.clone(),
+            sbp_sampen_first: first.sbp_sampen,
+            sbp_sampen_second: second.sbp_sampen,
+            sbp_difference: sbp,
+            sbp_nonstationary: sbp > max_difference,
+            mbp_sampen_first: first.mbp_sampen,
+            mbp_sampen_second: second.mbp_sampen,
+            mbp_difference: mbp,
+            mbp_nonstationary: mbp > max_difference,
+            dbp_sampen_first: first.dbp_sampen,
+            dbp_sampen_second: second.dbp_sampen,
+            dbp_difference: dbp,
+            dbp_nonstationary: dbp > max_difference,
+            status: first.status.max(second.status),
+            unreliable: first.unreliable || second.unreliable,
+            range_start: first.range_start,
+            range_end: first.range_end,
+        }
+    }
+
+    /// Rounds every entropy and difference to `decimals` decimal places, like
+    /// `VitalEntropies::round`. The flags keep the unrounded differences'.
+    pub fn round(&mut self, decimals: u32) {
+        for value in [
+            &mut self.sbp_sampen_first,
+            &mut self.sbp_sampen_second,
+            &mut self.sbp_difference,
+            &mut self.mbp_sampen_first,
+            &mut self.mbp_sampen_second,
+            &mut self.mbp_difference,
+            &mut self.dbp_sampen_first,
+            &mut self.dbp_sampen_second,
+            &mut self.dbp_difference,
+        ] {
+            *value = round_to(*value, decimals);
+        }
+    }
+}
+
+impl ToLong for HalvesComparison {
+    type Row = ChannelHalves;
+
+    fn to_long(&self) -> Vec<Self::Row> {
+        [
+            (
+                "sbp",
+                self.sbp_sampen_first,
+                self.sbp_sampen_second,
+                self.sbp_difference,
+                self.sbp_nonstationary,
+            ),
+            (
+                "mbp",
+                self.mbp_sampen_first,
+                self.mbp_sampen_second,
+                self.mbp_difference,
+                self.mbp_nonstationary,
+            ),
+            (
+                "dbp",
+                self.dbp_sampen_first,
+                self.dbp_sampen_second,
+                self.dbp_difference,
+                self.dbp_nonstationary,
+            ),
+        ]
+        .into_iter()
+        .map(
+            |(channel, sampen_first, sampen_second, difference, nonstationary)| ChannelHalves {
+                name: self.name.clone(),
+                channel: channel.to_string(),
+                sampen_first,
+                sampen_second,
+                difference,
+                nonstationary,
+                status: self.status,
+                unreliable: self.unreliable,
+                range_start: self.range_start,
+                range_end: self.range_end,
+            },
+        )
+        .collect()
+    }
+}
+
+/// Aggregate statistics of one channel's entropies across a cohort.
+///
+/// Only finite entropies are aggregated; `excluded` counts the NaN and
+/// infinite ones left out. Every statistic is NaN if nothing was aggregated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelSummary {
+    pub channel: &'static str,
+    /// The number of finite entropies aggregated.
+    pub count: usize,
+    pub excluded: usize,
+    pub mean: f32,
+    pub median: f32,
+    pub std: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl ChannelSummary {
+    fn new(channel: &'static str, entropies: &[f32]) -> Self {
+        let finite: Vec<f32> = entropies
+            .iter()
+            .copied()
+            .filter(|x| x.is_finite())
+            .collect();
+        let (mean, std) = if finite.is_empty() {
+            (f32::NAN, f32::NAN)
+        } else {
+            (stats::mean(&finite), stats::standard_deviation(&finite))
+        };
+        ChannelSummary {
+            channel,
+            count: finite.len(),
+            excluded: entropies.len() - finite.len(),
+            mean,
+            median: stats::median(&finite),
+            std,
+            min: finite.iter().copied().reduce(f32::min).unwrap_or(f32::NAN),
+            max: finite.iter().copied().reduce(f32::max).unwrap_or(f32::NAN),
+        }
+    }
+}
+
+/// Aggregate statistics of the entropies of every file in a cohort, as
+/// computed by `summarize`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CohortSummary {
+    pub num_files: usize,
+    /// The number of files with each status, leaving out statuses no file has.
+    pub statuses: BTreeMap<Status, usize>,
+    /// The sbp, mbp and dbp summaries, in that order.
+    pub channels: [ChannelSummary; 3],
+}
+
+/// Summarizes the entropies of a cohort per channel.
+///
+/// # Arguments
+/// * `results` - the entropies of every file.
+///
+pub fn summarize(results: &[VitalEntropies]) -> CohortSummary {
+    let mut statuses: BTreeMap<Status, usize> = BTreeMap::new();
+    for result in results {
+        *statuses.entry(result.status).or_default() += 1;
+    }
+    let channel = |name: &'static str, sampen: fn(&VitalEntropies) -> f32| {
+        let entropies: Vec<f32> = results.iter().map(sampen).collect();
+        ChannelSummary::new(name, &entropies)
+    };
+    CohortSummary {
+        num_files: results.len(),
+        statuses,
+        channels: [
+            channel("sbp", |result| result.sbp_sampen),
+            channel("mbp", |result| result.mbp_sampen),
+            channel("dbp", |result| result.dbp_sampen),
+        ],
+    }
+}
+
+impl fmt::Display for CohortSummary {
+    /// A short table of the per-channel statistics, preceded by the status
+    /// counts.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} files:", self.num_files)?;
+        for (status, count) in self.statuses.iter() {
+            write!(f, " {} {:?},", count, status)?;
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "channel    count excluded     mean   median      std      min      max"
+        )?;
+        for channel in self.channels.iter() {
+            writeln!(
+                f,
+                "{:<7} {:>8} {:>8} {:>8.4} {:>8.4} {:>8.4} {:>8.4} {:>8.4}",
+                channel.channel,
+                channel.count,
+                channel.excluded,
+                channel.mean,
+                channel.median,
+                channel.std,
+                channel.min,
+                channel.max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The version of the MessagePack results layout written by `write_msgpack`.
+///
+/// MessagePack files carry field names but no schema, so a reader built
+/// against a different `VitalEntropies` could silently misread them (e.g. a
+/// renamed field becoming missing). The version is written alongside the
+/// results and must be bumped whenever a field is renamed, removed or changes
+/// meaning; `read_msgpack` refuses files of any other version.
+///
+/// Version 2 replaced `timed_out` with `status`.
+#[cfg(feature = "msgpack")]
+pub const MSGPACK_VERSION: u32 = 2;
+
+#[cfg(feature = "msgpack")]
+#[derive(Serialize)]
+struct MsgpackResults<'a> {
+    version: u32,
+    /// The `provenance::provenance` of the build that wrote the results.
+    producer: String,
+    entropies: &'a [VitalEntropies],
+}
+
+#[cfg(feature = "msgpack")]
+#[derive(Deserialize)]
+struct OwnedMsgpackResults {
+    version: u32,
+    // Absent from files written before it was added.
+    #[serde(default)]
+    producer: Option<String>,
+    entropies: Vec<VitalEntropies>,
+}
+
+/// Writes the results as MessagePack, tagged with `MSGPACK_VERSION` and the
+/// `provenance::provenance` of this build.
+///
+/// Every field is stored under its name, and NaN entropies are stored as
+/// NaN floats, so the results read back by `read_msgpack` are identical.
+///
+/// # Arguments
+/// * `entropies` - the results to write.
+/// * `wtr` - where the MessagePack is written.
+///
+#[cfg(feature = "msgpack")]
+pub fn write_msgpack<W: std::io::Write>(
+    entropies: &[VitalEntropies],
+    wtr: &mut W,
+) -> Result<(), SampenError> {
+    let results = MsgpackResults {
+        version: MSGPACK_VERSION,
+        producer: crate::provenance::provenance(),
+        entropies,
+    };
+    rmp_serde::encode::write_named(wtr, &results)?;
+    Ok(())
+}
+
+/// Reads results written by `write_msgpack`.
+///
+/// # Arguments
+/// * `rdr` - the source of the MessagePack data.
+///
+#[cfg(feature = "msgpack")]
+pub fn read_msgpack<R: std::io::Read>(rdr: R) -> Result<Vec<VitalEntropies>, SampenError> {
+    read_msgpack_with_producer(rdr).map(|(entropies, _)| entropies)
+}
+
+/// Reads results written by `write_msgpack` along with the build that wrote
+/// them, `None` for files from before builds were recorded.
+///
+/// # Arguments
+/// * `rdr` - the source of the MessagePack data.
+///
+#[cfg(feature = "msgpack")]
+pub fn read_msgpack_with_producer<R: std::io::Read>(
+    rdr: R,
+) -> Result<(Vec<VitalEntropies>, Option<String>), SampenError> {
+    let results: OwnedMsgpackResults = rmp_serde::decode::from_read(rdr)?;
+    if results.version != MSGPACK_VERSION {
+        return Err(SampenError::UnsupportedVersion {
+            found: results.version,
+            expected: MSGPACK_VERSION,
+        });
+    }
+    Ok((results.entropies, results.producer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(name: &str, sampens: [f32; 3], status: Status) -> VitalEntropies {
+        VitalEntropies {
+            name: String::from(name),
+            sbp_sampen: sampens[0],
+            mbp_sampen: sampens[1],
+            dbp_sampen: sampens[2],
+            composite: None,
+            status,
+            unreliable: false,
+            range_start: None,
+            range_end: None,
+            missing_channels: String::new(),
+            sbp_match_fraction: None,
+            mbp_match_fraction: None,
+            dbp_match_fraction: None,
+        }
+    }
+
+    #[test]
+    fn test_summarize_excludes_non_finite() {
+        let results = vec![
+            result("a", [1.0, 0.5, 2.0], Status::Ok),
+            result("b", [2.0, f32::NAN, 2.0], Status::Flat),
+            result("c", [3.0, 1.5, f32::INFINITY], Status::Ok),
+            result("d", [6.0, f32::NAN, f32::NAN], Status::Timeout),
+        ];
+        let summary = summarize(&results);
+        assert_eq!(4, summary.num_files);
+        assert_eq!(
+            vec![(Status::Ok, 2), (Status::Flat, 1), (Status::Timeout, 1)],
+            summary.statuses.into_iter().collect::<Vec<_>>()
+        );
+        let [sbp, mbp, dbp] = summary.channels;
+        assert_eq!(("sbp", 4, 0), (sbp.channel, sbp.count, sbp.excluded));
+        assert_eq!(3.0, sbp.mean);
+        assert_eq!(2.5, sbp.median);
+        assert_eq!(stats::standard_deviation(&[1.0, 2.0, 3.0, 6.0]), sbp.std);
+        assert_eq!((1.0, 6.0), (sbp.min, sbp.max));
+        assert_eq!((2, 2), (mbp.count, mbp.excluded));
+        assert_eq!(1.0, mbp.median);
+        assert_eq!(
+            (2, 2, 2.0, 0.0),
+            (dbp.count, dbp.excluded, dbp.mean, dbp.std)
+        );
+    }
+
+    #[test]
+    fn test_composite_score() {
+        let equal: HashMap<String, f32> = ["sbp", "mbp", "dbp"]
+            .into_iter()
+            .map(|channel| (channel.to_string(), 1.0))
+            .collect();
+        let entropies = result("a", [0.6, 0.9, 1.5], Status::Ok);
+        let mean = stats::mean(&[0.6, 0.9, 1.5]);
+        assert!((entropies.composite_score(&equal) - mean).abs() < 1e-6);
+
+        // A failed channel is left out and the other weights renormalized.
+        let flat = result("b", [0.6, f32::NAN, 1.5], Status::Flat);
+        assert!((flat.composite_score(&equal) - 1.05).abs() < 1e-6);
+        let sbp_heavy: HashMap<String, f32> =
+            [(String::from("sbp"), 3.0), (String::from("dbp"), 1.0)].into();
+        assert!((flat.composite_score(&sbp_heavy) - 0.825).abs() < 1e-6);
+        let only_mbp: HashMap<String, f32> = [(String::from("mbp"), 1.0)].into();
+        assert!(flat.composite_score(&only_mbp).is_nan());
+    }
+
+    #[test]
+    fn test_round() {
+        let mut entropies = result("a", [1.234_567_9, f32::NAN, f32::INFINITY], Status::Ok);
+        entropies.round(4);
+        assert_eq!(1.2346, entropies.sbp_sampen);
+        assert!(entropies.mbp_sampen.is_nan());
+        assert_eq!(f32::INFINITY, entropies.dbp_sampen);
+        entropies.round(0);
+        assert_eq!(1.0, entropies.sbp_sampen);
+    }
+
+    #[test]
+    fn test_summarize_empty_cohort() {
+        let summary = summarize(&[]);
+        assert_eq!(0, summary.num_files);
+        assert!(summary.channels.iter().all(|channel| channel.mean.is_nan()
+            && channel.median.is_nan()
+            && channel.max.is_nan()));
+        assert!(summary.to_string().starts_with("0 files:"));
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack_round_trip() {
+        let entropies = vec![
+            VitalEntropies {
+                name: String::from("case1"),
+                sbp_sampen: 0.5,
+                mbp_sampen: f32::NAN,
+                dbp_sampen: f32::INFINITY,
+                composite: None,
+                status: Status::Timeout,
+                unreliable: false,
+                range_start: None,
+                range_end: None,
+                missing_channels: String::new(),
+                sbp_match_fraction: None,
+                mbp_match_fraction: None,
+                dbp_match_fraction: None,
+            },
+            VitalEntropies {
+                name: String::from("case2"),
+                sbp_sampen: 1.25,
+                mbp_sampen: 2.0,
+                dbp_sampen: 0.0,
+                composite: Some(1.5),
+                status: Status::Ok,
+                unreliable: true,
+                range_start: Some(100),
+                range_end: Some(2100),
+                missing_channels: String::from("dbp"),
+                sbp_match_fraction: None,
+                mbp_match_fraction: None,
+                dbp_match_fraction: None,
+            },
+        ];
+        let mut buffer: Vec<u8> = Vec::new();
+        write_msgpack(&entropies, &mut buffer).unwrap();
+        let (read, producer) = read_msgpack_with_producer(buffer.as_slice()).unwrap();
+        assert_eq!(Some(crate::provenance::provenance()), producer);
+        assert_eq!(entropies.len(), read.len());
+        for (expected, actual) in entropies.iter().zip(&read) {
+            assert_eq!(expected.name, actual.name);
+            assert_eq!(expected.sbp_sampen.to_bits(), actual.sbp_sampen.to_bits());
+            assert_eq!(expected.mbp_sampen.to_bits(), actual.mbp_sampen.to_bits());
+            assert_eq!(expected.dbp_sampen.to_bits(), actual.dbp_sampen.to_bits());
+            assert_eq!(expected.composite, actual.composite);
+            assert_eq!(expected.status, actual.status);
+            assert_eq!(expected.unreliable, actual.unreliable);
+            assert_eq!(
+                (expected.range_start, expected.range_end),
+                (actual.range_start, actual.range_end)
+            );
+        }
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn test_msgpack_rejects_other_versions() {
+        let mut buffer: Vec<u8> = Vec::new();
+        let results = MsgpackResults {
+            version: MSGPACK_VERSION + 1,
+            producer: String::new(),
+            entropies: &[],
+        };
+        rmp_serde::encode::write_named(&mut buffer, &results).unwrap();
+        assert!(matches!(
+            read_msgpack(buffer.as_slice()),
+            Err(SampenError::UnsupportedVersion { .. })
+        ));
+        assert!(matches!(
+            read_msgpack(&b"not msgpack"[..]),
+            Err(SampenError::MsgpackDecode(_))
+        ));
+    }
 }
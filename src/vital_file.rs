@@ -0,0 +1,1353 @@
+//! The waveform data for a single vital file, and the readers that load it.
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::error::SampenError;
+use crate::stats;
+
+/// Vital file struct for holding the data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VitalFile {
+    pub name: String,
+    /// Samples per second shared by every channel, if the format records it.
+    pub sample_rate: Option<f32>,
+    pub sbp: Channel,
+    pub mbp: Channel,
+    pub dbp: Channel,
+}
+
+/// The samples of a single waveform channel.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Channel {
+    pub data: Vec<f32>,
+    /// The physical unit of the samples, e.g. `mmHg`, if the format records it.
+    pub unit: Option<String>,
+    /// Whether the file has no such channel at all, e.g. a csv without its
+    /// column. A missing channel has no data, and its entropy isn't computed
+    /// (see `VitalFile::missing_channels`).
+    pub missing: bool,
+}
+
+impl Channel {
+    /// A channel of unknown unit.
+    pub fn new(data: Vec<f32>) -> Self {
+        Channel {
+            data,
+            unit: None,
+            missing: false,
+        }
+    }
+
+    /// A channel the file doesn't have.
+    pub fn missing() -> Self {
+        Channel {
+            missing: true,
+            ..Channel::default()
+        }
+    }
+}
+
+impl VitalFile {
+    /// Builds a file from samples already in memory, e.g. from a reader of
+    /// the caller's own, with an unknown sample rate and units. Fails like
+    /// `validate` if a channel is empty or the channels have different
+    /// lengths; the fields are public, so a file built field by field should
+    /// be checked with `validate`.
+    ///
+    /// # Arguments
+    /// * `name` - the name of the file, e.g. its case id.
+    /// * `sbp` - the systolic blood pressure samples.
+    /// * `mbp` - the mean blood pressure samples.
+    /// * `dbp` - the diastolic blood pressure samples.
+    ///
+    pub fn new(
+        name: String,
+        sbp: Vec<f32>,
+        mbp: Vec<f32>,
+        dbp: Vec<f32>,
+    ) -> Result<Self, SampenError> {
+        let vital_file = VitalFile {
+            name,
+            sample_rate: None,
+            sbp: Channel::new(sbp),
+            mbp: Channel::new(mbp),
+            dbp: Channel::new(dbp),
+        };
+        vital_file.validate()?;
+        Ok(vital_file)
+    }
+
+    /// The channels of the file, paired with their names.
+    pub fn channels(&self) -> [(&'static str, &[f32]); 3] {
+        [
+            ("sbp", &self.sbp.data),
+            ("mbp", &self.mbp.data),
+            ("dbp", &self.dbp.data),
+        ]
+    }
+
+    /// The names of the channels the file doesn't have, in the order of
+    /// `channels`.
+    ///
+    /// A file read without some of its channels keeps the others: their
+    /// entropies are computed as usual, and each missing channel's is NaN
+    /// with `Status::MissingChannel`, which tells it apart from an entropy
+    /// that was computed and came out NaN. Only a file missing every channel
+    /// fails to read.
+    pub fn missing_channels(&self) -> Vec<&'static str> {
+        [("sbp", &self.sbp), ("mbp", &self.mbp), ("dbp", &self.dbp)]
+            .into_iter()
+            .filter(|(_, channel)| channel.missing)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// The number of samples in each channel the file has, or 0 if it has
+    /// none.
+    pub fn num_samples(&self) -> usize {
+        [&self.sbp, &self.mbp, &self.dbp]
+            .into_iter()
+            .find(|channel| !channel.missing)
+            .map_or(0, |channel| channel.data.len())
+    }
+
+    /// The sample rate of the file, or `SampenError::MissingSampleRate` if it
+    /// is unknown.
+    pub fn require_sample_rate(&self) -> Result<f32, SampenError> {
+        self.sample_rate.ok_or(SampenError::MissingSampleRate)
+    }
+
+    /// Computes `stats::epoch_sample_entropy` on every channel, with epochs
+    /// of `epoch_seconds` at the file's sample rate.
+    ///
+    /// # Arguments
+    /// * `m` - the smaller of the two template sizes.
+    /// * `r` - the distance threshold over which a match does not occur.
+    /// * `epoch_seconds` - the duration of each epoch.
+    ///
+    pub fn epoch_sample_entropy(
+        &self,
+        m: usize,
+        r: f32,
+        epoch_seconds: f32,
+    ) -> Result<[(&'static str, Vec<f32>); 3], SampenError> {
+        let sample_rate = self.require_sample_rate()?;
+        Ok(self.channels().map(|(channel, data)| {
+            (
+                channel,
+                stats::epoch_sample_entropy(m, r, data, sample_rate, epoch_seconds),
+            )
+        }))
+    }
+
+    /// Keeps only the samples of every channel within `range`. The sample
+    /// rate and units are unchanged.
+    pub fn restrict(&mut self, range: Range<usize>) {
+        for channel in [&mut self.sbp, &mut self.mbp, &mut self.dbp] {
+            if channel.missing {
+                continue;
+            }
+            channel.data.truncate(range.end);
+            channel.data.drain(..range.start);
+        }
+    }
+
+    /// Splits the file into its first and second halves, the first half
+    /// holding `num_samples() / 2` samples of every channel and the second
+    /// the rest. Both keep the name and sample rate, and missing channels
+    /// stay missing in both.
+    pub fn halves(&self) -> (VitalFile, VitalFile) {
+        let (len, mid) = (self.num_samples(), self.num_samples() / 2);
+        let mut first = self.clone();
+        first.restrict(0..mid);
+        let mut second = self.clone();
+        second.restrict(mid..len);
+        (first, second)
+    }
+
+    /// Checks that every channel the file has is non-empty, that they all
+    /// have the same length, and that the file has at least one.
+    ///
+    /// Readers that fill channels row by row get this by construction, but a
+    /// file assembled by hand or from a format that stores channels separately
+    /// might not, and ragged channels would otherwise give confusing
+    /// per-channel results. Every reader calls this before returning.
+    pub fn validate(&self) -> Result<(), SampenError> {
+        let invalid = |reason: String| SampenError::InvalidFile {
+            name: self.name.clone(),
+            reason,
+        };
+        let missing = self.missing_channels();
+        let lengths: Vec<(&str, usize)> = self
+            .channels()
+            .into_iter()
+            .filter(|(channel, _)| !missing.contains(channel))
+            .map(|(channel, data)| (channel, data.len()))
+            .collect();
+        let Some((first, expected)) = lengths.first().copied() else {
+            return Err(invalid(String::from("every channel is missing")));
+        };
+        if let Some((channel, _)) = lengths.iter().find(|(_, len)| *len == 0) {
+            return Err(invalid(format!("channel {} is empty", channel)));
+        }
+        if let Some((channel, len)) = lengths.iter().find(|(_, len)| *len != expected) {
+            return Err(invalid(format!(
+                "channel {} has {} samples but {} has {}",
+                channel, len, first, expected
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A span of every channel to analyze, e.g. a stable stretch of a long
+/// recording, so it needn't be cut out of the csv beforehand.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleRange {
+    /// Sample indices, from the first to one past the last.
+    Samples(usize, usize),
+    /// Seconds from the start of the recording, converted to samples at the
+    /// file's sample rate.
+    Seconds(f32, f32),
+}
+
+impl FromStr for SampleRange {
+    type Err = SampenError;
+
+    /// Parses `start:end`, in samples, or `start s:end s` (e.g. `60s:120s`)
+    /// in seconds.
+    fn from_str(range: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| SampenError::InvalidParameter(reason);
+        let (start, end) = range
+            .split_once(':')
+            .ok_or_else(|| invalid(format!("expected start:end, got {}", range)))?;
+        let (start, end) = (start.trim(), end.trim());
+        match (start.strip_suffix('s'), end.strip_suffix('s')) {
+            (Some(start), Some(end)) => {
+                let seconds = |bound: &str| {
+                    bound
+                        .trim()
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+                        .ok_or_else(|| invalid(format!("{}s is not a time in seconds", bound)))
+                };
+                Ok(SampleRange::Seconds(seconds(start)?, seconds(end)?))
+            }
+            (None, None) => {
+                let samples = |bound: &str| {
+                    bound
+                        .parse::<usize>()
+                        .map_err(|error| invalid(format!("{}: {}", bound, error)))
+                };
+                Ok(SampleRange::Samples(samples(start)?, samples(end)?))
+            }
+            _ => Err(invalid(format!(
+                "both ends of {} must be in samples or both in seconds",
+                range
+            ))),
+        }
+    }
+}
+
+impl SampleRange {
+    /// Returns the sample indices of the range within `vf`.
+    ///
+    /// Fails with `SampenError::MissingSampleRate` for a range in seconds of
+    /// a file without a sample rate, and with `SampenError::InvalidParameter`
+    /// if the range is empty or runs past the end of the channels.
+    pub fn resolve(&self, vf: &VitalFile) -> Result<Range<usize>, SampenError> {
+        let range = match *self {
+            SampleRange::Samples(start, end) => start..end,
+            SampleRange::Seconds(start, end) => {
+                let sample_rate = vf.require_sample_rate()?;
+                let to_sample = |seconds: f32| (seconds * sample_rate).round() as usize;
+                to_sample(start)..to_sample(end)
+            }
+        };
+        let len = vf.num_samples();
+        if range.is_empty() {
+            return Err(SampenError::InvalidParameter(format!(
+                "range {}..{} of {} is empty",
+                range.start, range.end, vf.name
+            )));
+        }
+        if range.end > len {
+            return Err(SampenError::InvalidParameter(format!(
+                "range {}..{} is past the end of the {} samples of {}",
+                range.start, range.end, len, vf.name
+            )));
+        }
+        Ok(range)
+    }
+}
+
+/// Computes the cross-sample entropy between every pair of channels in a
+/// file.
+///
+/// Entry `[i][j]` is `cross_sample_entropy(m, r, channel_i, channel_j)`, with
+/// channels in the order of `VitalFile::channels`. Since the same `r` is used
+/// for every channel and all channels have the same length, the matrix is
+/// symmetric, so each unordered pair is computed once. The diagonal holds the
+/// ordinary sample entropy of each channel: comparing a channel's templates
+/// against themselves would count every template as its own match. A
+/// channel the file doesn't have (see `VitalFile::missing_channels`) has a
+/// row and column of NaN.
+///
+/// # Arguments
+/// * `m` - the smaller of the two template sizes.
+/// * `r` - the distance threshold over which a match does not occur.
+/// * `vf` - the file whose channels are compared.
+///
+pub fn cross_entropy_matrix(m: usize, r: f32, vf: &VitalFile) -> Vec<Vec<f32>> {
+    let channels = vf.channels();
+    let missing = vf.missing_channels();
+    let mut matrix: Vec<Vec<f32>> = vec![vec![f32::NAN; channels.len()]; channels.len()];
+    for (i, (channel, u)) in channels.iter().enumerate() {
+        if missing.contains(channel) {
+            continue;
+        }
+        matrix[i][i] = stats::sample_entropy(m, r, u);
+        for (j, (other, v)) in channels.iter().enumerate().skip(i + 1) {
+            if missing.contains(other) {
+                continue;
+            }
+            let entropy = stats::cross_sample_entropy(m, r, u, v);
+            matrix[i][j] = entropy;
+            matrix[j][i] = entropy;
+        }
+    }
+    matrix
+}
+
+/// A single row of a vital csv file.
+///
+/// Columns are matched to fields by the header row, so the column order in
+/// the file doesn't matter as long as the header names them `name`, `mbp`,
+/// `sbp` and `dbp`. Extra columns are ignored.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct VitalRecord {
+    pub name: String,
+    pub mbp: f32,
+    pub sbp: f32,
+    pub dbp: f32,
+}
+
+/// Reads waveform data from a file into a vector.
+///
+/// The csv format records neither a sample rate nor units, so both are left
+/// unknown.
+///
+/// Due to waves being different length, they cannot be put into a single csv
+/// file without doing awkward things. For convenience, csv files for each
+/// vital filename was made. The vital_file struct holds this data.
+///
+/// # Arguments
+/// * `path` - a reference to a string filepath to a csv file.
+///
+pub fn read_csv(path: &str) -> Result<VitalFile, SampenError> {
+    read_csv_from_reader(File::open(path)?)
+}
+
+/// Reads waveform data in the csv format described by `VitalRecord` from any
+/// reader.
+///
+/// # Arguments
+/// * `rdr` - the source of the csv data.
+///
+pub fn read_csv_from_reader<R: Read>(rdr: R) -> Result<VitalFile, SampenError> {
+    read_csv_from_reader_with_header(rdr, true)
+}
+
+/// Reads waveform data in the csv format described by `VitalRecord` from any
+/// reader, with or without a header row.
+///
+/// With a header, the columns are matched by name and may be in any order,
+/// and a channel without a column is read as missing (see
+/// `VitalFile::missing_channels`). Without one, every row is data, and the
+/// columns are taken by position in the order of `VitalRecord`'s fields:
+/// `name,mbp,sbp,dbp`.
+///
+/// Blank rows, those of nothing but whitespace and commas, are skipped
+/// wherever they are, such as the trailing lines of a file an editor padded,
+/// and the last row is read whether or not the file ends in a newline.
+///
+/// # Arguments
+/// * `rdr` - the source of the csv data.
+/// * `has_header` - whether the first row names the columns.
+///
+pub fn read_csv_from_reader_with_header<R: Read>(
+    rdr: R,
+    has_header: bool,
+) -> Result<VitalFile, SampenError> {
+    // Flexible, so that a blank row of fewer fields can be skipped rather
+    // than rejected before it is seen.
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(has_header)
+        .flexible(true)
+        .from_reader(rdr);
+    let headers = if has_header {
+        let headers = reader.headers()?.clone();
+        let has_column = |column: &str| headers.iter().any(|header| header == column);
+        if !["mbp", "sbp", "dbp"].into_iter().all(has_column) {
+            // Deserializing a `VitalRecord` needs every column.
+            return read_records_with_layout(reader, &CsvLayout::default(), "");
+        }
+        Some(headers)
+    } else {
+        None
+    };
+
+    // Initialize vectors.
+    let mut name: Option<String> = None;
+    let mut mean_blood_pressures: Vec<f32> = vec![];
+    let mut systolic_blood_pressures: Vec<f32> = vec![];
+    let mut diastolic_blood_pressures: Vec<f32> = vec![];
+    // Read the values into the arrays.
+    for result in reader.records() {
+        let record = result?;
+        if is_blank_record(&record) {
+            continue;
+        }
+        let record: VitalRecord = record.deserialize(headers.as_ref())?;
+
+        name.get_or_insert(record.name);
+        mean_blood_pressures.push(record.mbp);
+        systolic_blood_pressures.push(record.sbp);
+        diastolic_blood_pressures.push(record.dbp);
+    }
+
+    let new_vital_file = VitalFile {
+        name: name.unwrap_or_default(),
+        sample_rate: None,
+        sbp: Channel::new(systolic_blood_pressures),
+        mbp: Channel::new(mean_blood_pressures),
+        dbp: Channel::new(diastolic_blood_pressures),
+    };
+
+    new_vital_file.validate()?;
+    Ok(new_vital_file)
+}
+
+/// Whether every field of a row is empty or whitespace, as in a blank line
+/// or one of bare commas.
+fn is_blank_record(record: &csv::StringRecord) -> bool {
+    record.iter().all(|field| field.trim().is_empty())
+}
+
+/// Reads waveform data like `read_csv_from_reader_with_header`, splitting
+/// simple csvs itself instead of through the `csv` crate.
+///
+/// The csvs this tool writes and reads are plain numbers and a name, with no
+/// quoting, so each line can be split on commas and its fields parsed
+/// directly, without building a `csv::StringRecord` and deserializing a
+/// `VitalRecord` from it per row. Reading a 1M row file in memory took 0.56
+/// times as long this way, in a release build. The input is only read this
+/// way if it is UTF-8 and every line is simple: no quotes, no byte order
+/// mark, no bare carriage returns, a field for every column, and parseable
+/// numbers. Otherwise the same bytes are read again with
+/// `read_csv_from_reader_with_header`, so the result, and any error, is
+/// always the same as that function's. An error reading the input is returned
+/// as the `csv` crate would return it. Empty lines are skipped, as the `csv`
+/// crate does, and any other blank row is read again, to be skipped there.
+/// The whole input is held in memory before parsing.
+///
+/// # Arguments
+/// * `rdr` - the source of the csv data.
+/// * `has_header` - whether the first row names the columns.
+///
+pub fn read_simple_csv_from_reader<R: Read>(
+    mut rdr: R,
+    has_header: bool,
+) -> Result<VitalFile, SampenError> {
+    let mut contents: Vec<u8> = Vec::new();
+    rdr.read_to_end(&mut contents)
+        .map_err(|error| SampenError::Csv(csv::Error::from(error)))?;
+    let simple = std::str::from_utf8(&contents)
+        .ok()
+        .and_then(|contents| split_simple_csv(contents, has_header));
+    match simple {
+        Some(vital_file) => {
+            vital_file.validate()?;
+            Ok(vital_file)
+        }
+        // The csv crate reports where the input isn't UTF-8, if it isn't.
+        None => read_csv_from_reader_with_header(contents.as_slice(), has_header),
+    }
+}
+
+/// Splits a csv in the canonical layout on commas, or returns `None` if any
+/// of it isn't simple enough for that (see `read_simple_csv_from_reader`).
+fn split_simple_csv(contents: &str, has_header: bool) -> Option<VitalFile> {
+    if contents.starts_with('\u{feff}') {
+        return None;
+    }
+    let mut body = contents;
+    // What each column holds: 0 for the name, 1 to 3 for mbp, sbp and dbp,
+    // and `IGNORED` for any other column.
+    const IGNORED: usize = 4;
+    let roles: Vec<usize> = if has_header {
+        let (header, rest) = loop {
+            let (line, rest) = body.split_once('\n').unwrap_or((body, ""));
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            if !line.is_empty() || rest.is_empty() {
+                break (line, rest);
+            }
+            body = rest;
+        };
+        body = rest;
+        let roles: Vec<usize> = header
+            .split(',')
+            .map(|column| match column {
+                "name" => 0,
+                "mbp" => 1,
+                "sbp" => 2,
+                "dbp" => 3,
+                _ => IGNORED,
+            })
+            .collect();
+        // Every field must have exactly one column.
+        if (0..IGNORED).any(|role| roles.iter().filter(|r| **r == role).count() != 1) {
+            return None;
+        }
+        roles
+    } else {
+        vec![0, 1, 2, 3]
+    };
+
+    let mut name: Option<String> = None;
+    let mut channels: [Vec<f32>; 3] = Default::default();
+    let mut row = [0.0_f32; 3];
+    let mut column = 0;
+    let mut field_start = 0;
+    let bytes = body.as_bytes();
+    // Splits on every comma and newline in a single pass over the bytes, and
+    // once more at the end for a last line without a newline.
+    for end in (0..bytes.len()).chain([bytes.len()]) {
+        let byte = bytes.get(end).copied().unwrap_or(b'\n');
+        if byte == b'"' {
+            return None;
+        }
+        if byte != b',' && byte != b'\n' {
+            continue;
+        }
+        let mut field = &body[field_start..end];
+        field_start = end + 1;
+        if byte == b'\n' {
+            field = field.strip_suffix('\r').unwrap_or(field);
+            if column == 0 && field.is_empty() {
+                continue;
+            }
+        }
+        if field.contains('\r') {
+            return None;
+        }
+        match roles.get(column) {
+            Some(0) => {
+                name.get_or_insert_with(|| field.to_string());
+            }
+            Some(&channel) if channel < IGNORED => row[channel - 1] = field.parse().ok()?,
+            Some(_) => {}
+            None => return None,
+        }
+        column += 1;
+        if byte == b'\n' {
+            if column != roles.len() {
+                return None;
+            }
+            for (channel, value) in channels.iter_mut().zip(row) {
+                channel.push(value);
+            }
+            column = 0;
+        }
+    }
+    let [mean_blood_pressures, systolic_blood_pressures, diastolic_blood_pressures] = channels;
+    Some(VitalFile {
+        name: name.unwrap_or_default(),
+        sample_rate: None,
+        sbp: Channel::new(systolic_blood_pressures),
+        mbp: Channel::new(mean_blood_pressures),
+        dbp: Channel::new(diastolic_blood_pressures),
+    })
+}
+
+/// Which columns of a csv hold the name and each channel.
+///
+/// The canonical layout read by `read_csv` is the default, where each column
+/// is named after its field. `CsvLayout` lets files with other headers be
+/// read by `read_csv_with_layout`, e.g. to convert them into the canonical
+/// layout with `write_csv`.
+///
+/// A file with a header row has its columns matched by name, so they may be
+/// in any order. A file without one (`has_header: false`) has nothing to
+/// match names against, so each column must instead be given as its 0-based
+/// index, e.g. `sbp=2`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvLayout {
+    /// The column holding the file name, or `None` if the file has none and
+    /// the name is supplied by the caller.
+    pub name: Option<String>,
+    pub mbp: String,
+    pub sbp: String,
+    pub dbp: String,
+    /// Whether the first row names the columns rather than holding data.
+    pub has_header: bool,
+}
+
+impl Default for CsvLayout {
+    fn default() -> Self {
+        CsvLayout {
+            name: Some(String::from("name")),
+            mbp: String::from("mbp"),
+            sbp: String::from("sbp"),
+            dbp: String::from("dbp"),
+            has_header: true,
+        }
+    }
+}
+
+impl FromStr for CsvLayout {
+    type Err = SampenError;
+
+    /// Parses a comma separated list of `field=column` mappings, e.g.
+    /// `sbp=ART_SBP,mbp=ART_MBP,dbp=ART_DBP`. Fields that aren't mentioned
+    /// keep their canonical column name, except `name`, which is taken to be
+    /// missing from the file. The file is taken to have a header.
+    fn from_str(mapping: &str) -> Result<Self, Self::Err> {
+        let mut layout = CsvLayout {
+            name: None,
+            ..CsvLayout::default()
+        };
+        for pair in mapping.split(',').filter(|pair| !pair.trim().is_empty()) {
+            let (field, column) = pair.split_once('=').ok_or_else(|| {
+                SampenError::InvalidParameter(format!("expected field=column, got {}", pair))
+            })?;
+            let column = column.trim().to_string();
+            match field.trim() {
+                "name" => layout.name = Some(column),
+                "mbp" => layout.mbp = column,
+                "sbp" => layout.sbp = column,
+                "dbp" => layout.dbp = column,
+                other => {
+                    return Err(SampenError::InvalidParameter(format!(
+                        "unknown field {}",
+                        other
+                    )))
+                }
+            }
+        }
+        Ok(layout)
+    }
+}
+
+/// Reads waveform data from a csv whose columns are given by `layout`.
+///
+/// Values may be surrounded by whitespace, columns not in the layout are
+/// ignored, and blank rows are skipped as by
+/// `read_csv_from_reader_with_header`. If the layout has no name column, the
+/// file is named `default_name`. With a header, a channel whose column isn't
+/// in it is read as missing (see `VitalFile::missing_channels`), and only a
+/// header without any of the channels, or without the name column, fails with
+/// `SampenError::MissingColumn`. Without a header, a column that isn't an
+/// index fails with `SampenError::InvalidParameter`, and one past the end of
+/// the rows with `SampenError::MissingColumn`.
+///
+/// # Arguments
+/// * `rdr` - the source of the csv data.
+/// * `layout` - which columns hold the name and each channel.
+/// * `default_name` - the name used when the layout has no name column.
+///
+pub fn read_csv_with_layout<R: Read>(
+    rdr: R,
+    layout: &CsvLayout,
+    default_name: &str,
+) -> Result<VitalFile, SampenError> {
+    let reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .has_headers(layout.has_header)
+        .flexible(true)
+        .from_reader(rdr);
+    read_records_with_layout(reader, layout, default_name)
+}
+
+/// Reads the rows of a csv reader set up for `layout`, as
+/// `read_csv_with_layout`.
+fn read_records_with_layout<R: Read>(
+    mut reader: csv::Reader<R>,
+    layout: &CsvLayout,
+    default_name: &str,
+) -> Result<VitalFile, SampenError> {
+    let headers = if layout.has_header {
+        Some(reader.headers()?.clone())
+    } else {
+        None
+    };
+    let column = |column: &str| -> Result<usize, SampenError> {
+        match &headers {
+            Some(headers) => headers
+                .iter()
+                .position(|header| header == column)
+                .ok_or_else(|| SampenError::MissingColumn(column.to_string())),
+            None => column.parse().map_err(|_| {
+                SampenError::InvalidParameter(format!(
+                    "without a header, columns are given by index, got {}",
+                    column
+                ))
+            }),
+        }
+    };
+    let name_column = layout.name.as_deref().map(column).transpose()?;
+    // With a header, a channel without a column is missing.
+    let channel_column = |channel: &str| match column(channel) {
+        Err(SampenError::MissingColumn(_)) => Ok(None),
+        found => found.map(Some),
+    };
+    let (mbp_column, sbp_column, dbp_column) = (
+        channel_column(&layout.mbp)?,
+        channel_column(&layout.sbp)?,
+        channel_column(&layout.dbp)?,
+    );
+    if mbp_column.is_none() && sbp_column.is_none() && dbp_column.is_none() {
+        return Err(SampenError::MissingColumn(layout.mbp.clone()));
+    }
+
+    let mut name: Option<String> = None;
+    let mut mean_blood_pressures: Vec<f32> = vec![];
+    let mut systolic_blood_pressures: Vec<f32> = vec![];
+    let mut diastolic_blood_pressures: Vec<f32> = vec![];
+    for result in reader.records() {
+        let record = result?;
+        if is_blank_record(&record) {
+            continue;
+        }
+        let value = |column: usize| -> Result<f32, SampenError> {
+            let field = record
+                .get(column)
+                .ok_or_else(|| SampenError::MissingColumn(column.to_string()))?;
+            field
+                .trim()
+                .parse()
+                .map_err(
+                    |error: std::num::ParseFloatError| SampenError::InvalidValue {
+                        value: field.to_string(),
+                        reason: error.to_string(),
+                    },
+                )
+        };
+        if let Some(column) = name_column {
+            name.get_or_insert_with(|| record.get(column).unwrap_or_default().to_string());
+        }
+        for (column, values) in [
+            (mbp_column, &mut mean_blood_pressures),
+            (sbp_column, &mut systolic_blood_pressures),
+            (dbp_column, &mut diastolic_blood_pressures),
+        ] {
+            if let Some(column) = column {
+                values.push(value(column)?);
+            }
+        }
+    }
+
+    let channel = |column: Option<usize>, values: Vec<f32>| match column {
+        Some(_) => Channel::new(values),
+        None => Channel::missing(),
+    };
+    let new_vital_file = VitalFile {
+        name: name.unwrap_or_else(|| default_name.to_string()),
+        sample_rate: None,
+        sbp: channel(sbp_column, systolic_blood_pressures),
+        mbp: channel(mbp_column, mean_blood_pressures),
+        dbp: channel(dbp_column, diastolic_blood_pressures),
+    };
+
+    new_vital_file.validate()?;
+    Ok(new_vital_file)
+}
+
+/// Writes a file in the canonical csv layout read by `read_csv`.
+///
+/// The layout has a column for every channel, so a file with a missing
+/// channel fails with `SampenError::InvalidFile`.
+///
+/// # Arguments
+/// * `vf` - the file to write.
+/// * `wtr` - where the csv is written.
+///
+pub fn write_csv<W: Write>(vf: &VitalFile, wtr: W) -> Result<(), SampenError> {
+    let missing = vf.missing_channels();
+    if !missing.is_empty() {
+        return Err(SampenError::InvalidFile {
+            name: vf.name.clone(),
+            reason: format!("missing channels {}", missing.join(", ")),
+        });
+    }
+    let mut writer = csv::Writer::from_writer(wtr);
+    for ((mbp, sbp), dbp) in vf.mbp.data.iter().zip(&vf.sbp.data).zip(&vf.dbp.data) {
+        writer.serialize(VitalRecord {
+            name: vf.name.clone(),
+            mbp: *mbp,
+            sbp: *sbp,
+            dbp: *dbp,
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// One line of an NDJSON file of vital files: a JSON object holding a whole
+/// file, e.g. `{"name":"case1","mbp":[90,91],"sbp":[120,121],"dbp":[70,71]}`.
+/// `sample_rate` may be left out, and so may a channel the file is missing.
+#[cfg(feature = "ndjson")]
+#[derive(Debug, Serialize, Deserialize)]
+struct NdjsonRecord {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sample_rate: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mbp: Option<Vec<f32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sbp: Option<Vec<f32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dbp: Option<Vec<f32>>,
+}
+
+#[cfg(feature = "ndjson")]
+impl Channel {
+    /// The channel of an NDJSON field, missing if the field is.
+    fn from_field(field: Option<Vec<f32>>) -> Self {
+        field.map_or_else(Channel::missing, Channel::new)
+    }
+
+    /// The NDJSON field of a channel, left out if it is missing.
+    fn to_field(&self) -> Option<Vec<f32>> {
+        (!self.missing).then(|| self.data.clone())
+    }
+}
+
+/// Reads every vital file in an NDJSON file, one per line.
+///
+/// Lines that aren't a valid record are skipped with a warning on stderr
+/// naming the line, so that one corrupt record doesn't lose the rest; use
+/// `read_ndjson_from_reader` to handle them otherwise.
+///
+/// # Arguments
+/// * `path` - the path of the NDJSON file.
+///
+#[cfg(feature = "ndjson")]
+pub fn read_ndjson(path: &str) -> Result<Vec<VitalFile>, SampenError> {
+    let file = File::open(path)?;
+    read_ndjson_from_reader(std::io::BufReader::new(file), |line, error| {
+        eprintln!("Skipping line {} of {}: {}", line, path, error)
+    })
+}
+
+/// Reads every vital file in NDJSON from any reader, passing the line
+/// number (from 1) and error of each line that isn't a valid record to
+/// `on_skip`. Blank lines are ignored. Each file is validated as by the csv
+/// readers.
+///
+/// # Arguments
+/// * `rdr` - the source of the NDJSON data.
+/// * `on_skip` - called with every line that is skipped.
+///
+#[cfg(feature = "ndjson")]
+pub fn read_ndjson_from_reader<R: std::io::BufRead>(
+    rdr: R,
+    mut on_skip: impl FnMut(usize, SampenError),
+) -> Result<Vec<VitalFile>, SampenError> {
+    let mut vital_files: Vec<VitalFile> = Vec::new();
+    for (ix, line) in rdr.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parsed = serde_json::from_str::<NdjsonRecord>(&line)
+            .map_err(SampenError::from)
+            .and_then(|record| {
+                let vital_file = VitalFile {
+                    name: record.name,
+                    sample_rate: record.sample_rate,
+                    sbp: Channel::from_field(record.sbp),
+                    mbp: Channel::from_field(record.mbp),
+                    dbp: Channel::from_field(record.dbp),
+                };
+                vital_file.validate()?;
+                Ok(vital_file)
+            });
+        match parsed {
+            Ok(vital_file) => vital_files.push(vital_file),
+            Err(error) => on_skip(ix + 1, error),
+        }
+    }
+    Ok(vital_files)
+}
+
+/// Writes vital files as NDJSON, one per line, in the format read by
+/// `read_ndjson`. Units are not written.
+///
+/// # Arguments
+/// * `vital_files` - the files to write.
+/// * `wtr` - where the NDJSON is written.
+///
+#[cfg(feature = "ndjson")]
+pub fn write_ndjson<W: Write>(vital_files: &[VitalFile], mut wtr: W) -> Result<(), SampenError> {
+    for vf in vital_files {
+        let record = NdjsonRecord {
+            name: vf.name.clone(),
+            sample_rate: vf.sample_rate,
+            mbp: vf.mbp.to_field(),
+            sbp: vf.sbp.to_field(),
+            dbp: vf.dbp.to_field(),
+        };
+        serde_json::to_writer(&mut wtr, &record)?;
+        wtr.write_all(b"\n")?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_vital_file() -> VitalFile {
+        VitalFile {
+            name: String::from("example"),
+            sample_rate: None,
+            sbp: Channel::new(vec![120.0, 121.0, 119.0]),
+            mbp: Channel::new(vec![90.0, 91.0, 89.0]),
+            dbp: Channel::new(vec![70.0, 71.0, 69.0]),
+        }
+    }
+
+    #[cfg(feature = "ndjson")]
+    #[test]
+    fn test_ndjson_round_trip_skips_malformed_lines() {
+        let fixture = concat!(
+            r#"{"name":"case1","sample_rate":100.0,"mbp":[90.0,91.5],"sbp":[120.0,121.0],"dbp":[70.0,71.0]}"#,
+            "\n",
+            r#"{"name":"truncated","mbp":[90.0"#,
+            "\n\n",
+            r#"{"name":"ragged","mbp":[90.0],"sbp":[120.0,121.0],"dbp":[70.0]}"#,
+            "\n",
+            r#"{"name":"case2","mbp":[88.0],"sbp":[110.0],"dbp":[65.0]}"#,
+            "\n",
+        );
+        let mut skipped: Vec<usize> = Vec::new();
+        let vital_files =
+            read_ndjson_from_reader(fixture.as_bytes(), |line, _| skipped.push(line)).unwrap();
+        assert_eq!(vec![2, 4], skipped);
+        assert_eq!(2, vital_files.len());
+        assert_eq!("case1", vital_files[0].name);
+        assert_eq!(Some(100.0), vital_files[0].sample_rate);
+        assert_eq!(vec![90.0, 91.5], vital_files[0].mbp.data);
+        assert_eq!(None, vital_files[1].sample_rate);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        write_ndjson(&vital_files, &mut buffer).unwrap();
+        let written = String::from_utf8(buffer).unwrap();
+        let expected: Vec<&str> = fixture
+            .lines()
+            .enumerate()
+            .filter(|(ix, line)| !line.is_empty() && !skipped.contains(&(ix + 1)))
+            .map(|(_, line)| line)
+            .collect();
+        assert_eq!(expected, written.lines().collect::<Vec<&str>>());
+    }
+
+    #[test]
+    fn test_sample_range() {
+        assert_eq!(
+            SampleRange::Samples(1, 3),
+            "1:3".parse::<SampleRange>().unwrap()
+        );
+        assert_eq!(
+            SampleRange::Seconds(0.5, 1.5),
+            "0.5s:1.5s".parse::<SampleRange>().unwrap()
+        );
+        for invalid in ["1-3", "1:3s", "a:3", "-1s:2s"] {
+            assert!(matches!(
+                invalid.parse::<SampleRange>(),
+                Err(SampenError::InvalidParameter(_))
+            ));
+        }
+
+        let mut vital_file = example_vital_file();
+        assert_eq!(
+            0..3,
+            SampleRange::Samples(0, 3).resolve(&vital_file).unwrap()
+        );
+        for out_of_bounds in [SampleRange::Samples(2, 2), SampleRange::Samples(1, 4)] {
+            assert!(matches!(
+                out_of_bounds.resolve(&vital_file),
+                Err(SampenError::InvalidParameter(_))
+            ));
+        }
+        assert!(matches!(
+            SampleRange::Seconds(0.0, 1.0).resolve(&vital_file),
+            Err(SampenError::MissingSampleRate)
+        ));
+        vital_file.sample_rate = Some(2.0);
+        let range = SampleRange::Seconds(0.5, 1.5).resolve(&vital_file).unwrap();
+        assert_eq!(1..3, range);
+        vital_file.restrict(range);
+        assert_eq!(vec![121.0, 119.0], vital_file.sbp.data);
+        assert_eq!(vec![71.0, 69.0], vital_file.dbp.data);
+    }
+
+    #[test]
+    fn test_read_csv_matches_columns_by_header() {
+        let in_order = "name,mbp,sbp,dbp\ncase1,90,120,70\ncase1,91,121,71\n";
+        let reordered = "dbp,name,sbp,extra,mbp\n70,case1,120,x,90\n71,case1,121,y,91\n";
+        for csv in [in_order, reordered] {
+            let vital_file = read_csv_from_reader(csv.as_bytes()).unwrap();
+            assert_eq!("case1", vital_file.name);
+            assert_eq!(vec![120.0, 121.0], vital_file.sbp.data);
+            assert_eq!(vec![90.0, 91.0], vital_file.mbp.data);
+            assert_eq!(vec![70.0, 71.0], vital_file.dbp.data);
+            assert_eq!(None, vital_file.sample_rate);
+        }
+    }
+
+    #[test]
+    fn test_read_csv_rejects_missing_columns_and_empty_files() {
+        assert!(matches!(
+            read_csv_from_reader("name,map\ncase1,90\n".as_bytes()),
+            Err(SampenError::MissingColumn(column)) if column == "mbp"
+        ));
+        assert!(matches!(
+            read_csv_from_reader("name,mbp,sbp,dbp\n".as_bytes()),
+            Err(SampenError::InvalidFile { .. })
+        ));
+        assert!(matches!(
+            read_csv("no/such/file.csv"),
+            Err(SampenError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_missing_channel_is_read_as_missing() {
+        let csv = "name,sbp,mbp\ncase1,120,90\ncase1,121,91\n";
+        for vital_file in [
+            read_csv_from_reader(csv.as_bytes()).unwrap(),
+            read_simple_csv_from_reader(csv.as_bytes(), true).unwrap(),
+        ] {
+            assert_eq!("case1", vital_file.name);
+            assert_eq!(vec!["dbp"], vital_file.missing_channels());
+            assert_eq!(vec![90.0, 91.0], vital_file.mbp.data);
+            assert!(vital_file.dbp.missing && vital_file.dbp.data.is_empty());
+            assert_eq!(2, vital_file.num_samples());
+            assert!(matches!(
+                write_csv(&vital_file, Vec::new()),
+                Err(SampenError::InvalidFile { .. })
+            ));
+        }
+
+        #[cfg(feature = "ndjson")]
+        {
+            let files = [read_csv_from_reader(csv.as_bytes()).unwrap()];
+            let mut buffer: Vec<u8> = Vec::new();
+            write_ndjson(&files, &mut buffer).unwrap();
+            assert!(!String::from_utf8_lossy(&buffer).contains("dbp"));
+            let read = read_ndjson_from_reader(buffer.as_slice(), |_, error| panic!("{}", error));
+            assert_eq!(files.to_vec(), read.unwrap());
+        }
+    }
+
+    #[test]
+    fn test_cross_entropy_matrix() {
+        let wave = |offset: usize| -> Vec<f32> {
+            (0..120)
+                .map(|x| (((x + offset) * 13) % 17) as f32)
+                .collect()
+        };
+        let vital_file = VitalFile {
+            name: String::from("example"),
+            sample_rate: None,
+            sbp: Channel::new(wave(0)),
+            mbp: Channel::new(wave(3)),
+            dbp: Channel::new(wave(5)),
+        };
+        let matrix = cross_entropy_matrix(2, 3.0, &vital_file);
+        assert_eq!(
+            stats::sample_entropy(2, 3.0, &vital_file.sbp.data),
+            matrix[0][0]
+        );
+        assert_eq!(
+            stats::cross_sample_entropy(2, 3.0, &vital_file.sbp.data, &vital_file.dbp.data),
+            matrix[0][2]
+        );
+        assert_eq!(
+            stats::cross_sample_entropy(2, 3.0, &vital_file.dbp.data, &vital_file.mbp.data),
+            matrix[2][1]
+        );
+        assert_eq!(matrix[1][2], matrix[2][1]);
+    }
+
+    #[test]
+    fn test_cross_entropy_matrix_of_a_file_missing_a_channel() {
+        let csv: String = std::iter::once(String::from("name,sbp,mbp"))
+            .chain((0..120).map(|x| format!("case1,{},{}", (x * 13) % 17, ((x + 3) * 13) % 17)))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let vital_file = read_csv_from_reader(csv.as_bytes()).unwrap();
+        assert_eq!(vec!["dbp"], vital_file.missing_channels());
+        let matrix = cross_entropy_matrix(2, 3.0, &vital_file);
+        assert_eq!(
+            stats::sample_entropy(2, 3.0, &vital_file.sbp.data),
+            matrix[0][0]
+        );
+        assert_eq!(
+            stats::cross_sample_entropy(2, 3.0, &vital_file.sbp.data, &vital_file.mbp.data),
+            matrix[0][1]
+        );
+        assert!(matrix[2].iter().all(|entropy| entropy.is_nan()));
+        assert!(matrix.iter().all(|row| row[2].is_nan()));
+    }
+
+    #[test]
+    fn test_validate_accepts_equal_channels() {
+        assert!(example_vital_file().validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_rejects_ragged_channels() {
+        let mut ragged = example_vital_file();
+        ragged.dbp.data.pop();
+        assert!(matches!(
+            ragged.validate(),
+            Err(SampenError::InvalidFile { .. })
+        ));
+    }
+
+    #[test]
+    fn test_new_validates_channels() {
+        let vital_file = VitalFile::new(
+            String::from("example"),
+            vec![120.0, 121.0, 119.0],
+            vec![90.0, 91.0, 89.0],
+            vec![70.0, 71.0, 69.0],
+        )
+        .unwrap();
+        assert_eq!(example_vital_file(), vital_file);
+
+        let ragged = VitalFile::new(
+            String::from("ragged"),
+            vec![120.0, 121.0],
+            vec![90.0, 91.0, 89.0],
+            vec![70.0, 71.0],
+        );
+        match ragged {
+            Err(SampenError::InvalidFile { name, reason }) => {
+                assert_eq!("ragged", name);
+                assert!(reason.contains("mbp"), "{}", reason);
+            }
+            other => panic!("expected InvalidFile, got {:?}", other),
+        }
+        assert!(VitalFile::new(String::from("empty"), vec![], vec![], vec![]).is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_empty_channels() {
+        let empty = VitalFile {
+            name: String::from("empty"),
+            sample_rate: None,
+            sbp: Channel::default(),
+            mbp: Channel::default(),
+            dbp: Channel::default(),
+        };
+        assert!(matches!(
+            empty.validate(),
+            Err(SampenError::InvalidFile { .. })
+        ));
+    }
+
+    #[test]
+    fn test_epoch_sample_entropy_requires_sample_rate() {
+        let mut vital_file = example_vital_file();
+        assert!(matches!(
+            vital_file.epoch_sample_entropy(2, 1.0, 1.0),
+            Err(SampenError::MissingSampleRate)
+        ));
+        vital_file.sample_rate = Some(1.0);
+        let epochs = vital_file.epoch_sample_entropy(2, 1.0, 3.0).unwrap();
+        assert_eq!("dbp", epochs[2].0);
+        assert_eq!(1, epochs[2].1.len());
+    }
+
+    #[test]
+    fn test_csv_layout_from_str() {
+        let layout: CsvLayout = "sbp = ART_SBP, dbp=ART_DBP".parse().unwrap();
+        assert_eq!(None, layout.name);
+        assert_eq!("ART_SBP", layout.sbp);
+        assert_eq!("mbp", layout.mbp);
+        assert_eq!("ART_DBP", layout.dbp);
+        assert!(matches!(
+            "sbp".parse::<CsvLayout>(),
+            Err(SampenError::InvalidParameter(_))
+        ));
+        assert!(matches!(
+            "hr=HR".parse::<CsvLayout>(),
+            Err(SampenError::InvalidParameter(_))
+        ));
+    }
+
+    #[test]
+    fn test_messy_csv_round_trips_into_canonical_layout() {
+        let messy =
+            "Time, ART_DBP ,ART_SBP,ART_MBP\n0.0, 70, 120 ,90\n0.5,71,121,91\n1.0,69,119,89\n";
+        let layout: CsvLayout = "sbp=ART_SBP,mbp=ART_MBP,dbp=ART_DBP".parse().unwrap();
+        let prepared = read_csv_with_layout(messy.as_bytes(), &layout, "case7").unwrap();
+        let mut canonical: Vec<u8> = Vec::new();
+        write_csv(&prepared, &mut canonical).unwrap();
+        assert!(String::from_utf8(canonical.clone())
+            .unwrap()
+            .starts_with("name,mbp,sbp,dbp\ncase7,90.0,120.0,70.0\n"));
+        let reread = read_csv_from_reader(canonical.as_slice()).unwrap();
+        assert_eq!("case7", reread.name);
+        assert_eq!(vec![120.0, 121.0, 119.0], reread.sbp.data);
+        assert_eq!(vec![90.0, 91.0, 89.0], reread.mbp.data);
+        assert_eq!(vec![70.0, 71.0, 69.0], reread.dbp.data);
+    }
+
+    #[test]
+    fn test_read_csv_with_layout_rejects_missing_columns_and_bad_values() {
+        let layout = CsvLayout::default();
+        assert!(matches!(
+            read_csv_with_layout("mbp,sbp,dbp\n1,2,3\n".as_bytes(), &layout, "x"),
+            Err(SampenError::MissingColumn(column)) if column == "name"
+        ));
+        assert!(matches!(
+            read_csv_with_layout("name,mbp,sbp,dbp\nc,1,2,x\n".as_bytes(), &layout, "x"),
+            Err(SampenError::InvalidValue { value, .. }) if value == "x"
+        ));
+    }
+
+    #[test]
+    fn test_simple_csv_path_matches_csv_crate() {
+        let simple = "dbp,name,sbp,mbp,extra\r\n70,case1,120,90.5,x\n\n71,case1,121.25,91,y\n";
+        let not_utf8: &[u8] = b"name,mbp,sbp,dbp\ncase\xff1,90,120,70\n";
+        let headerless = "case1,90.5,120,70\ncase1,91,121.25,71";
+        let quoted = "name,mbp,sbp,dbp\n\"case, 1\",90,120,70\n";
+        let ragged = "name,mbp,sbp,dbp\ncase1,90,120,70\ncase1,91,121\n";
+        let not_numbers = "name,mbp,sbp,dbp\ncase1,90,high,70\n";
+        for (csv, has_header) in [
+            (simple.as_bytes(), true),
+            (headerless.as_bytes(), false),
+            (quoted.as_bytes(), true),
+            (ragged.as_bytes(), true),
+            (not_numbers.as_bytes(), true),
+            (not_utf8, true),
+            (b"", true),
+        ] {
+            let expected = read_csv_from_reader_with_header(csv, has_header);
+            let fast = read_simple_csv_from_reader(csv, has_header);
+            match (expected, fast) {
+                (Ok(expected), Ok(fast)) => assert_eq!(expected, fast),
+                (Err(expected), Err(fast)) => {
+                    assert_eq!(expected.to_string(), fast.to_string())
+                }
+                (expected, fast) => panic!("{:?} vs {:?}", expected, fast),
+            }
+        }
+        assert!(split_simple_csv(simple, true).is_some());
+        assert!(split_simple_csv(headerless, false).is_some());
+        let vital_file = read_simple_csv_from_reader(simple.as_bytes(), true).unwrap();
+        assert_eq!(vec![120.0, 121.25], vital_file.sbp.data);
+        assert_eq!(
+            "case, 1",
+            read_simple_csv_from_reader(quoted.as_bytes(), true)
+                .unwrap()
+                .name
+        );
+        assert!(split_simple_csv(quoted, true).is_none());
+        assert!(split_simple_csv(ragged, true).is_none());
+        assert!(matches!(
+            read_simple_csv_from_reader(not_utf8, true),
+            Err(SampenError::Csv(_))
+        ));
+    }
+
+    #[test]
+    fn test_blank_rows_and_missing_final_newline() {
+        let no_final_newline = "name,mbp,sbp,dbp\ncase1,90,120,70\ncase1,91,121,71";
+        let trailing_blanks =
+            "name,mbp,sbp,dbp\r\ncase1,90,120,70\r\n\r\ncase1,91,121,71\n  \n,,,\n\n";
+        let missing_channel = "name,sbp,mbp\ncase1,120,90\n , \ncase1,121,91\n,,\n";
+        let headerless = "case1,90,120,70\ncase1,91,121,71\n \n";
+        for (csv, has_header) in [
+            (no_final_newline, true),
+            (trailing_blanks, true),
+            (missing_channel, true),
+            (headerless, false),
+        ] {
+            for read in [
+                read_csv_from_reader_with_header(csv.as_bytes(), has_header),
+                read_simple_csv_from_reader(csv.as_bytes(), has_header),
+            ] {
+                let vital_file = read.unwrap();
+                assert_eq!(2, vital_file.num_samples(), "{:?}", csv);
+                assert_eq!(vec![91.0], vital_file.mbp.data[1..]);
+            }
+        }
+        let layout = CsvLayout::default();
+        let read = read_csv_with_layout(trailing_blanks.as_bytes(), &layout, "x").unwrap();
+        assert_eq!(vec![121.0], read.sbp.data[1..]);
+
+        // A row with some of its fields is still an error.
+        assert!(
+            read_csv_from_reader("name,mbp,sbp,dbp\ncase1,90,120,70\ncase1,,,\n".as_bytes())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_headerless_csv_keeps_first_row() {
+        let headerless = "case3,90,120,70\ncase3,91,121,71\n";
+        let canonical = read_csv_from_reader_with_header(headerless.as_bytes(), false).unwrap();
+        assert_eq!("case3", canonical.name);
+        assert_eq!(vec![120.0, 121.0], canonical.sbp.data);
+        assert_eq!(vec![70.0, 71.0], canonical.dbp.data);
+
+        let headerless = "0.0, 70, 120 ,90\n0.5,71,121,91\n";
+        let layout = CsvLayout {
+            has_header: false,
+            ..CsvLayout::from_str("dbp=1,sbp=2,mbp=3").unwrap()
+        };
+        let indexed = read_csv_with_layout(headerless.as_bytes(), &layout, "case4").unwrap();
+        assert_eq!(vec![120.0, 121.0], indexed.sbp.data);
+        assert_eq!(vec![90.0, 91.0], indexed.mbp.data);
+        assert!(matches!(
+            read_csv_with_layout(
+                headerless.as_bytes(),
+                &CsvLayout {
+                    has_header: false,
+                    ..CsvLayout::default()
+                },
+                "case4"
+            ),
+            Err(SampenError::InvalidParameter(_))
+        ));
+        let past_end = CsvLayout {
+            has_header: false,
+            ..CsvLayout::from_str("dbp=1,sbp=2,mbp=4").unwrap()
+        };
+        assert!(matches!(
+            read_csv_with_layout(headerless.as_bytes(), &past_end, "case4"),
+            Err(SampenError::MissingColumn(column)) if column == "4"
+        ));
+    }
+
+    #[test]
+    fn test_headered_csv_maps_columns_by_name() {
+        let reordered = "dbp,sbp,name,mbp\n70,120,case5,90\n71,121,case5,91\n";
+        let vital_file = read_csv_from_reader_with_header(reordered.as_bytes(), true).unwrap();
+        assert_eq!("case5", vital_file.name);
+        assert_eq!(vec![120.0, 121.0], vital_file.sbp.data);
+        assert_eq!(vec![90.0, 91.0], vital_file.mbp.data);
+        assert_eq!(vec![70.0, 71.0], vital_file.dbp.data);
+    }
+}
@@ -0,0 +1,30 @@
+//! JavaScript bindings for the core entropy functions, enabled by the `wasm`
+//! feature.
+//!
+//! Slices of `f32` are passed from JavaScript as a `Float32Array`, and
+//! returned vectors come back as one. Nothing here uses rayon, so the module
+//! runs on the single thread a browser gives it.
+use wasm_bindgen::prelude::*;
+
+use crate::multiscale::{self, MeanGrain, MultiscaleOptions};
+use crate::stats;
+
+/// Computes sample entropy for a waveform. See `stats::sample_entropy`.
+#[wasm_bindgen]
+pub fn sample_entropy(m: usize, r: f32, data: &[f32]) -> f32 {
+    stats::sample_entropy(m, r, data)
+}
+
+/// Computes multiscale entropy for scales 1 through `max_scale` with the
+/// mean coarse-graining. See `multiscale::multiscale_entropy`.
+#[wasm_bindgen]
+pub fn multiscale_entropy(m: usize, r: f32, data: &[f32], max_scale: usize) -> Vec<f32> {
+    multiscale::multiscale_entropy(
+        m,
+        r,
+        data,
+        max_scale,
+        &MeanGrain,
+        MultiscaleOptions::default(),
+    )
+}
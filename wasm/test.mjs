@@ -0,0 +1,24 @@
+// Smoke test for the wasm build. Generate the bindings first with:
+//
+//   cargo build --lib --release --target wasm32-unknown-unknown --no-default-features --features wasm
+//   wasm-bindgen --target nodejs --out-dir pkg \
+//       target/wasm32-unknown-unknown/release/sample_entropy.wasm
+//   node wasm/test.mjs
+import assert from "node:assert/strict";
+import { createRequire } from "node:module";
+
+const require = createRequire(import.meta.url);
+const { sample_entropy, multiscale_entropy } = require("../pkg/sample_entropy.js");
+
+// Period-2 signal: at m=2, 8 templates in two phases of 4 give 12 matches,
+// and the 7 templates at m+1 split 4/3 giving 9, so sampen is -ln(9/12).
+const data = Float32Array.from({ length: 9 }, (_, i) => i % 2);
+const sampen = sample_entropy(2, 0.5, data);
+assert.ok(Math.abs(sampen - -Math.log(9 / 12)) < 1e-6, `unexpected sampen ${sampen}`);
+
+const mse = multiscale_entropy(2, 0.5, data, 1);
+assert.ok(mse instanceof Float32Array);
+assert.equal(mse.length, 1);
+assert.equal(mse[0], sampen);
+
+console.log("wasm bindings ok");